@@ -1,23 +1,59 @@
 use clap::Parser;
 use microcosm::{
-    device::{Rtc, Serial, I8042},
-    Hypervisor,
+    device::{PvPanic, Rtc, Serial, I8042},
+    CoreDumper, ExitProfiler, GdbSocket, HugePageSize, Hypervisor, KvmStats, LazyRestore,
+    MemoryBackend, MemoryHotplug, Migrator, Prefault, PvFeature, SchedPolicy, Snapshotter,
+    ThreadConfig, VmExit,
 };
 use nix::sys::termios::{tcgetattr, tcsetattr, LocalFlags, SetArg, Termios};
 use std::{
     ffi::{CString, NulError},
-    io::Read,
-    num::NonZeroUsize,
-    os::fd::AsFd,
+    fs::File,
+    io::{BufWriter, Read, Write},
+    net::{TcpListener, TcpStream},
+    num::{NonZeroU32, NonZeroUsize},
+    os::{
+        fd::AsFd,
+        unix::net::{UnixListener, UnixStream},
+    },
     path::PathBuf,
-    sync::{Arc, Mutex},
+    sync::{mpsc, Arc, Mutex},
+    time::{Duration, Instant},
 };
 
 #[derive(Debug, Parser)]
 struct Cli {
     /// Path to Kernel image
-    #[clap(short, long)]
-    kernel: PathBuf,
+    #[clap(short, long, required_unless_present_any = ["restore", "incoming"])]
+    kernel: Option<PathBuf>,
+
+    /// Restore the guest from a snapshot instead of booting a kernel
+    #[clap(
+        long,
+        conflicts_with_all = ["kernel", "cpus", "memory", "cmdline", "initrd", "modules"]
+    )]
+    restore: Option<PathBuf>,
+
+    /// Receive the guest from another instance migrating it to a socket
+    /// (unix:PATH or tcp:HOST:PORT) instead of booting a kernel
+    #[clap(
+        long,
+        value_parser = try_parse_migration_socket,
+        conflicts_with_all = ["kernel", "restore", "cpus", "memory", "cmdline", "initrd", "modules"]
+    )]
+    incoming: Option<MigrationSocket>,
+
+    /// Load the memory of the restored guest as it is accessed
+    /// (on-demand, or prefetch to also load it in the background)
+    #[clap(
+        long,
+        requires = "restore",
+        conflicts_with_all = ["kernel", "incoming"],
+        value_parser = try_parse_lazy_restore,
+        num_args = 0..=1,
+        default_missing_value = "on-demand"
+    )]
+    lazy_restore: Option<LazyRestore>,
 
     /// Number of CPUs
     #[clap(short = 'n', long, default_value = "1")]
@@ -27,6 +63,29 @@ struct Cli {
     #[clap(short, long, value_parser = try_parse_size, default_value = "64M")]
     memory: NonZeroUsize,
 
+    /// Where guest memory is allocated from
+    /// (anon, hugetlb:2M, hugetlb:1G, hugetlbfs:<PATH>, thp, memfd,
+    /// memfd:sealed or file:<PATH>)
+    #[clap(long, value_parser = try_parse_memory_backend, default_value = "anon")]
+    memory_backend: MemoryBackend,
+
+    /// Fault in guest memory before starting the guest
+    /// (populate, or touch:<THREADS> to write to it from several threads)
+    #[clap(long, value_parser = try_parse_prefault)]
+    prefault: Option<Prefault>,
+
+    #[clap(flatten)]
+    memory_flags: MemoryFlags,
+
+    /// Size of a region of memory that the guest can plug through a
+    /// virtio-mem device, requested with Ctrl-A + and Ctrl-A -
+    #[clap(
+        long,
+        value_parser = try_parse_size,
+        conflicts_with_all = ["restore", "incoming"]
+    )]
+    hotplug_memory: Option<NonZeroUsize>,
+
     /// Kernel command line
     #[clap(
         short,
@@ -43,12 +102,216 @@ struct Cli {
     /// Paths to Multiboot modules
     #[clap(long = "module")]
     modules: Vec<PathBuf>,
+
+    /// Paravirtual features to hide from the guest
+    /// (clock, nop-io-delay, async-pf, steal-time, pv-eoi, pv-unhalt,
+    /// pv-tlb-flush, pv-send-ipi, poll-control, pv-sched-yield,
+    /// msi-ext-dest-id, tsc-frequency)
+    #[clap(long = "disable-pv-feature")]
+    disabled_pv_features: Vec<PvFeature>,
+
+    /// Fixed TSC frequency in kHz
+    #[clap(long)]
+    tsc_khz: Option<NonZeroU32>,
+
+    /// Pin a vCPU thread to host CPUs (e.g. 0=2,4-5)
+    #[clap(long = "vcpu-affinity", value_parser = try_parse_vcpu_affinity)]
+    vcpu_affinities: Vec<(usize, CpuList)>,
+
+    /// Scheduling policy of vCPU threads (fifo:<PRIORITY> or nice:<NICE>)
+    #[clap(long, value_parser = try_parse_sched_policy)]
+    vcpu_sched: Option<SchedPolicy>,
+
+    /// Host CPUs to pin the device I/O threads to (e.g. 0,2-3)
+    #[clap(long, value_parser = try_parse_cpu_list)]
+    io_affinity: Option<CpuList>,
+
+    /// Scheduling policy of the device I/O threads
+    /// (fifo:<PRIORITY> or nice:<NICE>)
+    #[clap(long, value_parser = try_parse_sched_policy)]
+    io_sched: Option<SchedPolicy>,
+
+    /// Maximum halt polling time in nanoseconds (0 disables halt polling)
+    #[clap(long)]
+    halt_poll_ns: Option<u32>,
+
+    /// Exit instead of rebooting when the guest requests a reset
+    #[clap(long)]
+    no_reboot: bool,
+
+    /// Exit to the VMM on every byte written to the serial port instead of
+    /// letting KVM buffer them
+    #[clap(long)]
+    no_coalesced_io: bool,
+
+    /// Wait for a GDB connection on a socket before starting the guest
+    /// ([tcp:][HOST:]PORT or unix:PATH)
+    #[clap(long)]
+    gdb: Option<GdbSocket>,
+
+    /// Write an ELF core dump of the guest to a file when it crashes
+    /// (triple fault or KVM internal error) or when Ctrl-A d is pressed
+    #[clap(long)]
+    dump_core: Option<PathBuf>,
+
+    /// Write a snapshot of the guest to a file when Ctrl-A w is pressed
+    #[clap(long)]
+    snapshot: Option<PathBuf>,
+
+    /// Migrate the guest to another instance listening on a socket
+    /// (unix:PATH or tcp:HOST:PORT) when Ctrl-A m is pressed, then exit
+    #[clap(long, value_parser = try_parse_migration_socket)]
+    migrate_to: Option<MigrationSocket>,
+
+    /// Print VM exit statistics when the guest stops
+    /// (Ctrl-A s prints them while it runs)
+    #[clap(long)]
+    exit_stats: bool,
+
+    /// Periodically append the statistics maintained by KVM to a file
+    #[clap(long)]
+    kvm_stats: Option<PathBuf>,
+
+    /// Interval between dumps of KVM statistics in milliseconds
+    #[clap(long, default_value = "1000")]
+    kvm_stats_interval: u64,
+}
+
+#[derive(Debug, clap::Args)]
+struct MemoryFlags {
+    /// Lock guest memory so that it is never swapped out
+    #[clap(long)]
+    mlock: bool,
+
+    /// Leave guest memory out of core dumps of this process
+    #[clap(long)]
+    dontdump: bool,
+
+    /// Let KSM merge identical pages of guest memory
+    #[clap(long)]
+    ksm: bool,
 }
 
 fn try_parse_cmdline(s: &str) -> Result<CString, NulError> {
     CString::new(s)
 }
 
+#[derive(Debug, Clone)]
+struct CpuList(Vec<usize>);
+
+fn try_parse_cpu_list(s: &str) -> Result<CpuList, String> {
+    let mut cpus = Vec::new();
+    for part in s.split(',') {
+        let parse = |s: &str| {
+            s.trim()
+                .parse::<usize>()
+                .map_err(|e| format!("Invalid CPU number {s}: {e}"))
+        };
+        match part.split_once('-') {
+            Some((start, end)) => {
+                let (start, end) = (parse(start)?, parse(end)?);
+                if start > end {
+                    return Err(format!("Invalid CPU range {part}"));
+                }
+                cpus.extend(start..=end);
+            }
+            None => cpus.push(parse(part)?),
+        }
+    }
+    Ok(CpuList(cpus))
+}
+
+fn try_parse_vcpu_affinity(s: &str) -> Result<(usize, CpuList), String> {
+    let (id, cpus) = s
+        .split_once('=')
+        .ok_or_else(|| "Expected <ID>=<CPUS>".to_owned())?;
+    let id = id
+        .trim()
+        .parse()
+        .map_err(|e| format!("Invalid vCPU ID {id}: {e}"))?;
+    Ok((id, try_parse_cpu_list(cpus)?))
+}
+
+fn try_parse_sched_policy(s: &str) -> Result<SchedPolicy, String> {
+    let (policy, value) = s
+        .split_once(':')
+        .ok_or_else(|| "Expected fifo:<PRIORITY> or nice:<NICE>".to_owned())?;
+    let value = value
+        .trim()
+        .parse()
+        .map_err(|e| format!("Invalid value {value}: {e}"))?;
+    match policy {
+        "fifo" if (1..=99).contains(&value) => Ok(SchedPolicy::Fifo(value)),
+        "fifo" => Err("Priority must be between 1 and 99".to_owned()),
+        "nice" if (-20..=19).contains(&value) => Ok(SchedPolicy::Nice(value)),
+        "nice" => Err("Nice value must be between -20 and 19".to_owned()),
+        _ => Err(format!("Unknown scheduling policy {policy}")),
+    }
+}
+
+fn try_parse_memory_backend(s: &str) -> Result<MemoryBackend, String> {
+    let (kind, arg) = s.split_once(':').unwrap_or((s, ""));
+    match (kind, arg) {
+        ("anon", "") => Ok(MemoryBackend::Anonymous),
+        ("hugetlb", "2M") => Ok(MemoryBackend::HugeTlb(HugePageSize::Size2M)),
+        ("hugetlb", "1G") => Ok(MemoryBackend::HugeTlb(HugePageSize::Size1G)),
+        ("hugetlb", _) => Err("Hugepage size must be 2M or 1G".to_owned()),
+        ("hugetlbfs", "") => Err("Expected hugetlbfs:<PATH>".to_owned()),
+        ("hugetlbfs", path) => Ok(MemoryBackend::HugeTlbFs(path.into())),
+        ("thp", "") => Ok(MemoryBackend::TransparentHugePages),
+        ("memfd", "") => Ok(MemoryBackend::Memfd { seal: false }),
+        ("memfd", "sealed") => Ok(MemoryBackend::Memfd { seal: true }),
+        ("file", "") => Err("Expected file:<PATH>".to_owned()),
+        ("file", path) => Ok(MemoryBackend::File(path.into())),
+        _ => Err(format!("Unknown memory backend {s}")),
+    }
+}
+
+fn try_parse_prefault(s: &str) -> Result<Prefault, String> {
+    match s.split_once(':') {
+        None if s == "populate" => Ok(Prefault::Populate),
+        Some(("touch", threads)) => threads
+            .trim()
+            .parse()
+            .map(Prefault::Touch)
+            .map_err(|e| format!("Invalid number of threads {threads}: {e}")),
+        _ => Err("Expected populate or touch:<THREADS>".to_owned()),
+    }
+}
+
+fn try_parse_lazy_restore(s: &str) -> Result<LazyRestore, String> {
+    match s {
+        "on-demand" => Ok(LazyRestore::OnDemand),
+        "prefetch" => Ok(LazyRestore::Prefetch),
+        _ => Err("Expected on-demand or prefetch".to_owned()),
+    }
+}
+
+/// Socket that a guest is migrated through
+#[derive(Debug, Clone)]
+enum MigrationSocket {
+    Unix(PathBuf),
+    Tcp(String),
+}
+
+impl std::fmt::Display for MigrationSocket {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Unix(path) => write!(f, "unix:{}", path.display()),
+            Self::Tcp(addr) => write!(f, "tcp:{addr}"),
+        }
+    }
+}
+
+fn try_parse_migration_socket(s: &str) -> Result<MigrationSocket, String> {
+    if let Some(path) = s.strip_prefix("unix:") {
+        return Ok(MigrationSocket::Unix(path.into()));
+    }
+    s.strip_prefix("tcp:")
+        .map(|addr| MigrationSocket::Tcp(addr.to_owned()))
+        .ok_or_else(|| "Expected unix:<PATH> or tcp:<HOST>:<PORT>".to_owned())
+}
+
 fn try_parse_size(s: &str) -> Result<NonZeroUsize, String> {
     let s = s.trim();
     let mut chars = s.chars().peekable();
@@ -93,37 +356,226 @@ fn main() -> anyhow::Result<()> {
 
     let hypervisor = Hypervisor::new()?;
 
-    let mut builder = hypervisor
-        .guest(cli.kernel)
-        .num_cpus(cli.cpus)
-        .memory_size(cli.memory)
-        .cmdline(cli.cmdline);
-    if let Some(path) = cli.initrd {
-        builder = builder.initrd(path);
+    let mut builder = if let Some(path) = cli.restore {
+        hypervisor.restore(path)?
+    } else if let Some(socket) = &cli.incoming {
+        eprintln!("Waiting for an incoming migration on {socket}");
+        match socket {
+            MigrationSocket::Unix(path) => {
+                let listener = UnixListener::bind(path)?;
+                let (stream, _) = listener.accept()?;
+                std::fs::remove_file(path)?;
+                hypervisor.incoming_migration(stream)?
+            }
+            MigrationSocket::Tcp(addr) => {
+                let (stream, _) = TcpListener::bind(addr)?.accept()?;
+                hypervisor.incoming_migration(stream)?
+            }
+        }
+    } else {
+        let kernel = cli
+            .kernel
+            .expect("--kernel is required without --restore or --incoming");
+        let mut builder = hypervisor
+            .guest(kernel)
+            .num_cpus(cli.cpus)
+            .memory_size(cli.memory)
+            .cmdline(cli.cmdline);
+        if let Some(path) = cli.initrd {
+            builder = builder.initrd(path);
+        }
+        for path in cli.modules {
+            builder = builder.add_module(path);
+        }
+        if let Some(size) = cli.hotplug_memory {
+            builder = builder.hotplug_memory(size);
+        }
+        builder
+    };
+    builder = builder
+        .memory_backend(cli.memory_backend)
+        .lock_memory(cli.memory_flags.mlock)
+        .dontdump_memory(cli.memory_flags.dontdump)
+        .mergeable_memory(cli.memory_flags.ksm)
+        .reboot(!cli.no_reboot)
+        .coalesced_io(!cli.no_coalesced_io);
+    if let Some(prefault) = cli.prefault {
+        builder = builder.prefault(prefault);
+    }
+    if let Some(mode) = cli.lazy_restore {
+        builder = builder.lazy_restore(mode);
+    }
+    if let Some(path) = &cli.dump_core {
+        builder = builder.core_dump_on_crash(path);
+    }
+    for feature in cli.disabled_pv_features {
+        builder = builder.pv_feature(feature, false);
+    }
+    if let Some(tsc_khz) = cli.tsc_khz {
+        builder = builder.tsc_khz(tsc_khz);
+    }
+    if let Some(ns) = cli.halt_poll_ns {
+        builder = builder.halt_poll_ns(ns);
+    }
+    // Affinities of vCPUs beyond the count are left for `build` to reject.
+    let ids = (0..builder.vcpu_count()).chain(cli.vcpu_affinities.iter().map(|&(id, _)| id));
+    for id in ids {
+        let mut config = ThreadConfig::new();
+        if let Some((_, cpus)) = cli.vcpu_affinities.iter().rfind(|(i, _)| *i == id) {
+            config = config.affinity(cpus.0.iter().copied());
+        }
+        if let Some(policy) = cli.vcpu_sched {
+            config = config.policy(policy);
+        }
+        builder = builder.vcpu_thread_config(id, config);
+    }
+
+    let mut io_thread_config = ThreadConfig::new();
+    if let Some(cpus) = cli.io_affinity {
+        io_thread_config = io_thread_config.affinity(cpus.0);
     }
-    for path in cli.modules {
-        builder = builder.add_module(path);
+    if let Some(policy) = cli.io_sched {
+        io_thread_config = io_thread_config.policy(policy);
     }
+    builder = builder.device_thread_config(io_thread_config.clone());
 
-    let mut guest = builder.build()?;
-    guest.add_device(Mutex::new(I8042::new()))?;
+    let guest = builder.build()?;
+    guest.add_device(Mutex::new(I8042::new(guest.exit_trigger())))?;
     guest.add_device(Mutex::new(Rtc::new()))?;
+    guest.add_device(Mutex::new(PvPanic::new(guest.exit_trigger())))?;
 
     let serial = Arc::new(Mutex::new(Serial::new(0, guest.irq())));
     guest.add_device(serial.clone())?;
 
-    std::thread::spawn(move || guest.run());
+    if let Some(socket) = &cli.gdb {
+        let stub = guest.gdb_stub(socket)?;
+        eprintln!("Waiting for GDB connection on {socket}");
+        std::thread::Builder::new()
+            .name("gdb".to_owned())
+            .spawn(move || {
+                if let Err(e) = stub.serve() {
+                    eprintln!("GDB stub error: {e}");
+                }
+            })?;
+    }
+
+    if let Some(path) = &cli.kvm_stats {
+        let stats = guest.kvm_stats()?;
+        let file = File::create(path)?;
+        let interval = Duration::from_millis(cli.kvm_stats_interval);
+        std::thread::Builder::new()
+            .name("kvm-stats".to_owned())
+            .spawn(move || {
+                if let Err(e) = dump_kvm_stats(&stats, file, interval) {
+                    eprintln!("Failed to dump KVM statistics: {e}");
+                }
+            })?;
+    }
+
+    let profiler = guest.exit_profiler();
+    let core_dump = cli.dump_core.map(|path| (guest.core_dumper(), path));
+    let snapshot = cli.snapshot.map(|path| (guest.snapshotter(), path));
+    let migration = cli.migrate_to.map(|socket| (guest.migrator(), socket));
+    let hotplug = guest.memory_hotplug();
 
-    let stdin = std::io::stdin().lock();
-    let mut stdin = RawModeReader::new(stdin)?;
+    let handle = guest.run()?;
 
+    let raw_mode = RawMode::new(std::io::stdin())?;
+
+    let (tx, rx) = mpsc::channel();
+    {
+        let tx = tx.clone();
+        std::thread::spawn(move || {
+            let _ = tx.send(Event::Exited(handle.wait()));
+        });
+    }
+    let io_profiler = profiler.clone();
+    std::thread::Builder::new()
+        .name("io".to_owned())
+        .spawn(move || {
+            let result = io_thread_config.apply().map_err(Into::into).and_then(|()| {
+                forward_stdin(
+                    &serial,
+                    core_dump.as_ref(),
+                    snapshot.as_ref(),
+                    migration.as_ref(),
+                    hotplug.as_ref(),
+                    &io_profiler,
+                )
+            });
+            let _ = tx.send(Event::Quit(result));
+        })?;
+
+    let code = match rx.recv()? {
+        Event::Exited(exit) => {
+            let exit = exit?;
+            if !matches!(exit, VmExit::Poweroff | VmExit::Reset) {
+                eprintln!("{exit}");
+            }
+            if let Some(dump) = exit.cpu_dump() {
+                eprintln!("{dump}");
+            }
+            if cli.exit_stats {
+                eprintln!("{}", profiler.snapshot());
+            }
+            exit_code(&exit)
+        }
+        Event::Quit(result) => {
+            result?;
+            0
+        }
+    };
+    drop(raw_mode);
+    std::process::exit(code);
+}
+
+enum Event {
+    /// The guest stopped.
+    Exited(microcosm::Result<VmExit>),
+
+    /// The user requested to quit.
+    Quit(anyhow::Result<()>),
+}
+
+/// Maps the reason of a guest stop to the exit code of the process.
+fn exit_code(exit: &VmExit) -> i32 {
+    match exit {
+        VmExit::Poweroff | VmExit::Reset => 0,
+        VmExit::GuestPanic => 2,
+        VmExit::TripleFault { .. } => 3,
+        VmExit::FailEntry { .. } | VmExit::InternalError { .. } => 4,
+    }
+}
+
+/// Appends a snapshot of KVM statistics to `file` every `interval`, each
+/// preceded by a line with the elapsed time.
+fn dump_kvm_stats(stats: &KvmStats, file: File, interval: Duration) -> anyhow::Result<()> {
+    let mut writer = BufWriter::new(file);
+    let start = Instant::now();
+    loop {
+        std::thread::sleep(interval);
+        let snapshot = stats.snapshot()?;
+        writeln!(writer, "# {:.3}", start.elapsed().as_secs_f64())?;
+        write!(writer, "{snapshot}")?;
+        writer.flush()?;
+    }
+}
+
+fn forward_stdin(
+    serial: &Mutex<Serial>,
+    core_dump: Option<&(CoreDumper, PathBuf)>,
+    snapshot: Option<&(Snapshotter, PathBuf)>,
+    migration: Option<&(Migrator, MigrationSocket)>,
+    hotplug: Option<&MemoryHotplug>,
+    profiler: &ExitProfiler,
+) -> anyhow::Result<()> {
+    let mut stdin = std::io::stdin().lock();
     let mut buf = [0; 1024];
     let mut escape = false;
-    'outer: loop {
+    loop {
         match stdin.read(&mut buf) {
-            Ok(0) => break,
+            Ok(0) => return Ok(()),
             Ok(n) => {
-                let mut serial = serial.lock().unwrap();
                 for &b in &buf[..n] {
                     if !escape && b == 0x1 {
                         // Ctrl-A
@@ -131,10 +583,61 @@ fn main() -> anyhow::Result<()> {
                         continue;
                     }
                     if escape && b == b'x' {
-                        break 'outer;
+                        return Ok(());
+                    }
+                    if escape && b == b's' {
+                        escape = false;
+                        eprintln!("{}", profiler.snapshot());
+                        continue;
+                    }
+                    if escape && b == b'd' {
+                        escape = false;
+                        match core_dump {
+                            Some((dumper, path)) => match dumper.dump(path) {
+                                Ok(()) => eprintln!("Core dump written to {}", path.display()),
+                                Err(e) => eprintln!("Failed to write core dump: {e}"),
+                            },
+                            None => eprintln!("Core dumps are disabled (use --dump-core)"),
+                        }
+                        continue;
+                    }
+                    if escape && b == b'w' {
+                        escape = false;
+                        match snapshot {
+                            Some((snapshotter, path)) => match snapshotter.save(path) {
+                                Ok(()) => eprintln!("Snapshot written to {}", path.display()),
+                                Err(e) => eprintln!("Failed to write snapshot: {e}"),
+                            },
+                            None => eprintln!("Snapshots are disabled (use --snapshot)"),
+                        }
+                        continue;
+                    }
+                    if escape && b == b'm' {
+                        escape = false;
+                        match migration {
+                            Some((migrator, socket)) => match migrate(migrator, socket) {
+                                Ok(()) => {
+                                    eprintln!("Guest migrated to {socket}");
+                                    return Ok(());
+                                }
+                                Err(e) => eprintln!("Failed to migrate: {e}"),
+                            },
+                            None => eprintln!("Migration is disabled (use --migrate-to)"),
+                        }
+                        continue;
+                    }
+                    if escape && matches!(b, b'+' | b'-') {
+                        escape = false;
+                        match hotplug {
+                            Some(hotplug) => resize_hotplug_memory(hotplug, b == b'+')?,
+                            None => {
+                                eprintln!("Memory hotplug is disabled (use --hotplug-memory)");
+                            }
+                        }
+                        continue;
                     }
                     escape = false;
-                    serial.queue_rx(b)?;
+                    serial.lock().unwrap().queue_rx(b)?;
                 }
             }
             Err(e)
@@ -145,21 +648,54 @@ fn main() -> anyhow::Result<()> {
                         | std::io::ErrorKind::UnexpectedEof
                 ) =>
             {
-                break
+                return Ok(())
             }
             Err(e) => return Err(e.into()),
         }
     }
+}
+
+/// Amount by which Ctrl-A + and Ctrl-A - change the size of hotplugged
+/// memory
+const HOTPLUG_STEP: u64 = 128 << 20;
 
+/// Asks the guest to plug or unplug another step of hotpluggable memory.
+fn resize_hotplug_memory(hotplug: &MemoryHotplug, grow: bool) -> microcosm::Result<()> {
+    let size = hotplug.requested_size();
+    let size = if grow {
+        size.saturating_add(HOTPLUG_STEP)
+    } else {
+        size.saturating_sub(HOTPLUG_STEP)
+    };
+    let size = hotplug.request_size(size)?;
+    eprintln!(
+        "Requested {} MiB of hotplugged memory out of {} MiB ({} MiB plugged)",
+        size >> 20,
+        hotplug.region_size() >> 20,
+        hotplug.plugged_size() >> 20
+    );
     Ok(())
 }
 
-struct RawModeReader<T: AsFd> {
+/// Sends the guest to the instance listening on `socket`.
+fn migrate(migrator: &Migrator, socket: &MigrationSocket) -> anyhow::Result<()> {
+    match socket {
+        MigrationSocket::Unix(path) => migrator.send(UnixStream::connect(path)?)?,
+        MigrationSocket::Tcp(addr) => {
+            let stream = TcpStream::connect(addr)?;
+            stream.set_nodelay(true)?;
+            migrator.send(stream)?;
+        }
+    }
+    Ok(())
+}
+
+struct RawMode<T: AsFd> {
     inner: T,
     original_termios: Termios,
 }
 
-impl<T: AsFd> RawModeReader<T> {
+impl<T: AsFd> RawMode<T> {
     fn new(inner: T) -> nix::Result<Self> {
         let original_termios = tcgetattr(&inner)?;
         let mut raw_mode = original_termios.clone();
@@ -172,14 +708,8 @@ impl<T: AsFd> RawModeReader<T> {
     }
 }
 
-impl<T: AsFd> Drop for RawModeReader<T> {
+impl<T: AsFd> Drop for RawMode<T> {
     fn drop(&mut self) {
         let _ = tcsetattr(&self.inner, SetArg::TCSANOW, &self.original_termios);
     }
 }
-
-impl<T: AsFd + Read> Read for RawModeReader<T> {
-    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
-        self.inner.read(buf)
-    }
-}
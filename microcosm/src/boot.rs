@@ -1,15 +1,17 @@
 use crate::{
+    device::PVPANIC_PORT,
     load::BootProtocol,
     memory::{CopyToGuest, RangeAllocator},
-    Result,
+    Error, Result,
 };
 use std::{ffi::c_char, mem::size_of};
 use sys::{
     acpi::{
         acpi_madt_io_apic, acpi_madt_local_apic, acpi_madt_type_ACPI_MADT_TYPE_IO_APIC,
-        acpi_madt_type_ACPI_MADT_TYPE_LOCAL_APIC, acpi_subtable_header, acpi_table_header,
-        acpi_table_madt, acpi_table_rsdp, ACPI_MADT_ENABLED, ACPI_RSDP_CHECKSUM_LENGTH,
-        ACPI_SIG_MADT, ACPI_SIG_RSDP, ACPI_SIG_XSDT,
+        acpi_madt_type_ACPI_MADT_TYPE_LOCAL_APIC, acpi_subtable_header, acpi_table_fadt,
+        acpi_table_header, acpi_table_madt, acpi_table_rsdp, ACPI_FADT_8042,
+        ACPI_FADT_LEGACY_DEVICES, ACPI_FADT_NO_VGA, ACPI_MADT_ENABLED, ACPI_RSDP_CHECKSUM_LENGTH,
+        ACPI_SIG_DSDT, ACPI_SIG_FADT, ACPI_SIG_MADT, ACPI_SIG_RSDP, ACPI_SIG_XSDT,
     },
     kvm_bindings::{kvm_regs, kvm_segment, kvm_sregs},
 };
@@ -23,7 +25,11 @@ pub struct Bootable {
 }
 
 impl Bootable {
-    pub fn configure_memory(&self, memory: &mut [u8]) -> Result<()> {
+    /// Writes the descriptor tables and identity-mapping page tables.
+    ///
+    /// `ram_end` is the end of the highest RAM region, and `gib_pages`
+    /// whether the vCPUs support 1 GiB pages.
+    pub fn configure_memory(&self, memory: &mut [u8], ram_end: u64, gib_pages: bool) -> Result<()> {
         if self.protocol.is_32bit() {
             GDT32.copy_to_guest(memory, GDT_BASE)?;
             IDT32.copy_to_guest(memory, IDT_BASE)?;
@@ -31,25 +37,14 @@ impl Bootable {
             // The structure of the page table is as follows:
             //    # | Kind  |   Size | Memory range
             // -----|-------|--------|-------------
-            //    n | PDE   | (4*n)B |  memory_size
-            // 1024 | PTE   |   4KiB |         4MiB
-
-            let n = memory.len().div_ceil(0x0040_0000) as u32;
-            let pde_addr = PAGE_TABLE_ADDR as u32;
-            let pte_addr = pde_addr + 0x1000;
-            for pde in 0u32..n {
-                ((pte_addr + (pde << 12)) | 0x3) // P | RW
-                    .copy_to_guest(
-                        memory,
-                        u64::from(pde_addr) + u64::from(pde) * size_of::<u32>() as u64,
-                    )?;
-            }
-            for pte in 0u32..1024 * n {
-                ((pte << 12) | 0x3) // P | RW
-                    .copy_to_guest(
-                        memory,
-                        u64::from(pte_addr) + u64::from(pte) * size_of::<u32>() as u64,
-                    )?;
+            // 1024 | PDE   |   4KiB |         4MiB
+            //
+            // RAM above 4 GiB cannot be mapped without PAE.
+
+            let pde_addr = PAGE_TABLE_ADDR;
+            for pde in 0u32..1024 {
+                ((pde << 22) | 0x83) // P | RW | PS
+                    .copy_to_guest(memory, pde_addr + u64::from(pde) * size_of::<u32>() as u64)?;
             }
         } else {
             GDT64.copy_to_guest(memory, GDT_BASE)?;
@@ -58,26 +53,49 @@ impl Bootable {
             // The structure of the page table is as follows:
             //   # | Kind  | Size | Memory range
             // ----|-------|------|-------------
-            //   1 | PML4E |   8B |         4GiB
-            //   4 | PDPTE |  32B |         1GiB
+            //   n | PML4E |   8B |       512GiB
+            //   m | PDPTE |   8B |         1GiB
             // 512 | PDE   | 4KiB |         2MiB
+            //
+            // The PDPTEs map 1 GiB pages directly when they are supported.
+            // Otherwise there is a PD per GiB, which must all fit in the page
+            // table area.
 
             let pml4_addr = PAGE_TABLE_ADDR;
-            let pdpte_addr = pml4_addr + 0x1000;
-            let pde_addr = pdpte_addr + 0x1000;
-            (pdpte_addr | 0x3) // P | RW
-                .copy_to_guest(memory, pml4_addr)?;
-            for pdpte in 0u64..4 {
-                ((pde_addr + (pdpte << 12)) | 0x3) // P | RW
-                    .copy_to_guest(
-                         memory,pdpte_addr  + pdpte  * size_of::<u64>() as u64
-                    )?;
+            let pdpt_addr = pml4_addr + 0x1000;
+            // Map the whole 32-bit address space for the local APIC and the
+            // I/O APIC, and all RAM above it.
+            let num_gibs = ram_end.max(1 << 32).div_ceil(1 << 30);
+            if !gib_pages {
+                // A single PDPT is enough, since the PDs of far less than
+                // 512 GiB fit.
+                let max_gibs = (PAGE_TABLE_END - pdpt_addr) / 0x1000 - 1;
+                if num_gibs > max_gibs {
+                    return Err(Error::MemoryTooLargeToMap {
+                        ram_end,
+                        max_end: max_gibs << 30,
+                    });
+                }
+            }
+            let num_pdpts = num_gibs.div_ceil(512);
+            let pd_addr = pdpt_addr + (num_pdpts << 12);
+            for pml4e in 0..num_pdpts {
+                ((pdpt_addr + (pml4e << 12)) | 0x3) // P | RW
+                    .copy_to_guest(memory, pml4_addr + pml4e * size_of::<u64>() as u64)?;
             }
-            for pde in 0u64..4 * 512 {
-                ((pde << 21) | 0x83) // P | RW | PS
-                    .copy_to_guest(
-                         memory,pde_addr  + pde  * size_of::<u64>() as u64
-                    )?;
+            for pdpte in 0..num_gibs {
+                let entry = if gib_pages {
+                    (pdpte << 30) | 0x83 // P | RW | PS
+                } else {
+                    (pd_addr + (pdpte << 12)) | 0x3 // P | RW
+                };
+                entry.copy_to_guest(memory, pdpt_addr + pdpte * size_of::<u64>() as u64)?;
+            }
+            if !gib_pages {
+                for pde in 0..num_gibs * 512 {
+                    ((pde << 21) | 0x83) // P | RW | PS
+                        .copy_to_guest(memory, pd_addr + pde * size_of::<u64>() as u64)?;
+                }
             }
         }
         Ok(())
@@ -100,6 +118,7 @@ impl Bootable {
             sregs.idt.limit = IDT32.as_bytes().len() as u16 - 1;
             sregs.cr0 |= 0x1; // PE
             sregs.cr0 &= !0x8000_0000; // PG
+            sregs.cr4 |= 0x10; // PSE
             sregs.cr4 &= !0x20; // PAE
             sregs.efer &= !0x500; // LME | LMA
         } else {
@@ -145,9 +164,16 @@ pub fn configure_acpi(memory: &mut [u8], num_cpus: usize) -> Result<()> {
     let xsdp_addr = allocator.raw_alloc(xsdp_size, 16);
     assert_eq!(xsdp_addr, RSDP_ADDR);
 
-    let xsdt_size = size_of::<acpi_table_header>() + size_of::<u64>();
+    let xsdt_size = size_of::<acpi_table_header>() + 2 * size_of::<u64>();
     let xsdt_addr = allocator.raw_alloc(xsdt_size, 1);
 
+    let fadt_size = size_of::<acpi_table_fadt>();
+    let fadt_addr = allocator.raw_alloc(fadt_size, 1);
+
+    let dsdt_aml = dsdt_aml();
+    let dsdt_size = size_of::<acpi_table_header>() + dsdt_aml.len();
+    let dsdt_addr = allocator.raw_alloc(dsdt_size, 1);
+
     let madt_size = size_of::<acpi_table_madt>()
         + size_of::<acpi_madt_io_apic>()
         + num_cpus * size_of::<acpi_madt_local_apic>();
@@ -170,9 +196,37 @@ pub fn configure_acpi(memory: &mut [u8], num_cpus: usize) -> Result<()> {
         revision: 1,
         ..Default::default()
     };
-    xsdt_header.checksum = checksum!(xsdt_header, madt_addr);
+    let xsdt_entries = [fadt_addr, madt_addr];
+    xsdt_header.checksum = checksum!(xsdt_header, xsdt_entries);
     xsdt_header.copy_to_guest(memory, xsdt_addr)?;
-    madt_addr.copy_to_guest(memory, xsdt_addr + size_of::<acpi_table_header>() as u64)?;
+    xsdt_entries.copy_to_guest(memory, xsdt_addr + size_of::<acpi_table_header>() as u64)?;
+
+    // The FADT is only there to point to the DSDT, so the fixed hardware
+    // registers are left out.
+    let mut fadt = acpi_table_fadt {
+        header: acpi_table_header {
+            signature: signature!(ACPI_SIG_FADT; 4),
+            length: fadt_size as u32,
+            revision: 6, // ACPI 6.5
+            ..Default::default()
+        },
+        boot_flags: (ACPI_FADT_LEGACY_DEVICES | ACPI_FADT_8042 | ACPI_FADT_NO_VGA) as u16,
+        minor_revision: 5,
+        Xdsdt: dsdt_addr,
+        ..Default::default()
+    };
+    fadt.header.checksum = checksum!(fadt);
+    fadt.copy_to_guest(memory, fadt_addr)?;
+
+    let mut dsdt_header = acpi_table_header {
+        signature: signature!(ACPI_SIG_DSDT; 4),
+        length: dsdt_size as u32,
+        revision: 2, // 64-bit integers
+        ..Default::default()
+    };
+    dsdt_header.checksum = checksum!(dsdt_header, dsdt_aml);
+    dsdt_header.copy_to_guest(memory, dsdt_addr)?;
+    dsdt_aml.copy_to_guest(memory, dsdt_addr + size_of::<acpi_table_header>() as u64)?;
 
     let mut madt_header = acpi_table_madt {
         header: acpi_table_header {
@@ -208,7 +262,7 @@ pub fn configure_acpi(memory: &mut [u8], num_cpus: usize) -> Result<()> {
             }
         })
         .collect();
-    madt_header.header.checksum = checksum!(madt_header.header, madt_io_apic, madt_local_apics);
+    madt_header.header.checksum = checksum!(madt_header, madt_io_apic, madt_local_apics);
     let mut addr = madt_addr;
     madt_header.copy_to_guest(memory, addr)?;
     addr += size_of::<acpi_table_madt>() as u64;
@@ -219,9 +273,96 @@ pub fn configure_acpi(memory: &mut [u8], num_cpus: usize) -> Result<()> {
     Ok(())
 }
 
+// https://uefi.org/specs/ACPI/6.5/20_AML_Specification.html
+
+const AML_NAME_OP: u8 = 0x08;
+const AML_BYTE_PREFIX: u8 = 0x0a;
+const AML_STRING_PREFIX: u8 = 0x0d;
+const AML_SCOPE_OP: u8 = 0x10;
+const AML_BUFFER_OP: u8 = 0x11;
+const AML_EXT_OP_PREFIX: u8 = 0x5b;
+const AML_DEVICE_OP: u8 = 0x82;
+
+// https://uefi.org/specs/ACPI/6.5/06_Device_Configuration.html#resource-data-types-for-acpi
+
+const ACPI_IO_DESCRIPTOR: u8 = 0x47;
+const ACPI_IO_DECODE_16: u8 = 0x01;
+const ACPI_END_TAG: u8 = 0x79;
+
+/// Returns the AML code of the DSDT, which declares the devices that are not
+/// discovered otherwise.
+fn dsdt_aml() -> Vec<u8> {
+    // Name (_HID, "QEMU0001")
+    let mut pvpanic = aml_name(*b"_HID", &aml_string("QEMU0001"));
+    // Name (_CRS, ResourceTemplate () { IO (Decode16, 0x505, 0x505, 1, 1) })
+    let [port_lo, port_hi] = PVPANIC_PORT.to_le_bytes();
+    pvpanic.extend(aml_name(
+        *b"_CRS",
+        &aml_buffer(&[
+            ACPI_IO_DESCRIPTOR,
+            ACPI_IO_DECODE_16,
+            port_lo,
+            port_hi,
+            port_lo,
+            port_hi,
+            1, // Alignment
+            1, // Length
+            ACPI_END_TAG,
+            0, // Checksum, none
+        ]),
+    ));
+    // Name (_STA, 0x0F): present, enabled, shown in the UI and functioning
+    pvpanic.extend(aml_name(*b"_STA", &[AML_BYTE_PREFIX, 0x0f]));
+
+    // Scope (\_SB) { Device (PEVT) { ... } }
+    let device = aml_package(&[AML_EXT_OP_PREFIX, AML_DEVICE_OP], b"PEVT", &pvpanic);
+    aml_package(&[AML_SCOPE_OP], b"\\_SB_", &device)
+}
+
+fn aml_name(name: [u8; 4], value: &[u8]) -> Vec<u8> {
+    let mut aml = vec![AML_NAME_OP];
+    aml.extend(name);
+    aml.extend(value);
+    aml
+}
+
+fn aml_string(s: &str) -> Vec<u8> {
+    let mut aml = vec![AML_STRING_PREFIX];
+    aml.extend(s.as_bytes());
+    aml.push(0);
+    aml
+}
+
+fn aml_buffer(data: &[u8]) -> Vec<u8> {
+    // The size is a ByteConst, which is enough for resource templates.
+    let mut body = vec![AML_BYTE_PREFIX, data.len() as u8];
+    body.extend(data);
+    aml_package(&[AML_BUFFER_OP], &[], &body)
+}
+
+/// Encodes `op`, followed by the length of the package, `name` and `body`.
+fn aml_package(op: &[u8], name: &[u8], body: &[u8]) -> Vec<u8> {
+    let content_len = name.len() + body.len();
+    let mut aml = op.to_vec();
+    // The length includes its own encoding, of one byte for up to 63 bytes
+    // and otherwise of 2 bytes for up to 4095 bytes.
+    if content_len + 1 < 1 << 6 {
+        aml.push((content_len + 1) as u8);
+    } else {
+        let len = content_len + 2;
+        assert!(len < 1 << 12, "AML package too large");
+        aml.push((1 << 6) | (len & 0xf) as u8);
+        aml.push((len >> 4) as u8);
+    }
+    aml.extend(name);
+    aml.extend(body);
+    aml
+}
+
 const GDT_BASE: u64 = 0x0500;
 const IDT_BASE: u64 = 0x0530;
 const PAGE_TABLE_ADDR: u64 = 0x8000;
+const PAGE_TABLE_END: u64 = 0x0004_0000;
 const STACK_POINTER: u64 = 0x0008_0000;
 
 pub const EBDA_START: u64 = 0x0009_fc00;
@@ -323,3 +464,110 @@ impl Segment {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::CopyFromGuest;
+
+    fn checksum(table: &[u8]) -> u8 {
+        table.iter().fold(0, |sum, &b| sum.wrapping_add(b))
+    }
+
+    fn table(memory: &[u8], addr: u64) -> &[u8] {
+        let len = u32::copy_from_guest(memory, addr + 4).unwrap() as usize;
+        &memory[addr as usize..addr as usize + len]
+    }
+
+    #[test]
+    fn dsdt_declares_pvpanic() {
+        #[rustfmt::skip]
+        let expected: &[u8] = &[
+            0x10, 0x36, b'\\', b'_', b'S', b'B', b'_',
+            0x5b, 0x82, 0x2e, b'P', b'E', b'V', b'T',
+            0x08, b'_', b'H', b'I', b'D',
+            0x0d, b'Q', b'E', b'M', b'U', b'0', b'0', b'0', b'1', 0x00,
+            0x08, b'_', b'C', b'R', b'S',
+            0x11, 0x0d, 0x0a, 0x0a,
+            0x47, 0x01, 0x05, 0x05, 0x05, 0x05, 0x01, 0x01, 0x79, 0x00,
+            0x08, b'_', b'S', b'T', b'A', 0x0a, 0x0f,
+        ];
+        assert_eq!(dsdt_aml(), expected);
+    }
+
+    #[test]
+    fn aml_package_long_length() {
+        let aml = aml_package(&[AML_SCOPE_OP], b"ABCD", &[0; 100]);
+        // 106 bytes in total with the 2 bytes of the length itself
+        assert_eq!(&aml[..3], &[AML_SCOPE_OP, 0x4a, 0x06]);
+        assert_eq!(aml.len(), 107);
+    }
+
+    fn bootable() -> Bootable {
+        Bootable {
+            protocol: BootProtocol::Linux64,
+            entry_addr: HIGH_MEMORY_START,
+            params_addr: 0,
+        }
+    }
+
+    #[test]
+    fn page_tables_map_all_ram_with_2mib_pages() {
+        let mut memory = vec![0; HIGH_MEMORY_START as usize];
+        let ram_end = 40 << 30;
+        bootable()
+            .configure_memory(&mut memory, ram_end, false)
+            .unwrap();
+
+        let entry = |addr: u64| u64::copy_from_guest(&memory, addr).unwrap();
+        let pdpt_addr = entry(PAGE_TABLE_ADDR) & !0xfff;
+        assert_eq!(entry(PAGE_TABLE_ADDR + 8), 0);
+        let last_pd_addr = entry(pdpt_addr + 39 * 8) & !0xfff;
+        assert_eq!(entry(pdpt_addr + 40 * 8), 0);
+        assert_eq!(entry(last_pd_addr + 511 * 8), (ram_end - (2 << 20)) | 0x83);
+    }
+
+    #[test]
+    fn page_tables_reject_ram_beyond_2mib_pages() {
+        let mut memory = vec![0; HIGH_MEMORY_START as usize];
+        assert!(matches!(
+            bootable().configure_memory(&mut memory, 64 << 30, false),
+            Err(Error::MemoryTooLargeToMap { .. })
+        ));
+        bootable()
+            .configure_memory(&mut memory, 64 << 30, true)
+            .unwrap();
+    }
+
+    #[test]
+    fn fadt_points_to_dsdt() {
+        let mut memory = vec![0; HIGH_MEMORY_START as usize];
+        configure_acpi(&mut memory, 2).unwrap();
+
+        let xsdt_addr = u64::copy_from_guest(&memory, RSDP_ADDR + 24).unwrap();
+        let xsdt = table(&memory, xsdt_addr);
+        assert_eq!(&xsdt[..4], b"XSDT");
+        assert_eq!(checksum(xsdt), 0);
+
+        let entries = &xsdt[size_of::<acpi_table_header>()..];
+        let signatures: Vec<_> = entries
+            .chunks_exact(8)
+            .map(|entry| {
+                let addr = u64::from_le_bytes(entry.try_into().unwrap());
+                let table = table(&memory, addr);
+                assert_eq!(checksum(table), 0);
+                &table[..4]
+            })
+            .collect();
+        assert_eq!(signatures, [b"FACP", b"APIC"]);
+
+        let fadt_addr = u64::from_le_bytes(entries[..8].try_into().unwrap());
+        let flags = u32::copy_from_guest(&memory, fadt_addr + 112).unwrap();
+        assert_eq!(flags, 0);
+        let dsdt_addr = u64::copy_from_guest(&memory, fadt_addr + 140).unwrap();
+        let dsdt = table(&memory, dsdt_addr);
+        assert_eq!(&dsdt[..4], b"DSDT");
+        assert_eq!(checksum(dsdt), 0);
+        assert_eq!(&dsdt[size_of::<acpi_table_header>()..], dsdt_aml());
+    }
+}
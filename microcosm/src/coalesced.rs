@@ -0,0 +1,126 @@
+use crate::{
+    device::{IoWidth, PortIoBus, PortWrite},
+    memory::{Mmapped, PAGE_SIZE},
+    thread::ThreadConfig,
+    Result,
+};
+use std::{
+    mem::size_of,
+    num::NonZeroUsize,
+    os::fd::AsFd,
+    sync::{
+        atomic::{fence, Ordering},
+        Mutex, Weak,
+    },
+    thread::JoinHandle,
+    time::Duration,
+};
+use sys::kvm_bindings::{kvm_coalesced_mmio, kvm_coalesced_mmio_ring};
+
+/// Number of entries in the ring, as computed by KVM
+const RING_LEN: usize =
+    (PAGE_SIZE - size_of::<kvm_coalesced_mmio_ring>()) / size_of::<kvm_coalesced_mmio>();
+
+/// Interval at which the ring is drained while no vCPU exits to userspace
+const FLUSH_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Writes that KVM buffered instead of exiting to userspace
+///
+/// There is a single ring per VM. KVM appends to it on behalf of all the
+/// vCPUs, and falls back to a regular exit when it is full.
+pub struct CoalescedRing {
+    /// The page holding `kvm_coalesced_mmio_ring`, typed for its alignment
+    page: Mmapped<u64>,
+
+    /// Serializes consumers so that writes are dispatched in order
+    lock: Mutex<()>,
+}
+
+impl CoalescedRing {
+    /// Maps the ring through the file of a vCPU.
+    ///
+    /// `page_offset` is the value of `KVM_CAP_COALESCED_MMIO`.
+    pub fn new(vcpu: impl AsFd, page_offset: usize) -> nix::Result<Self> {
+        let page = Mmapped::new_file(
+            vcpu,
+            NonZeroUsize::new(PAGE_SIZE).unwrap(),
+            (page_offset * PAGE_SIZE) as i64,
+        )?;
+        Ok(Self {
+            page,
+            lock: Mutex::default(),
+        })
+    }
+
+    /// Dispatches all the buffered writes to the devices as one batch.
+    pub fn drain(&self, port_io_bus: &mut PortIoBus) -> Result<()> {
+        let _guard = self.lock.lock().unwrap();
+        let ring = self.page.as_ptr().cast::<kvm_coalesced_mmio_ring>();
+        let first = unsafe { (&raw const (*ring).first).read_volatile() } as usize % RING_LEN;
+        let last = unsafe { (&raw const (*ring).last).read_volatile() } as usize % RING_LEN;
+        if first == last {
+            return Ok(());
+        }
+
+        // KVM fills in an entry before publishing it by advancing `last`.
+        fence(Ordering::Acquire);
+        let ptr = unsafe { (&raw const (*ring).coalesced_mmio).cast::<kvm_coalesced_mmio>() };
+        let mut entries = Vec::with_capacity((last + RING_LEN - first) % RING_LEN);
+        let mut i = first;
+        while i != last {
+            entries.push(unsafe { ptr.add(i).read() });
+            i = (i + 1) % RING_LEN;
+        }
+
+        // The entries must be copied out before KVM can reuse their slots.
+        fence(Ordering::Release);
+        unsafe { (&raw mut (*ring).first).write_volatile(last as u32) };
+
+        let mut writes = Vec::with_capacity(entries.len());
+        for entry in &entries {
+            if unsafe { entry.__bindgen_anon_1.pio } == 0 {
+                eprintln!("Unhandled coalesced MMIO write at {:#x}", entry.phys_addr);
+                continue;
+            }
+            let Some(width) = IoWidth::from_bytes(entry.len as usize) else {
+                eprintln!("Invalid coalesced write size {}", entry.len);
+                continue;
+            };
+            let mut value = [0; 4];
+            value[..width.bytes()].copy_from_slice(&entry.data[..width.bytes()]);
+            writes.push(PortWrite {
+                port: entry.phys_addr as u16,
+                width,
+                value: u32::from_le_bytes(value),
+            });
+        }
+        port_io_bus.write_coalesced(&writes)
+    }
+}
+
+/// Drains the ring periodically, so that buffered writes are not held back
+/// while the vCPUs stay in the guest, for example when they are halted.
+///
+/// The thread exits once the ring is dropped.
+pub fn spawn_flusher(
+    ring: Weak<CoalescedRing>,
+    mut port_io_bus: PortIoBus,
+    thread_config: ThreadConfig,
+) -> std::io::Result<JoinHandle<()>> {
+    std::thread::Builder::new()
+        .name("coalesced-io".to_owned())
+        .spawn(move || {
+            if let Err(e) = thread_config.apply() {
+                eprintln!("Failed to configure the coalesced I/O thread: {e}");
+            }
+            loop {
+                std::thread::sleep(FLUSH_INTERVAL);
+                let Some(ring) = ring.upgrade() else {
+                    return;
+                };
+                if let Err(e) = ring.drain(&mut port_io_bus) {
+                    eprintln!("Failed to dispatch coalesced writes: {e}");
+                }
+            }
+        })
+}
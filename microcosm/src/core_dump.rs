@@ -0,0 +1,331 @@
+use crate::{cpu::Control, memory::GuestMemory, Result};
+use std::io::Write;
+use sys::{
+    elf::{
+        Elf64_Ehdr, Elf64_Nhdr, Elf64_Phdr, EI_CLASS, EI_DATA, EI_VERSION, ELFCLASS64, ELFDATA2LSB,
+        ELFMAG, EM_X86_64, ET_CORE, EV_CURRENT, NT_PRSTATUS, PF_R, PF_W, PF_X, PT_LOAD, PT_NOTE,
+    },
+    kvm_bindings::{kvm_dtable, kvm_msr_entry, kvm_segment, Msrs},
+};
+use zerocopy::{AsBytes, FromZeroes};
+
+// The layout follows the one produced by QEMU's dump-guest-memory command,
+// which is understood by crash and gdb:
+//
+// - ELF header
+// - PT_NOTE program header, followed by a PT_LOAD program header for each
+//   region of guest RAM
+// - NT_PRSTATUS notes for all the vCPUs, followed by QEMU notes carrying the
+//   system registers
+// - Guest RAM
+
+const MSR_KERNEL_GS_BASE: u32 = 0xc000_0102;
+
+const QEMU_CPU_STATE_VERSION: u32 = 1;
+
+/// `struct elf_prstatus` of x86-64 Linux
+#[repr(C)]
+#[derive(AsBytes, FromZeroes)]
+struct ElfPrstatus {
+    si_signo: i32,
+    si_code: i32,
+    si_errno: i32,
+    cursig: i16,
+    _pad0: [u8; 2],
+    sigpend: u64,
+    sighold: u64,
+    pid: u32,
+    ppid: u32,
+    pgrp: u32,
+    sid: u32,
+    utime: [u64; 2],
+    stime: [u64; 2],
+    cutime: [u64; 2],
+    cstime: [u64; 2],
+    regs: ElfGregs,
+    fpvalid: u32,
+    _pad1: [u8; 4],
+}
+
+/// `struct user_regs_struct` of x86-64 Linux
+#[repr(C)]
+#[derive(AsBytes, FromZeroes)]
+struct ElfGregs {
+    r15: u64,
+    r14: u64,
+    r13: u64,
+    r12: u64,
+    rbp: u64,
+    rbx: u64,
+    r11: u64,
+    r10: u64,
+    r9: u64,
+    r8: u64,
+    rax: u64,
+    rcx: u64,
+    rdx: u64,
+    rsi: u64,
+    rdi: u64,
+    orig_rax: u64,
+    rip: u64,
+    cs: u64,
+    eflags: u64,
+    rsp: u64,
+    ss: u64,
+    fs_base: u64,
+    gs_base: u64,
+    ds: u64,
+    es: u64,
+    fs: u64,
+    gs: u64,
+}
+
+/// `QEMUCPUState` of QEMU
+#[repr(C)]
+#[derive(AsBytes, FromZeroes)]
+struct QemuCpuState {
+    version: u32,
+    size: u32,
+    rax: u64,
+    rbx: u64,
+    rcx: u64,
+    rdx: u64,
+    rsi: u64,
+    rdi: u64,
+    rsp: u64,
+    rbp: u64,
+    r8: u64,
+    r9: u64,
+    r10: u64,
+    r11: u64,
+    r12: u64,
+    r13: u64,
+    r14: u64,
+    r15: u64,
+    rip: u64,
+    rflags: u64,
+    cs: QemuCpuSegment,
+    ds: QemuCpuSegment,
+    es: QemuCpuSegment,
+    fs: QemuCpuSegment,
+    gs: QemuCpuSegment,
+    ss: QemuCpuSegment,
+    ldt: QemuCpuSegment,
+    tr: QemuCpuSegment,
+    gdt: QemuCpuSegment,
+    idt: QemuCpuSegment,
+    cr: [u64; 5],
+    kernel_gs_base: u64,
+}
+
+#[repr(C)]
+#[derive(AsBytes, FromZeroes)]
+struct QemuCpuSegment {
+    selector: u32,
+    limit: u32,
+    flags: u32,
+    _pad: u32,
+    base: u64,
+}
+
+impl From<&kvm_segment> for QemuCpuSegment {
+    fn from(segment: &kvm_segment) -> Self {
+        // Bits 8-23 of the upper half of a segment descriptor
+        let flags = u32::from(segment.type_) << 8
+            | u32::from(segment.s) << 12
+            | u32::from(segment.dpl) << 13
+            | u32::from(segment.present) << 15
+            | u32::from(segment.avl) << 20
+            | u32::from(segment.l) << 21
+            | u32::from(segment.db) << 22
+            | u32::from(segment.g) << 23;
+        Self {
+            selector: segment.selector.into(),
+            limit: segment.limit,
+            flags,
+            _pad: 0,
+            base: segment.base,
+        }
+    }
+}
+
+impl From<&kvm_dtable> for QemuCpuSegment {
+    fn from(dtable: &kvm_dtable) -> Self {
+        Self {
+            selector: 0,
+            limit: dtable.limit.into(),
+            flags: 0,
+            _pad: 0,
+            base: dtable.base,
+        }
+    }
+}
+
+/// Writes the register state of the vCPUs and guest memory as an ELF core
+/// file.
+///
+/// The vCPUs must not be running.
+pub fn write_core(writer: &mut impl Write, control: &Control, memory: &GuestMemory) -> Result<()> {
+    let contexts = control.contexts();
+    let regions = memory.host_regions(control)?;
+    let mut prstatus_notes = Vec::new();
+    let mut qemu_notes = Vec::new();
+    for context in contexts {
+        let regs = context.vcpu.regs()?;
+        let sregs = context.vcpu.sregs()?;
+
+        let mut msrs = Msrs::from_entries(&[kvm_msr_entry {
+            index: MSR_KERNEL_GS_BASE,
+            ..Default::default()
+        }])
+        .unwrap();
+        let kernel_gs_base = match context.vcpu.get_msrs(&mut msrs)? {
+            1 => msrs.as_slice()[0].data,
+            _ => 0,
+        };
+
+        let mut prstatus = ElfPrstatus::new_zeroed();
+        prstatus.pid = context.id as u32 + 1;
+        prstatus.regs = ElfGregs {
+            r15: regs.r15,
+            r14: regs.r14,
+            r13: regs.r13,
+            r12: regs.r12,
+            rbp: regs.rbp,
+            rbx: regs.rbx,
+            r11: regs.r11,
+            r10: regs.r10,
+            r9: regs.r9,
+            r8: regs.r8,
+            rax: regs.rax,
+            rcx: regs.rcx,
+            rdx: regs.rdx,
+            rsi: regs.rsi,
+            rdi: regs.rdi,
+            orig_rax: regs.rax,
+            rip: regs.rip,
+            cs: sregs.cs.selector.into(),
+            eflags: regs.rflags,
+            rsp: regs.rsp,
+            ss: sregs.ss.selector.into(),
+            fs_base: sregs.fs.base,
+            gs_base: sregs.gs.base,
+            ds: sregs.ds.selector.into(),
+            es: sregs.es.selector.into(),
+            fs: sregs.fs.selector.into(),
+            gs: sregs.gs.selector.into(),
+        };
+        write_note(
+            &mut prstatus_notes,
+            b"CORE",
+            NT_PRSTATUS,
+            prstatus.as_bytes(),
+        );
+
+        let state = QemuCpuState {
+            version: QEMU_CPU_STATE_VERSION,
+            size: std::mem::size_of::<QemuCpuState>() as u32,
+            rax: regs.rax,
+            rbx: regs.rbx,
+            rcx: regs.rcx,
+            rdx: regs.rdx,
+            rsi: regs.rsi,
+            rdi: regs.rdi,
+            rsp: regs.rsp,
+            rbp: regs.rbp,
+            r8: regs.r8,
+            r9: regs.r9,
+            r10: regs.r10,
+            r11: regs.r11,
+            r12: regs.r12,
+            r13: regs.r13,
+            r14: regs.r14,
+            r15: regs.r15,
+            rip: regs.rip,
+            rflags: regs.rflags,
+            cs: (&sregs.cs).into(),
+            ds: (&sregs.ds).into(),
+            es: (&sregs.es).into(),
+            fs: (&sregs.fs).into(),
+            gs: (&sregs.gs).into(),
+            ss: (&sregs.ss).into(),
+            ldt: (&sregs.ldt).into(),
+            tr: (&sregs.tr).into(),
+            gdt: (&sregs.gdt).into(),
+            idt: (&sregs.idt).into(),
+            cr: [sregs.cr0, 0, sregs.cr2, sregs.cr3, sregs.cr4],
+            kernel_gs_base,
+        };
+        write_note(&mut qemu_notes, b"QEMU", 0, state.as_bytes());
+    }
+    let mut notes = prstatus_notes;
+    notes.append(&mut qemu_notes);
+
+    let ehdr_size = std::mem::size_of::<Elf64_Ehdr>();
+    let phdr_size = std::mem::size_of::<Elf64_Phdr>();
+    let num_phdrs = 1 + regions.len();
+    let note_offset = (ehdr_size + phdr_size * num_phdrs) as u64;
+
+    let mut ehdr = Elf64_Ehdr::new_zeroed();
+    ehdr.e_ident[..4].copy_from_slice(&ELFMAG[..4]);
+    ehdr.e_ident[EI_CLASS as usize] = ELFCLASS64 as u8;
+    ehdr.e_ident[EI_DATA as usize] = ELFDATA2LSB as u8;
+    ehdr.e_ident[EI_VERSION as usize] = EV_CURRENT as u8;
+    ehdr.e_type = ET_CORE as u16;
+    ehdr.e_machine = EM_X86_64 as u16;
+    ehdr.e_version = EV_CURRENT;
+    ehdr.e_phoff = ehdr_size as u64;
+    ehdr.e_ehsize = ehdr_size as u16;
+    ehdr.e_phentsize = phdr_size as u16;
+    ehdr.e_phnum = num_phdrs as u16;
+
+    let note_phdr = Elf64_Phdr {
+        p_type: PT_NOTE,
+        p_flags: 0,
+        p_offset: note_offset,
+        p_vaddr: 0,
+        p_paddr: 0,
+        p_filesz: notes.len() as u64,
+        p_memsz: notes.len() as u64,
+        p_align: 0,
+    };
+
+    writer.write_all(ehdr.as_bytes())?;
+    writer.write_all(note_phdr.as_bytes())?;
+    let mut memory_offset = note_offset + notes.len() as u64;
+    for (region, _) in &regions {
+        let load_phdr = Elf64_Phdr {
+            p_type: PT_LOAD,
+            p_flags: PF_R | PF_W | PF_X,
+            p_offset: memory_offset,
+            p_vaddr: 0,
+            p_paddr: region.guest_addr,
+            p_filesz: region.size,
+            p_memsz: region.size,
+            p_align: 0,
+        };
+        writer.write_all(load_phdr.as_bytes())?;
+        memory_offset += region.size;
+    }
+    writer.write_all(&notes)?;
+    for (_, host_memory) in &regions {
+        writer.write_all(host_memory)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Appends an ELF note, padding the name and the descriptor to 4 bytes.
+fn write_note(buf: &mut Vec<u8>, name: &[u8], note_type: u32, desc: &[u8]) {
+    let header = Elf64_Nhdr {
+        n_namesz: name.len() as u32 + 1,
+        n_descsz: desc.len() as u32,
+        n_type: note_type,
+    };
+    buf.extend_from_slice(header.as_bytes());
+    buf.extend_from_slice(name);
+    buf.push(0);
+    buf.resize(buf.len().next_multiple_of(4), 0);
+    buf.extend_from_slice(desc);
+    buf.resize(buf.len().next_multiple_of(4), 0);
+}
@@ -0,0 +1,691 @@
+use crate::{
+    boot::Bootable,
+    coalesced::CoalescedRing,
+    cpuid::PvFeatures,
+    device::{IoWidth, MmioBus, PortIoBus},
+    dirty::DirtyRing,
+    dump::CpuDump,
+    kvm::{Vcpu, Vm},
+    memory::{GuestMemory, Mmapped},
+    profile::{self, VcpuExitStats},
+    Result,
+};
+use nix::libc;
+use std::{
+    num::{NonZeroU32, NonZeroUsize},
+    sync::{Arc, Condvar, Mutex, Once},
+    time::Instant,
+};
+use sys::{
+    kvm_bindings::{
+        self, kvm_fpu, kvm_lapic_state, kvm_msr_entry, kvm_regs, kvm_run, kvm_sregs,
+        kvm_vcpu_events, CpuId, Msrs, KVM_EXIT_DEBUG, KVM_EXIT_DIRTY_RING_FULL,
+        KVM_EXIT_FAIL_ENTRY, KVM_EXIT_HLT, KVM_EXIT_INTERNAL_ERROR, KVM_EXIT_IO, KVM_EXIT_IO_IN,
+        KVM_EXIT_IO_OUT, KVM_EXIT_MMIO, KVM_EXIT_SHUTDOWN, KVM_EXIT_SYSTEM_EVENT,
+        KVM_SYSTEM_EVENT_CRASH, KVM_SYSTEM_EVENT_RESET, KVM_SYSTEM_EVENT_SHUTDOWN,
+        KVM_VCPUEVENT_VALID_NMI_PENDING, KVM_VCPUEVENT_VALID_SIPI_VECTOR,
+    },
+    kvm_para::{
+        MSR_KVM_ASYNC_PF_EN, MSR_KVM_ASYNC_PF_INT, MSR_KVM_POLL_CONTROL, MSR_KVM_PV_EOI_EN,
+        MSR_KVM_STEAL_TIME, MSR_KVM_SYSTEM_TIME, MSR_KVM_SYSTEM_TIME_NEW,
+    },
+};
+
+const MSR_IA32_TSC_DEADLINE: u32 = 0x6e0;
+
+/// Reset values of the MSRs that are not covered by the other vCPU state.
+///
+/// The paravirtual MSRs point KVM to guest memory, which must not be written
+/// to after the guest memory is reloaded.
+const RESET_MSRS: [(u32, u64); 8] = [
+    (MSR_IA32_TSC_DEADLINE, 0),
+    (MSR_KVM_SYSTEM_TIME, 0),
+    (MSR_KVM_SYSTEM_TIME_NEW, 0),
+    (MSR_KVM_ASYNC_PF_EN, 0),
+    (MSR_KVM_ASYNC_PF_INT, 0),
+    (MSR_KVM_STEAL_TIME, 0),
+    (MSR_KVM_PV_EOI_EN, 0),
+    (MSR_KVM_POLL_CONTROL, 1),
+];
+
+/// Signal used to kick vCPU threads out of `KVM_RUN`
+fn kick_signal() -> libc::c_int {
+    libc::SIGRTMIN()
+}
+
+/// Installs a no-op handler for the kick signal so that delivering it only
+/// interrupts `KVM_RUN` instead of terminating the process.
+pub fn install_kick_handler() {
+    static ONCE: Once = Once::new();
+    ONCE.call_once(|| {
+        extern "C" fn handle_kick(_: libc::c_int) {}
+
+        let mut action: libc::sigaction = unsafe { std::mem::zeroed() };
+        action.sa_sigaction = handle_kick as *const () as libc::sighandler_t;
+        unsafe {
+            libc::sigemptyset(&raw mut action.sa_mask);
+            libc::sigaction(kick_signal(), &raw const action, std::ptr::null_mut());
+        }
+    });
+}
+
+/// A vCPU along with the state shared between its thread and the others
+pub struct CpuContext {
+    pub id: usize,
+    pub vcpu: Vcpu,
+    run: Mmapped<kvm_run>,
+    thread: Mutex<Option<libc::pthread_t>>,
+    pub exit_stats: Mutex<VcpuExitStats>,
+    pub dirty_ring: Option<DirtyRing>,
+
+    /// State of the vCPU right after its creation, restored on reset
+    initial_sregs: kvm_sregs,
+    initial_lapic: kvm_lapic_state,
+
+    /// Runnable for the BSP, while the APs wait for INIT and SIPI
+    initial_mp_state: u32,
+}
+
+impl CpuContext {
+    fn set_immediate_exit(&self, immediate_exit: bool) {
+        let ptr = self.run.as_ptr();
+        unsafe { (&raw mut (*ptr).immediate_exit).write_volatile(immediate_exit.into()) };
+    }
+
+    /// Lets KVM complete the instruction that caused the last exit without
+    /// entering the guest again, so that the state of the vCPU reflects it.
+    ///
+    /// The vCPU must not be running.
+    pub fn complete_exit(&self) -> Result<()> {
+        self.set_immediate_exit(true);
+        let result = unsafe { self.vcpu.run() };
+        self.set_immediate_exit(false);
+        match result {
+            Ok(()) | Err(nix::Error::EAGAIN | nix::Error::EINTR) => Ok(()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Forces the vCPU to return from `KVM_RUN` as soon as possible.
+    fn kick(&self) {
+        self.set_immediate_exit(true);
+        if let Some(thread) = *self.thread.lock().unwrap() {
+            unsafe { libc::pthread_kill(thread, kick_signal()) };
+        }
+    }
+}
+
+/// Reason why a guest stopped running
+#[derive(Debug, Clone)]
+pub enum VmExit {
+    /// The guest powered itself off.
+    Poweroff,
+
+    /// The guest requested a system reset.
+    Reset,
+
+    /// A vCPU encountered a triple fault.
+    TripleFault { dump: Box<CpuDump> },
+
+    /// KVM failed to enter the guest on a vCPU.
+    FailEntry {
+        hardware_entry_failure_reason: u64,
+        dump: Box<CpuDump>,
+    },
+
+    /// KVM encountered an internal error on a vCPU.
+    InternalError {
+        suberror: u32,
+        data: Vec<u64>,
+        dump: Box<CpuDump>,
+    },
+
+    /// The guest reported a kernel panic.
+    GuestPanic,
+}
+
+impl VmExit {
+    /// Returns the state of the vCPU that caused the exit, if any.
+    pub fn cpu_dump(&self) -> Option<&CpuDump> {
+        match self {
+            Self::TripleFault { dump }
+            | Self::FailEntry { dump, .. }
+            | Self::InternalError { dump, .. } => Some(dump),
+            Self::Poweroff | Self::Reset | Self::GuestPanic => None,
+        }
+    }
+}
+
+impl std::fmt::Display for VmExit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Poweroff => write!(f, "Poweroff"),
+            Self::Reset => write!(f, "Reset"),
+            Self::TripleFault { dump } => write!(
+                f,
+                "Triple fault on vCPU {} at RIP {:#x}",
+                dump.vcpu, dump.regs.rip
+            ),
+            Self::FailEntry {
+                hardware_entry_failure_reason,
+                dump,
+            } => write!(
+                f,
+                "Failed to enter vCPU {} (hardware entry failure reason {hardware_entry_failure_reason:#x})",
+                dump.vcpu
+            ),
+            Self::InternalError {
+                suberror,
+                data,
+                dump,
+            } => {
+                macro_rules! suberror_name {
+                    ($x:expr; $($v:ident,)*) => {
+                        match $x {
+                            $(kvm_bindings::$v => stringify!($v),)*
+                            _ => "unknown",
+                        }
+                    }
+                }
+                let name = suberror_name! {
+                    *suberror;
+                    KVM_INTERNAL_ERROR_EMULATION,
+                    KVM_INTERNAL_ERROR_SIMUL_EX,
+                    KVM_INTERNAL_ERROR_DELIVERY_EV,
+                    KVM_INTERNAL_ERROR_UNEXPECTED_EXIT_REASON,
+                };
+                write!(
+                    f,
+                    "Internal error on vCPU {}: {name} ({suberror}), data {data:x?}",
+                    dump.vcpu
+                )
+            }
+            Self::GuestPanic => write!(f, "Guest panic"),
+        }
+    }
+}
+
+/// Reason why the vCPUs were stopped for a debugger
+#[derive(Debug, Clone, Copy)]
+pub enum DebugEvent {
+    /// A vCPU hit a breakpoint or a watchpoint, or completed a single step.
+    Trap {
+        vcpu: usize,
+        exception: u32,
+        dr6: u64,
+    },
+
+    /// The debugger requested the guest to stop.
+    Interrupt,
+}
+
+/// Coordinates the vCPUs of a guest so that they are paused, resumed and
+/// stopped together.
+pub struct Control {
+    contexts: Vec<Arc<CpuContext>>,
+    state: Mutex<ControlState>,
+    cond: Condvar,
+}
+
+#[derive(Default)]
+struct ControlState {
+    pause_requested: bool,
+
+    /// Whether the pause was requested with `pause` rather than by a debugger
+    user_paused: bool,
+
+    stop_requested: bool,
+
+    /// The first exit reported by a vCPU or a device
+    exit: Option<VmExit>,
+
+    /// Number of vCPU threads that have not exited yet
+    num_running: usize,
+
+    /// Number of vCPU threads parked due to a pause request
+    num_paused: usize,
+
+    /// The event that made the vCPUs stop for a debugger
+    debug_event: Option<DebugEvent>,
+}
+
+impl Control {
+    pub fn new(contexts: Vec<Arc<CpuContext>>) -> Self {
+        Self {
+            contexts,
+            state: Mutex::default(),
+            cond: Condvar::new(),
+        }
+    }
+
+    pub fn contexts(&self) -> &[Arc<CpuContext>] {
+        &self.contexts
+    }
+
+    /// Requests all the vCPUs to pause and waits until they are parked.
+    pub fn pause(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.pause_requested = true;
+        state.user_paused = true;
+        for context in &self.contexts {
+            context.kick();
+        }
+        let _state = self
+            .cond
+            .wait_while(state, |state| state.num_paused < state.num_running)
+            .unwrap();
+    }
+
+    pub fn resume(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.pause_requested = false;
+        state.user_paused = false;
+        self.cond.notify_all();
+    }
+
+    pub fn is_paused(&self) -> bool {
+        let state = self.state.lock().unwrap();
+        state.pause_requested && state.num_paused == state.num_running
+    }
+
+    /// Stops all the vCPUs.
+    ///
+    /// `exit` is recorded as the reason unless another one has been recorded
+    /// before.
+    pub fn stop(&self, exit: Option<VmExit>) {
+        let mut state = self.state.lock().unwrap();
+        state.stop_requested = true;
+        if state.exit.is_none() {
+            state.exit = exit;
+        }
+        for context in &self.contexts {
+            context.kick();
+        }
+        self.cond.notify_all();
+    }
+
+    /// Pauses all the vCPUs on behalf of a debugger.
+    ///
+    /// Only the first event is recorded until the vCPUs are resumed with
+    /// `debug_resume`.
+    pub fn debug_stop(&self, event: DebugEvent) {
+        let mut state = self.state.lock().unwrap();
+        state.pause_requested = true;
+        if state.debug_event.is_none() {
+            state.debug_event = Some(event);
+        }
+        for context in &self.contexts {
+            context.kick();
+        }
+        self.cond.notify_all();
+    }
+
+    /// Waits until all the vCPUs are parked due to a debug event and returns
+    /// it.
+    ///
+    /// Returns `None` if the guest stopped instead.
+    pub fn wait_debug_event(&self) -> Option<DebugEvent> {
+        let state = self.state.lock().unwrap();
+        let state = self
+            .cond
+            .wait_while(state, |state| {
+                !state.stop_requested
+                    && (state.debug_event.is_none() || state.num_paused < state.num_running)
+            })
+            .unwrap();
+        if state.stop_requested {
+            None
+        } else {
+            state.debug_event
+        }
+    }
+
+    /// Resumes the vCPUs after a debug event.
+    pub fn debug_resume(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.pause_requested = false;
+        state.user_paused = false;
+        state.debug_event = None;
+        self.cond.notify_all();
+    }
+
+    /// Clears a debug event when the debugger goes away.
+    ///
+    /// Unlike `debug_resume`, this keeps the vCPUs parked if they were paused
+    /// with `pause`.
+    pub fn debug_release(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.pause_requested = state.user_paused;
+        state.debug_event = None;
+        self.cond.notify_all();
+    }
+
+    pub fn take_exit(&self) -> Option<VmExit> {
+        self.state.lock().unwrap().exit.take()
+    }
+
+    /// Clears a previous stop so that the vCPUs can run again.
+    ///
+    /// Returns `false` without doing so if another stop has been requested
+    /// since the exit was taken.
+    pub fn restart(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        if state.exit.is_some() {
+            return false;
+        }
+        state.stop_requested = false;
+        for context in &self.contexts {
+            context.set_immediate_exit(false);
+        }
+        true
+    }
+
+    /// Parks the calling vCPU thread while a pause is requested.
+    ///
+    /// Returns `false` if the vCPU should stop running.
+    fn checkpoint(&self, context: &CpuContext) -> bool {
+        let mut state = self.state.lock().unwrap();
+        if state.pause_requested && !state.stop_requested {
+            state.num_paused += 1;
+            self.cond.notify_all();
+            state = self
+                .cond
+                .wait_while(state, |state| {
+                    state.pause_requested && !state.stop_requested
+                })
+                .unwrap();
+            state.num_paused -= 1;
+
+            // This is done while holding the lock so that it does not race
+            // with a subsequent pause request.
+            context.set_immediate_exit(false);
+        }
+        !state.stop_requested
+    }
+
+    /// Counts a vCPU thread as running until the returned guard is dropped.
+    pub fn enter(self: &Arc<Self>) -> RunningGuard {
+        self.state.lock().unwrap().num_running += 1;
+        RunningGuard(self.clone())
+    }
+}
+
+pub struct RunningGuard(Arc<Control>);
+
+impl Drop for RunningGuard {
+    fn drop(&mut self) {
+        self.0.state.lock().unwrap().num_running -= 1;
+        self.0.cond.notify_all();
+    }
+}
+
+pub struct CpuConfig {
+    pub cpuid: CpuId,
+    pub vcpu_mmap_size: NonZeroUsize,
+    pub bootable: Bootable,
+    pub pv_features: PvFeatures,
+    pub tsc_khz: Option<NonZeroU32>,
+
+    /// Size in bytes of the dirty ring of each vCPU, if enabled
+    pub dirty_ring_size: Option<NonZeroUsize>,
+}
+
+impl CpuConfig {
+    /// Creates the vCPU `id` and sets up its initial state.
+    pub fn create_vcpu(&self, vm: Arc<Vm>, id: usize) -> Result<CpuContext> {
+        let vcpu = Vcpu::new(vm, id as u32)?;
+
+        let tsc_khz = match self.tsc_khz {
+            Some(tsc_khz) => {
+                vcpu.set_tsc_khz(tsc_khz.get())?;
+                tsc_khz.get()
+            }
+            None => vcpu.tsc_khz()?,
+        };
+
+        let mut cpuid = self.cpuid.clone();
+        self.pv_features.apply(&mut cpuid, tsc_khz)?;
+        for entry in cpuid.as_mut_slice() {
+            match entry.function {
+                0x1 => {
+                    // Set local APIC ID
+                    entry.ebx &= !(0xff << 24);
+                    entry.ebx |= (id as u32) << 24;
+
+                    if entry.index == 0 {
+                        // Set X86_FEATURE_HYPERVISOR
+                        entry.ecx |= 1 << 31;
+                    }
+                }
+                0xb => {
+                    // Set x2APIC ID
+                    entry.edx = id as u32;
+                }
+                0x8000_0001 if self.bootable.protocol.is_32bit() => {
+                    entry.ecx &= !(1 << 29); // Disable 64-bit mode
+                }
+                _ => {}
+            }
+        }
+        vcpu.set_cpuid(&cpuid)?;
+
+        let initial_sregs = vcpu.sregs()?;
+        let initial_lapic = vcpu.lapic()?;
+        let initial_mp_state = vcpu.mp_state()?;
+        self.configure_registers(&vcpu, &initial_sregs)?;
+
+        let run = Mmapped::<kvm_run>::new_file(&vcpu, self.vcpu_mmap_size, 0)?;
+        let dirty_ring = self
+            .dirty_ring_size
+            .map(|size| DirtyRing::new(&vcpu, size))
+            .transpose()?;
+
+        Ok(CpuContext {
+            id,
+            vcpu,
+            run,
+            thread: Mutex::new(None),
+            exit_stats: Mutex::default(),
+            dirty_ring,
+            initial_sregs,
+            initial_lapic,
+            initial_mp_state,
+        })
+    }
+
+    /// Brings the vCPU back to the state it had after `create_vcpu`, except
+    /// that the registers are set up for `self.bootable`.
+    pub fn reset_vcpu(&self, context: &CpuContext) -> Result<()> {
+        let vcpu = &context.vcpu;
+        vcpu.set_lapic(&context.initial_lapic)?;
+        vcpu.set_mp_state(context.initial_mp_state)?;
+        vcpu.set_vcpu_events(&kvm_vcpu_events {
+            flags: KVM_VCPUEVENT_VALID_NMI_PENDING | KVM_VCPUEVENT_VALID_SIPI_VECTOR,
+            ..Default::default()
+        })?;
+        vcpu.set_fpu(&kvm_fpu {
+            fcw: 0x37f,
+            mxcsr: 0x1f80,
+            ..Default::default()
+        })?;
+
+        let entries = RESET_MSRS.map(|(index, data)| kvm_msr_entry {
+            index,
+            data,
+            ..Default::default()
+        });
+        let msrs = Msrs::from_entries(&entries).unwrap();
+        let n = vcpu.set_msrs(&msrs)?;
+        if n < entries.len() {
+            eprintln!("Failed to reset MSR {:#x}", entries[n].index);
+        }
+
+        self.configure_registers(vcpu, &context.initial_sregs)
+    }
+
+    fn configure_registers(&self, vcpu: &Vcpu, initial_sregs: &kvm_sregs) -> Result<()> {
+        let mut sregs = *initial_sregs;
+        self.bootable.configure_sregs(&mut sregs);
+        vcpu.set_sregs(&sregs)?;
+
+        let mut regs = kvm_regs::default();
+        self.bootable.configure_regs(&mut regs);
+        vcpu.set_regs(&regs)?;
+        Ok(())
+    }
+}
+
+pub struct Cpu {
+    pub context: Arc<CpuContext>,
+    pub control: Arc<Control>,
+    pub port_io_bus: PortIoBus,
+    pub mmio_bus: MmioBus,
+    pub coalesced_ring: Option<Arc<CoalescedRing>>,
+    pub memory: GuestMemory,
+    pub _running: RunningGuard,
+}
+
+impl Cpu {
+    pub fn run(mut self) -> Result<()> {
+        *self.context.thread.lock().unwrap() = Some(unsafe { libc::pthread_self() });
+        let result = self.run_loop();
+        *self.context.thread.lock().unwrap() = None;
+        match result {
+            Ok(exit) => {
+                self.control.stop(exit);
+                Ok(())
+            }
+            Err(e) => {
+                self.control.stop(None);
+                Err(e)
+            }
+        }
+    }
+
+    /// Runs the vCPU until it either exits by itself or is stopped by others.
+    fn run_loop(&mut self) -> Result<Option<VmExit>> {
+        loop {
+            if !self.control.checkpoint(&self.context) {
+                return Ok(None);
+            }
+            let result = unsafe { self.context.vcpu.run() };
+
+            // Buffered writes happened before the exit, so they are handled
+            // first.
+            if let Some(ring) = &self.coalesced_ring {
+                ring.drain(&mut self.port_io_bus)?;
+            }
+
+            match result {
+                Ok(()) => {}
+                Err(nix::Error::EAGAIN | nix::Error::EINTR) => continue,
+                Err(e) => return Err(e.into()),
+            }
+            let start = Instant::now();
+            let exit = self.handle_exit()?;
+            self.context
+                .exit_stats
+                .lock()
+                .unwrap()
+                .record(self.context.run.as_ref(), start.elapsed());
+            if let Some(exit) = exit {
+                return Ok(Some(exit));
+            }
+        }
+    }
+
+    fn handle_exit(&mut self) -> Result<Option<VmExit>> {
+        let run = &self.context.run;
+        let dump = || -> Result<_> { Ok(Box::new(CpuDump::capture(&self.context, &self.memory)?)) };
+        let exit_reason = run.as_ref().exit_reason;
+        match exit_reason {
+            KVM_EXIT_IO => {
+                let io = unsafe { run.as_ref().__bindgen_anon_1.io };
+                let ptr = run.as_ptr().cast::<u8>();
+                let ptr = unsafe { ptr.offset(io.data_offset as isize) };
+                let len = io.size as usize * io.count as usize;
+                let data = unsafe { std::slice::from_raw_parts_mut(ptr, len) };
+                let Some(width) = IoWidth::from_bytes(io.size.into()) else {
+                    eprintln!("Invalid IO size {}", io.size);
+                    return Ok(None);
+                };
+                // String instructions with a REP prefix transfer `count`
+                // values at once.
+                let values = data.chunks_exact_mut(width.bytes());
+                match io.direction.into() {
+                    KVM_EXIT_IO_IN => {
+                        for value in values {
+                            let bytes = self.port_io_bus.read(io.port, width)?.to_le_bytes();
+                            value.copy_from_slice(&bytes[..value.len()]);
+                        }
+                    }
+                    KVM_EXIT_IO_OUT => {
+                        for value in values {
+                            let mut bytes = [0; 4];
+                            bytes[..value.len()].copy_from_slice(value);
+                            self.port_io_bus
+                                .write(io.port, width, u32::from_le_bytes(bytes))?;
+                        }
+                    }
+                    _ => eprintln!("Unknown IO direction {}", io.direction),
+                }
+            }
+            KVM_EXIT_MMIO => {
+                let mmio = unsafe { &mut (*run.as_ptr()).__bindgen_anon_1.mmio };
+                let len = (mmio.len as usize).min(mmio.data.len());
+                let data = &mut mmio.data[..len];
+                if mmio.is_write != 0 {
+                    self.mmio_bus.write(mmio.phys_addr, data)?;
+                } else {
+                    self.mmio_bus.read(mmio.phys_addr, data)?;
+                }
+            }
+            KVM_EXIT_DEBUG => {
+                let debug = unsafe { run.as_ref().__bindgen_anon_1.debug.arch };
+                self.control.debug_stop(DebugEvent::Trap {
+                    vcpu: self.context.id,
+                    exception: debug.exception,
+                    dr6: debug.dr6,
+                });
+            }
+            KVM_EXIT_DIRTY_RING_FULL => {
+                if let Some(ring) = &self.context.dirty_ring {
+                    ring.harvest(self.memory.dirty_pages());
+                }
+                self.context.vcpu.vm().reset_dirty_rings()?;
+            }
+            KVM_EXIT_HLT => return Ok(Some(VmExit::Poweroff)),
+            KVM_EXIT_SHUTDOWN => return Ok(Some(VmExit::TripleFault { dump: dump()? })),
+            KVM_EXIT_FAIL_ENTRY => {
+                let fail_entry = unsafe { run.as_ref().__bindgen_anon_1.fail_entry };
+                return Ok(Some(VmExit::FailEntry {
+                    hardware_entry_failure_reason: fail_entry.hardware_entry_failure_reason,
+                    dump: dump()?,
+                }));
+            }
+            KVM_EXIT_INTERNAL_ERROR => {
+                let internal = unsafe { run.as_ref().__bindgen_anon_1.internal };
+                let ndata = (internal.ndata as usize).min(internal.data.len());
+                return Ok(Some(VmExit::InternalError {
+                    suberror: internal.suberror,
+                    data: internal.data[..ndata].to_vec(),
+                    dump: dump()?,
+                }));
+            }
+            KVM_EXIT_SYSTEM_EVENT => {
+                let system_event = unsafe { run.as_ref().__bindgen_anon_1.system_event };
+                match system_event.type_ {
+                    KVM_SYSTEM_EVENT_SHUTDOWN => return Ok(Some(VmExit::Poweroff)),
+                    KVM_SYSTEM_EVENT_RESET => return Ok(Some(VmExit::Reset)),
+                    KVM_SYSTEM_EVENT_CRASH => return Ok(Some(VmExit::GuestPanic)),
+                    type_ => eprintln!("Unknown system event {type_}"),
+                }
+            }
+            reason => match profile::exit_reason_name(reason) {
+                "Unknown" => eprintln!("Unknown exit reason {reason}"),
+                name => eprintln!("{name}"),
+            },
+        }
+        Ok(None)
+    }
+}
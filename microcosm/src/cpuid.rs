@@ -0,0 +1,301 @@
+use crate::Error;
+use std::str::FromStr;
+use sys::{
+    kvm_bindings::{kvm_cpuid_entry2, CpuId},
+    kvm_para::{
+        KVM_CPUID_FEATURES, KVM_CPUID_SIGNATURE, KVM_FEATURE_ASYNC_PF, KVM_FEATURE_ASYNC_PF_INT,
+        KVM_FEATURE_ASYNC_PF_VMEXIT, KVM_FEATURE_CLOCKSOURCE, KVM_FEATURE_CLOCKSOURCE2,
+        KVM_FEATURE_CLOCKSOURCE_STABLE_BIT, KVM_FEATURE_MSI_EXT_DEST_ID, KVM_FEATURE_NOP_IO_DELAY,
+        KVM_FEATURE_POLL_CONTROL, KVM_FEATURE_PV_EOI, KVM_FEATURE_PV_SCHED_YIELD,
+        KVM_FEATURE_PV_SEND_IPI, KVM_FEATURE_PV_TLB_FLUSH, KVM_FEATURE_PV_UNHALT,
+        KVM_FEATURE_STEAL_TIME, KVM_SIGNATURE,
+    },
+};
+
+// The layout of the hypervisor CPUID leaves is described in
+// https://www.kernel.org/doc/Documentation/virt/kvm/x86/cpuid.rst
+
+/// VMware-style timing information leaf, reporting the TSC frequency in eax
+/// and the local APIC bus frequency in ebx, both in kHz.
+const KVM_CPUID_TIMING_INFO: u32 = 0x4000_0010;
+
+/// KVM emulates the local APIC timer with a 1 GHz bus clock.
+const APIC_BUS_KHZ: u32 = 1_000_000;
+
+/// Paravirtual features that can be advertised to the guest.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PvFeature {
+    /// kvmclock clock source
+    Clock,
+
+    /// No delay is needed on I/O ports
+    NopIoDelay,
+
+    /// Asynchronous page faults
+    AsyncPf,
+
+    /// Steal time accounting
+    StealTime,
+
+    /// Paravirtual end of interrupt
+    PvEoi,
+
+    /// Paravirtual spinlock unhalting
+    PvUnhalt,
+
+    /// Paravirtual TLB flush
+    PvTlbFlush,
+
+    /// Paravirtual IPI sending
+    PvSendIpi,
+
+    /// Host-side halt polling control
+    PollControl,
+
+    /// Paravirtual yield to preempted vCPUs
+    PvSchedYield,
+
+    /// Extended destination ID in MSI address
+    MsiExtDestId,
+
+    /// TSC and APIC bus frequencies in leaf `0x4000_0010`
+    TscFrequency,
+}
+
+impl PvFeature {
+    pub const ALL: [Self; 12] = [
+        Self::Clock,
+        Self::NopIoDelay,
+        Self::AsyncPf,
+        Self::StealTime,
+        Self::PvEoi,
+        Self::PvUnhalt,
+        Self::PvTlbFlush,
+        Self::PvSendIpi,
+        Self::PollControl,
+        Self::PvSchedYield,
+        Self::MsiExtDestId,
+        Self::TscFrequency,
+    ];
+
+    pub const fn name(self) -> &'static str {
+        match self {
+            Self::Clock => "clock",
+            Self::NopIoDelay => "nop-io-delay",
+            Self::AsyncPf => "async-pf",
+            Self::StealTime => "steal-time",
+            Self::PvEoi => "pv-eoi",
+            Self::PvUnhalt => "pv-unhalt",
+            Self::PvTlbFlush => "pv-tlb-flush",
+            Self::PvSendIpi => "pv-send-ipi",
+            Self::PollControl => "poll-control",
+            Self::PvSchedYield => "pv-sched-yield",
+            Self::MsiExtDestId => "msi-ext-dest-id",
+            Self::TscFrequency => "tsc-frequency",
+        }
+    }
+
+    /// Bits of `KVM_CPUID_FEATURES` eax covered by the feature
+    const fn cpuid_bits(self) -> u32 {
+        match self {
+            Self::Clock => {
+                1 << KVM_FEATURE_CLOCKSOURCE
+                    | 1 << KVM_FEATURE_CLOCKSOURCE2
+                    | 1 << KVM_FEATURE_CLOCKSOURCE_STABLE_BIT
+            }
+            Self::NopIoDelay => 1 << KVM_FEATURE_NOP_IO_DELAY,
+            Self::AsyncPf => {
+                1 << KVM_FEATURE_ASYNC_PF
+                    | 1 << KVM_FEATURE_ASYNC_PF_VMEXIT
+                    | 1 << KVM_FEATURE_ASYNC_PF_INT
+            }
+            Self::StealTime => 1 << KVM_FEATURE_STEAL_TIME,
+            Self::PvEoi => 1 << KVM_FEATURE_PV_EOI,
+            Self::PvUnhalt => 1 << KVM_FEATURE_PV_UNHALT,
+            Self::PvTlbFlush => 1 << KVM_FEATURE_PV_TLB_FLUSH,
+            Self::PvSendIpi => 1 << KVM_FEATURE_PV_SEND_IPI,
+            Self::PollControl => 1 << KVM_FEATURE_POLL_CONTROL,
+            Self::PvSchedYield => 1 << KVM_FEATURE_PV_SCHED_YIELD,
+            Self::MsiExtDestId => 1 << KVM_FEATURE_MSI_EXT_DEST_ID,
+            Self::TscFrequency => 0,
+        }
+    }
+}
+
+impl FromStr for PvFeature {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|feature| feature.name() == s)
+            .ok_or_else(|| Error::UnknownPvFeature(s.to_owned()))
+    }
+}
+
+/// Returns whether `cpuid` advertises 1 GiB pages.
+pub fn has_gib_pages(cpuid: &CpuId) -> bool {
+    cpuid
+        .as_slice()
+        .iter()
+        .find(|entry| entry.function == 0x8000_0001)
+        .is_some_and(|entry| entry.edx & (1 << 26) != 0)
+}
+
+/// Registers of the CPUID leaves that enumerate features, as
+/// `(function, index, register, mask)`
+///
+/// The masks leave out the bits that KVM updates as the guest runs, such as
+/// OSXSAVE, which follow the state of the vCPU rather than the host.
+const FEATURE_REGISTERS: [(u32, u32, Register, u32); 10] = [
+    // OSXSAVE and the hypervisor bit in ecx, APIC in edx
+    (0x1, 0, Register::Ecx, !(1 << 27 | 1 << 31)),
+    (0x1, 0, Register::Edx, !(1 << 9)),
+    (0x7, 0, Register::Ebx, !0),
+    // OSPKE
+    (0x7, 0, Register::Ecx, !(1 << 4)),
+    (0x7, 0, Register::Edx, !0),
+    (0x7, 1, Register::Eax, !0),
+    (0xd, 1, Register::Eax, !0),
+    (0x8000_0001, 0, Register::Ecx, !0),
+    (0x8000_0001, 0, Register::Edx, !0),
+    (KVM_CPUID_FEATURES, 0, Register::Eax, !0),
+];
+
+#[derive(Clone, Copy)]
+enum Register {
+    Eax,
+    Ebx,
+    Ecx,
+    Edx,
+}
+
+impl Register {
+    const fn get(self, entry: &kvm_cpuid_entry2) -> u32 {
+        match self {
+            Self::Eax => entry.eax,
+            Self::Ebx => entry.ebx,
+            Self::Ecx => entry.ecx,
+            Self::Edx => entry.edx,
+        }
+    }
+
+    const fn name(self) -> &'static str {
+        match self {
+            Self::Eax => "eax",
+            Self::Ebx => "ebx",
+            Self::Ecx => "ecx",
+            Self::Edx => "edx",
+        }
+    }
+}
+
+/// Checks that a host supporting `supported` (as reported by
+/// `KVM_GET_SUPPORTED_CPUID`) offers all the features in `guest`, the CPUID
+/// that the vCPUs of a guest were configured from, so that the guest can
+/// move to it.
+pub fn check_compatible(guest: &CpuId, supported: &CpuId) -> Result<(), Error> {
+    let find = |cpuid: &CpuId, function: u32, index: u32| {
+        cpuid
+            .as_slice()
+            .iter()
+            .find(|entry| entry.function == function && entry.index == index)
+            .copied()
+            .unwrap_or_default()
+    };
+    for (function, index, register, mask) in FEATURE_REGISTERS {
+        let wanted = register.get(&find(guest, function, index)) & mask;
+        let missing = wanted & !register.get(&find(supported, function, index));
+        if missing != 0 {
+            return Err(Error::IncompatibleCpuid(format!(
+                "leaf {function:#x}.{index} {} bits {missing:#x} are not supported by the host",
+                register.name()
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Set of enabled paravirtual features
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PvFeatures(u32);
+
+impl Default for PvFeatures {
+    fn default() -> Self {
+        Self((1 << PvFeature::ALL.len()) - 1)
+    }
+}
+
+impl PvFeatures {
+    pub fn set(&mut self, feature: PvFeature, enabled: bool) {
+        if enabled {
+            self.0 |= 1 << feature as u32;
+        } else {
+            self.0 &= !(1 << feature as u32);
+        }
+    }
+
+    pub fn contains(self, feature: PvFeature) -> bool {
+        self.0 & (1 << feature as u32) != 0
+    }
+
+    /// Replaces the hypervisor leaves of `cpuid` with the KVM ones.
+    ///
+    /// Only the features that are both supported by the host (as reported in
+    /// `cpuid`) and enabled are advertised. The timing information leaf is
+    /// populated with `tsc_khz` if the feature is enabled.
+    pub fn apply(self, cpuid: &mut CpuId, tsc_khz: u32) -> Result<(), Error> {
+        let supported = cpuid
+            .as_slice()
+            .iter()
+            .find(|entry| entry.function == KVM_CPUID_FEATURES)
+            .map_or(0, |entry| entry.eax);
+        let features = PvFeature::ALL
+            .into_iter()
+            .filter(|&feature| self.contains(feature))
+            .fold(0, |bits, feature| bits | feature.cpuid_bits());
+
+        cpuid.retain(|entry| {
+            !(KVM_CPUID_SIGNATURE..=KVM_CPUID_TIMING_INFO).contains(&entry.function)
+        });
+
+        let with_timing_info = self.contains(PvFeature::TscFrequency);
+        let signature: Vec<_> = KVM_SIGNATURE
+            .chunks_exact(4)
+            .map(|chunk| u32::from_le_bytes(chunk.try_into().unwrap()))
+            .collect();
+        let entries = [
+            kvm_cpuid_entry2 {
+                function: KVM_CPUID_SIGNATURE,
+                eax: if with_timing_info {
+                    KVM_CPUID_TIMING_INFO
+                } else {
+                    KVM_CPUID_FEATURES
+                },
+                ebx: signature[0],
+                ecx: signature[1],
+                edx: signature[2],
+                ..Default::default()
+            },
+            kvm_cpuid_entry2 {
+                function: KVM_CPUID_FEATURES,
+                eax: supported & features,
+                ..Default::default()
+            },
+        ];
+        for entry in entries {
+            cpuid.push(entry).map_err(|_| Error::TooManyCpuidEntries)?;
+        }
+        if with_timing_info {
+            cpuid
+                .push(kvm_cpuid_entry2 {
+                    function: KVM_CPUID_TIMING_INFO,
+                    eax: tsc_khz,
+                    ebx: APIC_BUS_KHZ,
+                    ..Default::default()
+                })
+                .map_err(|_| Error::TooManyCpuidEntries)?;
+        }
+        Ok(())
+    }
+}
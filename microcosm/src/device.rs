@@ -1,21 +1,147 @@
+mod hub;
 mod i8042;
+mod mmio;
+mod pvpanic;
 mod rtc;
 mod serial;
+mod virtio;
+mod virtio_mem;
 
+pub(crate) use hub::{PortIoBus, PortIoHub};
 pub use i8042::I8042;
+pub(crate) use mmio::{MmioBus, SharedMmioDevice};
+pub use pvpanic::PvPanic;
+pub(crate) use pvpanic::PORT as PVPANIC_PORT;
 pub use rtc::Rtc;
 pub use serial::Serial;
+pub(crate) use virtio::{VirtioMmio, MMIO_SIZE as VIRTIO_MMIO_SIZE};
+pub(crate) use virtio_mem::{VirtioMem, BLOCK_SIZE as VIRTIO_MEM_BLOCK_SIZE};
 
-use crate::{Error, Result};
+use crate::Result;
 use std::{
     ops::{Range, RangeInclusive},
     sync::{Arc, Mutex},
 };
 
+/// Size of a port access
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum IoWidth {
+    Byte,
+    Word,
+    Dword,
+}
+
+impl IoWidth {
+    pub fn from_bytes(bytes: usize) -> Option<Self> {
+        match bytes {
+            1 => Some(Self::Byte),
+            2 => Some(Self::Word),
+            4 => Some(Self::Dword),
+            _ => None,
+        }
+    }
+
+    pub fn bytes(self) -> usize {
+        match self {
+            Self::Byte => 1,
+            Self::Word => 2,
+            Self::Dword => 4,
+        }
+    }
+
+    /// Returns the width of each of the two accesses that this one is split
+    /// into.
+    fn half(self) -> Option<Self> {
+        match self {
+            Self::Byte => None,
+            Self::Word => Some(Self::Byte),
+            Self::Dword => Some(Self::Word),
+        }
+    }
+}
+
+/// A write that KVM buffered in the coalesced ring
+#[derive(Debug, Clone, Copy)]
+pub struct PortWrite {
+    pub port: u16,
+    pub width: IoWidth,
+    pub value: u32,
+}
+
+/// A device accessed with the IN and OUT instructions
+///
+/// Values are little-endian and only the low `width` bytes are meaningful.
 pub trait PortIoDevice {
     fn port_range(&self) -> PortRange;
-    fn read(&mut self, port: u16, data: &mut [u8]) -> Result<()>;
-    fn write(&mut self, port: u16, data: &[u8]) -> Result<()>;
+    fn read(&mut self, port: u16, width: IoWidth) -> Result<u32>;
+    fn write(&mut self, port: u16, width: IoWidth, value: u32) -> Result<()>;
+
+    /// Widest access the device handles
+    ///
+    /// Wider accesses are split into narrower ones at consecutive ports, as an
+    /// ISA bus does for 8-bit devices.
+    fn max_width(&self) -> IoWidth {
+        IoWidth::Dword
+    }
+
+    /// Name of the device, used in diagnostics
+    fn name(&self) -> &'static str {
+        let type_name = std::any::type_name::<Self>();
+        type_name.rsplit("::").next().unwrap_or(type_name)
+    }
+
+    /// Ports whose writes KVM may buffer instead of exiting to userspace
+    ///
+    /// Buffered writes are handed to `write_coalesced` in order, before any
+    /// other access to the device is handled, but possibly long after the
+    /// guest made them. Only ports whose writes do not need an immediate
+    /// effect should opt in.
+    fn coalesced_ports(&self) -> Vec<PortRange> {
+        Vec::new()
+    }
+
+    /// Whether writes to `coalesced_ports` may currently be buffered
+    ///
+    /// Checked again after each write that is not buffered and after a
+    /// reset, so that devices can opt out while the guest expects their
+    /// writes to take effect promptly.
+    fn coalescing_enabled(&self) -> bool {
+        true
+    }
+
+    /// Handles a batch of buffered writes to the ports returned by
+    /// `coalesced_ports`.
+    ///
+    /// Devices can override this to amortize work over the batch.
+    fn write_coalesced(&mut self, writes: &[PortWrite]) -> Result<()> {
+        for write in writes {
+            self.write(write.port, write.width, write.value)?;
+        }
+        Ok(())
+    }
+
+    /// Brings the device back to its power-on state when the guest reboots.
+    fn reset(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Serializes the state of the device into a snapshot of the guest.
+    ///
+    /// Devices without state of their own return nothing.
+    fn save_state(&self) -> Vec<u8> {
+        Vec::new()
+    }
+
+    /// Restores the state returned by `save_state` when the guest is
+    /// restored from a snapshot, before the device is first accessed.
+    fn restore_state(&mut self, _state: &[u8]) -> Result<()> {
+        Ok(())
+    }
+
+    /// Gives the device a new identity, such as a MAC address, a vsock CID
+    /// or the seed of an RNG, after `restore_state` copied the state of the
+    /// device of the guest it was cloned from.
+    fn regenerate_identity(&mut self) {}
 }
 
 impl<T: PortIoDevice + ?Sized> PortIoDevice for &mut T {
@@ -23,12 +149,44 @@ impl<T: PortIoDevice + ?Sized> PortIoDevice for &mut T {
         (**self).port_range()
     }
 
-    fn read(&mut self, port: u16, data: &mut [u8]) -> Result<()> {
-        (**self).read(port, data)
+    fn read(&mut self, port: u16, width: IoWidth) -> Result<u32> {
+        (**self).read(port, width)
+    }
+
+    fn write(&mut self, port: u16, width: IoWidth, value: u32) -> Result<()> {
+        (**self).write(port, width, value)
+    }
+
+    fn name(&self) -> &'static str {
+        (**self).name()
+    }
+
+    fn coalesced_ports(&self) -> Vec<PortRange> {
+        (**self).coalesced_ports()
     }
 
-    fn write(&mut self, port: u16, data: &[u8]) -> Result<()> {
-        (*self).write(port, data)
+    fn max_width(&self) -> IoWidth {
+        (**self).max_width()
+    }
+
+    fn write_coalesced(&mut self, writes: &[PortWrite]) -> Result<()> {
+        (**self).write_coalesced(writes)
+    }
+
+    fn reset(&mut self) -> Result<()> {
+        (**self).reset()
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        (**self).save_state()
+    }
+
+    fn restore_state(&mut self, state: &[u8]) -> Result<()> {
+        (**self).restore_state(state)
+    }
+
+    fn regenerate_identity(&mut self) {
+        (**self).regenerate_identity();
     }
 }
 
@@ -37,12 +195,44 @@ impl<T: PortIoDevice + ?Sized> PortIoDevice for Box<T> {
         (**self).port_range()
     }
 
-    fn read(&mut self, port: u16, data: &mut [u8]) -> Result<()> {
-        (**self).read(port, data)
+    fn read(&mut self, port: u16, width: IoWidth) -> Result<u32> {
+        (**self).read(port, width)
+    }
+
+    fn write(&mut self, port: u16, width: IoWidth, value: u32) -> Result<()> {
+        (**self).write(port, width, value)
+    }
+
+    fn name(&self) -> &'static str {
+        (**self).name()
+    }
+
+    fn coalesced_ports(&self) -> Vec<PortRange> {
+        (**self).coalesced_ports()
+    }
+
+    fn max_width(&self) -> IoWidth {
+        (**self).max_width()
+    }
+
+    fn write_coalesced(&mut self, writes: &[PortWrite]) -> Result<()> {
+        (**self).write_coalesced(writes)
     }
 
-    fn write(&mut self, port: u16, data: &[u8]) -> Result<()> {
-        (**self).write(port, data)
+    fn reset(&mut self) -> Result<()> {
+        (**self).reset()
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        (**self).save_state()
+    }
+
+    fn restore_state(&mut self, state: &[u8]) -> Result<()> {
+        (**self).restore_state(state)
+    }
+
+    fn regenerate_identity(&mut self) {
+        (**self).regenerate_identity();
     }
 }
 
@@ -51,12 +241,44 @@ impl<T: PortIoDevice + ?Sized> PortIoDevice for Mutex<T> {
         self.lock().unwrap().port_range()
     }
 
-    fn read(&mut self, port: u16, data: &mut [u8]) -> Result<()> {
-        self.get_mut().unwrap().read(port, data)
+    fn read(&mut self, port: u16, width: IoWidth) -> Result<u32> {
+        self.get_mut().unwrap().read(port, width)
+    }
+
+    fn write(&mut self, port: u16, width: IoWidth, value: u32) -> Result<()> {
+        self.get_mut().unwrap().write(port, width, value)
+    }
+
+    fn name(&self) -> &'static str {
+        self.lock().unwrap().name()
+    }
+
+    fn coalesced_ports(&self) -> Vec<PortRange> {
+        self.lock().unwrap().coalesced_ports()
+    }
+
+    fn max_width(&self) -> IoWidth {
+        self.lock().unwrap().max_width()
+    }
+
+    fn write_coalesced(&mut self, writes: &[PortWrite]) -> Result<()> {
+        self.get_mut().unwrap().write_coalesced(writes)
+    }
+
+    fn reset(&mut self) -> Result<()> {
+        self.get_mut().unwrap().reset()
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        self.lock().unwrap().save_state()
     }
 
-    fn write(&mut self, port: u16, data: &[u8]) -> Result<()> {
-        self.get_mut().unwrap().write(port, data)
+    fn restore_state(&mut self, state: &[u8]) -> Result<()> {
+        self.get_mut().unwrap().restore_state(state)
+    }
+
+    fn regenerate_identity(&mut self) {
+        self.get_mut().unwrap().regenerate_identity();
     }
 }
 
@@ -65,61 +287,44 @@ impl<T: PortIoDevice + ?Sized> PortIoDevice for Arc<Mutex<T>> {
         self.lock().unwrap().port_range()
     }
 
-    fn read(&mut self, port: u16, data: &mut [u8]) -> Result<()> {
-        self.lock().unwrap().read(port, data)
+    fn read(&mut self, port: u16, width: IoWidth) -> Result<u32> {
+        self.lock().unwrap().read(port, width)
     }
 
-    fn write(&mut self, port: u16, data: &[u8]) -> Result<()> {
-        self.lock().unwrap().write(port, data)
+    fn write(&mut self, port: u16, width: IoWidth, value: u32) -> Result<()> {
+        self.lock().unwrap().write(port, width, value)
     }
-}
 
-pub(crate) struct PortIoHub<T> {
-    devices: Vec<T>,
-}
+    fn name(&self) -> &'static str {
+        self.lock().unwrap().name()
+    }
 
-impl<T> Default for PortIoHub<T> {
-    fn default() -> Self {
-        Self {
-            devices: Vec::new(),
-        }
+    fn coalesced_ports(&self) -> Vec<PortRange> {
+        self.lock().unwrap().coalesced_ports()
     }
-}
 
-impl<T: PortIoDevice> PortIoHub<T> {
-    pub fn add_device(&mut self, device: T) -> Result<()> {
-        let range = device.port_range();
-        for d in &self.devices {
-            if range.overlaps(d.port_range()) {
-                return Err(Error::DeviceRangeOverlap);
-            }
-        }
-        self.devices.push(device);
-        Ok(())
+    fn max_width(&self) -> IoWidth {
+        self.lock().unwrap().max_width()
     }
-}
 
-impl<T: PortIoDevice> PortIoDevice for PortIoHub<T> {
-    fn port_range(&self) -> PortRange {
-        (0..=u16::MAX).into()
+    fn write_coalesced(&mut self, writes: &[PortWrite]) -> Result<()> {
+        self.lock().unwrap().write_coalesced(writes)
     }
 
-    fn read(&mut self, port: u16, data: &mut [u8]) -> Result<()> {
-        for device in &mut self.devices {
-            if device.port_range().contains(port) {
-                return device.read(port, data);
-            }
-        }
-        Ok(())
+    fn reset(&mut self) -> Result<()> {
+        self.lock().unwrap().reset()
     }
 
-    fn write(&mut self, port: u16, data: &[u8]) -> Result<()> {
-        for device in &mut self.devices {
-            if device.port_range().contains(port) {
-                return device.write(port, data);
-            }
-        }
-        Ok(())
+    fn save_state(&self) -> Vec<u8> {
+        self.lock().unwrap().save_state()
+    }
+
+    fn restore_state(&mut self, state: &[u8]) -> Result<()> {
+        self.lock().unwrap().restore_state(state)
+    }
+
+    fn regenerate_identity(&mut self) {
+        self.lock().unwrap().regenerate_identity();
     }
 }
 
@@ -162,11 +367,26 @@ impl PartialOrd for PortRange {
 }
 
 impl PortRange {
+    pub(crate) fn base(self) -> u16 {
+        self.base
+    }
+
+    pub(crate) fn len(self) -> u16 {
+        self.len
+    }
+
     fn contains(self, port: u16) -> bool {
-        self.base <= port && port < self.base + self.len
+        self.base <= port && u32::from(port) < u32::from(self.base) + u32::from(self.len)
+    }
+
+    /// Returns whether an access of `width` at `port` falls within the range.
+    fn contains_access(self, port: u16, width: IoWidth) -> bool {
+        self.base <= port
+            && u32::from(port) + width.bytes() as u32 <= u32::from(self.base) + u32::from(self.len)
     }
 
     fn overlaps(self, other: Self) -> bool {
-        self.base < other.base + other.len && other.base < self.base + self.len
+        u32::from(self.base) < u32::from(other.base) + u32::from(other.len)
+            && u32::from(other.base) < u32::from(self.base) + u32::from(self.len)
     }
 }
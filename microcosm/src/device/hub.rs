@@ -0,0 +1,525 @@
+use super::{IoWidth, PortIoDevice, PortRange, PortWrite};
+use crate::{kvm::Vm, Error, Result};
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc, Mutex,
+};
+use sys::kvm_bindings::{kvm_coalesced_mmio_zone, kvm_coalesced_mmio_zone__bindgen_ty_1};
+
+pub type SharedPortIoDevice = Arc<Mutex<dyn PortIoDevice + Send>>;
+
+#[derive(Clone)]
+struct Entry {
+    range: PortRange,
+    max_width: IoWidth,
+    device: SharedPortIoDevice,
+    coalescing: Option<Arc<Coalescing>>,
+}
+
+impl Entry {
+    /// Returns whether the device handles the access without splitting it.
+    fn handles(&self, port: u16, width: IoWidth) -> bool {
+        width <= self.max_width && self.range.contains_access(port, width)
+    }
+
+    /// Registers or unregisters the coalesced ports of the device, depending
+    /// on whether it currently allows it.
+    ///
+    /// Called with the device locked, so that the zones follow the state
+    /// changes of the device in order.
+    fn update_coalescing(&self, device: &(dyn PortIoDevice + Send)) -> Result<()> {
+        self.coalescing.as_ref().map_or(Ok(()), |coalescing| {
+            coalescing.update(device.coalescing_enabled())
+        })
+    }
+}
+
+/// Coalesced ports of a device, registered with KVM as zones of the
+/// coalesced ring
+struct Coalescing {
+    vm: Arc<Vm>,
+    ranges: Vec<PortRange>,
+
+    /// Whether the zones are registered, or `None` once the device is being
+    /// removed
+    registered: Mutex<Option<bool>>,
+}
+
+impl Coalescing {
+    fn update(&self, enabled: bool) -> Result<()> {
+        let mut registered = self.registered.lock().unwrap();
+        match *registered {
+            Some(registered) if registered == enabled => return Ok(()),
+            None => return Ok(()),
+            Some(_) => {}
+        }
+        for &range in &self.ranges {
+            let zone = coalesced_port_zone(range);
+            if enabled {
+                self.vm.register_coalesced_mmio(&zone)?;
+            } else {
+                self.vm.unregister_coalesced_mmio(&zone)?;
+            }
+        }
+        *registered = Some(enabled);
+        Ok(())
+    }
+
+    /// Unregisters the zones for good.
+    fn detach(&self) -> Result<()> {
+        self.update(false)?;
+        *self.registered.lock().unwrap() = None;
+        Ok(())
+    }
+}
+
+fn coalesced_port_zone(range: PortRange) -> kvm_coalesced_mmio_zone {
+    kvm_coalesced_mmio_zone {
+        addr: range.base().into(),
+        size: range.len().into(),
+        __bindgen_anon_1: kvm_coalesced_mmio_zone__bindgen_ty_1 { pio: 1 },
+    }
+}
+
+/// Immutable set of devices sorted by port range
+#[derive(Default)]
+struct PortIoTable {
+    /// Sorted by base port and without overlaps
+    devices: Vec<Entry>,
+}
+
+/// Returns the index of the entry that handles `port` in `devices`, which
+/// must be sorted by base port and without overlaps.
+fn position(devices: &[Entry], port: u16) -> Option<usize> {
+    let i = devices.partition_point(|entry| entry.range.base <= port);
+    let i = i.checked_sub(1)?;
+    devices[i].range.contains(port).then_some(i)
+}
+
+impl PortIoTable {
+    fn find(&self, port: u16) -> Option<&Entry> {
+        position(&self.devices, port).map(|i| &self.devices[i])
+    }
+
+    fn read(&self, port: u16, width: IoWidth) -> Result<u32> {
+        let entry = self.find(port);
+        if let Some(entry) = entry.filter(|entry| entry.handles(port, width)) {
+            return entry.device.lock().unwrap().read(port, width);
+        }
+        match width.half() {
+            Some(half) => {
+                let low = self.read(port, half)?;
+                let high = self.read(port.wrapping_add(half.bytes() as u16), half)?;
+                Ok(low | high << (half.bytes() * 8))
+            }
+            // Nothing drives the bus.
+            None => Ok(0xff),
+        }
+    }
+
+    fn write(&self, port: u16, width: IoWidth, value: u32) -> Result<()> {
+        let entry = self.find(port);
+        if let Some(entry) = entry.filter(|entry| entry.handles(port, width)) {
+            let mut device = entry.device.lock().unwrap();
+            device.write(port, width, value)?;
+            return entry.update_coalescing(&*device);
+        }
+        match width.half() {
+            Some(half) => {
+                self.write(port, half, value)?;
+                let high = value >> (half.bytes() * 8);
+                self.write(port.wrapping_add(half.bytes() as u16), half, high)
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+/// Registry of the port I/O devices of a guest
+///
+/// Adding or removing a device publishes a new copy of the table. Dispatch
+/// goes through a [`PortIoBus`], which keeps using its copy until it notices
+/// an update, so that the vCPUs only contend on the locks of the devices
+/// they access.
+///
+/// With a coalesced ring, the hub also registers the coalesced ports of the
+/// devices whenever they allow it.
+#[derive(Default)]
+pub struct PortIoHub {
+    table: Mutex<Arc<PortIoTable>>,
+
+    /// Incremented each time `table` is replaced
+    generation: AtomicU64,
+
+    /// VM with a coalesced ring
+    coalescing_vm: Option<Arc<Vm>>,
+}
+
+impl PortIoHub {
+    pub fn new(coalescing_vm: Option<Arc<Vm>>) -> Self {
+        Self {
+            coalescing_vm,
+            ..Self::default()
+        }
+    }
+
+    pub fn add_device(&self, device: SharedPortIoDevice) -> Result<()> {
+        let (range, max_width, coalesced_ports) = {
+            let device = device.lock().unwrap();
+            (
+                device.port_range(),
+                device.max_width(),
+                device.coalesced_ports(),
+            )
+        };
+        let coalescing = self
+            .coalescing_vm
+            .as_ref()
+            .filter(|_| !coalesced_ports.is_empty())
+            .map(|vm| {
+                Arc::new(Coalescing {
+                    vm: vm.clone(),
+                    ranges: coalesced_ports,
+                    registered: Mutex::new(Some(false)),
+                })
+            });
+        let entry = Entry {
+            range,
+            max_width,
+            device,
+            coalescing,
+        };
+        self.update(|devices| {
+            let i = devices.partition_point(|entry| entry.range.base < range.base);
+            let prev = i.checked_sub(1).and_then(|i| devices.get(i));
+            if prev
+                .into_iter()
+                .chain(devices.get(i))
+                .any(|entry| entry.range.overlaps(range))
+            {
+                return Err(Error::DeviceRangeOverlap);
+            }
+            devices.insert(i, entry.clone());
+            Ok(())
+        })?;
+        let device = entry.device.lock().unwrap();
+        entry.update_coalescing(&*device)
+    }
+
+    /// Stops KVM from buffering writes to the device that handles `port`,
+    /// before it is removed.
+    ///
+    /// Writes that were already buffered stay in the ring.
+    pub fn detach_coalesced_ports(&self, port: u16) -> Result<()> {
+        let (table, _) = self.snapshot();
+        let entry = table.find(port).ok_or(Error::NoDeviceAtPort(port))?;
+        entry
+            .coalescing
+            .as_ref()
+            .map_or(Ok(()), |coalescing| coalescing.detach())
+    }
+
+    /// Removes the device that handles `port` and returns it.
+    ///
+    /// Accesses that were already being dispatched may still reach the
+    /// device after this returns.
+    pub fn remove_device(&self, port: u16) -> Result<SharedPortIoDevice> {
+        self.update(|devices| {
+            let i = position(devices, port).ok_or(Error::NoDeviceAtPort(port))?;
+            Ok(devices.remove(i).device)
+        })
+    }
+
+    /// Replaces the table with an updated copy.
+    fn update<R>(&self, f: impl FnOnce(&mut Vec<Entry>) -> Result<R>) -> Result<R> {
+        let mut table = self.table.lock().unwrap();
+        let mut devices = table.devices.clone();
+        let result = f(&mut devices)?;
+        *table = Arc::new(PortIoTable { devices });
+        self.generation.fetch_add(1, Ordering::Release);
+        Ok(result)
+    }
+
+    fn snapshot(&self) -> (Arc<PortIoTable>, u64) {
+        let table = self.table.lock().unwrap();
+        (table.clone(), self.generation.load(Ordering::Relaxed))
+    }
+
+    /// Returns a bus dispatching accesses to the devices, to be used by a
+    /// single thread.
+    pub fn bus(self: &Arc<Self>) -> PortIoBus {
+        let (table, generation) = self.snapshot();
+        PortIoBus {
+            hub: self.clone(),
+            table,
+            generation,
+        }
+    }
+
+    /// Returns the device that handles `port`.
+    pub fn device(&self, port: u16) -> Option<SharedPortIoDevice> {
+        let (table, _) = self.snapshot();
+        table.find(port).map(|entry| entry.device.clone())
+    }
+
+    /// Returns all the devices, in ascending order of port.
+    pub fn devices(&self) -> Vec<SharedPortIoDevice> {
+        let (table, _) = self.snapshot();
+        table
+            .devices
+            .iter()
+            .map(|entry| entry.device.clone())
+            .collect()
+    }
+
+    /// Returns the name of the device that handles `port`.
+    pub fn device_name(&self, port: u16) -> Option<&'static str> {
+        let name = self.device(port)?.lock().unwrap().name();
+        Some(name)
+    }
+
+    pub fn reset(&self) -> Result<()> {
+        let (table, _) = self.snapshot();
+        for entry in &table.devices {
+            let mut device = entry.device.lock().unwrap();
+            device.reset()?;
+            entry.update_coalescing(&*device)?;
+        }
+        Ok(())
+    }
+}
+
+/// Dispatches port I/O to the devices of a [`PortIoHub`]
+pub struct PortIoBus {
+    hub: Arc<PortIoHub>,
+    table: Arc<PortIoTable>,
+    generation: u64,
+}
+
+impl PortIoBus {
+    /// Returns the current table, picking up updates made to the hub.
+    fn table(&mut self) -> &PortIoTable {
+        if self.hub.generation.load(Ordering::Acquire) != self.generation {
+            (self.table, self.generation) = self.hub.snapshot();
+        }
+        &self.table
+    }
+
+    /// Reads from `port`, splitting the access if it is wider than the
+    /// device supports or spans several devices.
+    pub fn read(&mut self, port: u16, width: IoWidth) -> Result<u32> {
+        self.table().read(port, width)
+    }
+
+    pub fn write(&mut self, port: u16, width: IoWidth, value: u32) -> Result<()> {
+        self.table().write(port, width, value)
+    }
+
+    /// Hands each run of consecutive writes to the same device over to it as
+    /// one batch.
+    pub fn write_coalesced(&mut self, mut writes: &[PortWrite]) -> Result<()> {
+        let table = self.table();
+        while let Some(write) = writes.first() {
+            let Some(entry) = table
+                .find(write.port)
+                .filter(|entry| entry.handles(write.port, write.width))
+            else {
+                table.write(write.port, write.width, write.value)?;
+                writes = &writes[1..];
+                continue;
+            };
+            let len = writes
+                .iter()
+                .position(|write| !entry.handles(write.port, write.width))
+                .unwrap_or(writes.len());
+            entry
+                .device
+                .lock()
+                .unwrap()
+                .write_coalesced(&writes[..len])?;
+            writes = &writes[len..];
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Device that answers reads with the low byte of the port, repeated
+    /// over the width of the access, and records the accesses it handles
+    /// with the meaningful bytes of the values written
+    struct Recorder {
+        range: PortRange,
+        max_width: IoWidth,
+        writes: Vec<(u16, IoWidth, u32)>,
+        reads: Vec<(u16, IoWidth)>,
+    }
+
+    impl PortIoDevice for Recorder {
+        fn port_range(&self) -> PortRange {
+            self.range
+        }
+
+        fn read(&mut self, port: u16, width: IoWidth) -> Result<u32> {
+            self.reads.push((port, width));
+            Ok(u32::from_le_bytes([port as u8; 4]) >> (32 - width.bytes() * 8))
+        }
+
+        fn write(&mut self, port: u16, width: IoWidth, value: u32) -> Result<()> {
+            let value = value & (u32::MAX >> (32 - width.bytes() * 8));
+            self.writes.push((port, width, value));
+            Ok(())
+        }
+
+        fn max_width(&self) -> IoWidth {
+            self.max_width
+        }
+    }
+
+    fn recorder(range: impl Into<PortRange>, max_width: IoWidth) -> Arc<Mutex<Recorder>> {
+        Arc::new(Mutex::new(Recorder {
+            range: range.into(),
+            max_width,
+            writes: Vec::new(),
+            reads: Vec::new(),
+        }))
+    }
+
+    fn add_recorder(
+        hub: &PortIoHub,
+        range: impl Into<PortRange>,
+        max_width: IoWidth,
+    ) -> Arc<Mutex<Recorder>> {
+        let recorder = recorder(range, max_width);
+        hub.add_device(recorder.clone()).unwrap();
+        recorder
+    }
+
+    #[test]
+    fn wide_access_is_split_for_narrow_device() {
+        let hub = Arc::new(PortIoHub::default());
+        let device = add_recorder(&hub, 0x10..0x18, IoWidth::Byte);
+        let mut bus = hub.bus();
+
+        assert_eq!(bus.read(0x12, IoWidth::Dword).unwrap(), 0x1514_1312);
+        bus.write(0x14, IoWidth::Word, 0xbbaa).unwrap();
+
+        let device = device.lock().unwrap();
+        assert_eq!(
+            device.reads,
+            [0x12, 0x13, 0x14, 0x15].map(|port| (port, IoWidth::Byte))
+        );
+        assert_eq!(
+            device.writes,
+            [(0x14, IoWidth::Byte, 0xaa), (0x15, IoWidth::Byte, 0xbb)]
+        );
+    }
+
+    #[test]
+    fn access_within_max_width_is_not_split() {
+        let hub = Arc::new(PortIoHub::default());
+        let device = add_recorder(&hub, 0x10..0x18, IoWidth::Dword);
+        let mut bus = hub.bus();
+
+        assert_eq!(bus.read(0x14, IoWidth::Dword).unwrap(), 0x1414_1414);
+        bus.write(0x10, IoWidth::Word, 0xbbaa).unwrap();
+
+        let device = device.lock().unwrap();
+        assert_eq!(device.reads, [(0x14, IoWidth::Dword)]);
+        assert_eq!(device.writes, [(0x10, IoWidth::Word, 0xbbaa)]);
+    }
+
+    #[test]
+    fn access_straddling_devices_reaches_both() {
+        let hub = Arc::new(PortIoHub::default());
+        let low = add_recorder(&hub, 0x10..0x12, IoWidth::Dword);
+        let high = add_recorder(&hub, 0x12..0x14, IoWidth::Dword);
+        let mut bus = hub.bus();
+
+        assert_eq!(bus.read(0x10, IoWidth::Dword).unwrap(), 0x1212_1010);
+        bus.write(0x11, IoWidth::Word, 0xbbaa).unwrap();
+
+        let low = low.lock().unwrap();
+        let high = high.lock().unwrap();
+        assert_eq!(low.reads, [(0x10, IoWidth::Word)]);
+        assert_eq!(high.reads, [(0x12, IoWidth::Word)]);
+        assert_eq!(low.writes, [(0x11, IoWidth::Byte, 0xaa)]);
+        assert_eq!(high.writes, [(0x12, IoWidth::Byte, 0xbb)]);
+    }
+
+    #[test]
+    fn unclaimed_ports_read_as_ones() {
+        let hub = Arc::new(PortIoHub::default());
+        let device = add_recorder(&hub, 0x10..0x12, IoWidth::Byte);
+        let mut bus = hub.bus();
+
+        assert_eq!(bus.read(0x0f, IoWidth::Word).unwrap(), 0x10ff);
+        assert_eq!(bus.read(0x11, IoWidth::Dword).unwrap(), 0xffff_ff11);
+        bus.write(0x12, IoWidth::Dword, 0).unwrap();
+
+        assert!(device.lock().unwrap().writes.is_empty());
+    }
+
+    #[test]
+    fn overlapping_device_is_rejected() {
+        let hub = PortIoHub::default();
+        add_recorder(&hub, 0x10..0x18, IoWidth::Byte);
+        add_recorder(&hub, 0x20..0x28, IoWidth::Byte);
+        for range in [0x17..0x20, 0x08..0x11, 0x12..0x14, 0x00..0x30, 0x18..0x21] {
+            assert!(matches!(
+                hub.add_device(recorder(range, IoWidth::Byte)),
+                Err(Error::DeviceRangeOverlap)
+            ));
+        }
+
+        // Adjacent ranges do not overlap.
+        add_recorder(&hub, 0x18..0x20, IoWidth::Byte);
+        add_recorder(&hub, 0x08..0x10, IoWidth::Byte);
+        assert_eq!(hub.devices().len(), 4);
+    }
+
+    #[test]
+    fn lookup_at_range_edges() {
+        let hub = PortIoHub::default();
+        let low = add_recorder(&hub, 0x10..0x18, IoWidth::Byte);
+        let high = add_recorder(&hub, 0x18..0x20, IoWidth::Byte);
+
+        let is = |port, device: &Arc<Mutex<Recorder>>| {
+            let found = hub.device(port).unwrap();
+            std::ptr::addr_eq(Arc::as_ptr(&found), Arc::as_ptr(device))
+        };
+        assert!(hub.device(0x0f).is_none());
+        assert!(is(0x10, &low));
+        assert!(is(0x17, &low));
+        assert!(is(0x18, &high));
+        assert!(is(0x1f, &high));
+        assert!(hub.device(0x20).is_none());
+    }
+
+    #[test]
+    fn removed_device_can_be_added_again() {
+        let hub = Arc::new(PortIoHub::default());
+        add_recorder(&hub, 0x10..0x18, IoWidth::Byte);
+        let device = add_recorder(&hub, 0x18..0x20, IoWidth::Byte);
+        let mut bus = hub.bus();
+
+        assert!(matches!(
+            hub.remove_device(0x20),
+            Err(Error::NoDeviceAtPort(0x20))
+        ));
+        hub.remove_device(0x1f).unwrap();
+        assert!(hub.device(0x18).is_none());
+        assert!(matches!(
+            hub.remove_device(0x18),
+            Err(Error::NoDeviceAtPort(0x18))
+        ));
+        bus.write(0x18, IoWidth::Byte, 1).unwrap();
+
+        hub.add_device(device.clone()).unwrap();
+        bus.write(0x18, IoWidth::Byte, 2).unwrap();
+        assert_eq!(device.lock().unwrap().writes, [(0x18, IoWidth::Byte, 2)]);
+        assert_eq!(hub.devices().len(), 2);
+    }
+}
@@ -1,18 +1,17 @@
-use super::{PortIoDevice, PortRange};
-use crate::Result;
+use super::{IoWidth, PortIoDevice, PortRange};
+use crate::{guest::ExitTrigger, Result, VmExit};
 
 const I8042_DATA_REG: u16 = 0x60;
 const I8042_COMMAND_REG: u16 = 0x64;
 const I8042_CMD_SYSTEM_RESET: u8 = 0xfe;
 
-#[derive(Default)]
 pub struct I8042 {
-    _private: (),
+    exit: ExitTrigger,
 }
 
 impl I8042 {
-    pub fn new() -> Self {
-        Self::default()
+    pub fn new(exit: ExitTrigger) -> Self {
+        Self { exit }
     }
 }
 
@@ -21,13 +20,17 @@ impl PortIoDevice for I8042 {
         (I8042_DATA_REG..=I8042_COMMAND_REG).into()
     }
 
-    fn read(&mut self, _port: u16, _data: &mut [u8]) -> Result<()> {
-        Ok(())
+    fn max_width(&self) -> IoWidth {
+        IoWidth::Byte
+    }
+
+    fn read(&mut self, _port: u16, _width: IoWidth) -> Result<u32> {
+        Ok(0)
     }
 
-    fn write(&mut self, port: u16, data: &[u8]) -> Result<()> {
-        if port == I8042_COMMAND_REG && data.first() == Some(&I8042_CMD_SYSTEM_RESET) {
-            std::process::exit(0);
+    fn write(&mut self, port: u16, _width: IoWidth, value: u32) -> Result<()> {
+        if port == I8042_COMMAND_REG && value as u8 == I8042_CMD_SYSTEM_RESET {
+            self.exit.trigger(VmExit::Reset);
         }
         Ok(())
     }
@@ -0,0 +1,96 @@
+use crate::{Error, Result};
+use std::{
+    ops::Range,
+    sync::{Arc, Mutex},
+};
+
+/// A device accessed through guest physical addresses that are not backed
+/// by RAM
+///
+/// Offsets are relative to the start of the address range of the device.
+/// Values are little-endian.
+pub trait MmioDevice {
+    fn address_range(&self) -> Range<u64>;
+    fn read(&mut self, offset: u64, data: &mut [u8]) -> Result<()>;
+    fn write(&mut self, offset: u64, data: &[u8]) -> Result<()>;
+
+    /// Brings the device back to its power-on state when the guest reboots.
+    fn reset(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+pub type SharedMmioDevice = Arc<Mutex<dyn MmioDevice + Send>>;
+
+struct Entry {
+    range: Range<u64>,
+    device: SharedMmioDevice,
+}
+
+/// Dispatches MMIO accesses to the devices of a guest
+///
+/// The devices are fixed when the guest is built, so the vCPUs share the
+/// same table and only contend on the locks of the devices they access.
+#[derive(Clone, Default)]
+pub struct MmioBus {
+    /// Sorted by address and without overlaps
+    devices: Arc<[Entry]>,
+}
+
+impl MmioBus {
+    pub fn new(devices: Vec<SharedMmioDevice>) -> Result<Self> {
+        let mut devices = devices
+            .into_iter()
+            .map(|device| {
+                let range = device.lock().unwrap().address_range();
+                Entry { range, device }
+            })
+            .collect::<Vec<_>>();
+        devices.sort_by_key(|entry| entry.range.start);
+        if devices
+            .windows(2)
+            .any(|pair| pair[0].range.end > pair[1].range.start)
+        {
+            return Err(Error::DeviceRangeOverlap);
+        }
+        Ok(Self {
+            devices: devices.into(),
+        })
+    }
+
+    /// Returns the device whose range contains the whole access.
+    fn find(&self, addr: u64, len: usize) -> Option<&Entry> {
+        let i = self
+            .devices
+            .partition_point(|entry| entry.range.start <= addr);
+        let entry = self.devices.get(i.checked_sub(1)?)?;
+        let end = addr.checked_add(len as u64)?;
+        (end <= entry.range.end).then_some(entry)
+    }
+
+    pub fn read(&self, addr: u64, data: &mut [u8]) -> Result<()> {
+        let Some(entry) = self.find(addr, data.len()) else {
+            // Nothing drives the bus.
+            data.fill(0xff);
+            return Ok(());
+        };
+        let offset = addr - entry.range.start;
+        entry.device.lock().unwrap().read(offset, data)
+    }
+
+    pub fn write(&self, addr: u64, data: &[u8]) -> Result<()> {
+        let Some(entry) = self.find(addr, data.len()) else {
+            eprintln!("Unhandled MMIO write of {} bytes at {addr:#x}", data.len());
+            return Ok(());
+        };
+        let offset = addr - entry.range.start;
+        entry.device.lock().unwrap().write(offset, data)
+    }
+
+    pub fn reset(&self) -> Result<()> {
+        for entry in self.devices.iter() {
+            entry.device.lock().unwrap().reset()?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,65 @@
+use super::{IoWidth, PortIoDevice, PortRange};
+use crate::{guest::ExitTrigger, Result, VmExit};
+
+// https://www.qemu.org/docs/master/specs/pvpanic.html
+
+/// Port of the device, which is declared in the DSDT
+pub const PORT: u16 = 0x505;
+
+const PVPANIC_PANICKED: u8 = 1 << 0;
+const PVPANIC_CRASH_LOADED: u8 = 1 << 1;
+
+pub struct PvPanic {
+    exit: ExitTrigger,
+}
+
+impl PvPanic {
+    pub fn new(exit: ExitTrigger) -> Self {
+        Self { exit }
+    }
+}
+
+impl PortIoDevice for PvPanic {
+    fn port_range(&self) -> PortRange {
+        (PORT..=PORT).into()
+    }
+
+    fn max_width(&self) -> IoWidth {
+        IoWidth::Byte
+    }
+
+    fn read(&mut self, _port: u16, _width: IoWidth) -> Result<u32> {
+        // Supported events
+        Ok((PVPANIC_PANICKED | PVPANIC_CRASH_LOADED).into())
+    }
+
+    fn write(&mut self, _port: u16, _width: IoWidth, value: u32) -> Result<()> {
+        if value as u8 & PVPANIC_PANICKED != 0 {
+            self.exit.trigger(VmExit::GuestPanic);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::Control;
+    use std::sync::Arc;
+
+    #[test]
+    fn panic_stops_guest() {
+        let control = Arc::new(Control::new(Vec::new()));
+        let mut pvpanic = PvPanic::new(ExitTrigger::new(control.clone()));
+
+        pvpanic
+            .write(PORT, IoWidth::Byte, PVPANIC_CRASH_LOADED.into())
+            .unwrap();
+        assert!(control.take_exit().is_none());
+
+        pvpanic
+            .write(PORT, IoWidth::Byte, PVPANIC_PANICKED.into())
+            .unwrap();
+        assert!(matches!(control.take_exit(), Some(VmExit::GuestPanic)));
+    }
+}
@@ -1,5 +1,8 @@
-use super::{PortIoDevice, PortRange};
-use crate::Result;
+use super::{IoWidth, PortIoDevice, PortRange};
+use crate::{
+    snapshot::{Decoder, Encoder},
+    Result,
+};
 use chrono::{Datelike, Timelike, Utc};
 
 const RTC_PORT_INDEX: u16 = 0x70;
@@ -33,37 +36,53 @@ impl PortIoDevice for Rtc {
         (RTC_PORT_INDEX..=RTC_PORT_DATA).into()
     }
 
-    fn read(&mut self, port: u16, data: &mut [u8]) -> Result<()> {
-        let Some(data) = data.first_mut() else {
-            return Ok(());
-        };
-        if port == RTC_PORT_DATA {
-            let now = Utc::now();
-            *data = match self.cmos_index {
-                RTC_SECONDS => bin_to_bcd(now.second() as u8),
-                RTC_MINUTES => bin_to_bcd(now.minute() as u8),
-                RTC_HOURS => bin_to_bcd(now.hour() as u8),
-                RTC_DAY_OF_WEEK => bin_to_bcd(now.weekday().num_days_from_sunday() as u8 + 1),
-                RTC_DAY_OF_MONTH => bin_to_bcd(now.day() as u8),
-                RTC_MONTH => bin_to_bcd(now.month() as u8),
-                RTC_YEAR => bin_to_bcd((now.year() % 100) as u8),
-                RTC_CENTURY => bin_to_bcd((now.year() / 100) as u8),
-                RTC_STATUS_B => RTC_STATUS_B_24H,
-                _ => return Ok(()),
-            };
-        }
-        Ok(())
+    fn max_width(&self) -> IoWidth {
+        IoWidth::Byte
     }
 
-    fn write(&mut self, port: u16, data: &[u8]) -> Result<()> {
-        let Some(&data) = data.first() else {
-            return Ok(());
+    fn read(&mut self, port: u16, _width: IoWidth) -> Result<u32> {
+        if port != RTC_PORT_DATA {
+            return Ok(0);
+        }
+        let now = Utc::now();
+        let data = match self.cmos_index {
+            RTC_SECONDS => bin_to_bcd(now.second() as u8),
+            RTC_MINUTES => bin_to_bcd(now.minute() as u8),
+            RTC_HOURS => bin_to_bcd(now.hour() as u8),
+            RTC_DAY_OF_WEEK => bin_to_bcd(now.weekday().num_days_from_sunday() as u8 + 1),
+            RTC_DAY_OF_MONTH => bin_to_bcd(now.day() as u8),
+            RTC_MONTH => bin_to_bcd(now.month() as u8),
+            RTC_YEAR => bin_to_bcd((now.year() % 100) as u8),
+            RTC_CENTURY => bin_to_bcd((now.year() / 100) as u8),
+            RTC_STATUS_B => RTC_STATUS_B_24H,
+            _ => 0,
         };
+        Ok(data.into())
+    }
+
+    fn write(&mut self, port: u16, _width: IoWidth, value: u32) -> Result<()> {
         if port == RTC_PORT_INDEX {
-            self.cmos_index = data & !(1 << 7);
+            self.cmos_index = value as u8 & !(1 << 7);
         }
         Ok(())
     }
+
+    fn reset(&mut self) -> Result<()> {
+        self.cmos_index = 0;
+        Ok(())
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        let mut encoder = Encoder::new();
+        encoder.u8(self.cmos_index);
+        encoder.finish()
+    }
+
+    fn restore_state(&mut self, state: &[u8]) -> Result<()> {
+        let mut decoder = Decoder::new(state);
+        self.cmos_index = decoder.u8()?;
+        decoder.finish()
+    }
 }
 
 const fn bin_to_bcd(bin: u8) -> u8 {
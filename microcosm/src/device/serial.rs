@@ -1,5 +1,9 @@
-use super::{PortIoDevice, PortRange};
-use crate::{guest::Irq, Result};
+use super::{IoWidth, PortIoDevice, PortRange, PortWrite};
+use crate::{
+    guest::Irq,
+    snapshot::{Decoder, Encoder},
+    Result,
+};
 use std::{collections::VecDeque, io::Write};
 use sys::serial_reg::{
     UART_FCR, UART_FCR_CLEAR_RCVR, UART_FCR_CLEAR_XMIT, UART_IER, UART_IER_RDI, UART_IER_THRI,
@@ -28,6 +32,10 @@ pub struct Serial {
     scr: u8,
     rx_buf: VecDeque<u8>,
     tx_buf: VecDeque<u8>,
+
+    /// Whether flushing stdout is deferred to the end of a batch of
+    /// coalesced writes
+    defer_flush: bool,
 }
 
 impl Serial {
@@ -39,6 +47,10 @@ impl Serial {
             3 => (0x2e8, 3),
             _ => panic!("Invalid serial port number"),
         };
+        Self::with_port(base_port, irq, irq_number)
+    }
+
+    fn with_port(base_port: u16, irq: Irq, irq_number: u8) -> Self {
         Self {
             base_port,
             irq,
@@ -56,6 +68,7 @@ impl Serial {
             scr: UART_MCR_OUT2 as u8,
             rx_buf: VecDeque::with_capacity(FIFO_LEN),
             tx_buf: VecDeque::with_capacity(FIFO_LEN),
+            defer_flush: false,
         }
     }
 
@@ -73,7 +86,9 @@ impl Serial {
         let (a, b) = self.tx_buf.as_slices();
         stdout.write_all(a)?;
         stdout.write_all(b)?;
-        stdout.flush()?;
+        if !self.defer_flush {
+            stdout.flush()?;
+        }
         self.tx_buf.clear();
         Ok(())
     }
@@ -124,49 +139,70 @@ impl PortIoDevice for Serial {
         (self.base_port..(self.base_port + 8)).into()
     }
 
-    fn read(&mut self, port: u16, data: &mut [u8]) -> Result<()> {
-        let Some(data) = data.first_mut() else {
-            return Ok(());
-        };
-        match (port - self.base_port).into() {
-            UART_RX if self.lcr & UART_LCR_DLAB as u8 != 0 => *data = self.dll,
-            UART_RX if self.rx_buf.is_empty() => {}
+    /// The transmit holding register, since the output is only observed by
+    /// the host.
+    fn coalesced_ports(&self) -> Vec<PortRange> {
+        vec![(self.base_port..(self.base_port + 1)).into()]
+    }
+
+    /// Not while the guest waits for the THRE interrupt, which would be
+    /// delayed until the next exit, or loops the output back.
+    fn coalescing_enabled(&self) -> bool {
+        self.ier & UART_IER_THRI as u8 == 0 && self.mcr & UART_MCR_LOOP as u8 == 0
+    }
+
+    fn write_coalesced(&mut self, writes: &[PortWrite]) -> Result<()> {
+        self.defer_flush = true;
+        let result = writes
+            .iter()
+            .try_for_each(|write| self.write(write.port, write.width, write.value));
+        self.defer_flush = false;
+        std::io::stdout().flush()?;
+        result
+    }
+
+    fn max_width(&self) -> IoWidth {
+        IoWidth::Byte
+    }
+
+    fn read(&mut self, port: u16, _width: IoWidth) -> Result<u32> {
+        let data = match (port - self.base_port).into() {
+            UART_RX if self.lcr & UART_LCR_DLAB as u8 != 0 => self.dll,
+            UART_RX if self.rx_buf.is_empty() => 0,
             UART_RX if self.lsr & UART_LSR_BI as u8 != 0 => {
                 self.lsr &= !UART_LSR_BI as u8;
-                *data = 0;
+                0
             }
             UART_RX => {
-                *data = self.rx_buf.pop_front().unwrap();
+                let data = self.rx_buf.pop_front().unwrap();
                 if self.rx_buf.is_empty() {
                     self.lsr &= !UART_LSR_DR as u8;
                 }
+                data
             }
-            UART_IER if self.lcr & UART_LCR_DLAB as u8 != 0 => *data = self.dlm,
-            UART_IER => *data = self.ier,
-            UART_IIR => *data = self.iir | UART_IIR_FIFO_ENABLED_16550A as u8,
-            UART_LCR => *data = self.lcr,
-            UART_MCR => *data = self.mcr,
-            UART_LSR => *data = self.lsr,
-            UART_MSR => *data = self.msr,
-            UART_SCR => *data = self.scr,
-            _ => {}
-        }
+            UART_IER if self.lcr & UART_LCR_DLAB as u8 != 0 => self.dlm,
+            UART_IER => self.ier,
+            UART_IIR => self.iir | UART_IIR_FIFO_ENABLED_16550A as u8,
+            UART_LCR => self.lcr,
+            UART_MCR => self.mcr,
+            UART_LSR => self.lsr,
+            UART_MSR => self.msr,
+            UART_SCR => self.scr,
+            _ => 0,
+        };
         self.update_irq()?;
-        Ok(())
+        Ok(data.into())
     }
 
-    fn write(&mut self, port: u16, data: &[u8]) -> Result<()> {
-        let Some(&data) = data.first() else {
-            return Ok(());
-        };
+    fn write(&mut self, port: u16, _width: IoWidth, value: u32) -> Result<()> {
+        let data = value as u8;
         match (port - self.base_port).into() {
             UART_TX if self.lcr & UART_LCR_DLAB as u8 != 0 => self.dll = data,
-            UART_TX if self.mcr & UART_MCR_LOOP as u8 != 0 => {
-                if self.rx_buf.len() < FIFO_LEN {
-                    self.rx_buf.push_back(data);
-                    self.lsr |= UART_LSR_DR as u8;
-                }
+            UART_TX if self.mcr & UART_MCR_LOOP as u8 != 0 && self.rx_buf.len() < FIFO_LEN => {
+                self.rx_buf.push_back(data);
+                self.lsr |= UART_LSR_DR as u8;
             }
+            UART_TX if self.mcr & UART_MCR_LOOP as u8 != 0 => {}
             UART_TX if self.tx_buf.len() < FIFO_LEN => {
                 self.tx_buf.push_back(data);
                 self.lsr &= !UART_LSR_TEMT as u8;
@@ -187,4 +223,56 @@ impl PortIoDevice for Serial {
         self.update_irq()?;
         Ok(())
     }
+
+    fn reset(&mut self) -> Result<()> {
+        if self.irq_state != 0 {
+            self.irq.set_level(self.irq_number, false)?;
+        }
+        *self = Self::with_port(self.base_port, self.irq.clone(), self.irq_number);
+        Ok(())
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        let mut encoder = Encoder::new();
+        for reg in [
+            self.irq_state,
+            self.dll,
+            self.dlm,
+            self.iir,
+            self.ier,
+            self.fcr,
+            self.lcr,
+            self.mcr,
+            self.lsr,
+            self.msr,
+            self.scr,
+        ] {
+            encoder.u8(reg);
+        }
+        encoder.bytes(&self.rx_buf.iter().copied().collect::<Vec<_>>());
+        encoder.bytes(&self.tx_buf.iter().copied().collect::<Vec<_>>());
+        encoder.finish()
+    }
+
+    fn restore_state(&mut self, state: &[u8]) -> Result<()> {
+        let mut decoder = Decoder::new(state);
+        for reg in [
+            &mut self.irq_state,
+            &mut self.dll,
+            &mut self.dlm,
+            &mut self.iir,
+            &mut self.ier,
+            &mut self.fcr,
+            &mut self.lcr,
+            &mut self.mcr,
+            &mut self.lsr,
+            &mut self.msr,
+            &mut self.scr,
+        ] {
+            *reg = decoder.u8()?;
+        }
+        self.rx_buf = decoder.bytes()?.iter().copied().collect();
+        self.tx_buf = decoder.bytes()?.iter().copied().collect();
+        decoder.finish()
+    }
 }
@@ -0,0 +1,464 @@
+use super::mmio::MmioDevice;
+use crate::{guest::Irq, memory::GuestMemory, Error, Result};
+use std::{
+    ops::Range,
+    sync::atomic::{fence, Ordering},
+};
+use sys::virtio::{
+    vring_desc, vring_used_elem, VIRTIO_CONFIG_S_DRIVER_OK, VIRTIO_CONFIG_S_FEATURES_OK,
+    VIRTIO_CONFIG_S_NEEDS_RESET, VIRTIO_F_VERSION_1, VIRTIO_MMIO_CONFIG,
+    VIRTIO_MMIO_CONFIG_GENERATION, VIRTIO_MMIO_DEVICE_FEATURES, VIRTIO_MMIO_DEVICE_FEATURES_SEL,
+    VIRTIO_MMIO_DEVICE_ID, VIRTIO_MMIO_DRIVER_FEATURES, VIRTIO_MMIO_DRIVER_FEATURES_SEL,
+    VIRTIO_MMIO_INTERRUPT_ACK, VIRTIO_MMIO_INTERRUPT_STATUS, VIRTIO_MMIO_INT_CONFIG,
+    VIRTIO_MMIO_INT_VRING, VIRTIO_MMIO_MAGIC_VALUE, VIRTIO_MMIO_QUEUE_AVAIL_HIGH,
+    VIRTIO_MMIO_QUEUE_AVAIL_LOW, VIRTIO_MMIO_QUEUE_DESC_HIGH, VIRTIO_MMIO_QUEUE_DESC_LOW,
+    VIRTIO_MMIO_QUEUE_NOTIFY, VIRTIO_MMIO_QUEUE_NUM, VIRTIO_MMIO_QUEUE_NUM_MAX,
+    VIRTIO_MMIO_QUEUE_READY, VIRTIO_MMIO_QUEUE_SEL, VIRTIO_MMIO_QUEUE_USED_HIGH,
+    VIRTIO_MMIO_QUEUE_USED_LOW, VIRTIO_MMIO_SHM_LEN_HIGH, VIRTIO_MMIO_SHM_LEN_LOW,
+    VIRTIO_MMIO_STATUS, VIRTIO_MMIO_VENDOR_ID, VIRTIO_MMIO_VERSION, VRING_AVAIL_F_NO_INTERRUPT,
+    VRING_DESC_F_INDIRECT, VRING_DESC_F_NEXT, VRING_DESC_F_WRITE,
+};
+
+// https://docs.oasis-open.org/virtio/virtio/v1.2/virtio-v1.2.html
+
+/// "virt" in little-endian
+const MAGIC_VALUE: u32 = 0x7472_6976;
+
+/// The virtio 1.x register layout, as opposed to the legacy one
+const VERSION: u32 = 2;
+
+/// "MCSM" in little-endian
+const VENDOR_ID: u32 = 0x4d53_434d;
+
+/// Size of the registers and the configuration space of a device
+pub const MMIO_SIZE: u64 = 0x200;
+
+/// The device-specific part of a virtio device
+pub trait VirtioDevice {
+    fn device_id(&self) -> u32;
+
+    /// Maximum size of each of the virtqueues of the device
+    fn queue_max_sizes(&self) -> &'static [u16];
+
+    /// Device-specific feature bits, which the transport completes with
+    /// those it implements
+    fn features(&self) -> u64 {
+        0
+    }
+
+    /// Returns the configuration space of the device.
+    fn config(&self) -> Vec<u8>;
+
+    /// Handles the buffers that the driver made available in the virtqueue
+    /// `index`, and returns whether any were used.
+    fn process_queue(
+        &mut self,
+        index: usize,
+        queue: &mut Queue,
+        memory: &GuestMemory,
+    ) -> Result<bool>;
+
+    /// Handles a reset of the device by the driver.
+    fn reset(&mut self) {}
+
+    /// Brings the device back to its power-on state when the guest reboots.
+    fn system_reset(&mut self) -> Result<()> {
+        self.reset();
+        Ok(())
+    }
+}
+
+/// A buffer in guest memory
+#[derive(Debug, Clone, Copy)]
+pub struct Buffer {
+    pub addr: u64,
+    pub len: u32,
+}
+
+/// Buffers chained together by the driver, which the device reads from and
+/// then writes to
+pub struct DescriptorChain {
+    head: u16,
+    pub readable: Vec<Buffer>,
+    pub writable: Vec<Buffer>,
+}
+
+/// A split virtqueue
+pub struct Queue {
+    max_size: u16,
+    size: u16,
+    ready: bool,
+    desc_addr: u64,
+    avail_addr: u64,
+    used_addr: u64,
+    next_avail: u16,
+    next_used: u16,
+}
+
+impl Queue {
+    fn new(max_size: u16) -> Self {
+        Self {
+            max_size,
+            size: max_size,
+            ready: false,
+            desc_addr: 0,
+            avail_addr: 0,
+            used_addr: 0,
+            next_avail: 0,
+            next_used: 0,
+        }
+    }
+
+    /// Takes the next chain of buffers that the driver made available.
+    pub fn pop(&mut self, memory: &GuestMemory) -> Result<Option<DescriptorChain>> {
+        if !self.ready {
+            return Ok(None);
+        }
+        let avail_idx: u16 = memory.read_obj(self.avail_addr + 2)?;
+        if avail_idx == self.next_avail {
+            return Ok(None);
+        }
+        // The ring entry was written before the index.
+        fence(Ordering::Acquire);
+        let slot = u64::from(self.next_avail % self.size);
+        let head: u16 = memory.read_obj(self.avail_addr + 4 + 2 * slot)?;
+        self.next_avail = self.next_avail.wrapping_add(1);
+
+        let mut chain = DescriptorChain {
+            head,
+            readable: Vec::new(),
+            writable: Vec::new(),
+        };
+        let mut index = head;
+        // A chain cannot be longer than the queue, unless it loops.
+        for _ in 0..self.size {
+            if index >= self.size {
+                return Err(Error::InvalidVirtqueue(format!("descriptor index {index}")));
+            }
+            let desc: vring_desc = memory.read_obj(self.desc_addr + 16 * u64::from(index))?;
+            let flags = u32::from(desc.flags);
+            if flags & VRING_DESC_F_INDIRECT != 0 {
+                return Err(Error::InvalidVirtqueue("indirect descriptor".to_owned()));
+            }
+            let buffer = Buffer {
+                addr: desc.addr,
+                len: desc.len,
+            };
+            if flags & VRING_DESC_F_WRITE != 0 {
+                chain.writable.push(buffer);
+            } else if chain.writable.is_empty() {
+                chain.readable.push(buffer);
+            } else {
+                return Err(Error::InvalidVirtqueue(
+                    "readable after writable buffer".to_owned(),
+                ));
+            }
+            if flags & VRING_DESC_F_NEXT == 0 {
+                return Ok(Some(chain));
+            }
+            index = desc.next;
+        }
+        Err(Error::InvalidVirtqueue("descriptor loop".to_owned()))
+    }
+
+    /// Hands a chain back to the driver, with `len` bytes written to its
+    /// writable buffers.
+    pub fn push_used(
+        &mut self,
+        memory: &GuestMemory,
+        chain: &DescriptorChain,
+        len: u32,
+    ) -> Result<()> {
+        let slot = u64::from(self.next_used % self.size);
+        let elem = vring_used_elem {
+            id: chain.head.into(),
+            len,
+        };
+        memory.write_obj(&elem, self.used_addr + 4 + 8 * slot)?;
+        self.next_used = self.next_used.wrapping_add(1);
+        // The driver must see the entry before the index.
+        fence(Ordering::Release);
+        memory.write_obj(&self.next_used, self.used_addr + 2)
+    }
+
+    /// Returns whether the driver wants to be interrupted when buffers are
+    /// used.
+    fn needs_interrupt(&self, memory: &GuestMemory) -> Result<bool> {
+        fence(Ordering::SeqCst);
+        let flags: u16 = memory.read_obj(self.avail_addr)?;
+        Ok(u32::from(flags) & VRING_AVAIL_F_NO_INTERRUPT == 0)
+    }
+}
+
+/// Exposes a virtio device to the guest through the virtio-mmio transport
+///
+/// The guest finds the device through the `virtio_mmio.device` parameter
+/// of the kernel command line.
+pub struct VirtioMmio<D> {
+    device: D,
+    base: u64,
+    irq: Irq,
+    irq_number: u8,
+    memory: GuestMemory,
+
+    device_features_sel: u32,
+    driver_features_sel: u32,
+    driver_features: u64,
+    status: u32,
+    queue_sel: u32,
+    queues: Vec<Queue>,
+    interrupt_status: u32,
+    config_generation: u32,
+}
+
+impl<D: VirtioDevice> VirtioMmio<D> {
+    pub fn new(device: D, base: u64, irq: Irq, irq_number: u8, memory: GuestMemory) -> Self {
+        let queues = device
+            .queue_max_sizes()
+            .iter()
+            .map(|&max_size| Queue::new(max_size))
+            .collect();
+        Self {
+            device,
+            base,
+            irq,
+            irq_number,
+            memory,
+            device_features_sel: 0,
+            driver_features_sel: 0,
+            driver_features: 0,
+            status: 0,
+            queue_sel: 0,
+            queues,
+            interrupt_status: 0,
+            config_generation: 0,
+        }
+    }
+
+    pub fn device(&self) -> &D {
+        &self.device
+    }
+
+    /// Changes the configuration of the device on behalf of the host and
+    /// notifies the driver.
+    pub fn update_config<R>(&mut self, f: impl FnOnce(&mut D) -> R) -> Result<R> {
+        let result = f(&mut self.device);
+        self.config_generation = self.config_generation.wrapping_add(1);
+        if self.status & VIRTIO_CONFIG_S_DRIVER_OK != 0 {
+            self.raise_interrupt(VIRTIO_MMIO_INT_CONFIG)?;
+        }
+        Ok(result)
+    }
+
+    fn device_features(&self) -> u64 {
+        self.device.features() | 1 << VIRTIO_F_VERSION_1
+    }
+
+    fn selected_queue(&mut self) -> Option<&mut Queue> {
+        self.queues.get_mut(self.queue_sel as usize)
+    }
+
+    fn read_register(&mut self, offset: u32) -> u32 {
+        let half = |features: u64, sel: u32| match sel {
+            0 => features as u32,
+            1 => (features >> 32) as u32,
+            _ => 0,
+        };
+        match offset {
+            VIRTIO_MMIO_MAGIC_VALUE => MAGIC_VALUE,
+            VIRTIO_MMIO_VERSION => VERSION,
+            VIRTIO_MMIO_DEVICE_ID => self.device.device_id(),
+            VIRTIO_MMIO_VENDOR_ID => VENDOR_ID,
+            VIRTIO_MMIO_DEVICE_FEATURES => half(self.device_features(), self.device_features_sel),
+            VIRTIO_MMIO_QUEUE_NUM_MAX => self
+                .selected_queue()
+                .map_or(0, |queue| queue.max_size.into()),
+            VIRTIO_MMIO_QUEUE_READY => self.selected_queue().map_or(0, |queue| queue.ready.into()),
+            VIRTIO_MMIO_INTERRUPT_STATUS => self.interrupt_status,
+            VIRTIO_MMIO_STATUS => self.status,
+            VIRTIO_MMIO_CONFIG_GENERATION => self.config_generation,
+            // There are no shared memory regions.
+            VIRTIO_MMIO_SHM_LEN_LOW | VIRTIO_MMIO_SHM_LEN_HIGH => u32::MAX,
+            _ => 0,
+        }
+    }
+
+    fn write_register(&mut self, offset: u32, value: u32) -> Result<()> {
+        let set_half = |features: &mut u64, sel: u32| match sel {
+            0 => *features = *features & !0xffff_ffff | u64::from(value),
+            1 => *features = *features & 0xffff_ffff | u64::from(value) << 32,
+            _ => {}
+        };
+        let set_addr = |addr: &mut u64, high: bool| {
+            *addr = if high {
+                *addr & 0xffff_ffff | u64::from(value) << 32
+            } else {
+                *addr & !0xffff_ffff | u64::from(value)
+            };
+        };
+        match offset {
+            VIRTIO_MMIO_DEVICE_FEATURES_SEL => self.device_features_sel = value,
+            VIRTIO_MMIO_DRIVER_FEATURES_SEL => self.driver_features_sel = value,
+            VIRTIO_MMIO_DRIVER_FEATURES => {
+                if self.status & VIRTIO_CONFIG_S_FEATURES_OK == 0 {
+                    set_half(&mut self.driver_features, self.driver_features_sel);
+                }
+            }
+            VIRTIO_MMIO_QUEUE_SEL => self.queue_sel = value,
+            VIRTIO_MMIO_QUEUE_NUM => {
+                if let Some(queue) = self.selected_queue().filter(|queue| !queue.ready) {
+                    if (1..=u32::from(queue.max_size)).contains(&value) {
+                        queue.size = value as u16;
+                    }
+                }
+            }
+            VIRTIO_MMIO_QUEUE_READY => {
+                if let Some(queue) = self.selected_queue() {
+                    queue.ready = value == 1;
+                }
+            }
+            VIRTIO_MMIO_QUEUE_DESC_LOW
+            | VIRTIO_MMIO_QUEUE_DESC_HIGH
+            | VIRTIO_MMIO_QUEUE_AVAIL_LOW
+            | VIRTIO_MMIO_QUEUE_AVAIL_HIGH
+            | VIRTIO_MMIO_QUEUE_USED_LOW
+            | VIRTIO_MMIO_QUEUE_USED_HIGH => {
+                if let Some(queue) = self.selected_queue().filter(|queue| !queue.ready) {
+                    let (addr, high) = match offset {
+                        VIRTIO_MMIO_QUEUE_DESC_LOW => (&mut queue.desc_addr, false),
+                        VIRTIO_MMIO_QUEUE_DESC_HIGH => (&mut queue.desc_addr, true),
+                        VIRTIO_MMIO_QUEUE_AVAIL_LOW => (&mut queue.avail_addr, false),
+                        VIRTIO_MMIO_QUEUE_AVAIL_HIGH => (&mut queue.avail_addr, true),
+                        VIRTIO_MMIO_QUEUE_USED_LOW => (&mut queue.used_addr, false),
+                        _ => (&mut queue.used_addr, true),
+                    };
+                    set_addr(addr, high);
+                }
+            }
+            VIRTIO_MMIO_QUEUE_NOTIFY => self.notify(value as usize)?,
+            VIRTIO_MMIO_INTERRUPT_ACK => self.ack_interrupt(value)?,
+            VIRTIO_MMIO_STATUS => self.set_status(value)?,
+            _ => eprintln!("Ignored write to virtio-mmio register {offset:#x}"),
+        }
+        Ok(())
+    }
+
+    fn set_status(&mut self, status: u32) -> Result<()> {
+        if status == 0 {
+            self.reset_transport()?;
+            self.device.reset();
+            return Ok(());
+        }
+        let mut status = status;
+        // Refuse features that were not offered, and the legacy interface.
+        let features = self.driver_features;
+        if status & VIRTIO_CONFIG_S_FEATURES_OK != 0
+            && (features & !self.device_features() != 0 || features & 1 << VIRTIO_F_VERSION_1 == 0)
+        {
+            status &= !VIRTIO_CONFIG_S_FEATURES_OK;
+        }
+        self.status = status;
+        Ok(())
+    }
+
+    fn notify(&mut self, index: usize) -> Result<()> {
+        if self.status & VIRTIO_CONFIG_S_DRIVER_OK == 0 {
+            return Ok(());
+        }
+        let Some(queue) = self.queues.get_mut(index) else {
+            return Ok(());
+        };
+        match self.device.process_queue(index, queue, &self.memory) {
+            Ok(false) => Ok(()),
+            Ok(true) => {
+                if queue.needs_interrupt(&self.memory)? {
+                    self.raise_interrupt(VIRTIO_MMIO_INT_VRING)?;
+                }
+                Ok(())
+            }
+            // A broken driver should not bring down the vCPU.
+            Err(e @ (Error::InvalidVirtqueue(_) | Error::OutOfGuestMemory)) => {
+                eprintln!("Virtqueue {index}: {e}");
+                self.status |= VIRTIO_CONFIG_S_NEEDS_RESET;
+                self.raise_interrupt(VIRTIO_MMIO_INT_CONFIG)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Sets bits of the interrupt status and pulses the IRQ line, which is
+    /// edge-triggered.
+    fn raise_interrupt(&mut self, bits: u32) -> Result<()> {
+        self.interrupt_status |= bits;
+        self.irq.set_level(self.irq_number, false)?;
+        self.irq.set_level(self.irq_number, true)?;
+        Ok(())
+    }
+
+    fn ack_interrupt(&mut self, bits: u32) -> Result<()> {
+        self.interrupt_status &= !bits;
+        if self.interrupt_status == 0 {
+            self.irq.set_level(self.irq_number, false)?;
+        } else {
+            // Interrupts raised after the driver read the status would be
+            // missed otherwise.
+            self.raise_interrupt(0)?;
+        }
+        Ok(())
+    }
+
+    fn reset_transport(&mut self) -> Result<()> {
+        self.device_features_sel = 0;
+        self.driver_features_sel = 0;
+        self.driver_features = 0;
+        self.status = 0;
+        self.queue_sel = 0;
+        for queue in &mut self.queues {
+            *queue = Queue::new(queue.max_size);
+        }
+        self.interrupt_status = 0;
+        self.irq.set_level(self.irq_number, false)?;
+        Ok(())
+    }
+}
+
+impl<D: VirtioDevice> MmioDevice for VirtioMmio<D> {
+    fn address_range(&self) -> Range<u64> {
+        self.base..self.base + MMIO_SIZE
+    }
+
+    fn read(&mut self, offset: u64, data: &mut [u8]) -> Result<()> {
+        if let Some(offset) = offset.checked_sub(VIRTIO_MMIO_CONFIG.into()) {
+            let config = self.device.config();
+            for (i, byte) in data.iter_mut().enumerate() {
+                *byte = config.get(offset as usize + i).copied().unwrap_or(0);
+            }
+            return Ok(());
+        }
+        // Registers are only accessed whole.
+        if data.len() == 4 && offset.is_multiple_of(4) {
+            data.copy_from_slice(&self.read_register(offset as u32).to_le_bytes());
+        } else {
+            data.fill(0);
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, offset: u64, data: &[u8]) -> Result<()> {
+        // No device has a writable configuration space.
+        if offset >= VIRTIO_MMIO_CONFIG.into() {
+            return Ok(());
+        }
+        match <[u8; 4]>::try_from(data) {
+            Ok(value) if offset.is_multiple_of(4) => {
+                self.write_register(offset as u32, u32::from_le_bytes(value))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn reset(&mut self) -> Result<()> {
+        self.reset_transport()?;
+        self.device.system_reset()
+    }
+}
@@ -0,0 +1,226 @@
+use super::virtio::{DescriptorChain, Queue, VirtioDevice};
+use crate::{memory::GuestMemory, Result};
+use std::{mem::size_of, ops::Range};
+use sys::virtio::{
+    virtio_mem_config, virtio_mem_req, virtio_mem_resp, virtio_mem_resp__bindgen_ty_1,
+    virtio_mem_resp_state, VIRTIO_ID_MEM, VIRTIO_MEM_REQ_PLUG, VIRTIO_MEM_REQ_STATE,
+    VIRTIO_MEM_REQ_UNPLUG, VIRTIO_MEM_REQ_UNPLUG_ALL, VIRTIO_MEM_RESP_ACK, VIRTIO_MEM_RESP_ERROR,
+    VIRTIO_MEM_RESP_NACK, VIRTIO_MEM_STATE_MIXED, VIRTIO_MEM_STATE_PLUGGED,
+    VIRTIO_MEM_STATE_UNPLUGGED,
+};
+use zerocopy::AsBytes;
+
+// https://docs.oasis-open.org/virtio/virtio/v1.2/csd01/virtio-v1.2-csd01.html#x1-4890008
+
+/// Granularity at which memory is plugged and unplugged
+pub const BLOCK_SIZE: u64 = 2 * 1024 * 1024;
+
+const QUEUE_SIZE: u16 = 128;
+
+/// Memory device through which the guest plugs and unplugs blocks of a
+/// hotpluggable region, until the amount of plugged memory reaches the size
+/// requested by the host
+pub struct VirtioMem {
+    /// Guest physical address of the region
+    addr: u64,
+    size: u64,
+
+    /// Guest memory, which includes the region
+    memory: GuestMemory,
+
+    plugged: Vec<bool>,
+    plugged_size: u64,
+    requested_size: u64,
+}
+
+impl VirtioMem {
+    pub fn new(addr: u64, size: u64, memory: GuestMemory) -> Self {
+        let num_blocks = size / BLOCK_SIZE;
+        Self {
+            addr,
+            size,
+            memory,
+            plugged: vec![false; num_blocks as usize],
+            plugged_size: 0,
+            requested_size: 0,
+        }
+    }
+
+    pub fn region_size(&self) -> u64 {
+        self.size
+    }
+
+    pub fn plugged_size(&self) -> u64 {
+        self.plugged_size
+    }
+
+    pub fn requested_size(&self) -> u64 {
+        self.requested_size
+    }
+
+    /// Asks the guest to plug or unplug memory until `size` bytes are
+    /// plugged, and returns the size requested after rounding it down to a
+    /// multiple of the block size and clamping it to the size of the region.
+    pub fn set_requested_size(&mut self, size: u64) -> u64 {
+        self.requested_size = size.min(self.region_size()) / BLOCK_SIZE * BLOCK_SIZE;
+        self.requested_size
+    }
+
+    /// Returns the indices of the `nb_blocks` blocks starting at `addr`, if
+    /// they are all within the region.
+    fn blocks(&self, addr: u64, nb_blocks: u16) -> Option<Range<usize>> {
+        let offset = addr.checked_sub(self.addr)?;
+        if !offset.is_multiple_of(BLOCK_SIZE) || nb_blocks == 0 {
+            return None;
+        }
+        let start = (offset / BLOCK_SIZE) as usize;
+        let end = start + usize::from(nb_blocks);
+        (end <= self.plugged.len()).then_some(start..end)
+    }
+
+    fn plug(&mut self, addr: u64, nb_blocks: u16) -> u32 {
+        let Some(blocks) = self.blocks(addr, nb_blocks) else {
+            return VIRTIO_MEM_RESP_ERROR;
+        };
+        if self.plugged[blocks.clone()].iter().any(|&plugged| plugged) {
+            return VIRTIO_MEM_RESP_ERROR;
+        }
+        let size = u64::from(nb_blocks) * BLOCK_SIZE;
+        if self.plugged_size + size > self.requested_size {
+            return VIRTIO_MEM_RESP_NACK;
+        }
+        self.plugged[blocks].fill(true);
+        self.plugged_size += size;
+        VIRTIO_MEM_RESP_ACK
+    }
+
+    fn unplug(&mut self, addr: u64, nb_blocks: u16) -> Result<u32> {
+        let Some(blocks) = self.blocks(addr, nb_blocks) else {
+            return Ok(VIRTIO_MEM_RESP_ERROR);
+        };
+        if !self.plugged[blocks.clone()].iter().all(|&plugged| plugged) {
+            return Ok(VIRTIO_MEM_RESP_ERROR);
+        }
+        self.discard(blocks.clone())?;
+        self.plugged[blocks].fill(false);
+        self.plugged_size -= u64::from(nb_blocks) * BLOCK_SIZE;
+        Ok(VIRTIO_MEM_RESP_ACK)
+    }
+
+    fn unplug_all(&mut self) -> Result<()> {
+        self.discard(0..self.plugged.len())?;
+        self.plugged.fill(false);
+        self.plugged_size = 0;
+        Ok(())
+    }
+
+    fn state(&self, addr: u64, nb_blocks: u16) -> Option<u32> {
+        let blocks = &self.plugged[self.blocks(addr, nb_blocks)?];
+        let state = if blocks.iter().all(|&plugged| plugged) {
+            VIRTIO_MEM_STATE_PLUGGED
+        } else if blocks.iter().all(|&plugged| !plugged) {
+            VIRTIO_MEM_STATE_UNPLUGGED
+        } else {
+            VIRTIO_MEM_STATE_MIXED
+        };
+        Some(state)
+    }
+
+    /// Gives the memory of unplugged blocks back to the host, which reads as
+    /// zeroes afterwards.
+    fn discard(&self, blocks: Range<usize>) -> Result<()> {
+        let addr = self.addr + blocks.start as u64 * BLOCK_SIZE;
+        self.memory
+            .discard_hotplug(addr, blocks.len() as u64 * BLOCK_SIZE)
+    }
+
+    fn handle_request(&mut self, request: &virtio_mem_req) -> Result<virtio_mem_resp> {
+        // All the requests have the same layout.
+        let range = unsafe { request.u.plug };
+        let (type_, state) = match u32::from(request.type_) {
+            VIRTIO_MEM_REQ_PLUG => (self.plug(range.addr, range.nb_blocks), 0),
+            VIRTIO_MEM_REQ_UNPLUG => (self.unplug(range.addr, range.nb_blocks)?, 0),
+            VIRTIO_MEM_REQ_UNPLUG_ALL => {
+                self.unplug_all()?;
+                (VIRTIO_MEM_RESP_ACK, 0)
+            }
+            VIRTIO_MEM_REQ_STATE => self
+                .state(range.addr, range.nb_blocks)
+                .map_or((VIRTIO_MEM_RESP_ERROR, 0), |state| {
+                    (VIRTIO_MEM_RESP_ACK, state)
+                }),
+            _ => (VIRTIO_MEM_RESP_ERROR, 0),
+        };
+        Ok(virtio_mem_resp {
+            type_: type_ as u16,
+            padding: [0; 3],
+            u: virtio_mem_resp__bindgen_ty_1 {
+                state: virtio_mem_resp_state {
+                    state: state as u16,
+                },
+            },
+        })
+    }
+
+    fn handle_chain(&mut self, chain: &DescriptorChain, memory: &GuestMemory) -> Result<u32> {
+        let (Some(request), Some(response)) = (chain.readable.first(), chain.writable.first())
+        else {
+            return Ok(0);
+        };
+        if (request.len as usize) < size_of::<virtio_mem_req>()
+            || (response.len as usize) < size_of::<virtio_mem_resp>()
+        {
+            return Ok(0);
+        }
+        let resp = self.handle_request(&memory.read_obj(request.addr)?)?;
+        memory.write_obj(resp.as_bytes(), response.addr)?;
+        Ok(size_of::<virtio_mem_resp>() as u32)
+    }
+}
+
+impl VirtioDevice for VirtioMem {
+    fn device_id(&self) -> u32 {
+        VIRTIO_ID_MEM
+    }
+
+    fn queue_max_sizes(&self) -> &'static [u16] {
+        &[QUEUE_SIZE]
+    }
+
+    fn config(&self) -> Vec<u8> {
+        let size = self.region_size();
+        virtio_mem_config {
+            block_size: BLOCK_SIZE,
+            node_id: 0,
+            padding: [0; 6],
+            addr: self.addr,
+            region_size: size,
+            usable_region_size: size,
+            plugged_size: self.plugged_size,
+            requested_size: self.requested_size,
+        }
+        .as_bytes()
+        .to_vec()
+    }
+
+    fn process_queue(
+        &mut self,
+        _index: usize,
+        queue: &mut Queue,
+        memory: &GuestMemory,
+    ) -> Result<bool> {
+        let mut used = false;
+        while let Some(chain) = queue.pop(memory)? {
+            let len = self.handle_chain(&chain, memory)?;
+            queue.push_used(memory, &chain, len)?;
+            used = true;
+        }
+        Ok(used)
+    }
+
+    /// Unplugs all the memory, which otherwise stays plugged across resets
+    /// of the device for the next driver to take over.
+    fn system_reset(&mut self) -> Result<()> {
+        self.unplug_all()
+    }
+}
@@ -0,0 +1,332 @@
+use crate::{
+    cpu::Control,
+    kvm::{Vcpu, Vm},
+    memory::{GuestMemory, MemoryRegion, Mmapped, PAGE_SIZE},
+    Error, Result,
+};
+use std::{
+    mem::size_of,
+    num::NonZeroUsize,
+    sync::{
+        atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+use sys::kvm_bindings::{kvm_dirty_gfn, KVM_DIRTY_LOG_PAGE_OFFSET, KVM_MEM_LOG_DIRTY_PAGES};
+
+/// Set by KVM when it publishes an entry of a dirty ring
+const KVM_DIRTY_GFN_F_DIRTY: u32 = 1 << 0;
+
+/// Set by userspace when it has harvested an entry
+const KVM_DIRTY_GFN_F_RESET: u32 = 1 << 1;
+
+/// Pages written behind KVM's back, or harvested from the dirty rings, that
+/// have not been collected yet
+pub struct DirtyPages {
+    enabled: AtomicBool,
+
+    /// One bit per page of each region
+    bitmaps: Box<[Box<[AtomicU64]>]>,
+}
+
+impl DirtyPages {
+    pub fn new(regions: &[MemoryRegion]) -> Self {
+        let bitmaps = regions
+            .iter()
+            .map(|region| {
+                let num_pages = region.size.div_ceil(PAGE_SIZE as u64) as usize;
+                (0..num_pages.div_ceil(64))
+                    .map(|_| AtomicU64::new(0))
+                    .collect()
+            })
+            .collect();
+        Self {
+            enabled: AtomicBool::new(false),
+            bitmaps,
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Acquire)
+    }
+
+    /// Records that `len` bytes at `offset` in the region `index` were
+    /// written, if logging is enabled.
+    pub fn mark(&self, index: usize, offset: u64, len: usize) {
+        if len == 0 || !self.is_enabled() {
+            return;
+        }
+        let first = offset / PAGE_SIZE as u64;
+        let last = (offset + len as u64 - 1) / PAGE_SIZE as u64;
+        for page in first..=last {
+            self.mark_page(index, page);
+        }
+    }
+
+    /// Records every page of guest RAM as written, if logging is enabled.
+    pub fn mark_all(&self) {
+        if !self.is_enabled() {
+            return;
+        }
+        for bitmap in &self.bitmaps {
+            for word in bitmap {
+                word.store(u64::MAX, Ordering::Release);
+            }
+        }
+    }
+
+    fn mark_page(&self, index: usize, page: u64) {
+        let Some(word) = self
+            .bitmaps
+            .get(index)
+            .and_then(|bitmap| bitmap.get((page / 64) as usize))
+        else {
+            return;
+        };
+        word.fetch_or(1 << (page % 64), Ordering::Release);
+    }
+
+    /// Moves the pages recorded for the region `index` into `bits`.
+    fn take_into(&self, index: usize, bits: &mut [u64]) {
+        for (bits, word) in bits.iter_mut().zip(&*self.bitmaps[index]) {
+            *bits |= word.swap(0, Ordering::Acquire);
+        }
+    }
+
+    fn clear(&self) {
+        for bitmap in &self.bitmaps {
+            for word in bitmap {
+                word.store(0, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+/// Ring through which KVM reports the pages written by a vCPU
+pub struct DirtyRing {
+    entries: Mmapped<kvm_dirty_gfn>,
+    len: usize,
+
+    /// Index of the next entry to harvest, which also serializes harvesting
+    next: Mutex<usize>,
+}
+
+impl DirtyRing {
+    /// Maps the ring of `vcpu`, whose size in bytes was set with
+    /// `KVM_CAP_DIRTY_LOG_RING`.
+    pub fn new(vcpu: &Vcpu, size: NonZeroUsize) -> nix::Result<Self> {
+        let entries = Mmapped::new_file(
+            vcpu,
+            size,
+            (KVM_DIRTY_LOG_PAGE_OFFSET as usize * PAGE_SIZE) as i64,
+        )?;
+        Ok(Self {
+            entries,
+            len: size.get() / size_of::<kvm_dirty_gfn>(),
+            next: Mutex::new(0),
+        })
+    }
+
+    /// Records the pages that KVM has published in the ring into `pages` and
+    /// hands the entries back to KVM.
+    ///
+    /// KVM reuses the entries after `KVM_RESET_DIRTY_RINGS`.
+    pub fn harvest(&self, pages: &DirtyPages) {
+        let mut next = self.next.lock().unwrap();
+        loop {
+            let entry = unsafe { self.entries.as_ptr().add(*next % self.len) };
+            let flags = unsafe { AtomicU32::from_ptr(&raw mut (*entry).flags) };
+            if flags.load(Ordering::Acquire) & KVM_DIRTY_GFN_F_DIRTY == 0 {
+                return;
+            }
+            let (slot, offset) = unsafe { ((*entry).slot, (*entry).offset) };
+            // The upper half of `slot` is the address space, which is always 0.
+            pages.mark_page(usize::from(slot as u16), offset);
+            flags.store(KVM_DIRTY_GFN_F_RESET, Ordering::Release);
+            *next = next.wrapping_add(1);
+        }
+    }
+}
+
+/// Pages of a region of guest RAM written since the previous collection
+#[derive(Debug, Clone)]
+pub struct DirtyBitmap {
+    region: MemoryRegion,
+    bits: Vec<u64>,
+}
+
+impl DirtyBitmap {
+    pub fn region(&self) -> &MemoryRegion {
+        &self.region
+    }
+
+    /// One bit per page of the region, starting from the least significant
+    /// bit of the first word
+    pub fn bits(&self) -> &[u64] {
+        &self.bits
+    }
+
+    pub fn num_dirty(&self) -> usize {
+        self.bits
+            .iter()
+            .map(|word| word.count_ones() as usize)
+            .sum()
+    }
+
+    /// Returns whether the page containing the guest physical address `addr`
+    /// is dirty.
+    pub fn is_dirty(&self, addr: u64) -> bool {
+        if !self.region.contains(addr) {
+            return false;
+        }
+        let page = (addr - self.region.guest_addr) / PAGE_SIZE as u64;
+        self.bits[(page / 64) as usize] & (1 << (page % 64)) != 0
+    }
+
+    /// Returns the guest physical addresses of the dirty pages in ascending
+    /// order.
+    pub fn dirty_pages(&self) -> impl Iterator<Item = u64> + '_ {
+        self.bits.iter().enumerate().flat_map(move |(i, &word)| {
+            std::iter::successors((word != 0).then_some(word), |&word| {
+                let word = word & (word - 1);
+                (word != 0).then_some(word)
+            })
+            .map(move |word| {
+                let page = i as u64 * 64 + u64::from(word.trailing_zeros());
+                self.region.guest_addr + page * PAGE_SIZE as u64
+            })
+        })
+    }
+}
+
+/// Tracks the pages of guest RAM written by the vCPUs and by devices
+///
+/// Logging is off until [`start`](Self::start) is called. KVM reports the
+/// pages written by the vCPUs through the dirty rings if they were enabled
+/// with [`GuestBuilder::dirty_ring`](crate::GuestBuilder::dirty_ring), and
+/// through a bitmap per memslot otherwise. Writes made through
+/// [`GuestMemory`] are recorded alongside them.
+#[derive(Clone)]
+pub struct DirtyLog {
+    vm: Arc<Vm>,
+    control: Arc<Control>,
+    memory: GuestMemory,
+
+    /// Whether `KVM_CAP_MANUAL_DIRTY_LOG_PROTECT2` is enabled
+    manual_protect: bool,
+
+    /// Serializes starting, stopping and collecting
+    lock: Arc<Mutex<()>>,
+}
+
+impl DirtyLog {
+    pub(crate) fn new(
+        vm: Arc<Vm>,
+        control: Arc<Control>,
+        memory: GuestMemory,
+        manual_protect: bool,
+    ) -> Self {
+        Self {
+            vm,
+            control,
+            memory,
+            manual_protect,
+            lock: Arc::default(),
+        }
+    }
+
+    pub fn is_started(&self) -> bool {
+        self.memory.dirty_pages().is_enabled()
+    }
+
+    /// Starts logging writes to guest RAM.
+    ///
+    /// Pages written before this are not reported.
+    pub fn start(&self) -> Result<()> {
+        let _guard = self.lock.lock().unwrap();
+        let pages = self.memory.dirty_pages();
+        if pages.is_enabled() {
+            return Ok(());
+        }
+        // Writes by devices are recorded from now on, before the vCPUs are.
+        pages.enabled.store(true, Ordering::Release);
+        self.set_memslot_flags(KVM_MEM_LOG_DIRTY_PAGES)
+    }
+
+    /// Stops logging and discards the pages that have not been collected.
+    pub fn stop(&self) -> Result<()> {
+        let _guard = self.lock.lock().unwrap();
+        let pages = self.memory.dirty_pages();
+        if !pages.is_enabled() {
+            return Ok(());
+        }
+        self.set_memslot_flags(0)?;
+        pages.enabled.store(false, Ordering::Release);
+        if self.harvest_rings() {
+            self.vm.reset_dirty_rings()?;
+        }
+        pages.clear();
+        Ok(())
+    }
+
+    /// Returns the pages written since logging started or since the previous
+    /// call, one bitmap per region.
+    ///
+    /// This can be called while the guest is running. Pages written by the
+    /// vCPUs while it runs are reported by a later call.
+    pub fn collect(&self) -> Result<Vec<DirtyBitmap>> {
+        let _guard = self.lock.lock().unwrap();
+        let pages = self.memory.dirty_pages();
+        if !pages.is_enabled() {
+            return Err(Error::DirtyLogNotStarted);
+        }
+        let rings = self.harvest_rings();
+        if rings {
+            self.vm.reset_dirty_rings()?;
+        }
+
+        let mut bitmaps = Vec::with_capacity(self.memory.regions().len());
+        for (slot, region) in (0..).zip(self.memory.regions()) {
+            let num_pages = region.size.div_ceil(PAGE_SIZE as u64);
+            let mut bits = vec![0; num_pages.div_ceil(64) as usize];
+            if !rings {
+                self.vm.get_dirty_log(slot, &mut bits)?;
+                if self.manual_protect {
+                    // Write-protect the pages again only after the log has
+                    // been read, so that writes in between are reported by
+                    // the next call.
+                    self.vm
+                        .clear_dirty_log(slot, 0, num_pages as u32, &mut bits)?;
+                }
+            }
+            pages.take_into(slot as usize, &mut bits);
+            bitmaps.push(DirtyBitmap {
+                region: *region,
+                bits,
+            });
+        }
+        Ok(bitmaps)
+    }
+
+    /// Harvests the dirty rings of all the vCPUs, returning `false` if they
+    /// are not enabled.
+    fn harvest_rings(&self) -> bool {
+        let pages = self.memory.dirty_pages();
+        let mut rings = false;
+        for context in self.control.contexts() {
+            if let Some(ring) = &context.dirty_ring {
+                ring.harvest(pages);
+                rings = true;
+            }
+        }
+        rings
+    }
+
+    fn set_memslot_flags(&self, flags: u32) -> Result<()> {
+        for (slot, region) in (0..).zip(self.memory.regions()) {
+            self.vm
+                .set_user_memory_region(&region.memslot(slot, self.memory.as_ptr(), flags))?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,160 @@
+use crate::{cpu::CpuContext, memory::GuestMemory, paging, Result};
+use std::fmt;
+use sys::kvm_bindings::{kvm_dtable, kvm_regs, kvm_segment, kvm_sregs, kvm_vcpu_events};
+
+/// Number of instruction bytes captured at RIP
+const CODE_LEN: usize = 16;
+
+/// Snapshot of the state of a vCPU, taken when the guest crashes
+#[derive(Debug, Clone)]
+pub struct CpuDump {
+    pub vcpu: usize,
+    pub regs: kvm_regs,
+    pub sregs: kvm_sregs,
+    pub events: kvm_vcpu_events,
+
+    /// Bytes at CS:RIP, up to the first byte that is not mapped to guest
+    /// memory
+    pub code: Vec<u8>,
+}
+
+impl CpuDump {
+    pub(crate) fn capture(context: &CpuContext, memory: &GuestMemory) -> Result<Self> {
+        let regs = context.vcpu.regs()?;
+        let sregs = context.vcpu.sregs()?;
+        let events = context.vcpu.vcpu_events()?;
+
+        let rip = sregs.cs.base.wrapping_add(regs.rip);
+        let code = (0..CODE_LEN as u64)
+            .map_while(|i| {
+                let translation = paging::translate(memory, &sregs, rip.wrapping_add(i)).ok()?;
+                memory.read_obj(translation.phys_addr).ok()
+            })
+            .collect();
+
+        Ok(Self {
+            vcpu: context.id,
+            regs,
+            sregs,
+            events,
+            code,
+        })
+    }
+}
+
+impl fmt::Display for CpuDump {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let r = &self.regs;
+        let s = &self.sregs;
+        writeln!(f, "vCPU {}", self.vcpu)?;
+        writeln!(
+            f,
+            "RAX={:016x} RBX={:016x} RCX={:016x} RDX={:016x}",
+            r.rax, r.rbx, r.rcx, r.rdx
+        )?;
+        writeln!(
+            f,
+            "RSI={:016x} RDI={:016x} RBP={:016x} RSP={:016x}",
+            r.rsi, r.rdi, r.rbp, r.rsp
+        )?;
+        writeln!(
+            f,
+            "R8 ={:016x} R9 ={:016x} R10={:016x} R11={:016x}",
+            r.r8, r.r9, r.r10, r.r11
+        )?;
+        writeln!(
+            f,
+            "R12={:016x} R13={:016x} R14={:016x} R15={:016x}",
+            r.r12, r.r13, r.r14, r.r15
+        )?;
+        writeln!(f, "RIP={:016x} RFLAGS={:08x}", r.rip, r.rflags)?;
+
+        for (name, segment) in [
+            ("ES", &s.es),
+            ("CS", &s.cs),
+            ("SS", &s.ss),
+            ("DS", &s.ds),
+            ("FS", &s.fs),
+            ("GS", &s.gs),
+            ("LDT", &s.ldt),
+            ("TR", &s.tr),
+        ] {
+            writeln!(f, "{name:<3}={}", DisplaySegment(segment))?;
+        }
+        for (name, dtable) in [("GDT", &s.gdt), ("IDT", &s.idt)] {
+            writeln!(f, "{name}={}", DisplayDtable(dtable))?;
+        }
+
+        writeln!(
+            f,
+            "CR0={:08x} CR2={:016x} CR3={:016x} CR4={:08x} CR8={:x}",
+            s.cr0, s.cr2, s.cr3, s.cr4, s.cr8
+        )?;
+        write!(f, "EFER={:016x}", s.efer)?;
+        for (bit, name) in [(0, "SCE"), (8, "LME"), (10, "LMA"), (11, "NXE")] {
+            if s.efer & (1 << bit) != 0 {
+                write!(f, " {name}")?;
+            }
+        }
+        writeln!(f)?;
+        writeln!(f, "APIC_BASE={:016x}", s.apic_base)?;
+
+        let e = &self.events;
+        writeln!(
+            f,
+            "Exception: injected={} pending={} vector={} error_code={}",
+            e.exception.injected,
+            e.exception.pending,
+            e.exception.nr,
+            if e.exception.has_error_code != 0 {
+                format!("{:#x}", e.exception.error_code)
+            } else {
+                "none".to_owned()
+            }
+        )?;
+        writeln!(
+            f,
+            "Interrupt: injected={} vector={} soft={} shadow={}",
+            e.interrupt.injected, e.interrupt.nr, e.interrupt.soft, e.interrupt.shadow
+        )?;
+        writeln!(
+            f,
+            "NMI: injected={} pending={} masked={}",
+            e.nmi.injected, e.nmi.pending, e.nmi.masked
+        )?;
+
+        write!(f, "Code:")?;
+        if self.code.is_empty() {
+            write!(f, " <unmapped>")?;
+        }
+        for byte in &self.code {
+            write!(f, " {byte:02x}")?;
+        }
+        Ok(())
+    }
+}
+
+struct DisplaySegment<'a>(&'a kvm_segment);
+
+impl fmt::Display for DisplaySegment<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = self.0;
+        write!(
+            f,
+            "{:04x} {:016x} {:08x} type={:x} dpl={} p={} s={} db={} l={} g={}",
+            s.selector, s.base, s.limit, s.type_, s.dpl, s.present, s.s, s.db, s.l, s.g
+        )?;
+        if s.unusable != 0 {
+            write!(f, " unusable")?;
+        }
+        Ok(())
+    }
+}
+
+struct DisplayDtable<'a>(&'a kvm_dtable);
+
+impl fmt::Display for DisplayDtable<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "     {:016x} {:08x}", self.0.base, self.0.limit)
+    }
+}
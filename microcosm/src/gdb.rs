@@ -0,0 +1,937 @@
+use crate::{
+    cpu::{Control, DebugEvent},
+    memory::GuestMemory,
+    paging, Result, VmExit,
+};
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    fmt::Write as _,
+    io::{Read, Write},
+    net::{TcpListener, TcpStream},
+    os::unix::net::{UnixListener, UnixStream},
+    path::PathBuf,
+    str::FromStr,
+    sync::{mpsc, Arc},
+};
+use sys::kvm_bindings::{
+    kvm_guest_debug, kvm_guest_debug_arch, kvm_regs, kvm_sregs, KVM_GUESTDBG_ENABLE,
+    KVM_GUESTDBG_SINGLESTEP, KVM_GUESTDBG_USE_HW_BP, KVM_GUESTDBG_USE_SW_BP,
+};
+
+// The protocol is described in
+// https://sourceware.org/gdb/current/onlinedocs/gdb.html/Remote-Protocol.html
+
+const INT3: u8 = 0xcc;
+const DB_VECTOR: u32 = 1;
+const BP_VECTOR: u32 = 3;
+
+const SIGINT: u8 = 2;
+const SIGTRAP: u8 = 5;
+
+/// Largest packet that the debugger may send, which is also the size of its
+/// reply buffer
+const MAX_PACKET_SIZE: usize = 0x4000;
+
+/// Address that a GDB stub listens on
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum GdbSocket {
+    /// TCP socket address, e.g. `localhost:1234`
+    Tcp(String),
+
+    /// Path to a Unix domain socket
+    Unix(PathBuf),
+}
+
+impl FromStr for GdbSocket {
+    type Err = Infallible;
+
+    /// Parses `unix:<PATH>`, `[tcp:]<HOST>:<PORT>` or `[tcp:]<PORT>`.
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        if let Some(path) = s.strip_prefix("unix:") {
+            return Ok(Self::Unix(path.into()));
+        }
+        let addr = s.strip_prefix("tcp:").unwrap_or(s);
+        if addr.parse::<u16>().is_ok() {
+            return Ok(Self::Tcp(format!("localhost:{addr}")));
+        }
+        Ok(Self::Tcp(addr.to_owned()))
+    }
+}
+
+impl std::fmt::Display for GdbSocket {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Tcp(addr) => write!(f, "tcp:{addr}"),
+            Self::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+impl Listener {
+    fn bind(socket: &GdbSocket) -> std::io::Result<Self> {
+        Ok(match socket {
+            GdbSocket::Tcp(addr) => Self::Tcp(TcpListener::bind(addr)?),
+            GdbSocket::Unix(path) => Self::Unix(UnixListener::bind(path)?),
+        })
+    }
+
+    fn accept(&self) -> std::io::Result<Stream> {
+        Ok(match self {
+            Self::Tcp(listener) => {
+                let (stream, _) = listener.accept()?;
+                stream.set_nodelay(true)?;
+                Stream::Tcp(stream)
+            }
+            Self::Unix(listener) => Stream::Unix(listener.accept()?.0),
+        })
+    }
+}
+
+enum Stream {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+impl Stream {
+    fn try_clone(&self) -> std::io::Result<Self> {
+        Ok(match self {
+            Self::Tcp(stream) => Self::Tcp(stream.try_clone()?),
+            Self::Unix(stream) => Self::Unix(stream.try_clone()?),
+        })
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            Self::Tcp(stream) => stream.read(buf),
+            Self::Unix(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            Self::Tcp(stream) => stream.write(buf),
+            Self::Unix(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            Self::Tcp(stream) => stream.flush(),
+            Self::Unix(stream) => stream.flush(),
+        }
+    }
+}
+
+/// GDB remote serial protocol server
+///
+/// Each vCPU is reported as a thread whose ID is the vCPU ID plus one.
+pub struct GdbStub {
+    listener: Listener,
+    control: Arc<Control>,
+    memory: GuestMemory,
+}
+
+impl GdbStub {
+    pub(crate) fn new(
+        socket: &GdbSocket,
+        control: Arc<Control>,
+        memory: GuestMemory,
+    ) -> Result<Self> {
+        Ok(Self {
+            listener: Listener::bind(socket)?,
+            control,
+            memory,
+        })
+    }
+
+    /// Serves debugger connections one at a time.
+    ///
+    /// The vCPUs are stopped while a debugger is attached, until it resumes
+    /// them. This returns when the guest stops.
+    pub fn serve(self) -> Result<()> {
+        loop {
+            let stream = self.listener.accept()?;
+            let mut session = Session::new(&self, stream)?;
+            let result = session.run();
+            session.detach()?;
+            match result {
+                Ok(true) => {}
+                Ok(false) => return Ok(()),
+                Err(e) => eprintln!("GDB connection error: {e}"),
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum WatchKind {
+    Execute,
+    Write,
+    Access,
+}
+
+#[derive(Clone, Copy)]
+struct HwBreakpoint {
+    addr: u64,
+    kind: WatchKind,
+    len: u64,
+}
+
+enum Action {
+    Reply(String),
+    Resume { step: Option<usize> },
+    Detach,
+    Kill,
+}
+
+struct Session<'a> {
+    stub: &'a GdbStub,
+    writer: Stream,
+    packets: mpsc::Receiver<Option<Vec<u8>>>,
+    ack: bool,
+
+    /// vCPU targeted by register and memory accesses (`Hg`)
+    current_vcpu: usize,
+
+    /// vCPU to single-step (`Hc`)
+    step_vcpu: Option<usize>,
+
+    last_event: DebugEvent,
+
+    /// Software breakpoints, mapping virtual addresses to physical addresses
+    /// and the original bytes
+    sw_breakpoints: HashMap<u64, (u64, u8)>,
+
+    /// Hardware breakpoints in DR0-DR3
+    hw_breakpoints: [Option<HwBreakpoint>; 4],
+}
+
+impl<'a> Session<'a> {
+    fn new(stub: &'a GdbStub, stream: Stream) -> Result<Self> {
+        let writer = stream.try_clone()?;
+        let (tx, rx) = mpsc::channel();
+        let control = stub.control.clone();
+        std::thread::Builder::new()
+            .name("gdb".to_owned())
+            .spawn(move || read_packets(stream, &control, &tx))?;
+        Ok(Self {
+            stub,
+            writer,
+            packets: rx,
+            ack: true,
+            current_vcpu: 0,
+            step_vcpu: None,
+            last_event: DebugEvent::Interrupt,
+            sw_breakpoints: HashMap::new(),
+            hw_breakpoints: [None; 4],
+        })
+    }
+
+    /// Serves the connection until the debugger detaches or the guest stops.
+    ///
+    /// Returns `false` if the guest stopped.
+    fn run(&mut self) -> Result<bool> {
+        let control = &self.stub.control;
+        control.debug_stop(DebugEvent::Interrupt);
+        let Some(event) = control.wait_debug_event() else {
+            return Ok(false);
+        };
+        self.set_stopped(event);
+
+        // A `None` packet means that the received checksum was wrong.
+        while let Ok(packet) = self.packets.recv() {
+            let Some(packet) = packet else {
+                self.writer.write_all(b"-")?;
+                continue;
+            };
+            if self.ack {
+                self.writer.write_all(b"+")?;
+            }
+            let packet = String::from_utf8_lossy(&packet);
+            match self.handle_packet(&packet)? {
+                Action::Reply(reply) => self.send(&reply)?,
+                Action::Resume { step } => {
+                    self.set_guest_debug(step)?;
+                    let event = loop {
+                        control.debug_resume();
+                        let Some(event) = control.wait_debug_event() else {
+                            self.send("W00")?;
+                            return Ok(false);
+                        };
+                        if !self.forward_guest_breakpoint(event)? {
+                            break event;
+                        }
+                    };
+                    self.set_stopped(event);
+                    let reply = self.stop_reply();
+                    self.send(&reply)?;
+                }
+                Action::Detach => {
+                    self.send("OK")?;
+                    return Ok(true);
+                }
+                Action::Kill => {
+                    control.stop(Some(VmExit::Poweroff));
+                    return Ok(false);
+                }
+            }
+        }
+        Ok(true)
+    }
+
+    /// Removes all the breakpoints and lets the vCPUs run freely, unless the
+    /// guest was paused before the debugger stopped it.
+    fn detach(&mut self) -> Result<()> {
+        for (_, (phys_addr, byte)) in self.sw_breakpoints.drain() {
+            self.stub.write_phys(phys_addr, byte);
+        }
+        self.hw_breakpoints = [None; 4];
+        for context in self.stub.control.contexts() {
+            context.vcpu.set_guest_debug(&kvm_guest_debug::default())?;
+        }
+        self.stub.control.debug_release();
+        Ok(())
+    }
+
+    /// Passes a breakpoint exception on to the guest if it comes from one of
+    /// its own `int3` instructions, which trap to KVM as well as ours.
+    ///
+    /// Returns whether the exception was passed on, in which case the vCPUs
+    /// should resume instead of stopping.
+    fn forward_guest_breakpoint(&self, event: DebugEvent) -> Result<bool> {
+        let DebugEvent::Trap {
+            vcpu,
+            exception: BP_VECTOR,
+            ..
+        } = event
+        else {
+            return Ok(false);
+        };
+        let vcpu = &self.stub.control.contexts()[vcpu].vcpu;
+        // The vCPU stops before executing the instruction.
+        if self.sw_breakpoints.contains_key(&vcpu.regs()?.rip) {
+            return Ok(false);
+        }
+        let mut events = vcpu.vcpu_events()?;
+        events.exception.injected = 1;
+        events.exception.nr = BP_VECTOR as u8;
+        events.exception.has_error_code = 0;
+        vcpu.set_vcpu_events(&events)?;
+        Ok(true)
+    }
+
+    fn set_stopped(&mut self, event: DebugEvent) {
+        if let DebugEvent::Trap { vcpu, .. } = event {
+            self.current_vcpu = vcpu;
+        }
+        self.last_event = event;
+    }
+
+    fn send(&mut self, data: &str) -> Result<()> {
+        let checksum = data.bytes().fold(0u8, u8::wrapping_add);
+        write!(self.writer, "${data}#{checksum:02x}")?;
+        self.writer.flush()?;
+        Ok(())
+    }
+
+    fn handle_packet(&mut self, packet: &str) -> Result<Action> {
+        let (command, args) = packet.split_at(packet.len().min(1));
+        let reply = match command {
+            "?" => self.stop_reply(),
+            "g" => self.read_registers()?,
+            "G" => self.write_registers(args)?,
+            "m" => self.read_memory(args)?,
+            "M" => self.write_memory(args)?,
+            "c" => return Ok(Action::Resume { step: None }),
+            "s" => {
+                return Ok(Action::Resume {
+                    step: Some(self.step_vcpu.unwrap_or(self.current_vcpu)),
+                })
+            }
+            "H" => self.set_thread(args),
+            "T" => match parse_thread(args) {
+                Some(Thread::Vcpu(vcpu)) if vcpu < self.num_cpus() => "OK".to_owned(),
+                _ => "E02".to_owned(),
+            },
+            "Z" | "z" => self.update_breakpoint(command == "Z", args)?,
+            "D" => return Ok(Action::Detach),
+            "k" => return Ok(Action::Kill),
+            "q" | "Q" => self.query(packet),
+            "v" => return Ok(self.handle_v_packet(packet)),
+            _ => String::new(),
+        };
+        Ok(Action::Reply(reply))
+    }
+
+    fn query(&mut self, packet: &str) -> String {
+        let (name, args) = packet.split_once([':', ',']).unwrap_or((packet, ""));
+        match name {
+            "qSupported" => {
+                format!("PacketSize={MAX_PACKET_SIZE:x};vContSupported+;QStartNoAckMode+;swbreak+;hwbreak+")
+            }
+            "QStartNoAckMode" => {
+                self.ack = false;
+                "OK".to_owned()
+            }
+            "qAttached" => "1".to_owned(),
+            "qC" => format!("QC{:x}", self.current_vcpu + 1),
+            "qfThreadInfo" => {
+                let threads: Vec<_> = (1..=self.num_cpus())
+                    .map(|tid| format!("{tid:x}"))
+                    .collect();
+                format!("m{}", threads.join(","))
+            }
+            "qsThreadInfo" => "l".to_owned(),
+            "qThreadExtraInfo" => match parse_thread(args) {
+                Some(Thread::Vcpu(vcpu)) => encode_hex(format!("vCPU {vcpu}").as_bytes()),
+                _ => "E02".to_owned(),
+            },
+            _ => String::new(),
+        }
+    }
+
+    fn handle_v_packet(&self, packet: &str) -> Action {
+        if packet == "vCont?" {
+            return Action::Reply("vCont;c;C;s;S".to_owned());
+        }
+        let Some(actions) = packet.strip_prefix("vCont;") else {
+            return Action::Reply(String::new());
+        };
+
+        // All the vCPUs run. Only the one that is explicitly stepped stops
+        // after a single instruction.
+        let mut step = None;
+        for action in actions.split(';') {
+            let (action, thread) = action.split_once(':').unwrap_or((action, "-1"));
+            if action.starts_with(['s', 'S']) {
+                step = match parse_thread(thread) {
+                    Some(Thread::Vcpu(vcpu)) => Some(vcpu),
+                    Some(Thread::Any) => Some(self.current_vcpu),
+                    None => return Action::Reply("E02".to_owned()),
+                };
+            }
+        }
+        Action::Resume { step }
+    }
+
+    fn set_thread(&mut self, args: &str) -> String {
+        let (op, thread) = args.split_at(args.len().min(1));
+        let vcpu = match parse_thread(thread) {
+            Some(Thread::Vcpu(vcpu)) if vcpu < self.num_cpus() => Some(vcpu),
+            Some(Thread::Any) => None,
+            _ => return "E02".to_owned(),
+        };
+        match op {
+            "g" => self.current_vcpu = vcpu.unwrap_or(self.current_vcpu),
+            "c" => self.step_vcpu = vcpu,
+            _ => return "E02".to_owned(),
+        }
+        "OK".to_owned()
+    }
+
+    fn stop_reply(&self) -> String {
+        match self.last_event {
+            DebugEvent::Interrupt => {
+                format!("T{SIGINT:02x}thread:{:x};", self.current_vcpu + 1)
+            }
+            DebugEvent::Trap {
+                vcpu,
+                exception,
+                dr6,
+            } => {
+                let mut reply = format!("T{SIGTRAP:02x}");
+                if exception == DB_VECTOR {
+                    let hit = (0..4)
+                        .filter(|i| dr6 & (1 << i) != 0)
+                        .find_map(|i| self.hw_breakpoints[i]);
+                    match hit {
+                        Some(bp) if bp.kind == WatchKind::Write => {
+                            write!(reply, "watch:{:x};", bp.addr).unwrap();
+                        }
+                        Some(bp) if bp.kind == WatchKind::Access => {
+                            write!(reply, "awatch:{:x};", bp.addr).unwrap();
+                        }
+                        Some(_) => reply += "hwbreak:;",
+                        None => {}
+                    }
+                } else if exception == BP_VECTOR {
+                    reply += "swbreak:;";
+                }
+                write!(reply, "thread:{:x};", vcpu + 1).unwrap();
+                reply
+            }
+        }
+    }
+
+    fn read_registers(&self) -> Result<String> {
+        let vcpu = &self.stub.control.contexts()[self.current_vcpu].vcpu;
+        let regs = vcpu.regs()?;
+        let sregs = vcpu.sregs()?;
+        let mut reply = String::new();
+        for register in RegisterLayout::new(&sregs).registers() {
+            let value = register.get(&regs, &sregs);
+            reply += &encode_hex(&value.to_le_bytes()[..register.size()]);
+        }
+        Ok(reply)
+    }
+
+    fn write_registers(&self, args: &str) -> Result<String> {
+        let Some(bytes) = decode_hex(args) else {
+            return Ok("E16".to_owned());
+        };
+        let vcpu = &self.stub.control.contexts()[self.current_vcpu].vcpu;
+        let mut regs = vcpu.regs()?;
+        let sregs = vcpu.sregs()?;
+        let mut offset = 0;
+        for register in RegisterLayout::new(&sregs).registers() {
+            let size = register.size();
+            let Some(value) = bytes.get(offset..offset + size) else {
+                break;
+            };
+            let mut buf = [0; 8];
+            buf[..size].copy_from_slice(value);
+            register.set(&mut regs, u64::from_le_bytes(buf));
+            offset += size;
+        }
+        vcpu.set_regs(&regs)?;
+        Ok("OK".to_owned())
+    }
+
+    fn read_memory(&self, args: &str) -> Result<String> {
+        let Some((addr, len)) = parse_addr_len(args) else {
+            return Ok("E16".to_owned());
+        };
+        // The hex-encoded reply has to fit in a packet.
+        let len = len.min(MAX_PACKET_SIZE as u64 / 2) as usize;
+        let sregs = self.current_sregs()?;
+        let memory = &self.stub.memory;
+        let mut bytes = vec![0; len];
+        let mut done = 0;
+        while done < len {
+            let addr = addr.wrapping_add(done as u64);
+            let Ok(translation) = paging::translate(memory, &sregs, addr) else {
+                break;
+            };
+            let offset = addr & (translation.page_size - 1);
+            let chunk = ((translation.page_size - offset) as usize).min(len - done);
+            let buf = &mut bytes[done..done + chunk];
+            if memory.read_slice(buf, translation.phys_addr).is_err() {
+                break;
+            }
+            done += chunk;
+        }
+        bytes.truncate(done);
+        if bytes.is_empty() && len > 0 {
+            return Ok("E0e".to_owned());
+        }
+        Ok(encode_hex(&bytes))
+    }
+
+    fn write_memory(&self, args: &str) -> Result<String> {
+        let Some((addr_len, data)) = args.split_once(':') else {
+            return Ok("E16".to_owned());
+        };
+        let (Some((addr, len)), Some(data)) = (parse_addr_len(addr_len), decode_hex(data)) else {
+            return Ok("E16".to_owned());
+        };
+        if data.len() as u64 != len {
+            return Ok("E16".to_owned());
+        }
+        let sregs = self.current_sregs()?;
+        for (addr, byte) in (addr..).zip(data) {
+            let translation = paging::translate(&self.stub.memory, &sregs, addr);
+            if !translation
+                .is_ok_and(|translation| self.stub.write_phys(translation.phys_addr, byte))
+            {
+                return Ok("E0e".to_owned());
+            }
+        }
+        Ok("OK".to_owned())
+    }
+
+    fn update_breakpoint(&mut self, insert: bool, args: &str) -> Result<String> {
+        let mut parts = args.splitn(3, ',');
+        let (Some(kind), Some(addr), Some(len)) = (parts.next(), parts.next(), parts.next()) else {
+            return Ok("E16".to_owned());
+        };
+        let (Ok(addr), Ok(len)) = (u64::from_str_radix(addr, 16), u64::from_str_radix(len, 16))
+        else {
+            return Ok("E16".to_owned());
+        };
+        let kind = match kind {
+            "0" => return self.update_sw_breakpoint(insert, addr),
+            "1" => WatchKind::Execute,
+            "2" => WatchKind::Write,
+            "4" => WatchKind::Access,
+            // Read watchpoints are not supported by x86.
+            _ => return Ok(String::new()),
+        };
+        let len = if kind == WatchKind::Execute { 1 } else { len };
+        if !matches!(len, 1 | 2 | 4 | 8) || !addr.is_multiple_of(len) {
+            return Ok("E16".to_owned());
+        }
+        let bp = HwBreakpoint { addr, kind, len };
+        let matches = |slot: &Option<HwBreakpoint>| {
+            slot.is_some_and(|b| b.addr == addr && b.kind == kind && b.len == len)
+        };
+        if insert {
+            if self.hw_breakpoints.iter().any(matches) {
+                return Ok("OK".to_owned());
+            }
+            let Some(slot) = self.hw_breakpoints.iter_mut().find(|slot| slot.is_none()) else {
+                return Ok("E1c".to_owned());
+            };
+            *slot = Some(bp);
+        } else if let Some(slot) = self.hw_breakpoints.iter_mut().find(|slot| matches(slot)) {
+            *slot = None;
+        }
+        Ok("OK".to_owned())
+    }
+
+    fn update_sw_breakpoint(&mut self, insert: bool, addr: u64) -> Result<String> {
+        if !insert {
+            if let Some((phys_addr, byte)) = self.sw_breakpoints.remove(&addr) {
+                self.stub.write_phys(phys_addr, byte);
+            }
+            return Ok("OK".to_owned());
+        }
+        if self.sw_breakpoints.contains_key(&addr) {
+            return Ok("OK".to_owned());
+        }
+        let sregs = self.current_sregs()?;
+        let memory = &self.stub.memory;
+        let Some((phys_addr, byte)) =
+            paging::translate(memory, &sregs, addr)
+                .ok()
+                .and_then(|translation| {
+                    let phys_addr = translation.phys_addr;
+                    let byte = memory.read_obj(phys_addr).ok()?;
+                    Some((phys_addr, byte))
+                })
+        else {
+            return Ok("E0e".to_owned());
+        };
+        self.stub.write_phys(phys_addr, INT3);
+        self.sw_breakpoints.insert(addr, (phys_addr, byte));
+        Ok("OK".to_owned())
+    }
+
+    /// Programs the breakpoints into all the vCPUs and enables single-stepping
+    /// on `step`.
+    fn set_guest_debug(&self, step: Option<usize>) -> Result<()> {
+        let mut control = 0;
+        if !self.sw_breakpoints.is_empty() {
+            control |= KVM_GUESTDBG_ENABLE | KVM_GUESTDBG_USE_SW_BP;
+        }
+        let mut debugreg = [0; 8];
+        for (i, bp) in self.hw_breakpoints.iter().enumerate() {
+            let Some(bp) = bp else {
+                continue;
+            };
+            control |= KVM_GUESTDBG_ENABLE | KVM_GUESTDBG_USE_HW_BP;
+            debugreg[i] = bp.addr;
+            let rw = match bp.kind {
+                WatchKind::Execute => 0b00,
+                WatchKind::Write => 0b01,
+                WatchKind::Access => 0b11,
+            };
+            let len = match bp.len {
+                1 => 0b00,
+                2 => 0b01,
+                8 => 0b10,
+                _ => 0b11,
+            };
+            // Local enable, R/W and LEN fields of DR7
+            debugreg[7] |= 1 << (i * 2) | (rw | len << 2) << (16 + i * 4);
+        }
+        for context in self.stub.control.contexts() {
+            let mut debug = kvm_guest_debug {
+                control,
+                arch: kvm_guest_debug_arch { debugreg },
+                ..Default::default()
+            };
+            if step == Some(context.id) {
+                debug.control |= KVM_GUESTDBG_ENABLE | KVM_GUESTDBG_SINGLESTEP;
+            }
+            context.vcpu.set_guest_debug(&debug)?;
+        }
+        Ok(())
+    }
+
+    fn current_sregs(&self) -> Result<kvm_sregs> {
+        Ok(self.stub.control.contexts()[self.current_vcpu]
+            .vcpu
+            .sregs()?)
+    }
+
+    fn num_cpus(&self) -> usize {
+        self.stub.control.contexts().len()
+    }
+}
+
+impl GdbStub {
+    fn write_phys(&self, addr: u64, byte: u8) -> bool {
+        self.memory.write_obj(&byte, addr).is_ok()
+    }
+}
+
+/// Reads packets from the debugger and forwards them to the session.
+///
+/// An interrupt request (Ctrl-C) is handled here because the session is
+/// blocked while the vCPUs are running.
+fn read_packets(mut stream: Stream, control: &Control, tx: &mpsc::Sender<Option<Vec<u8>>>) {
+    enum State {
+        Idle,
+        Data,
+        Checksum(u8),
+    }
+
+    let mut buf = [0; 4096];
+    let mut state = State::Idle;
+    let mut packet = Vec::new();
+    let mut checksum = [0; 2];
+    loop {
+        let n = match stream.read(&mut buf) {
+            Ok(0) | Err(_) => return,
+            Ok(n) => n,
+        };
+        for &byte in &buf[..n] {
+            state = match state {
+                State::Idle => match byte {
+                    b'$' => {
+                        packet.clear();
+                        State::Data
+                    }
+                    0x03 => {
+                        control.debug_stop(DebugEvent::Interrupt);
+                        State::Idle
+                    }
+                    _ => State::Idle,
+                },
+                State::Data if byte == b'#' => State::Checksum(0),
+                State::Data => {
+                    packet.push(byte);
+                    State::Data
+                }
+                State::Checksum(0) => {
+                    checksum[0] = byte;
+                    State::Checksum(1)
+                }
+                State::Checksum(_) => {
+                    checksum[1] = byte;
+                    let expected = std::str::from_utf8(&checksum)
+                        .ok()
+                        .and_then(|s| u8::from_str_radix(s, 16).ok());
+                    let actual = packet.iter().copied().fold(0u8, u8::wrapping_add);
+                    let packet = (expected == Some(actual)).then(|| unescape(&packet));
+                    if tx.send(packet).is_err() {
+                        return;
+                    }
+                    State::Idle
+                }
+            }
+        }
+    }
+}
+
+fn unescape(data: &[u8]) -> Vec<u8> {
+    let mut unescaped = Vec::with_capacity(data.len());
+    let mut iter = data.iter();
+    while let Some(&byte) = iter.next() {
+        if byte == b'}' {
+            if let Some(&next) = iter.next() {
+                unescaped.push(next ^ 0x20);
+            }
+        } else {
+            unescaped.push(byte);
+        }
+    }
+    unescaped
+}
+
+/// Register layouts of the `g` and `G` packets
+///
+/// GDB picks the layout by the architecture of the program being debugged,
+/// so the one matching the current mode of the vCPU is used.
+enum RegisterLayout {
+    I386,
+    Amd64,
+}
+
+#[derive(Clone, Copy)]
+enum Register {
+    Gpr32(Gpr),
+    Gpr64(Gpr),
+    Eflags,
+    Segment(usize),
+}
+
+#[derive(Clone, Copy)]
+enum Gpr {
+    Rax,
+    Rbx,
+    Rcx,
+    Rdx,
+    Rsi,
+    Rdi,
+    Rbp,
+    Rsp,
+    R8,
+    R9,
+    R10,
+    R11,
+    R12,
+    R13,
+    R14,
+    R15,
+    Rip,
+}
+
+impl RegisterLayout {
+    fn new(sregs: &kvm_sregs) -> Self {
+        if sregs.efer & 0x400 != 0 {
+            // LMA
+            Self::Amd64
+        } else {
+            Self::I386
+        }
+    }
+
+    fn registers(&self) -> Vec<Register> {
+        use Gpr::{
+            Rax, Rbp, Rbx, Rcx, Rdi, Rdx, Rip, Rsi, Rsp, R10, R11, R12, R13, R14, R15, R8, R9,
+        };
+        let (gprs, width): (&[Gpr], fn(Gpr) -> Register) = match self {
+            Self::I386 => (
+                &[Rax, Rcx, Rdx, Rbx, Rsp, Rbp, Rsi, Rdi, Rip],
+                Register::Gpr32,
+            ),
+            Self::Amd64 => (
+                &[
+                    Rax, Rbx, Rcx, Rdx, Rsi, Rdi, Rbp, Rsp, R8, R9, R10, R11, R12, R13, R14, R15,
+                    Rip,
+                ],
+                Register::Gpr64,
+            ),
+        };
+        gprs.iter()
+            .copied()
+            .map(width)
+            .chain([Register::Eflags])
+            // cs, ss, ds, es, fs, gs
+            .chain((0..6).map(Register::Segment))
+            .collect()
+    }
+}
+
+impl Register {
+    const fn size(self) -> usize {
+        match self {
+            Self::Gpr64(_) => 8,
+            Self::Gpr32(_) | Self::Eflags | Self::Segment(_) => 4,
+        }
+    }
+
+    fn get(self, regs: &kvm_regs, sregs: &kvm_sregs) -> u64 {
+        match self {
+            Self::Gpr32(gpr) | Self::Gpr64(gpr) => {
+                let mut regs = *regs;
+                *gpr.field(&mut regs)
+            }
+            Self::Eflags => regs.rflags,
+            Self::Segment(i) => {
+                let segments = [sregs.cs, sregs.ss, sregs.ds, sregs.es, sregs.fs, sregs.gs];
+                segments[i].selector.into()
+            }
+        }
+    }
+
+    /// Segment registers are read-only.
+    fn set(self, regs: &mut kvm_regs, value: u64) {
+        match self {
+            Self::Gpr32(gpr) => {
+                let field = gpr.field(regs);
+                *field = (*field & !0xffff_ffff) | value;
+            }
+            Self::Gpr64(gpr) => *gpr.field(regs) = value,
+            Self::Eflags => regs.rflags = value,
+            Self::Segment(_) => {}
+        }
+    }
+}
+
+impl Gpr {
+    fn field(self, regs: &mut kvm_regs) -> &mut u64 {
+        match self {
+            Self::Rax => &mut regs.rax,
+            Self::Rbx => &mut regs.rbx,
+            Self::Rcx => &mut regs.rcx,
+            Self::Rdx => &mut regs.rdx,
+            Self::Rsi => &mut regs.rsi,
+            Self::Rdi => &mut regs.rdi,
+            Self::Rbp => &mut regs.rbp,
+            Self::Rsp => &mut regs.rsp,
+            Self::R8 => &mut regs.r8,
+            Self::R9 => &mut regs.r9,
+            Self::R10 => &mut regs.r10,
+            Self::R11 => &mut regs.r11,
+            Self::R12 => &mut regs.r12,
+            Self::R13 => &mut regs.r13,
+            Self::R14 => &mut regs.r14,
+            Self::R15 => &mut regs.r15,
+            Self::Rip => &mut regs.rip,
+        }
+    }
+}
+
+enum Thread {
+    /// Any thread (0) or all threads (-1)
+    Any,
+
+    Vcpu(usize),
+}
+
+fn parse_thread(s: &str) -> Option<Thread> {
+    match s {
+        "0" | "-1" => Some(Thread::Any),
+        _ => {
+            let tid = usize::from_str_radix(s, 16).ok()?;
+            Some(Thread::Vcpu(tid.checked_sub(1)?))
+        }
+    }
+}
+
+fn parse_addr_len(s: &str) -> Option<(u64, u64)> {
+    let (addr, len) = s.split_once(',')?;
+    Some((
+        u64::from_str_radix(addr, 16).ok()?,
+        u64::from_str_radix(len, 16).ok()?,
+    ))
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().fold(String::new(), |mut s, byte| {
+        write!(s, "{byte:02x}").unwrap();
+        s
+    })
+}
+
+fn decode_hex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
@@ -1,27 +1,72 @@
 use crate::{
     boot::{self, Bootable},
-    device::{self, PortIoDevice},
+    coalesced::{self, CoalescedRing},
+    core_dump,
+    cpu::{self, Control, Cpu, CpuConfig, VmExit},
+    cpuid::{self, PvFeature, PvFeatures},
+    device::{
+        MmioBus, PortIoDevice, PortIoHub, SharedMmioDevice, VirtioMem, VirtioMmio,
+        VIRTIO_MEM_BLOCK_SIZE, VIRTIO_MMIO_SIZE,
+    },
+    dirty::DirtyLog,
+    gdb::{GdbSocket, GdbStub},
     kvm::{Vcpu, Vm},
-    memory::Mmapped,
-    Hypervisor, KernelParams, Result,
+    memory::{self, GuestMemory, MemoryBackend, MemoryOptions, MemoryRegion, Mmapped, Prefault},
+    migration::{self, IncomingMigration, MigrationStream},
+    paging::{self, Translation},
+    profile::{ExitProfile, ExitProfiler},
+    snapshot::{
+        self, DeviceState, GuestState, LazyMemory, LazyRestore, SnapshotFile, VcpuState, VmState,
+    },
+    stats::{KvmStats, StatsReader},
+    thread::ThreadConfig,
+    Error, Hypervisor, KernelParams, Result,
 };
+use nix::sys::mman::MapFlags;
 use std::{
+    collections::HashMap,
     ffi::CString,
-    num::NonZeroUsize,
-    path::PathBuf,
+    fs::File,
+    io::BufWriter,
+    mem::size_of,
+    num::{NonZeroU32, NonZeroUsize},
+    path::{Path, PathBuf},
     sync::{Arc, Mutex},
+    thread::JoinHandle,
 };
 use sys::kvm_bindings::{
-    self, kvm_pit_config, kvm_regs, kvm_run, kvm_userspace_memory_region, CpuId, KVM_EXIT_HLT,
-    KVM_EXIT_INTERNAL_ERROR, KVM_EXIT_IO, KVM_EXIT_IO_IN, KVM_EXIT_IO_OUT, KVM_EXIT_SHUTDOWN,
+    self, kvm_dirty_gfn, kvm_irqchip, kvm_pit_config, kvm_pit_state2, kvm_regs, kvm_sregs, CpuId,
+    KVM_IRQCHIP_IOAPIC, KVM_IRQCHIP_PIC_MASTER, KVM_IRQCHIP_PIC_SLAVE,
 };
 
+/// Where the registers of the virtio-mem device are placed, in the MMIO hole
+const VIRTIO_MEM_MMIO_BASE: u64 = 0xd000_0000;
+
+/// ISA IRQ of the virtio-mem device, which no other device uses
+const VIRTIO_MEM_IRQ: u8 = 5;
+
 pub struct GuestBuilder<'a> {
     hypervisor: &'a Hypervisor,
     kernel_path: PathBuf,
     num_cpus: NonZeroUsize,
     memory_size: NonZeroUsize,
+    memory_backend: MemoryBackend,
+    memory_options: MemoryOptions,
     kernel_params: KernelParams,
+    pv_features: PvFeatures,
+    tsc_khz: Option<NonZeroU32>,
+    vcpu_thread_configs: HashMap<usize, ThreadConfig>,
+    device_thread_config: ThreadConfig,
+    halt_poll_ns: Option<u32>,
+    reboot: bool,
+    core_dump_path: Option<PathBuf>,
+    coalesced_io: bool,
+    dirty_ring_entries: Option<NonZeroUsize>,
+    snapshot: Option<SnapshotFile>,
+    lazy_restore: Option<LazyRestore>,
+    parent: Option<Parent>,
+    migration: Option<IncomingMigration>,
+    hotplug_memory_size: Option<NonZeroUsize>,
 }
 
 impl<'a> GuestBuilder<'a> {
@@ -31,22 +76,127 @@ impl<'a> GuestBuilder<'a> {
             kernel_path,
             num_cpus: NonZeroUsize::new(1).unwrap(),
             memory_size: NonZeroUsize::new(64 * 1024 * 1024).unwrap(),
+            memory_backend: MemoryBackend::default(),
+            memory_options: MemoryOptions::default(),
             kernel_params: KernelParams::default(),
+            pv_features: PvFeatures::default(),
+            tsc_khz: None,
+            vcpu_thread_configs: HashMap::new(),
+            device_thread_config: ThreadConfig::default(),
+            halt_poll_ns: None,
+            reboot: true,
+            core_dump_path: None,
+            coalesced_io: true,
+            dirty_ring_entries: None,
+            snapshot: None,
+            lazy_restore: None,
+            parent: None,
+            migration: None,
+            hotplug_memory_size: None,
         }
     }
 
+    /// Creates a builder for a guest restored from `snapshot`, with the
+    /// vCPUs, memory size and kernel of the saved guest.
+    pub(crate) fn restore(hypervisor: &'a Hypervisor, snapshot: SnapshotFile) -> Result<Self> {
+        let state = &snapshot.state;
+        let memory_size = usize::try_from(state.memory_size())
+            .ok()
+            .and_then(NonZeroUsize::new)
+            .ok_or_else(|| Error::InvalidSnapshot("Invalid memory size".to_owned()))?;
+        let mut builder = Self::new(hypervisor, state.kernel_path.clone());
+        builder.num_cpus = NonZeroUsize::new(state.vcpus.len()).unwrap();
+        builder.memory_size = memory_size;
+        builder.kernel_params = state.kernel_params.clone();
+        builder.snapshot = Some(snapshot);
+        Ok(builder)
+    }
+
+    /// Creates a builder for a guest received through `migration`, with the
+    /// vCPUs and memory size of the guest on the source. The kernel is only
+    /// known once the state of the guest has been received.
+    pub(crate) fn incoming(
+        hypervisor: &'a Hypervisor,
+        migration: IncomingMigration,
+    ) -> Result<Self> {
+        let memory_size = migration
+            .regions
+            .iter()
+            .map(|region| region.size)
+            .sum::<u64>();
+        let memory_size = NonZeroUsize::new(memory_size as usize)
+            .ok_or_else(|| Error::InvalidMigrationStream("No memory".to_owned()))?;
+        let num_cpus = NonZeroUsize::new(migration.num_cpus)
+            .ok_or_else(|| Error::InvalidMigrationStream("No vCPUs".to_owned()))?;
+        let mut builder = Self::new(hypervisor, PathBuf::new());
+        builder.num_cpus = num_cpus;
+        builder.memory_size = memory_size;
+        builder.migration = Some(migration);
+        Ok(builder)
+    }
+
     #[must_use]
     pub fn num_cpus(mut self, num_cpus: NonZeroUsize) -> Self {
         self.num_cpus = num_cpus;
         self
     }
 
+    /// Returns the number of vCPUs that the guest will have.
+    pub fn vcpu_count(&self) -> usize {
+        self.num_cpus.get()
+    }
+
     #[must_use]
     pub fn memory_size(mut self, bytes: NonZeroUsize) -> Self {
         self.memory_size = bytes;
         self
     }
 
+    /// Sets where guest RAM is allocated from.
+    ///
+    /// With hugepages, the memory size must be a multiple of the page size.
+    #[must_use]
+    pub fn memory_backend(mut self, backend: MemoryBackend) -> Self {
+        self.memory_backend = backend;
+        self
+    }
+
+    /// Faults in all of guest RAM before the guest starts, instead of on
+    /// first access.
+    #[must_use]
+    pub fn prefault(mut self, prefault: Prefault) -> Self {
+        self.memory_options.prefault = Some(prefault);
+        self
+    }
+
+    /// Sets whether guest RAM is locked with `mlock` so that it is never
+    /// swapped out.
+    ///
+    /// This is subject to `RLIMIT_MEMLOCK`.
+    #[must_use]
+    pub fn lock_memory(mut self, enabled: bool) -> Self {
+        self.memory_options.lock = enabled;
+        self
+    }
+
+    /// Sets whether guest RAM is left out of core dumps of the VMM process.
+    ///
+    /// Dumps written by [`CoreDumper`] are not affected.
+    #[must_use]
+    pub fn dontdump_memory(mut self, enabled: bool) -> Self {
+        self.memory_options.dontdump = enabled;
+        self
+    }
+
+    /// Sets whether KSM may merge identical pages of guest RAM.
+    ///
+    /// This has no effect with hugepages or shared backends.
+    #[must_use]
+    pub fn mergeable_memory(mut self, enabled: bool) -> Self {
+        self.memory_options.mergeable = enabled;
+        self
+    }
+
     #[must_use]
     pub fn cmdline(mut self, cmdline: impl Into<CString>) -> Self {
         self.kernel_params.cmdline = Some(cmdline.into());
@@ -65,60 +215,529 @@ impl<'a> GuestBuilder<'a> {
         self
     }
 
-    pub fn build(self) -> Result<Guest> {
-        let kernel = std::fs::read(&self.kernel_path)?;
+    /// Enables or disables a paravirtual feature.
+    ///
+    /// All the features supported by the host are enabled by default.
+    #[must_use]
+    pub fn pv_feature(mut self, feature: PvFeature, enabled: bool) -> Self {
+        self.pv_features.set(feature, enabled);
+        self
+    }
+
+    /// Fixes the TSC frequency seen by the guest.
+    ///
+    /// By default, the guest sees the TSC frequency of the host.
+    #[must_use]
+    pub fn tsc_khz(mut self, tsc_khz: NonZeroU32) -> Self {
+        self.tsc_khz = Some(tsc_khz);
+        self
+    }
+
+    /// Sets the placement and scheduling of the thread running the vCPU `id`.
+    #[must_use]
+    pub fn vcpu_thread_config(mut self, id: usize, config: ThreadConfig) -> Self {
+        self.vcpu_thread_configs.insert(id, config);
+        self
+    }
+
+    /// Sets the placement and scheduling of the other threads serving the
+    /// guest, which flush coalesced I/O and load lazily restored memory.
+    #[must_use]
+    pub fn device_thread_config(mut self, config: ThreadConfig) -> Self {
+        self.device_thread_config = config;
+        self
+    }
+
+    /// Sets the maximum time in nanoseconds that KVM polls for wakeup events
+    /// before halting a vCPU.
+    ///
+    /// Zero disables halt polling.
+    #[must_use]
+    pub fn halt_poll_ns(mut self, ns: u32) -> Self {
+        self.halt_poll_ns = Some(ns);
+        self
+    }
+
+    /// Sets whether the guest reboots when it requests a system reset.
+    ///
+    /// Rebooting reloads the kernel and resets the vCPUs and devices without
+    /// creating a new VM. If disabled, [`GuestHandle::wait`] returns
+    /// [`VmExit::Reset`] instead. Enabled by default.
+    #[must_use]
+    pub fn reboot(mut self, enabled: bool) -> Self {
+        self.reboot = enabled;
+        self
+    }
+
+    /// Writes an ELF core dump of the guest to `path` when it crashes due to
+    /// a triple fault, an entry failure or a KVM internal error.
+    #[must_use]
+    pub fn core_dump_on_crash(mut self, path: impl Into<PathBuf>) -> Self {
+        self.core_dump_path = Some(path.into());
+        self
+    }
+
+    /// Sets whether KVM may buffer writes to the ports that devices return
+    /// from [`PortIoDevice::coalesced_ports`] instead of exiting on each of
+    /// them.
+    ///
+    /// Enabled by default if the host supports `KVM_CAP_COALESCED_PIO`.
+    #[must_use]
+    pub fn coalesced_io(mut self, enabled: bool) -> Self {
+        self.coalesced_io = enabled;
+        self
+    }
+
+    /// Makes KVM report the pages written by each vCPU through a ring of at
+    /// least `entries` entries instead of a bitmap per memslot, if the host
+    /// supports `KVM_CAP_DIRTY_LOG_RING`.
+    ///
+    /// Rings avoid scanning the whole bitmap on each [`DirtyLog::collect`]
+    /// when few pages are written, but a vCPU exits to userspace each time
+    /// its ring fills up.
+    #[must_use]
+    pub fn dirty_ring(mut self, entries: NonZeroUsize) -> Self {
+        self.dirty_ring_entries = Some(entries);
+        self
+    }
+
+    /// Loads guest RAM from the snapshot as the guest accesses it, instead of
+    /// reading all of it before the guest starts, so that restoring takes
+    /// the same time whatever the size of the memory.
+    ///
+    /// This has no effect unless the guest is restored from a snapshot, and
+    /// requires a memory backend that does not keep contents of its own, with
+    /// memory that is neither prefaulted nor locked.
+    #[must_use]
+    pub fn lazy_restore(mut self, mode: LazyRestore) -> Self {
+        self.lazy_restore = Some(mode);
+        self
+    }
+
+    /// Adds a virtio-mem device through which the guest plugs up to `size`
+    /// more bytes of RAM while it runs, as requested with
+    /// [`MemoryHotplug::request_size`].
+    ///
+    /// The size is rounded up to a multiple of 2 MiB, the size of the blocks
+    /// that the guest plugs. The memory is placed above RAM and is not
+    /// included in snapshots, so a guest with hotpluggable memory cannot be
+    /// saved, cloned or migrated. The device is announced on the kernel
+    /// command line and has no effect on a guest that is restored instead
+    /// of booted.
+    #[must_use]
+    pub fn hotplug_memory(mut self, size: NonZeroUsize) -> Self {
+        self.hotplug_memory_size = Some(size);
+        self
+    }
+
+    pub fn build(mut self) -> Result<Guest> {
+        let kvm = &self.hypervisor.kvm;
+        if self.tsc_khz.is_some()
+            && kvm.check_extension(kvm_bindings::KVM_CAP_TSC_CONTROL as nix::libc::c_int)? <= 0
+        {
+            return Err(Error::KvmExtensionNotSupported("KVM_CAP_TSC_CONTROL"));
+        }
+        if self.halt_poll_ns.is_some()
+            && kvm.check_extension(kvm_bindings::KVM_CAP_HALT_POLL as nix::libc::c_int)? <= 0
+        {
+            return Err(Error::KvmExtensionNotSupported("KVM_CAP_HALT_POLL"));
+        }
+        if let Some(&id) = self
+            .vcpu_thread_configs
+            .keys()
+            .find(|&&id| id >= self.num_cpus.get())
+        {
+            return Err(Error::InvalidVcpuId(id));
+        }
+
+        // Restoring overwrites whatever the memory holds anyway.
+        let restoring = self.snapshot.is_some() || self.migration.is_some();
+        let (mut mmapped_memory, memory_fd) = match &self.parent {
+            Some(parent) => (parent.memory.map_copy_on_write()?, None),
+            None => self
+                .memory_backend
+                .map(self.memory_size, &self.memory_options, restoring)?,
+        };
+        let regions = MemoryRegion::layout(mmapped_memory.as_slice().len() as u64);
 
-        let mut mmapped_memory = Mmapped::new_anonymous(self.memory_size)?;
-        let memory = mmapped_memory.as_mut_slice();
+        if let Some(migration) = &mut self.migration {
+            if migration.regions != regions {
+                return Err(Error::InvalidMigrationStream(
+                    "Memory layout differs from the source".to_owned(),
+                ));
+            }
+            migration.receive(mmapped_memory.as_mut_slice())?;
+            let state = migration.state.as_ref().unwrap();
+            self.kernel_path.clone_from(&state.kernel_path);
+            self.kernel_params = state.kernel_params.clone();
+        }
+
+        let hotplug_region = self
+            .hotplug_memory_size
+            .filter(|_| self.state().is_none())
+            .map(|size| {
+                let size = (size.get() as u64).next_multiple_of(VIRTIO_MEM_BLOCK_SIZE);
+                MemoryRegion::hotplug(&regions, size)
+            });
+        if hotplug_region.is_some() {
+            let cmdline = self.kernel_params.cmdline.take().unwrap_or_default();
+            let mut cmdline = cmdline.into_bytes();
+            if !cmdline.is_empty() {
+                cmdline.push(b' ');
+            }
+            cmdline.extend_from_slice(
+                format!(
+                    "virtio_mmio.device={VIRTIO_MMIO_SIZE:#x}@{VIRTIO_MEM_MMIO_BASE:#x}:{VIRTIO_MEM_IRQ}"
+                )
+                .as_bytes(),
+            );
+            self.kernel_params.cmdline = Some(CString::new(cmdline).unwrap());
+        }
 
-        let bootable = Bootable::load(memory, &kernel, self.kernel_params)?;
-        eprintln!("Protocol: {:?}", bootable.protocol);
-        eprintln!("Entry: {:#x}", bootable.entry_addr);
-        bootable.configure_memory(memory)?;
-        boot::configure_acpi(memory, self.num_cpus.get())?;
+        let mut lazy_memory = None;
+        let bootable = match self.state() {
+            Some(state) => {
+                if state.regions != regions {
+                    return Err(Error::InvalidSnapshot(
+                        "Memory size differs from the snapshot".to_owned(),
+                    ));
+                }
+                if state.vcpus.len() != self.num_cpus.get() {
+                    return Err(Error::InvalidSnapshot(
+                        "Number of vCPUs differs from the snapshot".to_owned(),
+                    ));
+                }
+                // The memory of a clone already holds that of its parent.
+                if let Some(snapshot) = &self.snapshot {
+                    if let Some(mode) = self.lazy_restore {
+                        let registered = LazyMemory::register(
+                            snapshot,
+                            &mmapped_memory,
+                            &self.memory_backend,
+                            &self.memory_options,
+                        )?;
+                        lazy_memory = Some((registered, mode));
+                    } else {
+                        snapshot.read_memory(
+                            mmapped_memory.as_mut_slice(),
+                            self.memory_backend.keeps_contents(),
+                        )?;
+                    }
+                }
+                state.bootable.clone()
+            }
+            None => load_kernel(
+                &mut mmapped_memory.as_mut_slice()[regions[0].host_range()],
+                &regions,
+                &self.kernel_path,
+                self.kernel_params.clone(),
+                &self.hypervisor.supported_cpuid,
+                self.num_cpus.get(),
+            )?,
+        };
 
         let vm = Vm::new(self.hypervisor.kvm.clone())?;
-        vm.set_user_memory_region(&kvm_userspace_memory_region {
-            slot: 0,
-            flags: 0,
-            guest_phys_addr: 0,
-            memory_size: memory.len() as u64,
-            userspace_addr: memory.as_ptr() as u64,
-        })?;
+        for (slot, region) in (0..).zip(&regions) {
+            vm.set_user_memory_region(&region.memslot(slot, mmapped_memory.as_ptr(), 0))?;
+        }
+        let hotplug_memory = match hotplug_region {
+            Some(region) => {
+                let mapping = Mmapped::new(
+                    None,
+                    NonZeroUsize::new(region.size as usize).unwrap(),
+                    MapFlags::MAP_PRIVATE | MapFlags::MAP_NORESERVE,
+                    VIRTIO_MEM_BLOCK_SIZE as usize,
+                )?;
+                let slot = regions.len() as u32;
+                vm.set_user_memory_region(&region.memslot(slot, mapping.as_ptr(), 0))?;
+                Some((region, mapping))
+            }
+            None => None,
+        };
         vm.create_irqchip()?;
         vm.create_pit2(&kvm_pit_config::default())?;
+        let initial_irqchips = [
+            KVM_IRQCHIP_PIC_MASTER,
+            KVM_IRQCHIP_PIC_SLAVE,
+            KVM_IRQCHIP_IOAPIC,
+        ]
+        .map(|chip_id| vm.irqchip(chip_id))
+        .into_iter()
+        .collect::<nix::Result<Vec<_>>>()?;
+        let initial_pit = vm.pit2()?;
+        if let Some(ns) = self.halt_poll_ns {
+            vm.enable_cap(kvm_bindings::KVM_CAP_HALT_POLL, [ns.into(), 0, 0, 0])?;
+        }
+        let dirty_ring_size = match self.dirty_ring_entries {
+            Some(entries) => enable_dirty_ring(&vm, entries)?,
+            None => None,
+        };
+        let manual_protect = dirty_ring_size.is_none() && enable_manual_dirty_log_protect(&vm)?;
+        let vm = Arc::new(vm);
 
-        Ok(Guest {
-            vm: Arc::new(vm),
-            num_cpus: self.num_cpus,
-            port_io_hub: PortIoHub::default(),
-            supported_cpuid: self.hypervisor.supported_cpuid.clone(),
+        let cpu_config = CpuConfig {
+            cpuid: self.hypervisor.supported_cpuid.clone(),
             vcpu_mmap_size: self.hypervisor.vcpu_mmap_size,
             bootable,
-            _memory: mmapped_memory,
+            pv_features: self.pv_features,
+            tsc_khz: self.tsc_khz,
+            dirty_ring_size,
+        };
+        let contexts = (0..self.num_cpus.get())
+            .map(|id| cpu_config.create_vcpu(vm.clone(), id).map(Arc::new))
+            .collect::<Result<Vec<_>>>()?;
+        if let Some(state) = self.state() {
+            state.restore(&vm, &contexts)?;
+        }
+
+        let coalesced_ring = if self.coalesced_io {
+            map_coalesced_ring(&vm, &contexts[0].vcpu, cpu_config.vcpu_mmap_size)?.map(Arc::new)
+        } else {
+            None
+        };
+
+        let control = Arc::new(Control::new(contexts));
+        let memory = GuestMemory::new(mmapped_memory, memory_fd, regions, hotplug_memory);
+        if let Some((lazy_memory, mode)) = lazy_memory {
+            lazy_memory.spawn(&memory.mapping(), mode, &self.device_thread_config)?;
+        }
+        let dirty_log = DirtyLog::new(vm.clone(), control.clone(), memory.clone(), manual_protect);
+        let memory_hotplug = hotplug_region.map(|region| {
+            let device = VirtioMem::new(region.guest_addr, region.size, memory.clone());
+            let irq = Irq { vm: vm.clone() };
+            Arc::new(Mutex::new(VirtioMmio::new(
+                device,
+                VIRTIO_MEM_MMIO_BASE,
+                irq,
+                VIRTIO_MEM_IRQ,
+                memory.clone(),
+            )))
+        });
+        let mmio_bus = MmioBus::new(
+            memory_hotplug
+                .iter()
+                .map(|device| device.clone() as SharedMmioDevice)
+                .collect(),
+        )?;
+        if let Some(migration) = &mut self.migration {
+            migration.complete()?;
+        }
+        let cloned = self.parent.is_some();
+        let device_states = self
+            .snapshot
+            .map(|snapshot| snapshot.state)
+            .or_else(|| self.parent.map(|parent| parent.state))
+            .or_else(|| self.migration.and_then(|migration| migration.state))
+            .map(|state| state.devices)
+            .unwrap_or_default();
+
+        let port_io_hub = PortIoHub::new(coalesced_ring.as_ref().map(|_| vm.clone()));
+        Ok(Guest {
+            vm,
+            port_io_hub: Arc::new(port_io_hub),
+            mmio_bus,
+            memory_hotplug,
+            coalesced_ring,
+            control,
+            vcpu_thread_configs: self.vcpu_thread_configs,
+            device_thread_config: self.device_thread_config,
+            memory,
+            dirty_log,
+            device_states: Mutex::new(device_states),
+            cloned,
+            kernel_path: self.kernel_path,
+            kernel_params: self.kernel_params,
+            cpu_config,
+            initial_irqchips,
+            initial_pit,
+            reboot: self.reboot,
+            halt_poll_ns: self.halt_poll_ns,
+            dirty_ring_entries: self.dirty_ring_entries,
+            coalesced_io: self.coalesced_io,
+            core_dump_path: self.core_dump_path,
         })
     }
+
+    /// State that the guest takes instead of booting the kernel
+    fn state(&self) -> Option<&GuestState> {
+        self.snapshot
+            .as_ref()
+            .map(|snapshot| &snapshot.state)
+            .or_else(|| self.parent.as_ref().map(|parent| &parent.state))
+            .or_else(|| {
+                self.migration
+                    .as_ref()
+                    .and_then(|migration| migration.state.as_ref())
+            })
+    }
+}
+
+/// Guest that a guest being built is cloned from
+struct Parent {
+    memory: GuestMemory,
+    state: GuestState,
+}
+
+/// Maps the ring in which KVM buffers coalesced writes, if the host
+/// supports coalescing port I/O.
+fn map_coalesced_ring(
+    vm: &Vm,
+    vcpu: &Vcpu,
+    vcpu_mmap_size: NonZeroUsize,
+) -> Result<Option<CoalescedRing>> {
+    if vm.check_extension(kvm_bindings::KVM_CAP_COALESCED_PIO as nix::libc::c_int)? <= 0 {
+        return Ok(None);
+    }
+    let page_offset =
+        vm.check_extension(kvm_bindings::KVM_CAP_COALESCED_MMIO as nix::libc::c_int)?;
+    let Ok(page_offset) = usize::try_from(page_offset) else {
+        return Ok(None);
+    };
+    if page_offset == 0 || (page_offset + 1) * memory::PAGE_SIZE > vcpu_mmap_size.get() {
+        return Ok(None);
+    }
+    Ok(Some(CoalescedRing::new(vcpu, page_offset)?))
+}
+
+/// Enables the dirty rings with room for at least `entries` entries each, if
+/// the host supports them, and returns their size in bytes.
+fn enable_dirty_ring(vm: &Vm, entries: NonZeroUsize) -> Result<Option<NonZeroUsize>> {
+    // The acquire-release variant only differs on architectures with weaker
+    // memory ordering, where the plain one is not offered.
+    for cap in [
+        kvm_bindings::KVM_CAP_DIRTY_LOG_RING_ACQ_REL,
+        kvm_bindings::KVM_CAP_DIRTY_LOG_RING,
+    ] {
+        let max_size = vm.check_extension(cap as nix::libc::c_int)?;
+        let Ok(max_size) = usize::try_from(max_size) else {
+            continue;
+        };
+        if max_size < memory::PAGE_SIZE {
+            continue;
+        }
+        let size = entries
+            .get()
+            .saturating_mul(size_of::<kvm_dirty_gfn>())
+            .next_power_of_two()
+            .clamp(memory::PAGE_SIZE, max_size);
+        vm.enable_cap(cap, [size as u64, 0, 0, 0])?;
+        return Ok(NonZeroUsize::new(size));
+    }
+    Ok(None)
 }
 
-type PortIoHub = device::PortIoHub<Arc<Mutex<dyn PortIoDevice + Send>>>;
+/// Makes reading the dirty log leave the pages writable until the log is
+/// cleared, if the host supports it.
+fn enable_manual_dirty_log_protect(vm: &Vm) -> Result<bool> {
+    let cap = kvm_bindings::KVM_CAP_MANUAL_DIRTY_LOG_PROTECT2;
+    let flags = vm.check_extension(cap as nix::libc::c_int)?;
+    let flag = kvm_bindings::KVM_DIRTY_LOG_MANUAL_PROTECT_ENABLE;
+    if flags <= 0 || flags as u32 & flag == 0 {
+        return Ok(false);
+    }
+    vm.enable_cap(cap, [flag.into(), 0, 0, 0])?;
+    Ok(true)
+}
+
+/// Loads the kernel into zeroed guest memory and sets up the boot
+/// environment around it.
+///
+/// `low_memory` backs the first of `regions`.
+fn load_kernel(
+    low_memory: &mut [u8],
+    regions: &[MemoryRegion],
+    kernel_path: &Path,
+    kernel_params: KernelParams,
+    cpuid: &CpuId,
+    num_cpus: usize,
+) -> Result<Bootable> {
+    let kernel = std::fs::read(kernel_path)?;
+    let bootable = Bootable::load(low_memory, regions, &kernel, kernel_params)?;
+    eprintln!("Protocol: {:?}", bootable.protocol);
+    eprintln!("Entry: {:#x}", bootable.entry_addr);
+    let ram_end = regions.last().map_or(0, MemoryRegion::end);
+    bootable.configure_memory(low_memory, ram_end, cpuid::has_gib_pages(cpuid))?;
+    boot::configure_acpi(low_memory, num_cpus)?;
+    Ok(bootable)
+}
 
 pub struct Guest {
     vm: Arc<Vm>,
-    num_cpus: NonZeroUsize,
-    port_io_hub: PortIoHub,
-    supported_cpuid: CpuId,
-    vcpu_mmap_size: NonZeroUsize,
-    bootable: Bootable,
-    _memory: Mmapped<u8>,
+    port_io_hub: Arc<PortIoHub>,
+    mmio_bus: MmioBus,
+    memory_hotplug: Option<Arc<Mutex<VirtioMmio<VirtioMem>>>>,
+    coalesced_ring: Option<Arc<CoalescedRing>>,
+    control: Arc<Control>,
+    vcpu_thread_configs: HashMap<usize, ThreadConfig>,
+    device_thread_config: ThreadConfig,
+    memory: GuestMemory,
+    dirty_log: DirtyLog,
+
+    /// States of the devices of a restored guest that have not been added yet
+    device_states: Mutex<Vec<DeviceState>>,
+
+    /// Whether the guest is a clone, whose devices must not keep the
+    /// identity of those of its parent
+    cloned: bool,
+
+    // Needed to reboot the guest
+    kernel_path: PathBuf,
+    kernel_params: KernelParams,
+    cpu_config: CpuConfig,
+    initial_irqchips: Vec<kvm_irqchip>,
+    initial_pit: kvm_pit_state2,
+    reboot: bool,
+
+    // Needed to clone the guest
+    halt_poll_ns: Option<u32>,
+    dirty_ring_entries: Option<NonZeroUsize>,
+    coalesced_io: bool,
+
+    core_dump_path: Option<PathBuf>,
 }
 
 impl Guest {
-    pub fn add_device<I, D>(&mut self, device: I) -> Result<()>
+    /// Adds a device to the guest.
+    ///
+    /// Devices can be added and removed while the guest is running. If the
+    /// guest was restored from a snapshot, the device takes the state saved
+    /// for the device of the same type at the same port.
+    pub fn add_device<I, D>(&self, device: I) -> Result<()>
     where
         I: Into<Arc<Mutex<D>>>,
         D: PortIoDevice + Send + 'static,
     {
-        self.port_io_hub.add_device(device.into())
+        let device = device.into();
+        self.restore_device_state(&mut *device.lock().unwrap())?;
+        self.port_io_hub.add_device(device)
+    }
+
+    fn restore_device_state(&self, device: &mut impl PortIoDevice) -> Result<()> {
+        let mut states = self.device_states.lock().unwrap();
+        let port = device.port_range().base();
+        let Some(i) = states
+            .iter()
+            .position(|state| state.port == port && state.name == device.name())
+        else {
+            return Ok(());
+        };
+        let state = states.swap_remove(i);
+        device.restore_state(&state.data)?;
+        if self.cloned {
+            device.regenerate_identity();
+        }
+        Ok(())
+    }
+
+    /// Removes the device that handles `port`.
+    pub fn remove_device(&self, port: u16) -> Result<()> {
+        self.port_io_hub.detach_coalesced_ports(port)?;
+        if let Some(ring) = &self.coalesced_ring {
+            // Let the device see the writes buffered before its removal.
+            ring.drain(&mut self.port_io_hub.bus())?;
+        }
+        self.port_io_hub.remove_device(port)?;
+        Ok(())
     }
 
     pub fn irq(&self) -> Irq {
@@ -127,160 +746,381 @@ impl Guest {
         }
     }
 
-    pub fn run(self) -> Result<()> {
-        let cpu = Cpu {
-            vm: self.vm,
-            port_io_hub: Arc::new(Mutex::new(self.port_io_hub)),
-            cpuid: self.supported_cpuid,
-            vcpu_mmap_size: self.vcpu_mmap_size,
-            bootable: self.bootable,
+    /// Returns a handle through which devices access guest memory.
+    pub fn memory(&self) -> GuestMemory {
+        self.memory.clone()
+    }
+
+    /// Returns a handle that tracks the pages of guest RAM written since it
+    /// was last asked.
+    pub fn dirty_log(&self) -> DirtyLog {
+        self.dirty_log.clone()
+    }
+
+    /// Returns a trigger that devices can use to stop the guest.
+    pub fn exit_trigger(&self) -> ExitTrigger {
+        ExitTrigger::new(self.control.clone())
+    }
+
+    /// Returns a handle that reports VM exit statistics while the guest runs.
+    pub fn exit_profiler(&self) -> ExitProfiler {
+        ExitProfiler {
+            control: self.control.clone(),
+            port_io_hub: self.port_io_hub.clone(),
+        }
+    }
+
+    /// Opens the statistics that KVM maintains for the VM and the vCPUs.
+    pub fn kvm_stats(&self) -> Result<KvmStats> {
+        if self
+            .vm
+            .check_extension(kvm_bindings::KVM_CAP_BINARY_STATS_FD as nix::libc::c_int)?
+            <= 0
+        {
+            return Err(Error::KvmExtensionNotSupported("KVM_CAP_BINARY_STATS_FD"));
+        }
+        let vcpus = self
+            .control
+            .contexts()
+            .iter()
+            .map(|context| StatsReader::new(context.vcpu.stats_fd()?))
+            .collect::<Result<_>>()?;
+        Ok(KvmStats {
+            vm: StatsReader::new(self.vm.stats_fd()?)?,
+            vcpus,
+        })
+    }
+
+    /// Returns a handle that writes core dumps of the guest on demand.
+    pub fn core_dumper(&self) -> CoreDumper {
+        CoreDumper {
+            control: self.control.clone(),
+            memory: self.memory.clone(),
+        }
+    }
+
+    /// Creates a copy of the guest that shares its memory copy-on-write, and
+    /// pauses the guest.
+    ///
+    /// The copy runs in a VM of its own once [`Guest::run`] is called, from
+    /// the point at which the guest was paused. Devices added to it take
+    /// the state of the devices of the guest as with a restored snapshot,
+    /// then get a new identity with
+    /// [`PortIoDevice::regenerate_identity`].
+    ///
+    /// Guest memory must be shared through a file, as with
+    /// [`MemoryBackend::Memfd`]. The pages that a copy has not written to
+    /// show the writes made by the guest, so the guest should stay paused
+    /// for as long as its copies run. Copies cannot be cloned in turn.
+    pub fn clone_paused(&self) -> Result<Self> {
+        if self.memory.fd().is_none() {
+            return Err(Error::CloneUnsupported(
+                "guest memory is not backed by a file".to_owned(),
+            ));
+        }
+        if self.memory_hotplug.is_some() {
+            return Err(Error::CloneUnsupported(
+                "hotpluggable memory is not shared".to_owned(),
+            ));
+        }
+        self.control.pause();
+        let state = self.snapshotter().capture()?;
+
+        let hypervisor = Hypervisor {
+            kvm: self.vm.kvm().clone(),
+            supported_cpuid: self.cpu_config.cpuid.clone(),
+            vcpu_mmap_size: self.cpu_config.vcpu_mmap_size,
         };
-        let cpus: Vec<_> = (0..self.num_cpus.get())
-            .map(|id| {
-                let cpu = cpu.clone();
+        GuestBuilder {
+            hypervisor: &hypervisor,
+            kernel_path: self.kernel_path.clone(),
+            num_cpus: NonZeroUsize::new(state.vcpus.len()).unwrap(),
+            memory_size: NonZeroUsize::new(self.memory.size() as usize).unwrap(),
+            // The memory is mapped from that of the guest instead.
+            memory_backend: MemoryBackend::default(),
+            memory_options: MemoryOptions::default(),
+            kernel_params: self.kernel_params.clone(),
+            pv_features: self.cpu_config.pv_features,
+            tsc_khz: self.cpu_config.tsc_khz,
+            vcpu_thread_configs: self.vcpu_thread_configs.clone(),
+            device_thread_config: self.device_thread_config.clone(),
+            halt_poll_ns: self.halt_poll_ns,
+            reboot: self.reboot,
+            core_dump_path: self.core_dump_path.clone(),
+            coalesced_io: self.coalesced_io,
+            dirty_ring_entries: self.dirty_ring_entries,
+            snapshot: None,
+            lazy_restore: None,
+            parent: Some(Parent {
+                memory: self.memory.clone(),
+                state,
+            }),
+            migration: None,
+            hotplug_memory_size: None,
+        }
+        .build()
+    }
+
+    /// Returns a handle that changes the amount of hotplugged memory, if the
+    /// guest has hotpluggable memory.
+    pub fn memory_hotplug(&self) -> Option<MemoryHotplug> {
+        let device = self.memory_hotplug.clone()?;
+        Some(MemoryHotplug { device })
+    }
+
+    /// Returns a handle that sends the guest to another process on demand.
+    pub fn migrator(&self) -> Migrator {
+        Migrator {
+            snapshotter: self.snapshotter(),
+            dirty_log: self.dirty_log.clone(),
+            cpuid: self.cpu_config.cpuid.clone(),
+        }
+    }
+
+    /// Returns a handle that writes snapshots of the guest on demand.
+    pub fn snapshotter(&self) -> Snapshotter {
+        Snapshotter {
+            vm: self.vm.clone(),
+            control: self.control.clone(),
+            memory: self.memory.clone(),
+            port_io_hub: self.port_io_hub.clone(),
+            coalesced_ring: self.coalesced_ring.clone(),
+            kernel_path: self.kernel_path.clone(),
+            kernel_params: self.kernel_params.clone(),
+            bootable: self.cpu_config.bootable.clone(),
+            hotplug_memory: self.memory_hotplug.is_some(),
+        }
+    }
+
+    /// Creates a GDB stub listening on `socket`.
+    ///
+    /// The guest does not start running until a debugger attaches and
+    /// resumes it.
+    pub fn gdb_stub(&self, socket: &GdbSocket) -> Result<GdbStub> {
+        if self
+            .vm
+            .check_extension(kvm_bindings::KVM_CAP_SET_GUEST_DEBUG as nix::libc::c_int)?
+            <= 0
+        {
+            return Err(Error::KvmExtensionNotSupported("KVM_CAP_SET_GUEST_DEBUG"));
+        }
+        let stub = GdbStub::new(socket, self.control.clone(), self.memory.clone())?;
+        self.control.pause();
+        Ok(stub)
+    }
+
+    /// Starts running the guest.
+    ///
+    /// The vCPUs run on their own threads. The returned handle controls them.
+    pub fn run(self) -> Result<GuestHandle> {
+        for state in self.device_states.lock().unwrap().drain(..) {
+            eprintln!(
+                "No {} device at port {:#x} to restore the state of",
+                state.name, state.port
+            );
+        }
+        cpu::install_kick_handler();
+        if let Some(ring) = &self.coalesced_ring {
+            coalesced::spawn_flusher(
+                Arc::downgrade(ring),
+                self.port_io_hub.bus(),
+                self.device_thread_config.clone(),
+            )?;
+        }
+        let threads = self.spawn_vcpus()?;
+        Ok(GuestHandle {
+            guest: self,
+            threads,
+        })
+    }
+
+    fn spawn_vcpus(&self) -> Result<Vec<JoinHandle<Result<()>>>> {
+        let threads = self
+            .control
+            .contexts()
+            .iter()
+            .enumerate()
+            .map(|(id, context)| {
+                let cpu = Cpu {
+                    context: context.clone(),
+                    control: self.control.clone(),
+                    port_io_bus: self.port_io_hub.bus(),
+                    mmio_bus: self.mmio_bus.clone(),
+                    coalesced_ring: self.coalesced_ring.clone(),
+                    memory: self.memory.clone(),
+                    _running: self.control.enter(),
+                };
+                let thread_config = self
+                    .vcpu_thread_configs
+                    .get(&id)
+                    .cloned()
+                    .unwrap_or_default();
                 std::thread::Builder::new()
                     .name(format!("cpu{id}"))
-                    .spawn(move || cpu.run(id as u32))
+                    .spawn(move || {
+                        thread_config.apply()?;
+                        cpu.run()
+                    })
             })
-            .collect();
-        for cpu in cpus {
-            cpu?.join().unwrap()?;
+            .collect::<std::io::Result<Vec<_>>>()?;
+        Ok(threads)
+    }
+
+    /// Brings the guest back to the state it had after `build`, reloading
+    /// the kernel from disk.
+    ///
+    /// The vCPU threads must have exited.
+    fn reboot(&mut self) -> Result<()> {
+        // The kernel is loaded into a private mapping first, since guest RAM
+        // may be accessed by others, such as a GDB stub, at the same time.
+        let low_memory_size = self.memory.regions()[0].size as usize;
+        let mut low_memory = Mmapped::<u8>::new(
+            None,
+            NonZeroUsize::new(low_memory_size).unwrap(),
+            MapFlags::MAP_PRIVATE | MapFlags::MAP_NORESERVE,
+            0,
+        )?;
+        self.cpu_config.bootable = load_kernel(
+            low_memory.as_mut_slice(),
+            self.memory.regions(),
+            &self.kernel_path,
+            self.kernel_params.clone(),
+            &self.cpu_config.cpuid,
+            self.control.contexts().len(),
+        )?;
+        self.memory.reset(low_memory.as_slice());
+
+        self.port_io_hub.reset()?;
+        self.mmio_bus.reset()?;
+        for irqchip in &self.initial_irqchips {
+            self.vm.set_irqchip(irqchip)?;
+        }
+        self.vm.set_pit2(&self.initial_pit)?;
+        for context in self.control.contexts() {
+            self.cpu_config.reset_vcpu(context)?;
         }
         Ok(())
     }
 }
 
-#[derive(Clone)]
-struct Cpu {
-    vm: Arc<Vm>,
-    port_io_hub: Arc<Mutex<PortIoHub>>,
-    cpuid: CpuId,
-    vcpu_mmap_size: NonZeroUsize,
-    bootable: Bootable,
+/// Handle to a running guest
+pub struct GuestHandle {
+    guest: Guest,
+    threads: Vec<JoinHandle<Result<()>>>,
 }
 
-impl Cpu {
-    fn run(mut self, id: u32) -> Result<()> {
-        let vcpu = Vcpu::new(self.vm, id)?;
+impl GuestHandle {
+    pub fn num_cpus(&self) -> usize {
+        self.guest.control.contexts().len()
+    }
 
-        for entry in self.cpuid.as_mut_slice() {
-            match entry.function {
-                0x1 => {
-                    // Set local APIC ID
-                    entry.ebx &= !(0xff << 24);
-                    entry.ebx |= id << 24;
+    /// Pauses all the vCPUs.
+    ///
+    /// This returns after all the vCPUs have stopped running guest code.
+    pub fn pause(&self) {
+        self.guest.control.pause();
+    }
 
-                    if entry.index == 0 {
-                        // Set X86_FEATURE_HYPERVISOR
-                        entry.ecx |= 1 << 31;
-                    }
-                }
-                0xb => {
-                    // Set x2APIC ID
-                    entry.edx = id;
-                }
-                0x8000_0001 if self.bootable.protocol.is_32bit() => {
-                    entry.ecx &= !(1 << 29); // Disable 64-bit mode
-                }
-                _ => {}
-            }
-        }
-        vcpu.set_cpuid(&self.cpuid)?;
+    pub fn resume(&self) {
+        self.guest.control.resume();
+    }
 
-        let mut sregs = vcpu.sregs()?;
-        self.bootable.configure_sregs(&mut sregs);
-        vcpu.set_sregs(&sregs)?;
+    /// Creates a copy of the guest that shares its memory copy-on-write, and
+    /// pauses the guest. See [`Guest::clone_paused`].
+    pub fn clone_paused(&self) -> Result<Guest> {
+        self.guest.clone_paused()
+    }
 
-        let mut regs = kvm_regs::default();
-        self.bootable.configure_regs(&mut regs);
-        vcpu.set_regs(&regs)?;
+    pub fn is_paused(&self) -> bool {
+        self.guest.control.is_paused()
+    }
 
-        let run = Mmapped::<kvm_run>::new_file(&vcpu, self.vcpu_mmap_size)?;
+    /// Returns the VM exit statistics collected so far.
+    pub fn exit_profile(&self) -> ExitProfile {
+        self.guest.exit_profiler().snapshot()
+    }
 
-        macro_rules! eprintln_kvm_consts {
-            ($x:expr => $s:expr; $($v:ident,)*) => {
-                match $x {
-                    $(kvm_bindings::$v => eprintln!(stringify!($v)),)*
-                    _ => eprintln!(concat!("Unknown ", $s, " {}"), $x),
-                }
-            }
-        }
+    /// Stops all the vCPUs as if the guest powered itself off.
+    pub fn poweroff(&self) {
+        self.guest.control.stop(Some(VmExit::Poweroff));
+    }
 
+    /// Waits until the guest stops and returns the reason.
+    ///
+    /// If rebooting is enabled, system resets are handled transparently and
+    /// do not make this return.
+    pub fn wait(mut self) -> Result<VmExit> {
         loop {
-            match unsafe { vcpu.run() } {
-                Ok(()) => {}
-                Err(nix::Error::EAGAIN | nix::Error::EINTR) => continue,
-                Err(e) => return Err(e.into()),
-            }
-            let exit_reason = run.as_ref().exit_reason;
-            match exit_reason {
-                KVM_EXIT_IO => {
-                    let io = unsafe { run.as_ref().__bindgen_anon_1.io };
-                    let ptr = run.as_ptr().cast::<u8>();
-                    let ptr = unsafe { ptr.offset(io.data_offset as isize) };
-                    let len = io.size as usize * io.count as usize;
-                    let data = unsafe { std::slice::from_raw_parts_mut(ptr, len) };
-                    let mut port_io_hub = self.port_io_hub.lock().unwrap();
-                    match io.direction.into() {
-                        KVM_EXIT_IO_IN => port_io_hub.read(io.port, data)?,
-                        KVM_EXIT_IO_OUT => port_io_hub.write(io.port, data)?,
-                        _ => eprintln!("Unknown IO direction {}", io.direction),
-                    }
+            let mut result = Ok(());
+            for thread in self.threads.drain(..) {
+                let thread_result = thread.join().unwrap();
+                if result.is_ok() {
+                    result = thread_result;
                 }
-                KVM_EXIT_HLT | KVM_EXIT_SHUTDOWN => break,
-                KVM_EXIT_INTERNAL_ERROR => {
-                    let internal = unsafe { run.as_ref().__bindgen_anon_1.internal };
-                    eprintln_kvm_consts! {
-                        internal.suberror => "internal error";
-                        KVM_INTERNAL_ERROR_EMULATION,
-                        KVM_INTERNAL_ERROR_SIMUL_EX,
-                        KVM_INTERNAL_ERROR_DELIVERY_EV,
-                        KVM_INTERNAL_ERROR_UNEXPECTED_EXIT_REASON,
-                    }
-                    break;
-                }
-                reason => {
-                    eprintln_kvm_consts! {
-                        reason => "exit reason";
-                        KVM_EXIT_UNKNOWN,
-                        KVM_EXIT_EXCEPTION,
-                        KVM_EXIT_HYPERCALL,
-                        KVM_EXIT_DEBUG,
-                        KVM_EXIT_MMIO,
-                        KVM_EXIT_IRQ_WINDOW_OPEN,
-                        KVM_EXIT_FAIL_ENTRY,
-                        KVM_EXIT_INTR,
-                        KVM_EXIT_SET_TPR,
-                        KVM_EXIT_TPR_ACCESS,
-                        KVM_EXIT_S390_SIEIC,
-                        KVM_EXIT_S390_RESET,
-                        KVM_EXIT_DCR,
-                        KVM_EXIT_NMI,
-                        KVM_EXIT_OSI,
-                        KVM_EXIT_PAPR_HCALL,
-                        KVM_EXIT_S390_UCONTROL,
-                        KVM_EXIT_WATCHDOG,
-                        KVM_EXIT_S390_TSCH,
-                        KVM_EXIT_EPR,
-                        KVM_EXIT_SYSTEM_EVENT,
-                        KVM_EXIT_S390_STSI,
-                        KVM_EXIT_IOAPIC_EOI,
-                        KVM_EXIT_HYPERV,
-                        KVM_EXIT_ARM_NISV,
-                        KVM_EXIT_X86_RDMSR,
-                        KVM_EXIT_X86_WRMSR,
-                        KVM_EXIT_DIRTY_RING_FULL,
-                        KVM_EXIT_AP_RESET_HOLD,
-                        KVM_EXIT_X86_BUS_LOCK,
-                        KVM_EXIT_XEN,
-                        KVM_EXIT_RISCV_SBI,
-                        KVM_EXIT_RISCV_CSR,
-                        KVM_EXIT_NOTIFY,
+            }
+            result?;
+
+            let exit = self.guest.control.take_exit().unwrap_or(VmExit::Poweroff);
+            if exit.cpu_dump().is_some() {
+                if let Some(path) = &self.guest.core_dump_path {
+                    match self.guest.core_dumper().dump(path) {
+                        Ok(()) => eprintln!("Core dump written to {}", path.display()),
+                        Err(e) => eprintln!("Failed to write core dump: {e}"),
                     }
                 }
             }
+            if !matches!(exit, VmExit::Reset) || !self.guest.reboot {
+                return Ok(exit);
+            }
+            self.guest.reboot()?;
+            if self.guest.control.restart() {
+                self.threads = self.guest.spawn_vcpus()?;
+            }
         }
-        Ok(())
+    }
+
+    /// Returns the general purpose registers of the vCPU `id`.
+    ///
+    /// The guest must be paused.
+    pub fn regs(&self, id: usize) -> Result<kvm_regs> {
+        Ok(self.paused_vcpu(id)?.regs()?)
+    }
+
+    pub fn set_regs(&self, id: usize, regs: &kvm_regs) -> Result<()> {
+        Ok(self.paused_vcpu(id)?.set_regs(regs)?)
+    }
+
+    /// Returns the special registers of the vCPU `id`.
+    ///
+    /// The guest must be paused.
+    pub fn sregs(&self, id: usize) -> Result<kvm_sregs> {
+        Ok(self.paused_vcpu(id)?.sregs()?)
+    }
+
+    pub fn set_sregs(&self, id: usize, sregs: &kvm_sregs) -> Result<()> {
+        Ok(self.paused_vcpu(id)?.set_sregs(sregs)?)
+    }
+
+    /// Translates a linear address to a physical one with the page tables
+    /// that the vCPU `id` is currently using.
+    ///
+    /// The guest must be paused.
+    pub fn translate(&self, id: usize, addr: u64) -> Result<Translation> {
+        let sregs = self.paused_vcpu(id)?.sregs()?;
+        Ok(paging::translate(&self.guest.memory, &sregs, addr)?)
+    }
+
+    fn paused_vcpu(&self, id: usize) -> Result<&Vcpu> {
+        let context = self
+            .guest
+            .control
+            .contexts()
+            .get(id)
+            .ok_or(Error::InvalidVcpuId(id))?;
+        if !self.guest.control.is_paused() {
+            return Err(Error::GuestNotPaused);
+        }
+        Ok(&context.vcpu)
     }
 }
 
@@ -294,3 +1134,210 @@ impl Irq {
         self.vm.set_irq_line(irq, level)
     }
 }
+
+/// Writes ELF core dumps of a guest
+///
+/// The dumps contain guest RAM and the registers of all the vCPUs, in the
+/// layout of QEMU's `dump-guest-memory` so that they can be analyzed with
+/// crash or gdb.
+#[derive(Clone)]
+pub struct CoreDumper {
+    control: Arc<Control>,
+    memory: GuestMemory,
+}
+
+impl CoreDumper {
+    /// Writes a core dump to `path`.
+    ///
+    /// A running guest is paused while the dump is being written.
+    pub fn dump(&self, path: impl AsRef<Path>) -> Result<()> {
+        let was_paused = self.control.is_paused();
+        self.control.pause();
+        let result = File::create(path).map_err(Into::into).and_then(|file| {
+            core_dump::write_core(&mut BufWriter::new(file), &self.control, &self.memory)
+        });
+        if !was_paused {
+            self.control.resume();
+        }
+        result
+    }
+}
+
+/// Writes snapshots of a guest, from which it can be restored with
+/// [`Hypervisor::restore`]
+#[derive(Clone)]
+pub struct Snapshotter {
+    vm: Arc<Vm>,
+    control: Arc<Control>,
+    memory: GuestMemory,
+    port_io_hub: Arc<PortIoHub>,
+    coalesced_ring: Option<Arc<CoalescedRing>>,
+    kernel_path: PathBuf,
+    kernel_params: KernelParams,
+    bootable: Bootable,
+    hotplug_memory: bool,
+}
+
+impl Snapshotter {
+    /// Writes a snapshot of the vCPUs, the in-kernel devices, the port I/O
+    /// devices and RAM to `path`.
+    ///
+    /// A running guest is paused while the snapshot is being taken.
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<()> {
+        let was_paused = self.control.is_paused();
+        self.control.pause();
+        let result = self.capture().and_then(|state| {
+            let memory = self.memory.host_slice(&self.control)?;
+            snapshot::write(path.as_ref(), &state, memory)
+        });
+        if !was_paused {
+            self.control.resume();
+        }
+        result
+    }
+
+    fn check_supported(&self) -> Result<()> {
+        if self.hotplug_memory {
+            return Err(Error::SnapshotUnsupported(
+                "hotpluggable memory is not saved".to_owned(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Saves the state of a guest whose vCPUs are not running.
+    fn capture(&self) -> Result<GuestState> {
+        self.check_supported()?;
+        let contexts = self.control.contexts();
+        for context in contexts {
+            context.complete_exit()?;
+        }
+        if let Some(ring) = &self.coalesced_ring {
+            ring.drain(&mut self.port_io_hub.bus())?;
+        }
+
+        let msr_indices = self.vm.kvm().msr_index_list()?;
+        let vcpus = contexts
+            .iter()
+            .map(|context| VcpuState::capture(&context.vcpu, &msr_indices))
+            .collect::<Result<_>>()?;
+        let devices = self
+            .port_io_hub
+            .devices()
+            .iter()
+            .filter_map(|device| {
+                let device = device.lock().unwrap();
+                let data = device.save_state();
+                (!data.is_empty()).then(|| DeviceState {
+                    name: device.name().to_owned(),
+                    port: device.port_range().base(),
+                    data,
+                })
+            })
+            .collect();
+        Ok(GuestState {
+            regions: self.memory.regions().to_vec(),
+            kernel_path: self.kernel_path.clone(),
+            kernel_params: self.kernel_params.clone(),
+            bootable: self.bootable.clone(),
+            vm: VmState::capture(&self.vm)?,
+            vcpus,
+            devices,
+        })
+    }
+}
+
+/// Sends a guest to another process with pre-copy live migration
+#[derive(Clone)]
+pub struct Migrator {
+    snapshotter: Snapshotter,
+    dirty_log: DirtyLog,
+    cpuid: CpuId,
+}
+
+impl Migrator {
+    /// Sends the guest to the destination at the other end of `stream`,
+    /// which receives it with [`Hypervisor::incoming_migration`].
+    ///
+    /// RAM is sent while the guest keeps running, then again the pages
+    /// written in the meantime until few are left. The guest is then paused
+    /// for the rest of RAM and its state to be sent. Once the destination has
+    /// acknowledged, the guest stays paused here and should be powered off.
+    /// If the migration fails, the guest resumes.
+    ///
+    /// The dirty log of the guest is used, and stopped, along the way.
+    pub fn send(&self, stream: impl MigrationStream) -> Result<()> {
+        self.snapshotter.check_supported()?;
+        let control = &self.snapshotter.control;
+        let was_paused = control.is_paused();
+        let result = migration::send(
+            stream,
+            &self.snapshotter.memory,
+            &self.dirty_log,
+            control.contexts().len(),
+            &self.cpuid,
+            || {
+                control.pause();
+                self.snapshotter.capture()
+            },
+        );
+        if result.is_err() && !was_paused {
+            control.resume();
+        }
+        result
+    }
+}
+
+/// Grows or shrinks the RAM of a guest while it runs, through its virtio-mem
+/// device
+#[derive(Clone)]
+pub struct MemoryHotplug {
+    device: Arc<Mutex<VirtioMmio<VirtioMem>>>,
+}
+
+impl MemoryHotplug {
+    /// Asks the guest to plug or unplug memory until `bytes` of the
+    /// hotpluggable region are plugged, and returns the size requested.
+    ///
+    /// The size is rounded down to a multiple of the block size and capped
+    /// to the size of the region. The guest plugs and unplugs blocks at its
+    /// own pace, and may keep blocks that it cannot free plugged.
+    pub fn request_size(&self, bytes: u64) -> Result<u64> {
+        self.device
+            .lock()
+            .unwrap()
+            .update_config(|device| device.set_requested_size(bytes))
+    }
+
+    pub fn requested_size(&self) -> u64 {
+        self.device.lock().unwrap().device().requested_size()
+    }
+
+    /// Returns the number of bytes that the guest has plugged.
+    pub fn plugged_size(&self) -> u64 {
+        self.device.lock().unwrap().device().plugged_size()
+    }
+
+    /// Returns the size of the hotpluggable region, the most that can be
+    /// plugged.
+    pub fn region_size(&self) -> u64 {
+        self.device.lock().unwrap().device().region_size()
+    }
+}
+
+/// Stops a guest on behalf of a device
+#[derive(Clone)]
+pub struct ExitTrigger {
+    control: Arc<Control>,
+}
+
+impl ExitTrigger {
+    pub(crate) fn new(control: Arc<Control>) -> Self {
+        Self { control }
+    }
+
+    /// Stops all the vCPUs, reporting `exit` as the reason.
+    pub fn trigger(&self, exit: VmExit) {
+        self.control.stop(Some(exit));
+    }
+}
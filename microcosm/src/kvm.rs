@@ -13,8 +13,12 @@ use std::{
 use sys::{
     kvm,
     kvm_bindings::{
-        self, kvm_irq_level, kvm_pit_config, kvm_regs, kvm_sregs, kvm_userspace_memory_region,
-        CpuId, KVM_MAX_CPUID_ENTRIES,
+        self, kvm_clear_dirty_log, kvm_clear_dirty_log__bindgen_ty_1, kvm_clock_data,
+        kvm_coalesced_mmio_zone, kvm_debugregs, kvm_dirty_log, kvm_dirty_log__bindgen_ty_1,
+        kvm_enable_cap, kvm_fpu, kvm_guest_debug, kvm_irq_level, kvm_irqchip, kvm_lapic_state,
+        kvm_mp_state, kvm_pit_config, kvm_pit_state2, kvm_regs, kvm_sregs,
+        kvm_userspace_memory_region, kvm_vcpu_events, kvm_xcrs, kvm_xsave, CpuId, MsrList, Msrs,
+        KVM_MAX_CPUID_ENTRIES, KVM_MAX_MSR_ENTRIES,
     },
 };
 
@@ -43,6 +47,15 @@ impl Kvm {
         Ok(cpuid)
     }
 
+    /// Returns the indices of the MSRs that KVM can save and restore.
+    pub fn msr_index_list(&self) -> nix::Result<Vec<u32>> {
+        let mut msr_list = MsrList::new(KVM_MAX_MSR_ENTRIES).unwrap();
+        unsafe {
+            kvm::get_msr_index_list(self.file.as_raw_fd(), msr_list.as_mut_fam_struct_ptr())?
+        };
+        Ok(msr_list.as_slice().to_vec())
+    }
+
     pub fn vcpu_mmap_size(&self) -> Result<NonZeroUsize> {
         let size = unsafe { kvm::get_vcpu_mmap_size(self.file.as_raw_fd())? };
         let size: usize = size
@@ -54,14 +67,18 @@ impl Kvm {
 
 pub struct Vm {
     file: File,
-    _kvm: Arc<Kvm>,
+    kvm: Arc<Kvm>,
 }
 
 impl Vm {
     pub fn new(kvm: Arc<Kvm>) -> nix::Result<Self> {
         let fd = unsafe { kvm::create_vm(kvm.file.as_raw_fd(), 0)? };
         let file = unsafe { File::from_raw_fd(fd) };
-        Ok(Self { file, _kvm: kvm })
+        Ok(Self { file, kvm })
+    }
+
+    pub fn kvm(&self) -> &Arc<Kvm> {
+        &self.kvm
     }
 
     pub fn set_user_memory_region(
@@ -72,36 +89,167 @@ impl Vm {
         Ok(())
     }
 
+    pub fn check_extension(&self, cap: c_int) -> nix::Result<c_int> {
+        unsafe { kvm::check_extension(self.file.as_raw_fd(), cap) }
+    }
+
+    /// Copies the log of the pages of memslot `slot` written by the vCPUs into
+    /// `bitmap`, one bit per page.
+    ///
+    /// Unless manual protection is enabled, this also clears the log.
+    pub fn get_dirty_log(&self, slot: u32, bitmap: &mut [u64]) -> nix::Result<()> {
+        let dirty_log = kvm_dirty_log {
+            slot,
+            padding1: 0,
+            __bindgen_anon_1: kvm_dirty_log__bindgen_ty_1 {
+                dirty_bitmap: bitmap.as_mut_ptr().cast(),
+            },
+        };
+        unsafe { kvm::get_dirty_log(self.file.as_raw_fd(), &raw const dirty_log)? };
+        Ok(())
+    }
+
+    /// Clears the bits set in `bitmap` from the log of memslot `slot` and
+    /// write-protects the pages again, starting at `first_page`.
+    pub fn clear_dirty_log(
+        &self,
+        slot: u32,
+        first_page: u64,
+        num_pages: u32,
+        bitmap: &mut [u64],
+    ) -> nix::Result<()> {
+        let mut clear_dirty_log = kvm_clear_dirty_log {
+            slot,
+            num_pages,
+            first_page,
+            __bindgen_anon_1: kvm_clear_dirty_log__bindgen_ty_1 {
+                dirty_bitmap: bitmap.as_mut_ptr().cast(),
+            },
+        };
+        unsafe { kvm::clear_dirty_log(self.file.as_raw_fd(), &raw mut clear_dirty_log)? };
+        Ok(())
+    }
+
+    /// Lets KVM reuse the entries of the dirty rings that have been harvested,
+    /// returning their number.
+    pub fn reset_dirty_rings(&self) -> nix::Result<u32> {
+        let n = unsafe { kvm::reset_dirty_rings(self.file.as_raw_fd())? };
+        Ok(n as u32)
+    }
+
+    /// Returns a file from which the binary statistics of the VM are read.
+    pub fn stats_fd(&self) -> nix::Result<File> {
+        let fd = unsafe { kvm::get_stats_fd(self.file.as_raw_fd())? };
+        Ok(unsafe { File::from_raw_fd(fd) })
+    }
+
+    /// Makes KVM buffer writes to a port or MMIO range in the coalesced ring
+    /// instead of exiting to userspace.
+    pub fn register_coalesced_mmio(&self, zone: &kvm_coalesced_mmio_zone) -> nix::Result<()> {
+        unsafe { kvm::register_coalesced_mmio(self.file.as_raw_fd(), zone)? };
+        Ok(())
+    }
+
+    pub fn unregister_coalesced_mmio(&self, zone: &kvm_coalesced_mmio_zone) -> nix::Result<()> {
+        unsafe { kvm::unregister_coalesced_mmio(self.file.as_raw_fd(), zone)? };
+        Ok(())
+    }
+
     pub fn create_irqchip(&self) -> nix::Result<()> {
         unsafe { kvm::create_irqchip(self.file.as_raw_fd())? };
         Ok(())
     }
 
+    pub fn irqchip(&self, chip_id: u32) -> nix::Result<kvm_irqchip> {
+        let mut irqchip = kvm_irqchip {
+            chip_id,
+            ..Default::default()
+        };
+        unsafe { kvm::get_irqchip(self.file.as_raw_fd(), &raw mut irqchip)? };
+        Ok(irqchip)
+    }
+
+    pub fn set_irqchip(&self, irqchip: &kvm_irqchip) -> nix::Result<()> {
+        let mut irqchip = *irqchip;
+        unsafe { kvm::set_irqchip(self.file.as_raw_fd(), &raw mut irqchip)? };
+        Ok(())
+    }
+
     pub fn create_pit2(&self, pit_config: &kvm_pit_config) -> nix::Result<()> {
         unsafe { kvm::create_pit2(self.file.as_raw_fd(), pit_config)? };
         Ok(())
     }
 
+    pub fn pit2(&self) -> nix::Result<kvm_pit_state2> {
+        let mut pit_state = kvm_pit_state2::default();
+        unsafe { kvm::get_pit2(self.file.as_raw_fd(), &raw mut pit_state)? };
+        Ok(pit_state)
+    }
+
+    pub fn set_pit2(&self, pit_state: &kvm_pit_state2) -> nix::Result<()> {
+        unsafe { kvm::set_pit2(self.file.as_raw_fd(), pit_state)? };
+        Ok(())
+    }
+
+    /// Returns the value of kvmclock, the clock that the guest reads through
+    /// the paravirtual clock source.
+    pub fn clock(&self) -> nix::Result<kvm_clock_data> {
+        let mut clock = kvm_clock_data::default();
+        unsafe { kvm::get_clock(self.file.as_raw_fd(), &raw mut clock)? };
+        Ok(clock)
+    }
+
+    pub fn set_clock(&self, clock: &kvm_clock_data) -> nix::Result<()> {
+        unsafe { kvm::set_clock(self.file.as_raw_fd(), clock)? };
+        Ok(())
+    }
+
+    pub fn enable_cap(&self, cap: u32, args: [u64; 4]) -> nix::Result<()> {
+        let enable_cap = kvm_enable_cap {
+            cap,
+            args,
+            ..Default::default()
+        };
+        unsafe { kvm::enable_cap(self.file.as_raw_fd(), &raw const enable_cap)? };
+        Ok(())
+    }
+
     pub fn set_irq_line(&self, irq: u8, level: bool) -> nix::Result<()> {
         let irq_level = kvm_irq_level {
             __bindgen_anon_1: kvm_bindings::kvm_irq_level__bindgen_ty_1 { irq: irq.into() },
             level: level.into(),
         };
-        unsafe { kvm::irq_line(self.file.as_raw_fd(), &irq_level)? };
+        unsafe { kvm::irq_line(self.file.as_raw_fd(), &raw const irq_level)? };
         Ok(())
     }
 }
 
 pub struct Vcpu {
     file: File,
-    _vm: Arc<Vm>,
+    vm: Arc<Vm>,
 }
 
 impl Vcpu {
     pub fn new(vm: Arc<Vm>, id: u32) -> nix::Result<Self> {
         let fd = unsafe { kvm::create_vpu(vm.file.as_raw_fd(), id as c_int)? };
         let file = unsafe { File::from_raw_fd(fd) };
-        Ok(Self { file, _vm: vm })
+        Ok(Self { file, vm })
+    }
+
+    pub fn vm(&self) -> &Vm {
+        &self.vm
+    }
+
+    /// Returns a file from which the binary statistics of the vCPU are read.
+    pub fn stats_fd(&self) -> nix::Result<File> {
+        let fd = unsafe { kvm::get_stats_fd(self.file.as_raw_fd())? };
+        Ok(unsafe { File::from_raw_fd(fd) })
+    }
+
+    pub fn cpuid(&self) -> nix::Result<CpuId> {
+        let mut cpuid = CpuId::new(KVM_MAX_CPUID_ENTRIES).unwrap();
+        unsafe { kvm::get_cpuid2(self.file.as_raw_fd(), cpuid.as_mut_fam_struct_ptr())? };
+        Ok(cpuid)
     }
 
     pub fn set_cpuid(&self, cpuid: &CpuId) -> nix::Result<()> {
@@ -111,7 +259,7 @@ impl Vcpu {
 
     pub fn sregs(&self) -> nix::Result<kvm_sregs> {
         let mut sregs = kvm_sregs::default();
-        unsafe { kvm::get_sregs(self.file.as_raw_fd(), &mut sregs)? };
+        unsafe { kvm::get_sregs(self.file.as_raw_fd(), &raw mut sregs)? };
         Ok(sregs)
     }
 
@@ -120,11 +268,120 @@ impl Vcpu {
         Ok(())
     }
 
+    pub fn regs(&self) -> nix::Result<kvm_regs> {
+        let mut regs = kvm_regs::default();
+        unsafe { kvm::get_regs(self.file.as_raw_fd(), &raw mut regs)? };
+        Ok(regs)
+    }
+
     pub fn set_regs(&self, regs: &kvm_regs) -> nix::Result<()> {
         unsafe { kvm::set_regs(self.file.as_raw_fd(), regs)? };
         Ok(())
     }
 
+    /// Reads the MSRs whose indices are given in `msrs`, returning the number of
+    /// MSRs that were read successfully.
+    pub fn get_msrs(&self, msrs: &mut Msrs) -> nix::Result<usize> {
+        let n = unsafe { kvm::get_msrs(self.file.as_raw_fd(), msrs.as_mut_fam_struct_ptr())? };
+        Ok(n as usize)
+    }
+
+    /// Sets the given MSRs, returning the number of MSRs that were set
+    /// successfully.
+    pub fn set_msrs(&self, msrs: &Msrs) -> nix::Result<usize> {
+        let n = unsafe { kvm::set_msrs(self.file.as_raw_fd(), msrs.as_fam_struct_ptr())? };
+        Ok(n as usize)
+    }
+
+    pub fn set_fpu(&self, fpu: &kvm_fpu) -> nix::Result<()> {
+        unsafe { kvm::set_fpu(self.file.as_raw_fd(), fpu)? };
+        Ok(())
+    }
+
+    /// Returns the FPU, SSE and AVX state in the layout of the XSAVE
+    /// instruction.
+    pub fn xsave(&self) -> nix::Result<kvm_xsave> {
+        let mut xsave = kvm_xsave::default();
+        unsafe { kvm::get_xsave(self.file.as_raw_fd(), &raw mut xsave)? };
+        Ok(xsave)
+    }
+
+    pub fn set_xsave(&self, xsave: &kvm_xsave) -> nix::Result<()> {
+        unsafe { kvm::set_xsave(self.file.as_raw_fd(), xsave)? };
+        Ok(())
+    }
+
+    pub fn xcrs(&self) -> nix::Result<kvm_xcrs> {
+        let mut xcrs = kvm_xcrs::default();
+        unsafe { kvm::get_xcrs(self.file.as_raw_fd(), &raw mut xcrs)? };
+        Ok(xcrs)
+    }
+
+    pub fn set_xcrs(&self, xcrs: &kvm_xcrs) -> nix::Result<()> {
+        unsafe { kvm::set_xcrs(self.file.as_raw_fd(), xcrs)? };
+        Ok(())
+    }
+
+    pub fn debugregs(&self) -> nix::Result<kvm_debugregs> {
+        let mut debugregs = kvm_debugregs::default();
+        unsafe { kvm::get_debugregs(self.file.as_raw_fd(), &raw mut debugregs)? };
+        Ok(debugregs)
+    }
+
+    pub fn set_debugregs(&self, debugregs: &kvm_debugregs) -> nix::Result<()> {
+        unsafe { kvm::set_debugregs(self.file.as_raw_fd(), debugregs)? };
+        Ok(())
+    }
+
+    pub fn lapic(&self) -> nix::Result<kvm_lapic_state> {
+        let mut lapic = kvm_lapic_state::default();
+        unsafe { kvm::get_lapic(self.file.as_raw_fd(), &raw mut lapic)? };
+        Ok(lapic)
+    }
+
+    pub fn set_lapic(&self, lapic: &kvm_lapic_state) -> nix::Result<()> {
+        unsafe { kvm::set_lapic(self.file.as_raw_fd(), lapic)? };
+        Ok(())
+    }
+
+    pub fn mp_state(&self) -> nix::Result<u32> {
+        let mut mp_state = kvm_mp_state::default();
+        unsafe { kvm::get_mp_state(self.file.as_raw_fd(), &raw mut mp_state)? };
+        Ok(mp_state.mp_state)
+    }
+
+    pub fn set_mp_state(&self, mp_state: u32) -> nix::Result<()> {
+        let mp_state = kvm_mp_state { mp_state };
+        unsafe { kvm::set_mp_state(self.file.as_raw_fd(), &raw const mp_state)? };
+        Ok(())
+    }
+
+    pub fn set_guest_debug(&self, debug: &kvm_guest_debug) -> nix::Result<()> {
+        unsafe { kvm::set_guest_debug(self.file.as_raw_fd(), debug)? };
+        Ok(())
+    }
+
+    pub fn vcpu_events(&self) -> nix::Result<kvm_vcpu_events> {
+        let mut events = kvm_vcpu_events::default();
+        unsafe { kvm::get_vcpu_events(self.file.as_raw_fd(), &raw mut events)? };
+        Ok(events)
+    }
+
+    pub fn set_vcpu_events(&self, events: &kvm_vcpu_events) -> nix::Result<()> {
+        unsafe { kvm::set_vcpu_events(self.file.as_raw_fd(), events)? };
+        Ok(())
+    }
+
+    pub fn set_tsc_khz(&self, tsc_khz: u32) -> nix::Result<()> {
+        unsafe { kvm::set_tsc_khz(self.file.as_raw_fd(), tsc_khz as c_int)? };
+        Ok(())
+    }
+
+    pub fn tsc_khz(&self) -> nix::Result<u32> {
+        let tsc_khz = unsafe { kvm::get_tsc_khz(self.file.as_raw_fd())? };
+        Ok(tsc_khz as u32)
+    }
+
     pub unsafe fn run(&self) -> nix::Result<()> {
         kvm::run(self.file.as_raw_fd())?;
         Ok(())
@@ -1,16 +1,56 @@
 pub mod device;
 
 mod boot;
+mod coalesced;
+mod core_dump;
+mod cpu;
+mod cpuid;
+mod dirty;
+mod dump;
+mod gdb;
 mod guest;
 mod kvm;
 mod load;
 mod memory;
-
-pub use guest::{Guest, GuestBuilder};
+mod migration;
+mod paging;
+mod profile;
+mod snapshot;
+mod stats;
+mod thread;
+
+pub use cpu::VmExit;
+pub use cpuid::PvFeature;
+pub use dirty::{DirtyBitmap, DirtyLog};
+pub use dump::CpuDump;
+pub use gdb::{GdbSocket, GdbStub};
+pub use guest::{
+    CoreDumper, ExitTrigger, Guest, GuestBuilder, GuestHandle, MemoryHotplug, Migrator, Snapshotter,
+};
+pub use memory::{
+    CopyFromGuest, CopyToGuest, GuestMemory, HugePageSize, MemoryBackend, MemoryRegion, Prefault,
+};
+pub use migration::MigrationStream;
+pub use paging::{PageFault, PagingMode, Translation};
+pub use profile::{ExitProfile, ExitProfiler, ExitStats, Histogram, IoDirection, VcpuExitStats};
+pub use snapshot::LazyRestore;
+pub use stats::{
+    KvmStats, KvmStatsSnapshot, Stat, StatDesc, StatKind, StatUnit, StatValue, StatsReader,
+    StatsSnapshot,
+};
+pub use thread::{SchedPolicy, ThreadConfig};
+
+pub use sys::kvm_bindings;
 
 use kvm::Kvm;
-use std::{ffi::CString, num::NonZeroUsize, path::PathBuf, sync::Arc};
-use sys::kvm_bindings::{self, kvm_run, CpuId, KVM_API_VERSION};
+use snapshot::SnapshotFile;
+use std::{
+    ffi::CString,
+    num::NonZeroUsize,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+use sys::kvm_bindings::{kvm_run, CpuId, KVM_API_VERSION};
 
 pub type Result<T> = std::result::Result<T, Error>;
 
@@ -34,12 +74,77 @@ pub enum Error {
     #[error("initrd too large: {size} > {max_size}")]
     InitrdTooLarge { size: usize, max_size: usize },
 
+    #[error(
+        "Memory up to {ram_end:#x} is too large to map without 1 GiB pages (max {max_end:#x})"
+    )]
+    MemoryTooLargeToMap { ram_end: u64, max_end: u64 },
+
     #[error("Attempted to add device with overlapping port or address range")]
     DeviceRangeOverlap,
 
+    #[error("No device at port {0:#x}")]
+    NoDeviceAtPort(u16),
+
+    #[error("Unknown paravirtual feature: {0}")]
+    UnknownPvFeature(String),
+
+    #[error("Invalid vCPU ID {0}")]
+    InvalidVcpuId(usize),
+
+    #[error("Guest is not paused")]
+    GuestNotPaused,
+
     #[error("Out of guest memory")]
     OutOfGuestMemory,
 
+    #[error("Dirty page logging is not started")]
+    DirtyLogNotStarted,
+
+    #[error("Hugepages unavailable: {0}")]
+    HugePagesUnavailable(String),
+
+    #[error("Memory size {size:#x} is not a multiple of the page size {page_size:#x}")]
+    UnalignedMemorySize { size: usize, page_size: usize },
+
+    #[error(transparent)]
+    PageFault(#[from] PageFault),
+
+    #[error("Invalid snapshot: {0}")]
+    InvalidSnapshot(String),
+
+    #[error("Unsupported snapshot version {0}")]
+    UnsupportedSnapshotVersion(u32),
+
+    #[error("Cannot restore memory lazily: {0}")]
+    LazyRestoreUnsupported(String),
+
+    #[error("Cannot snapshot the guest: {0}")]
+    SnapshotUnsupported(String),
+
+    #[error("Cannot clone the guest: {0}")]
+    CloneUnsupported(String),
+
+    #[error("Invalid migration stream: {0}")]
+    InvalidMigrationStream(String),
+
+    #[error("Unsupported migration protocol version {0}")]
+    UnsupportedMigrationVersion(u32),
+
+    #[error("Migration rejected by the destination: {0}")]
+    MigrationRejected(String),
+
+    #[error("Incompatible CPUID: {0}")]
+    IncompatibleCpuid(String),
+
+    #[error("Too many CPUID entries")]
+    TooManyCpuidEntries,
+
+    #[error("Invalid virtqueue: {0}")]
+    InvalidVirtqueue(String),
+
+    #[error("Invalid KVM binary statistics")]
+    InvalidKvmStats,
+
     #[error(transparent)]
     Io(#[from] std::io::Error),
 
@@ -102,7 +207,32 @@ impl Hypervisor {
     }
 
     #[must_use]
-    pub fn guest(&self, kernel_path: impl Into<PathBuf>) -> GuestBuilder {
+    pub fn guest(&self, kernel_path: impl Into<PathBuf>) -> GuestBuilder<'_> {
         GuestBuilder::new(self, kernel_path.into())
     }
+
+    /// Prepares to restore a guest from a snapshot written by
+    /// [`Snapshotter::save`], instead of booting a kernel.
+    ///
+    /// The vCPUs, the memory size and the kernel used on reboot are those of
+    /// the saved guest. The snapshot must be restored on a host with the same
+    /// CPU features.
+    pub fn restore(&self, path: impl AsRef<Path>) -> Result<GuestBuilder<'_>> {
+        GuestBuilder::restore(self, SnapshotFile::open(path.as_ref())?)
+    }
+
+    /// Prepares to receive a guest sent with [`Migrator::send`] from the
+    /// other end of `stream`, instead of booting a kernel.
+    ///
+    /// The guest is refused unless the host supports its CPUID. The vCPUs
+    /// and the memory size are those of the guest on the source, and
+    /// [`GuestBuilder::build`] returns once all of it has been received.
+    pub fn incoming_migration(
+        &self,
+        stream: impl MigrationStream + 'static,
+    ) -> Result<GuestBuilder<'_>> {
+        let migration =
+            migration::IncomingMigration::accept(Box::new(stream), &self.supported_cpuid)?;
+        GuestBuilder::incoming(self, migration)
+    }
 }
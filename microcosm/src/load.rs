@@ -1,6 +1,6 @@
 use crate::{
     boot::{Bootable, EBDA_START, HIGH_MEMORY_START, RSDP_ADDR},
-    memory::{CopyToGuest, RangeAllocator},
+    memory::{CopyToGuest, MemoryRegion, RangeAllocator},
     Error, KernelParams, Result,
 };
 use std::{
@@ -105,15 +105,22 @@ impl BootProtocol {
 }
 
 impl Bootable {
-    pub fn load(memory: &mut [u8], kernel: &[u8], params: KernelParams) -> Result<Self> {
+    /// Loads `kernel` into low memory and describes the RAM regions `ram` to
+    /// it.
+    pub fn load(
+        memory: &mut [u8],
+        ram: &[MemoryRegion],
+        kernel: &[u8],
+        params: KernelParams,
+    ) -> Result<Self> {
         if let Ok(exe) = load_elf64(memory, kernel) {
-            if let Ok(bootable) = load_pvh(memory, kernel, exe.max_addr, params.clone()) {
+            if let Ok(bootable) = load_pvh(memory, ram, kernel, exe.max_addr, params.clone()) {
                 return Ok(bootable);
             }
 
             // Assume it's vmlinux.
             let params_addr =
-                write_linux_boot_params(memory, default_setup_header(), exe.max_addr, params)?;
+                write_linux_boot_params(memory, ram, default_setup_header(), exe.max_addr, params)?;
             return Ok(Self {
                 protocol: BootProtocol::Linux64,
                 entry_addr: exe.entry_addr,
@@ -124,8 +131,8 @@ impl Bootable {
         if let Ok(exe) = load_elf32(memory, kernel) {
             let count = kernel.len().min(MULTIBOOT_SEARCH as usize) / size_of::<u32>();
             let (slice, _) = u32::slice_from_prefix(kernel, count).unwrap();
-            if slice.iter().any(|&magic| magic == MULTIBOOT_HEADER_MAGIC) {
-                let params_addr = write_multiboot_info(memory, exe.max_addr, params)?;
+            if slice.contains(&MULTIBOOT_HEADER_MAGIC) {
+                let params_addr = write_multiboot_info(memory, ram, exe.max_addr, params)?;
                 return Ok(Self {
                     protocol: BootProtocol::Multiboot,
                     entry_addr: exe.entry_addr,
@@ -135,7 +142,7 @@ impl Bootable {
 
             // Assume it's vmlinux.
             let params_addr =
-                write_linux_boot_params(memory, default_setup_header(), exe.max_addr, params)?;
+                write_linux_boot_params(memory, ram, default_setup_header(), exe.max_addr, params)?;
             return Ok(Self {
                 protocol: BootProtocol::Linux32,
                 entry_addr: exe.entry_addr,
@@ -143,7 +150,7 @@ impl Bootable {
             });
         }
 
-        if let Ok(bootable) = load_bz_image(memory, kernel, params) {
+        if let Ok(bootable) = load_bz_image(memory, ram, kernel, params) {
             return Ok(bootable);
         }
 
@@ -226,7 +233,12 @@ fn load_elf64(memory: &mut [u8], image: &[u8]) -> Result<LoadedExecutable> {
 
 const SETUP_HEADER_MAGIC: u32 = 0x5372_6448; // "HdrS"
 
-fn load_bz_image(memory: &mut [u8], kernel: &[u8], params: KernelParams) -> Result<Bootable> {
+fn load_bz_image(
+    memory: &mut [u8],
+    ram: &[MemoryRegion],
+    kernel: &[u8],
+    params: KernelParams,
+) -> Result<Bootable> {
     let boot_params =
         boot_params::read_from_prefix(kernel).ok_or(Error::InvalidKernelImageFormat)?;
     let setup_header {
@@ -249,7 +261,7 @@ fn load_bz_image(memory: &mut [u8], kernel: &[u8], params: KernelParams) -> Resu
     image.copy_to_guest(memory, HIGH_MEMORY_START)?;
 
     let max_addr = HIGH_MEMORY_START + image.len() as u64;
-    let params_addr = write_linux_boot_params(memory, boot_params.hdr, max_addr, params)?;
+    let params_addr = write_linux_boot_params(memory, ram, boot_params.hdr, max_addr, params)?;
 
     // Both 32-bit and 64-bit bzImage can be booted with the same protocol.
     Ok(Bootable {
@@ -261,6 +273,7 @@ fn load_bz_image(memory: &mut [u8], kernel: &[u8], params: KernelParams) -> Resu
 
 fn load_pvh(
     memory: &mut [u8],
+    ram: &[MemoryRegion],
     image: &[u8],
     exe_end: u64,
     params: KernelParams,
@@ -303,22 +316,19 @@ fn load_pvh(
     } else {
         0
     };
-    let memmap_entries = [
-        hvm_memmap_table_entry {
-            addr: 0,
-            size: EBDA_START,
-            type_: XEN_HVM_MEMMAP_TYPE_RAM,
-            reserved: 0,
-        },
-        hvm_memmap_table_entry {
-            addr: HIGH_MEMORY_START,
-            size: memory.len() as u64 - HIGH_MEMORY_START,
+    let memmap_entries: Vec<_> = usable_ram(ram)
+        .into_iter()
+        .map(|(addr, size)| hvm_memmap_table_entry {
+            addr,
+            size,
             type_: XEN_HVM_MEMMAP_TYPE_RAM,
             reserved: 0,
-        },
-    ];
+        })
+        .collect();
     let memmap_paddr = allocator.alloc_array::<hvm_memmap_table_entry>(memmap_entries.len());
-    memmap_entries.copy_to_guest(memory, memmap_paddr)?;
+    memmap_entries
+        .as_slice()
+        .copy_to_guest(memory, memmap_paddr)?;
 
     let start_info = hvm_start_info {
         magic: XEN_HVM_START_MAGIC_VALUE,
@@ -340,6 +350,7 @@ fn load_pvh(
 
 fn write_linux_boot_params(
     memory: &mut [u8],
+    ram: &[MemoryRegion],
     mut hdr: setup_header,
     exe_end: u64,
     params: KernelParams,
@@ -388,12 +399,9 @@ fn write_linux_boot_params(
             boot_e820_entry { addr, size, type_ };
         boot_params.e820_entries += 1;
     };
-    add_e820_entry(0, EBDA_START, E820_RAM);
-    add_e820_entry(
-        HIGH_MEMORY_START,
-        memory.len() as u64 - HIGH_MEMORY_START,
-        E820_RAM,
-    );
+    for (addr, size) in usable_ram(ram) {
+        add_e820_entry(addr, size, E820_RAM);
+    }
 
     let zero_page_addr = allocator.alloc::<boot_params>();
     boot_params.copy_to_guest(memory, zero_page_addr)?;
@@ -413,19 +421,34 @@ fn default_setup_header() -> setup_header {
     }
 }
 
-fn write_multiboot_info(memory: &mut [u8], exe_end: u64, params: KernelParams) -> Result<u64> {
+fn write_multiboot_info(
+    memory: &mut [u8],
+    ram: &[MemoryRegion],
+    exe_end: u64,
+    params: KernelParams,
+) -> Result<u64> {
     let mut allocator = RangeAllocator::new(exe_end);
 
     let info_addr =
         allocator.raw_alloc(size_of::<multiboot_info_t>(), MULTIBOOT_INFO_ALIGN as usize);
     let mods_addr = allocator.alloc_array::<multiboot_module_t>(params.module_paths.len());
-    let mmap_addr = allocator.alloc::<multiboot_memory_map_t>();
+    let mmap_entries: Vec<_> = usable_ram(ram)
+        .into_iter()
+        .map(|(addr, len)| multiboot_memory_map_t {
+            // The size does not include the field itself.
+            size: (size_of::<multiboot_memory_map_t>() - size_of::<u32>()) as u32,
+            addr,
+            len,
+            type_: MULTIBOOT_MEMORY_AVAILABLE,
+        })
+        .collect();
+    let mmap_addr = allocator.alloc_array::<multiboot_memory_map_t>(mmap_entries.len());
     let mut info = multiboot_info_t {
         flags: MULTIBOOT_INFO_MODS | MULTIBOOT_INFO_MEM_MAP,
         mods_count: params.module_paths.len() as u32,
         mods_addr: mods_addr as u32,
         mmap_addr: mmap_addr as u32,
-        mmap_length: size_of::<multiboot_memory_map_t>() as u32,
+        mmap_length: (size_of::<multiboot_memory_map_t>() * mmap_entries.len()) as u32,
         ..Default::default()
     };
 
@@ -464,13 +487,20 @@ fn write_multiboot_info(memory: &mut [u8], exe_end: u64, params: KernelParams) -
         mod_entry_addr += size_of::<multiboot_module_t>() as u64;
     }
 
-    multiboot_memory_map_t {
-        size: size_of::<multiboot_memory_map_t>() as u32,
-        addr: HIGH_MEMORY_START,
-        len: memory.len() as u64 - HIGH_MEMORY_START,
-        type_: MULTIBOOT_MEMORY_AVAILABLE,
-    }
-    .copy_to_guest(memory, mmap_addr)?;
+    mmap_entries.as_slice().copy_to_guest(memory, mmap_addr)?;
 
     Ok(info_addr)
 }
+
+/// Returns the address and size of each range of RAM that the kernel may
+/// use, leaving out the EBDA and the legacy video and BIOS areas.
+fn usable_ram(ram: &[MemoryRegion]) -> Vec<(u64, u64)> {
+    let mut ranges = vec![(0, EBDA_START)];
+    for region in ram {
+        let start = region.guest_addr.max(HIGH_MEMORY_START);
+        if start < region.end() {
+            ranges.push((start, region.end() - start));
+        }
+    }
+    ranges
+}
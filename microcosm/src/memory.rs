@@ -1,50 +1,80 @@
-use crate::{Error, Result};
-use nix::sys::mman::{mmap, mmap_anonymous, munmap, MapFlags, ProtFlags};
+mod backend;
+
+pub use backend::{HugePageSize, MemoryBackend, MemoryOptions, Prefault};
+
+use crate::{cpu::Control, dirty::DirtyPages, Error, Result};
+use nix::sys::{
+    mman::{madvise, mlock, mmap, mmap_anonymous, munmap, MapFlags, MmapAdvise, ProtFlags},
+    statfs::fstatfs,
+};
 use std::{
+    ffi::c_void,
     mem::{align_of, size_of},
     num::NonZeroUsize,
-    os::fd::AsFd,
+    ops::Range,
+    os::fd::{AsFd, BorrowedFd, OwnedFd},
     ptr::NonNull,
+    sync::{Arc, Weak},
 };
-use zerocopy::AsBytes;
+use sys::kvm_bindings::kvm_userspace_memory_region;
+use zerocopy::{AsBytes, FromBytes};
+
+pub const PAGE_SIZE: usize = 4096;
 
 pub struct Mmapped<T> {
     ptr: NonNull<T>,
     size: NonZeroUsize,
+
+    /// Size of the inaccessible areas on both sides of the mapping
+    guard_size: usize,
 }
 
 impl<T: Copy> Mmapped<T> {
-    pub fn new_anonymous(size: NonZeroUsize) -> nix::Result<Self> {
-        assert!(size.get() >= size_of::<T>());
-        let ptr = unsafe {
-            mmap_anonymous(
-                None,
-                size,
-                ProtFlags::PROT_READ | ProtFlags::PROT_WRITE,
-                MapFlags::MAP_PRIVATE,
-            )?
-        };
-        Ok(Self {
-            ptr: ptr.cast(),
-            size,
-        })
+    pub fn new_file(fd: impl AsFd, size: NonZeroUsize, offset: i64) -> nix::Result<Self> {
+        Self::new(Some((fd.as_fd(), offset)), size, MapFlags::MAP_SHARED, 0)
     }
 
-    pub fn new_file(fd: impl AsFd, size: NonZeroUsize) -> nix::Result<Self> {
+    /// Maps `size` bytes of a file at an offset, or anonymous memory if
+    /// `file` is `None`.
+    ///
+    /// Unless `guard_size` is zero, the mapping is aligned to it and
+    /// surrounded by `guard_size` bytes of inaccessible memory on each side,
+    /// so that overruns fault instead of reaching neighboring mappings.
+    pub fn new(
+        file: Option<(BorrowedFd, i64)>,
+        size: NonZeroUsize,
+        flags: MapFlags,
+        guard_size: usize,
+    ) -> nix::Result<Self> {
         assert!(size.get() >= size_of::<T>());
-        let ptr = unsafe {
-            mmap(
-                None,
-                size,
-                ProtFlags::PROT_READ | ProtFlags::PROT_WRITE,
-                MapFlags::MAP_SHARED,
-                &fd,
-                0,
-            )?
+        let addr = reserve_guarded(size, guard_size)?;
+        let flags = if addr.is_some() {
+            flags | MapFlags::MAP_FIXED
+        } else {
+            flags
+        };
+        let prot = ProtFlags::PROT_READ | ProtFlags::PROT_WRITE;
+        let result = unsafe {
+            match file {
+                Some((fd, offset)) => mmap(addr, size, prot, flags, fd, offset),
+                None => mmap_anonymous(addr, size, prot, flags),
+            }
+        };
+        let ptr = match result {
+            Ok(ptr) => ptr,
+            Err(e) => {
+                if let Some(addr) = addr {
+                    let reservation =
+                        NonNull::new((addr.get() - guard_size) as *mut c_void).unwrap();
+                    let _ = unsafe { munmap(reservation, size.get() + 2 * guard_size) };
+                }
+                return Err(e);
+            }
         };
         Ok(Self {
             ptr: ptr.cast(),
             size,
+            guard_size,
         })
     }
 
@@ -52,10 +82,36 @@ impl<T: Copy> Mmapped<T> {
         self.ptr.as_ptr()
     }
 
+    /// Size of the mapping in bytes
+    pub fn size(&self) -> usize {
+        self.size.get()
+    }
+
+    pub fn advise(&self, advice: MmapAdvise) -> nix::Result<()> {
+        unsafe { madvise(self.ptr.cast(), self.size.get(), advice) }
+    }
+
+    /// Gives advice about the bytes of the mapping in `range`, which must be
+    /// page-aligned.
+    pub fn advise_range(&self, range: Range<usize>, advice: MmapAdvise) -> nix::Result<()> {
+        assert!(range.start <= range.end && range.end <= self.size.get());
+        unsafe { madvise(self.ptr.cast().byte_add(range.start), range.len(), advice) }
+    }
+
+    /// Locks the mapping in RAM, faulting it in.
+    pub fn lock(&self) -> nix::Result<()> {
+        unsafe { mlock(self.ptr.cast(), self.size.get()) }
+    }
+
     pub fn as_ref(&self) -> &T {
         unsafe { self.ptr.as_ref() }
     }
 
+    pub fn as_slice(&self) -> &[T] {
+        let len = self.size.get() / size_of::<T>();
+        unsafe { std::slice::from_raw_parts(self.ptr.as_ptr(), len) }
+    }
+
     pub fn as_mut_slice(&mut self) -> &mut [T] {
         let len = self.size.get() / size_of::<T>();
         unsafe { std::slice::from_raw_parts_mut(self.ptr.as_ptr(), len) }
@@ -63,13 +119,53 @@ impl<T: Copy> Mmapped<T> {
 }
 
 unsafe impl<T: Send> Send for Mmapped<T> {}
+unsafe impl<T: Sync> Sync for Mmapped<T> {}
 
 impl<T> Drop for Mmapped<T> {
     fn drop(&mut self) {
-        let _ = unsafe { munmap(self.ptr.cast(), self.size.get()) };
+        let _ = unsafe {
+            munmap(
+                self.ptr.cast().byte_sub(self.guard_size),
+                self.size.get() + 2 * self.guard_size,
+            )
+        };
     }
 }
 
+/// Reserves inaccessible address space for a mapping of `size` bytes with
+/// `guard_size` bytes on each side, and returns the address of the mapping,
+/// aligned to `guard_size`.
+fn reserve_guarded(size: NonZeroUsize, guard_size: usize) -> nix::Result<Option<NonZeroUsize>> {
+    if guard_size == 0 {
+        return Ok(None);
+    }
+    // Another guard area worth of space leaves room for the alignment.
+    let len = size.get() + 3 * guard_size;
+    let base = unsafe {
+        mmap_anonymous(
+            None,
+            NonZeroUsize::new(len).unwrap(),
+            ProtFlags::PROT_NONE,
+            MapFlags::MAP_PRIVATE | MapFlags::MAP_NORESERVE,
+        )?
+    };
+    let start = base.as_ptr() as usize;
+    let addr = (start + guard_size).next_multiple_of(guard_size);
+
+    // Give back the space that the alignment left unused.
+    let head = addr - guard_size - start;
+    let tail = start + len - (addr + size.get() + guard_size);
+    unsafe {
+        if head > 0 {
+            munmap(base, head)?;
+        }
+        if tail > 0 {
+            munmap(base.byte_add(len - tail), tail)?;
+        }
+    }
+    Ok(NonZeroUsize::new(addr))
+}
+
 pub struct RangeAllocator {
     addr: u64,
 }
@@ -94,6 +190,19 @@ impl RangeAllocator {
     }
 }
 
+pub trait CopyFromGuest: Sized {
+    fn copy_from_guest(memory: &[u8], addr: impl Into<u64>) -> Result<Self>;
+}
+
+impl<T: FromBytes> CopyFromGuest for T {
+    fn copy_from_guest(memory: &[u8], addr: impl Into<u64>) -> Result<Self> {
+        let addr: u64 = addr.into();
+        let addr: usize = addr.try_into().map_err(|_| Error::OutOfGuestMemory)?;
+        let memory = memory.get(addr..).ok_or(Error::OutOfGuestMemory)?;
+        Self::read_from_prefix(memory).ok_or(Error::OutOfGuestMemory)
+    }
+}
+
 pub trait CopyToGuest {
     fn copy_to_guest(&self, memory: &mut [u8], addr: impl Into<u64>) -> Result<()>;
 }
@@ -109,3 +218,311 @@ impl<T: AsBytes + ?Sized> CopyToGuest for T {
         self.write_to_prefix(memory).ok_or(Error::OutOfGuestMemory)
     }
 }
+
+/// Start of the 32-bit MMIO hole, where RAM below 4 GiB ends
+pub const MMIO_HOLE_START: u64 = 0xc000_0000;
+
+/// Where the RAM that does not fit below the MMIO hole is placed
+pub const HIGH_RAM_START: u64 = 0x1_0000_0000;
+
+/// A contiguous range of guest RAM, registered with KVM as one memslot
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryRegion {
+    pub guest_addr: u64,
+    pub size: u64,
+
+    /// Offset of the region in the host mapping that backs guest RAM, and in
+    /// the file returned by [`GuestMemory::fd`]
+    pub host_offset: u64,
+}
+
+impl MemoryRegion {
+    /// Splits `size` bytes of RAM into a region below the MMIO hole and, if
+    /// needed, one above 4 GiB.
+    pub(crate) fn layout(size: u64) -> Vec<Self> {
+        let low_size = size.min(MMIO_HOLE_START);
+        let mut regions = vec![Self {
+            guest_addr: 0,
+            size: low_size,
+            host_offset: 0,
+        }];
+        if size > low_size {
+            regions.push(Self {
+                guest_addr: HIGH_RAM_START,
+                size: size - low_size,
+                host_offset: low_size,
+            });
+        }
+        regions
+    }
+
+    /// Places a hotpluggable region of `size` bytes above the RAM described
+    /// by `ram`, aligned to 1 GiB.
+    pub(crate) fn hotplug(ram: &[Self], size: u64) -> Self {
+        let ram_end = ram.last().map_or(0, Self::end);
+        Self {
+            guest_addr: ram_end.max(HIGH_RAM_START).next_multiple_of(1 << 30),
+            size,
+            host_offset: 0,
+        }
+    }
+
+    pub fn end(&self) -> u64 {
+        self.guest_addr + self.size
+    }
+
+    pub fn contains(&self, addr: u64) -> bool {
+        self.guest_addr <= addr && addr < self.end()
+    }
+
+    /// Range of the region in the host mapping
+    pub(crate) fn host_range(&self) -> Range<usize> {
+        self.host_offset as usize..(self.host_offset + self.size) as usize
+    }
+
+    /// Describes the region to KVM as memslot `slot`, given the host address
+    /// at which guest RAM is mapped.
+    pub(crate) fn memslot(
+        &self,
+        slot: u32,
+        host_base: *const u8,
+        flags: u32,
+    ) -> kvm_userspace_memory_region {
+        kvm_userspace_memory_region {
+            slot,
+            flags,
+            guest_phys_addr: self.guest_addr,
+            memory_size: self.size,
+            userspace_addr: host_base as u64 + self.host_offset,
+        }
+    }
+}
+
+/// Guest RAM shared by the VMM, the vCPUs and devices
+///
+/// Accesses are bounds-checked against guest physical addresses and may not
+/// cross the boundary of a region. The vCPUs may access the same memory
+/// concurrently, as with DMA on real hardware.
+#[derive(Clone)]
+pub struct GuestMemory {
+    mmap: Arc<Mmapped<u8>>,
+    fd: Option<Arc<OwnedFd>>,
+    regions: Arc<[MemoryRegion]>,
+
+    /// Pages written through this handle while dirty logging is enabled
+    dirty: Arc<DirtyPages>,
+
+    /// Region whose memory the guest plugs through a virtio-mem device, and
+    /// the private mapping backing it on its own
+    ///
+    /// It is not part of `regions`, since it is neither shared nor saved,
+    /// and writes to it are not logged.
+    hotplug: Option<(MemoryRegion, Arc<Mmapped<u8>>)>,
+}
+
+impl GuestMemory {
+    pub(crate) fn new(
+        mmap: Mmapped<u8>,
+        fd: Option<OwnedFd>,
+        regions: Vec<MemoryRegion>,
+        hotplug: Option<(MemoryRegion, Mmapped<u8>)>,
+    ) -> Self {
+        Self {
+            mmap: Arc::new(mmap),
+            fd: fd.map(Arc::new),
+            dirty: Arc::new(DirtyPages::new(&regions)),
+            regions: regions.into(),
+            hotplug: hotplug.map(|(region, mmap)| (region, Arc::new(mmap))),
+        }
+    }
+
+    /// Size of guest RAM in bytes
+    pub fn size(&self) -> u64 {
+        self.mmap.size() as u64
+    }
+
+    /// Ranges of guest physical memory backed by RAM, in ascending order
+    pub fn regions(&self) -> &[MemoryRegion] {
+        &self.regions
+    }
+
+    /// File backing guest RAM, if the backend shares it
+    ///
+    /// Each region is at its `host_offset` in the file. The descriptor can be
+    /// passed to another process over `SCM_RIGHTS` for it to map the regions.
+    pub fn fd(&self) -> Option<BorrowedFd<'_>> {
+        self.fd.as_deref().map(AsFd::as_fd)
+    }
+
+    pub fn read_obj<T: FromBytes>(&self, addr: u64) -> Result<T> {
+        let ptr = self.host_ptr(addr, size_of::<T>())?;
+        Ok(unsafe { ptr.cast::<T>().read_unaligned() })
+    }
+
+    /// Writes `obj` at `addr`, recording the pages written if dirty logging
+    /// is enabled.
+    pub fn write_obj<T: AsBytes + ?Sized>(&self, obj: &T, addr: u64) -> Result<()> {
+        let bytes = obj.as_bytes();
+        let ptr = self.host_ptr(addr, bytes.len())?;
+        unsafe { std::ptr::copy_nonoverlapping(bytes.as_ptr(), ptr, bytes.len()) };
+        if let Ok((index, offset)) = self.locate(addr) {
+            self.dirty.mark(index, offset, bytes.len());
+        }
+        Ok(())
+    }
+
+    pub fn read_slice(&self, buf: &mut [u8], addr: u64) -> Result<()> {
+        let ptr = self.host_ptr(addr, buf.len())?;
+        unsafe { std::ptr::copy_nonoverlapping(ptr, buf.as_mut_ptr(), buf.len()) };
+        Ok(())
+    }
+
+    /// Returns a pointer to the `len` bytes at `addr` in the host mapping
+    /// backing them, which must all be in the same region.
+    ///
+    /// The vCPUs and other handles may access the memory at the same time,
+    /// so it is only ever copied from and to through the pointer, never
+    /// borrowed.
+    fn host_ptr(&self, addr: u64, len: usize) -> Result<*mut u8> {
+        let (region, mmap) = self.backing(addr)?;
+        let offset = addr - region.guest_addr;
+        offset
+            .checked_add(len as u64)
+            .filter(|&end| end <= region.size)
+            .ok_or(Error::OutOfGuestMemory)?;
+        Ok(unsafe { mmap.as_ptr().add((region.host_offset + offset) as usize) })
+    }
+
+    /// Returns the region containing `addr`, including the hotpluggable one,
+    /// and the mapping backing it.
+    fn backing(&self, addr: u64) -> Result<(&MemoryRegion, &Mmapped<u8>)> {
+        self.regions
+            .iter()
+            .map(|region| (region, &*self.mmap))
+            .chain(
+                self.hotplug
+                    .as_ref()
+                    .map(|(region, mmap)| (region, &**mmap)),
+            )
+            .find(|(region, _)| region.contains(addr))
+            .ok_or(Error::OutOfGuestMemory)
+    }
+
+    /// Gives the memory backing the `len` bytes at `addr` in the
+    /// hotpluggable region back to the host, after which it reads as zeroes.
+    ///
+    /// The range must be aligned to pages.
+    pub(crate) fn discard_hotplug(&self, addr: u64, len: u64) -> Result<()> {
+        let (region, mmap) = self.hotplug.as_ref().ok_or(Error::OutOfGuestMemory)?;
+        let start = addr
+            .checked_sub(region.guest_addr)
+            .filter(|&start| start.checked_add(len).is_some_and(|end| end <= region.size))
+            .ok_or(Error::OutOfGuestMemory)?;
+        let start = (region.host_offset + start) as usize;
+        Ok(mmap.advise_range(start..start + len as usize, MmapAdvise::MADV_DONTNEED)?)
+    }
+
+    /// Returns the index of the region containing `addr` and the offset of
+    /// `addr` in it.
+    fn locate(&self, addr: u64) -> Result<(usize, u64)> {
+        let index = self
+            .regions
+            .iter()
+            .position(|region| region.contains(addr))
+            .ok_or(Error::OutOfGuestMemory)?;
+        Ok((index, addr - self.regions[index].guest_addr))
+    }
+
+    pub(crate) fn as_ptr(&self) -> *mut u8 {
+        self.mmap.as_ptr()
+    }
+
+    /// The host mapping backing all the regions, which is not indexed by
+    /// guest physical address
+    ///
+    /// The vCPUs of `control` must be paused, so that the guest does not
+    /// write to the memory while it is borrowed.
+    pub(crate) fn host_slice(&self, control: &Control) -> Result<&[u8]> {
+        if !control.is_paused() {
+            return Err(Error::GuestNotPaused);
+        }
+        Ok(self.mmap.as_slice())
+    }
+
+    /// The RAM regions and the hotpluggable region, each with the host memory
+    /// backing it
+    ///
+    /// The vCPUs of `control` must be paused, as for [`Self::host_slice`].
+    pub(crate) fn host_regions(&self, control: &Control) -> Result<Vec<(MemoryRegion, &[u8])>> {
+        let host_slice = self.host_slice(control)?;
+        let mut regions = self
+            .regions
+            .iter()
+            .map(|region| (*region, &host_slice[region.host_range()]))
+            .collect::<Vec<_>>();
+        if let Some((region, mmap)) = &self.hotplug {
+            regions.push((*region, &mmap.as_slice()[region.host_range()]));
+        }
+        Ok(regions)
+    }
+
+    /// Copies the bytes at `offset` in the host mapping into `buf`, while
+    /// the guest may be running.
+    pub(crate) fn read_host(&self, offset: usize, buf: &mut [u8]) -> Result<()> {
+        offset
+            .checked_add(buf.len())
+            .filter(|&end| end <= self.mmap.size())
+            .ok_or(Error::OutOfGuestMemory)?;
+        unsafe {
+            std::ptr::copy_nonoverlapping(self.as_ptr().add(offset), buf.as_mut_ptr(), buf.len());
+        };
+        Ok(())
+    }
+
+    /// Zeroes all the memory and copies `low_memory` to the start of the host
+    /// mapping, once the vCPUs have stopped.
+    ///
+    /// Others such as a GDB stub may still hold the memory, so it is written
+    /// without borrowing it.
+    pub(crate) fn reset(&self, low_memory: &[u8]) {
+        assert!(low_memory.len() <= self.mmap.size());
+        unsafe {
+            std::ptr::copy_nonoverlapping(low_memory.as_ptr(), self.as_ptr(), low_memory.len());
+            std::ptr::write_bytes(
+                self.as_ptr().add(low_memory.len()),
+                0,
+                self.mmap.size() - low_memory.len(),
+            );
+        }
+        self.dirty.mark_all();
+    }
+
+    /// Maps guest RAM again, privately, so that writes through the new
+    /// mapping are copied instead of reaching this one.
+    ///
+    /// Until a page is written to through the new mapping, it shows the
+    /// writes made through this one. Guest RAM must be backed by a file.
+    pub(crate) fn map_copy_on_write(&self) -> Result<Mmapped<u8>> {
+        let fd = self.fd().ok_or_else(|| {
+            Error::CloneUnsupported("guest memory is not backed by a file".to_owned())
+        })?;
+        // The block size of hugetlbfs is the size of its pages.
+        let page_size = fstatfs(fd)?.block_size() as usize;
+        let size = NonZeroUsize::new(self.mmap.size()).unwrap();
+        Ok(Mmapped::new(
+            Some((fd, 0)),
+            size,
+            MapFlags::MAP_PRIVATE,
+            page_size,
+        )?)
+    }
+
+    /// The host mapping, for threads that must not keep it alive
+    pub(crate) fn mapping(&self) -> Weak<Mmapped<u8>> {
+        Arc::downgrade(&self.mmap)
+    }
+
+    pub(crate) fn dirty_pages(&self) -> &DirtyPages {
+        &self.dirty
+    }
+}
@@ -0,0 +1,287 @@
+use super::{Mmapped, PAGE_SIZE};
+use crate::{Error, Result};
+use nix::{
+    errno::Errno,
+    fcntl::{fcntl, FcntlArg, SealFlag},
+    sys::{
+        memfd::{memfd_create, MemFdCreateFlag},
+        mman::{MapFlags, MmapAdvise},
+        statfs::{fstatfs, HUGETLBFS_MAGIC},
+    },
+};
+use std::{
+    fmt,
+    fs::{File, OpenOptions},
+    num::NonZeroUsize,
+    os::fd::{AsFd, AsRawFd, OwnedFd},
+    path::{Path, PathBuf},
+};
+
+const THP_ENABLED_PATH: &str = "/sys/kernel/mm/transparent_hugepage/enabled";
+
+/// Size of the pages allocated from the hugetlb pool
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HugePageSize {
+    Size2M,
+    Size1G,
+}
+
+impl HugePageSize {
+    pub fn bytes(self) -> usize {
+        match self {
+            Self::Size2M => 2 << 20,
+            Self::Size1G => 1 << 30,
+        }
+    }
+
+    fn map_flags(self) -> MapFlags {
+        match self {
+            Self::Size2M => MapFlags::MAP_HUGETLB | MapFlags::MAP_HUGE_2MB,
+            Self::Size1G => MapFlags::MAP_HUGETLB | MapFlags::MAP_HUGE_1GB,
+        }
+    }
+
+    /// Directory in which the host exposes the pool of pages of this size
+    fn sysfs_dir(self) -> String {
+        format!(
+            "/sys/kernel/mm/hugepages/hugepages-{}kB",
+            self.bytes() >> 10
+        )
+    }
+}
+
+impl fmt::Display for HugePageSize {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Size2M => f.write_str("2M"),
+            Self::Size1G => f.write_str("1G"),
+        }
+    }
+}
+
+/// Where guest RAM is allocated from
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum MemoryBackend {
+    /// Private anonymous memory in 4 KiB pages
+    #[default]
+    Anonymous,
+
+    /// Private anonymous memory in hugepages preallocated in the host's
+    /// hugetlb pool (`MAP_HUGETLB`)
+    HugeTlb(HugePageSize),
+
+    /// A file on a hugetlbfs mount, mapped shared
+    ///
+    /// The file is created if it does not exist, and left in place when the
+    /// guest is dropped.
+    HugeTlbFs(PathBuf),
+
+    /// Private anonymous memory that the host backs with transparent
+    /// hugepages when it can (`MADV_HUGEPAGE`)
+    TransparentHugePages,
+
+    /// An anonymous file created with `memfd_create`, mapped shared
+    ///
+    /// With `seal`, the size of the file is sealed so that the processes it
+    /// is passed to can map it without guarding against truncation.
+    Memfd { seal: bool },
+
+    /// A regular file, mapped shared
+    ///
+    /// The file is created if it does not exist, resized to the size of
+    /// guest RAM, and left in place when the guest is dropped.
+    File(PathBuf),
+}
+
+/// How guest RAM is faulted in before the guest starts
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Prefault {
+    /// The host faults in all the pages when mapping them (`MAP_POPULATE`).
+    Populate,
+
+    /// The given number of threads write to every page, which is faster
+    /// for large amounts of memory.
+    Touch(NonZeroUsize),
+}
+
+/// How guest RAM is prepared once mapped
+#[derive(Debug, Clone, Default)]
+pub struct MemoryOptions {
+    pub prefault: Option<Prefault>,
+
+    /// Lock the memory with `mlock`.
+    pub lock: bool,
+
+    /// Leave the memory out of core dumps of the VMM (`MADV_DONTDUMP`).
+    pub dontdump: bool,
+
+    /// Let KSM merge identical pages (`MADV_MERGEABLE`).
+    pub mergeable: bool,
+}
+
+impl MemoryBackend {
+    /// Returns whether the memory may hold data from before it was mapped.
+    pub(crate) fn keeps_contents(&self) -> bool {
+        matches!(self, Self::HugeTlbFs(_) | Self::File(_))
+    }
+
+    /// Maps `size` bytes of memory for guest RAM, with a guard page on each
+    /// side.
+    ///
+    /// Also returns the file backing the memory for the backends that share
+    /// it. Unless `keep_contents` is set, a file that already exists is
+    /// truncated first, so that the memory starts zeroed.
+    pub(crate) fn map(
+        &self,
+        size: NonZeroUsize,
+        options: &MemoryOptions,
+        keep_contents: bool,
+    ) -> Result<(Mmapped<u8>, Option<OwnedFd>)> {
+        let (file, flags, page_size) = match self {
+            Self::Anonymous => (None, MapFlags::MAP_PRIVATE, PAGE_SIZE),
+            Self::HugeTlb(page_size) => {
+                let dir = page_size.sysfs_dir();
+                if !Path::new(&dir).exists() {
+                    return Err(Error::HugePagesUnavailable(format!(
+                        "{page_size} hugepages are not supported by the host"
+                    )));
+                }
+                let flags = MapFlags::MAP_PRIVATE | page_size.map_flags();
+                (None, flags, page_size.bytes())
+            }
+            Self::HugeTlbFs(path) => {
+                let file = open_file(path)?;
+                let statfs = fstatfs(&file)?;
+                if statfs.filesystem_type() != HUGETLBFS_MAGIC {
+                    return Err(Error::HugePagesUnavailable(format!(
+                        "{} is not on a hugetlbfs mount",
+                        path.display()
+                    )));
+                }
+                let page_size = statfs.block_size() as usize;
+                (Some(file), MapFlags::MAP_SHARED, page_size)
+            }
+            Self::TransparentHugePages => {
+                let enabled = std::fs::read_to_string(THP_ENABLED_PATH).map_err(|_| {
+                    Error::HugePagesUnavailable(
+                        "transparent hugepages are not supported by the host".to_owned(),
+                    )
+                })?;
+                if enabled.contains("[never]") {
+                    return Err(Error::HugePagesUnavailable(format!(
+                        "transparent hugepages are disabled (see {THP_ENABLED_PATH})"
+                    )));
+                }
+                // Aligning the mapping lets all of it be backed by hugepages.
+                (None, MapFlags::MAP_PRIVATE, HugePageSize::Size2M.bytes())
+            }
+            Self::Memfd { seal } => {
+                let flags = if *seal {
+                    MemFdCreateFlag::MFD_CLOEXEC | MemFdCreateFlag::MFD_ALLOW_SEALING
+                } else {
+                    MemFdCreateFlag::MFD_CLOEXEC
+                };
+                let file = File::from(memfd_create(c"microcosm-guest-ram", flags)?);
+                (Some(file), MapFlags::MAP_SHARED, PAGE_SIZE)
+            }
+            Self::File(path) => (Some(open_file(path)?), MapFlags::MAP_SHARED, PAGE_SIZE),
+        };
+        if !matches!(self, Self::TransparentHugePages) && !size.get().is_multiple_of(page_size) {
+            return Err(Error::UnalignedMemorySize {
+                size: size.get(),
+                page_size,
+            });
+        }
+        if let Some(file) = &file {
+            if self.keeps_contents() && !keep_contents {
+                file.set_len(0)?;
+            }
+            file.set_len(size.get() as u64)?;
+        }
+        if matches!(self, Self::Memfd { seal: true }) {
+            let seals = SealFlag::F_SEAL_SHRINK | SealFlag::F_SEAL_GROW | SealFlag::F_SEAL_SEAL;
+            fcntl(
+                file.as_ref().unwrap().as_raw_fd(),
+                FcntlArg::F_ADD_SEALS(seals),
+            )?;
+        }
+
+        let flags = if options.prefault == Some(Prefault::Populate) {
+            flags | MapFlags::MAP_POPULATE
+        } else {
+            flags
+        };
+        let file_offset = file.as_ref().map(|file| (file.as_fd(), 0));
+        let mmap = Mmapped::new(file_offset, size, flags, page_size).map_err(|e| {
+            match self {
+                // Hugetlb pages are reserved when mapping them.
+                Self::HugeTlb(page_size) if e == Errno::ENOMEM => {
+                    Error::HugePagesUnavailable(format!(
+                        "not enough free {page_size} hugepages (see {}/nr_hugepages)",
+                        page_size.sysfs_dir()
+                    ))
+                }
+                Self::HugeTlbFs(path) if e == Errno::ENOMEM => Error::HugePagesUnavailable(
+                    format!("not enough free hugepages for {}", path.display()),
+                ),
+                _ => e.into(),
+            }
+        })?;
+
+        if matches!(self, Self::TransparentHugePages) {
+            mmap.advise(MmapAdvise::MADV_HUGEPAGE).map_err(|e| {
+                if e == Errno::EINVAL {
+                    Error::HugePagesUnavailable(
+                        "transparent hugepages are not supported by the host".to_owned(),
+                    )
+                } else {
+                    Error::from(e)
+                }
+            })?;
+        }
+        if options.dontdump {
+            mmap.advise(MmapAdvise::MADV_DONTDUMP)?;
+        }
+        if options.mergeable {
+            mmap.advise(MmapAdvise::MADV_MERGEABLE)?;
+        }
+        if let Some(Prefault::Touch(num_threads)) = options.prefault {
+            touch(&mmap, num_threads);
+        }
+        if options.lock {
+            mmap.lock()?;
+        }
+        Ok((mmap, file.map(Into::into)))
+    }
+}
+
+/// Faults in every page of `mmap` by writing to it from `num_threads`
+/// threads.
+fn touch(mmap: &Mmapped<u8>, num_threads: NonZeroUsize) {
+    let size = mmap.as_slice().len();
+    let chunk_size = size.div_ceil(num_threads.get()).next_multiple_of(PAGE_SIZE);
+    std::thread::scope(|scope| {
+        for start in (0..size).step_by(chunk_size) {
+            scope.spawn(move || {
+                let end = size.min(start + chunk_size);
+                for offset in (start..end).step_by(PAGE_SIZE) {
+                    // Writing back the current contents preserves those of
+                    // file-backed memory.
+                    unsafe {
+                        let ptr = mmap.as_ptr().add(offset);
+                        ptr.write_volatile(ptr.read_volatile());
+                    }
+                }
+            });
+        }
+    });
+}
+
+fn open_file(path: &Path) -> Result<File> {
+    Ok(OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)?)
+}
@@ -0,0 +1,394 @@
+use crate::{
+    cpuid,
+    dirty::{DirtyBitmap, DirtyLog},
+    memory::{GuestMemory, MemoryRegion, PAGE_SIZE},
+    snapshot::{self, Decoder, Encoder, GuestState},
+    Error, Result,
+};
+use std::{
+    io::{Read, Write},
+    ops::Range,
+    time::{Duration, Instant},
+};
+use sys::kvm_bindings::CpuId;
+
+// Both ends first send a hello message with the magic and the version of
+// the protocol. The one of the source also describes guest RAM, the number
+// of vCPUs and their CPUID. The destination replies with the CPUID that its
+// host supports, or with the reason why it refuses the guest.
+//
+// The source then sends all of RAM and, while the guest keeps running, the
+// pages written in the meantime, until few enough are left to be sent
+// within `MAX_DOWNTIME`. It then pauses the guest and sends the remaining
+// pages followed by the state of the guest. The destination acknowledges
+// once the guest is ready to run.
+//
+// Each message is a frame made of the length of its contents as a u64 and
+// of the contents, encoded with `Encoder`.
+
+const MAGIC: [u8; 8] = *b"MCSMMIGR";
+
+/// Version of the protocol, incremented on incompatible changes
+const VERSION: u32 = 1;
+
+/// Upper bound on the size of a frame, against corrupted streams
+const MAX_FRAME_SIZE: u64 = 64 << 20;
+
+/// Amount of RAM sent in one frame
+const CHUNK_SIZE: usize = 1 << 20;
+
+/// How long the guest may stay paused while the last pages are sent, at the
+/// rate at which the previous pages were sent
+const MAX_DOWNTIME: Duration = Duration::from_millis(300);
+
+/// Number of passes over the dirty pages after which the guest is paused
+/// even if it writes to memory faster than it can be sent
+const MAX_ITERATIONS: usize = 30;
+
+// Kinds of the messages that follow the hello ones
+const MSG_PAGES: u8 = 0;
+const MSG_ZEROES: u8 = 1;
+const MSG_STATE: u8 = 2;
+
+const REPLY_ACCEPT: u8 = 0;
+const REPLY_REJECT: u8 = 1;
+
+/// Connection to the other end of a migration
+pub trait MigrationStream: Read + Write {}
+
+impl<T: Read + Write> MigrationStream for T {}
+
+struct Channel<S> {
+    stream: S,
+    buf: Vec<u8>,
+}
+
+impl<S: MigrationStream> Channel<S> {
+    const fn new(stream: S) -> Self {
+        Self {
+            stream,
+            buf: Vec::new(),
+        }
+    }
+
+    fn send(&mut self, encoder: Encoder) -> Result<()> {
+        let frame = encoder.finish();
+        self.stream.write_all(&(frame.len() as u64).to_le_bytes())?;
+        self.stream.write_all(&frame)?;
+        Ok(())
+    }
+
+    /// Receives a frame and decodes all of it with `f`.
+    fn recv<T>(&mut self, f: impl FnOnce(&mut Decoder) -> Result<T>) -> Result<T> {
+        let mut len = [0; 8];
+        self.stream.read_exact(&mut len)?;
+        let len = u64::from_le_bytes(len);
+        if len > MAX_FRAME_SIZE {
+            return Err(Error::InvalidMigrationStream(format!(
+                "Frame of {len} bytes is too large"
+            )));
+        }
+        self.buf.resize(len as usize, 0);
+        self.stream.read_exact(&mut self.buf)?;
+        let mut decoder = Decoder::new(&self.buf);
+        f(&mut decoder)
+            .and_then(|value| decoder.finish().map(|()| value))
+            .map_err(|e| match e {
+                // The codec is shared with snapshots.
+                Error::InvalidSnapshot(message) => Error::InvalidMigrationStream(message),
+                e => e,
+            })
+    }
+}
+
+fn encode_hello() -> Encoder {
+    let mut encoder = Encoder::new();
+    encoder.pod(&MAGIC);
+    encoder.u32(VERSION);
+    encoder
+}
+
+/// Checks the magic and the version at the start of a hello message.
+fn decode_hello(decoder: &mut Decoder) -> Result<()> {
+    if decoder.pod::<[u8; 8]>()? != MAGIC {
+        return Err(Error::InvalidMigrationStream(
+            "Not a migration stream".to_owned(),
+        ));
+    }
+    let version = decoder.u32()?;
+    if version != VERSION {
+        return Err(Error::UnsupportedMigrationVersion(version));
+    }
+    Ok(())
+}
+
+fn decode_cpuid(decoder: &mut Decoder) -> Result<CpuId> {
+    CpuId::from_entries(&decoder.pods()?)
+        .map_err(|e| Error::InvalidMigrationStream(format!("Invalid CPUID: {e:?}")))
+}
+
+/// Sends a guest to the destination at the other end of `stream`.
+///
+/// `cpuid` is the one the vCPUs were configured from. Once the guest has
+/// been sent while it ran, `stop` pauses it and returns its state. Dirty
+/// logging is stopped before returning.
+pub fn send(
+    stream: impl MigrationStream,
+    memory: &GuestMemory,
+    dirty_log: &DirtyLog,
+    num_cpus: usize,
+    cpuid: &CpuId,
+    stop: impl FnOnce() -> Result<GuestState>,
+) -> Result<()> {
+    let mut channel = Channel::new(stream);
+    let mut hello = encode_hello();
+    hello.u32(memory.regions().len() as u32);
+    for region in memory.regions() {
+        hello.u64(region.guest_addr);
+        hello.u64(region.size);
+        hello.u64(region.host_offset);
+    }
+    hello.u32(num_cpus as u32);
+    hello.pods(cpuid.as_slice());
+    channel.send(hello)?;
+
+    let supported = channel.recv(|decoder| {
+        decode_hello(decoder)?;
+        match decoder.u8()? {
+            REPLY_ACCEPT => decode_cpuid(decoder),
+            _ => Err(Error::MigrationRejected(
+                String::from_utf8_lossy(decoder.bytes()?).into_owned(),
+            )),
+        }
+    })?;
+    cpuid::check_compatible(cpuid, &supported)?;
+
+    dirty_log.start()?;
+    let result = send_memory(&mut channel, memory, dirty_log, stop);
+    let stopped = dirty_log.stop();
+    result?;
+    stopped?;
+
+    channel.recv(|decoder| match decoder.u8()? {
+        REPLY_ACCEPT => Ok(()),
+        _ => Err(Error::MigrationRejected(
+            String::from_utf8_lossy(decoder.bytes()?).into_owned(),
+        )),
+    })
+}
+
+fn send_memory<S: MigrationStream>(
+    channel: &mut Channel<S>,
+    memory: &GuestMemory,
+    dirty_log: &DirtyLog,
+    stop: impl FnOnce() -> Result<GuestState>,
+) -> Result<()> {
+    let start = Instant::now();
+    send_range(channel, memory, 0..memory.size() as usize)?;
+    let mut rate = memory.size() as f64 / start.elapsed().as_secs_f64();
+
+    let mut iterations = 0;
+    let remaining = loop {
+        let bitmaps = dirty_log.collect()?;
+        let dirty = bitmaps.iter().map(DirtyBitmap::num_dirty).sum::<usize>() * PAGE_SIZE;
+        iterations += 1;
+        if dirty as f64 <= rate * MAX_DOWNTIME.as_secs_f64() || iterations >= MAX_ITERATIONS {
+            break bitmaps;
+        }
+        let start = Instant::now();
+        send_dirty(channel, memory, &bitmaps)?;
+        rate = dirty as f64 / start.elapsed().as_secs_f64();
+    };
+
+    let state = stop()?;
+    send_dirty(channel, memory, &remaining)?;
+    // The pages written since the last collection, including by the devices
+    // while the state was being captured
+    send_dirty(channel, memory, &dirty_log.collect()?)?;
+
+    let mut encoder = Encoder::new();
+    encoder.u8(MSG_STATE);
+    state.encode(&mut encoder);
+    channel.send(encoder)
+}
+
+/// Sends the pages marked in `bitmaps`.
+fn send_dirty<S: MigrationStream>(
+    channel: &mut Channel<S>,
+    memory: &GuestMemory,
+    bitmaps: &[DirtyBitmap],
+) -> Result<()> {
+    for bitmap in bitmaps {
+        let region = bitmap.region();
+        let mut run: Option<Range<usize>> = None;
+        for addr in bitmap.dirty_pages() {
+            let offset = (region.host_offset + addr - region.guest_addr) as usize;
+            match &mut run {
+                Some(run) if run.end == offset => run.end += PAGE_SIZE,
+                _ => {
+                    if let Some(run) = run.replace(offset..offset + PAGE_SIZE) {
+                        send_range(channel, memory, run)?;
+                    }
+                }
+            }
+        }
+        if let Some(run) = run {
+            send_range(channel, memory, run)?;
+        }
+    }
+    Ok(())
+}
+
+/// Sends `range` of the host mapping of guest RAM, marking the pages that
+/// are zero instead of sending them.
+fn send_range<S: MigrationStream>(
+    channel: &mut Channel<S>,
+    memory: &GuestMemory,
+    range: Range<usize>,
+) -> Result<()> {
+    let mut buf = vec![0; CHUNK_SIZE.min(range.len())];
+    for chunk_offset in range.clone().step_by(CHUNK_SIZE) {
+        // The guest keeps running, so the chunk is copied before it is
+        // looked at.
+        let chunk = &mut buf[..CHUNK_SIZE.min(range.end - chunk_offset)];
+        memory.read_host(chunk_offset, chunk)?;
+        let mut pages = chunk.chunks(PAGE_SIZE).map(snapshot::is_zero).peekable();
+        let mut start = 0;
+        while let Some(zero) = pages.next() {
+            let mut end = start + PAGE_SIZE;
+            while pages.next_if_eq(&zero).is_some() {
+                end += PAGE_SIZE;
+            }
+            let end = end.min(chunk.len());
+            let mut encoder = Encoder::new();
+            if zero {
+                encoder.u8(MSG_ZEROES);
+                encoder.u64((chunk_offset + start) as u64);
+                encoder.u64((end - start) as u64);
+            } else {
+                encoder.u8(MSG_PAGES);
+                encoder.u64((chunk_offset + start) as u64);
+                encoder.bytes(&chunk[start..end]);
+            }
+            channel.send(encoder)?;
+            start = end;
+        }
+    }
+    Ok(())
+}
+
+/// A guest being received from another process
+pub struct IncomingMigration {
+    channel: Channel<Box<dyn MigrationStream>>,
+    pub regions: Vec<MemoryRegion>,
+    pub num_cpus: usize,
+
+    /// State of the guest, once all of it has been received
+    pub state: Option<GuestState>,
+}
+
+impl IncomingMigration {
+    /// Reads the hello message of the source at the other end of `stream`,
+    /// and accepts the guest if the host supports its CPUID.
+    pub fn accept(stream: Box<dyn MigrationStream>, supported_cpuid: &CpuId) -> Result<Self> {
+        let mut channel = Channel::new(stream);
+        let hello = channel.recv(|decoder| {
+            decode_hello(decoder)?;
+            let regions = (0..decoder.u32()?)
+                .map(|_| {
+                    Ok(MemoryRegion {
+                        guest_addr: decoder.u64()?,
+                        size: decoder.u64()?,
+                        host_offset: decoder.u64()?,
+                    })
+                })
+                .collect::<Result<Vec<_>>>()?;
+            let num_cpus = decoder.u32()? as usize;
+            let cpuid = decode_cpuid(decoder)?;
+            Ok((regions, num_cpus, cpuid))
+        });
+        let checked = hello.and_then(|(regions, num_cpus, cpuid)| {
+            cpuid::check_compatible(&cpuid, supported_cpuid)?;
+            Ok((regions, num_cpus))
+        });
+
+        let mut reply = encode_hello();
+        match checked {
+            Ok((regions, num_cpus)) => {
+                reply.u8(REPLY_ACCEPT);
+                reply.pods(supported_cpuid.as_slice());
+                channel.send(reply)?;
+                Ok(Self {
+                    channel,
+                    regions,
+                    num_cpus,
+                    state: None,
+                })
+            }
+            Err(e) => {
+                reply.u8(REPLY_REJECT);
+                reply.bytes(e.to_string().as_bytes());
+                // The source may have hung up already.
+                let _ = channel.send(reply);
+                Err(e)
+            }
+        }
+    }
+
+    /// Receives guest RAM into `memory`, its host mapping, then the state of
+    /// the guest.
+    ///
+    /// The pages that the source reports as zero are cleared only if they
+    /// are not zero already, so that untouched memory stays unallocated.
+    pub fn receive(&mut self, memory: &mut [u8]) -> Result<()> {
+        loop {
+            let state = self.channel.recv(|decoder| {
+                let size = memory.len();
+                let range = |offset: u64, len: usize| {
+                    usize::try_from(offset)
+                        .ok()
+                        .and_then(|offset| Some(offset..offset.checked_add(len)?))
+                        .filter(|range| range.end <= size)
+                        .ok_or_else(|| {
+                            Error::InvalidMigrationStream(format!(
+                                "Pages at {offset:#x} are out of guest memory"
+                            ))
+                        })
+                };
+                match decoder.u8()? {
+                    MSG_PAGES => {
+                        let offset = decoder.u64()?;
+                        let data = decoder.bytes()?;
+                        memory[range(offset, data.len())?].copy_from_slice(data);
+                        Ok(None)
+                    }
+                    MSG_ZEROES => {
+                        let offset = decoder.u64()?;
+                        let len = decoder.u64()? as usize;
+                        for page in memory[range(offset, len)?].chunks_mut(PAGE_SIZE) {
+                            if !snapshot::is_zero(page) {
+                                page.fill(0);
+                            }
+                        }
+                        Ok(None)
+                    }
+                    MSG_STATE => GuestState::decode(decoder).map(Some),
+                    kind => Err(Error::InvalidMigrationStream(format!(
+                        "Unknown message kind {kind}"
+                    ))),
+                }
+            })?;
+            if let Some(state) = state {
+                self.state = Some(state);
+                return Ok(());
+            }
+        }
+    }
+
+    /// Tells the source that the guest is ready to run on this end.
+    pub fn complete(&mut self) -> Result<()> {
+        let mut encoder = Encoder::new();
+        encoder.u8(REPLY_ACCEPT);
+        self.channel.send(encoder)
+    }
+}
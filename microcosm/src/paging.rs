@@ -0,0 +1,551 @@
+use crate::memory::GuestMemory;
+use sys::kvm_bindings::kvm_sregs;
+
+const CR0_PG: u64 = 1 << 31;
+const CR4_PSE: u64 = 1 << 4;
+const CR4_PAE: u64 = 1 << 5;
+const CR4_LA57: u64 = 1 << 12;
+const EFER_LMA: u64 = 1 << 10;
+const EFER_NXE: u64 = 1 << 11;
+
+const PRESENT: u64 = 1;
+const WRITABLE: u64 = 1 << 1;
+const USER: u64 = 1 << 2;
+const PAGE_SIZE: u64 = 1 << 7;
+const NO_EXECUTE: u64 = 1 << 63;
+
+/// Paging mode selected by CR0, CR4 and EFER
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PagingMode {
+    Disabled,
+
+    /// 2-level 32-bit paging
+    Bits32,
+
+    /// 3-level PAE paging
+    Pae,
+
+    /// 4-level paging
+    Level4,
+
+    /// 5-level paging (LA57)
+    Level5,
+}
+
+impl PagingMode {
+    pub const fn from_sregs(sregs: &kvm_sregs) -> Self {
+        if sregs.cr0 & CR0_PG == 0 {
+            Self::Disabled
+        } else if sregs.efer & EFER_LMA != 0 {
+            if sregs.cr4 & CR4_LA57 != 0 {
+                Self::Level5
+            } else {
+                Self::Level4
+            }
+        } else if sregs.cr4 & CR4_PAE != 0 {
+            Self::Pae
+        } else {
+            Self::Bits32
+        }
+    }
+}
+
+/// Physical address that a linear address maps to, and the access rights of
+/// the page
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Translation {
+    pub phys_addr: u64,
+
+    /// Size of the page containing the address
+    pub page_size: u64,
+
+    pub writable: bool,
+    pub user: bool,
+    pub executable: bool,
+}
+
+/// Reason why a linear address could not be translated
+///
+/// Levels are numbered from 1 (the page table) up to the root table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+pub enum PageFault {
+    #[error("Non-canonical address {0:#x}")]
+    NonCanonical(u64),
+
+    #[error("Page not present (level {level})")]
+    NotPresent { level: u32 },
+
+    #[error("Reserved bit set in page table entry (level {level})")]
+    ReservedBit { level: u32 },
+
+    #[error("Page table entry at {0:#x} is outside guest memory")]
+    OutsideMemory(u64),
+}
+
+/// Translates a linear address to a physical one by walking the page tables
+/// that are currently in use.
+pub fn translate(
+    memory: &GuestMemory,
+    sregs: &kvm_sregs,
+    addr: u64,
+) -> Result<Translation, PageFault> {
+    let read = |entry_addr: u64, size: usize| -> Result<u64, PageFault> {
+        let mut buf = [0; 8];
+        memory
+            .read_slice(&mut buf[..size], entry_addr)
+            .map_err(|_| PageFault::OutsideMemory(entry_addr))?;
+        Ok(u64::from_le_bytes(buf))
+    };
+
+    let mode = PagingMode::from_sregs(sregs);
+    // (table entry size, bits translated per level, number of levels)
+    let (entry_size, bits, levels) = match mode {
+        PagingMode::Disabled => {
+            return Ok(Translation {
+                phys_addr: addr,
+                page_size: 1 << 12,
+                writable: true,
+                user: true,
+                executable: true,
+            })
+        }
+        PagingMode::Bits32 => (4, 10, 2),
+        PagingMode::Pae => (8, 9, 3),
+        PagingMode::Level4 => (8, 9, 4),
+        PagingMode::Level5 => (8, 9, 5),
+    };
+
+    let addr = if entry_size == 4 || levels == 3 {
+        // Linear addresses are 32-bit outside long mode.
+        addr & 0xffff_ffff
+    } else {
+        let unused_bits = 64 - (12 + bits * levels);
+        if ((addr << unused_bits) as i64 >> unused_bits) as u64 != addr {
+            return Err(PageFault::NonCanonical(addr));
+        }
+        addr
+    };
+    let addr_mask = if entry_size == 8 {
+        0x000f_ffff_ffff_f000
+    } else {
+        0xffff_f000
+    };
+    let nx_enabled = entry_size == 8 && sregs.efer & EFER_NXE != 0;
+
+    let mut translation = Translation {
+        phys_addr: 0,
+        page_size: 0,
+        writable: true,
+        user: true,
+        executable: true,
+    };
+    let mut table = if levels == 3 {
+        // The PDPT is 32-byte aligned.
+        sregs.cr3 & 0xffff_ffe0
+    } else {
+        sregs.cr3 & addr_mask
+    };
+    for level in (1..=levels).rev() {
+        let shift = 12 + bits * (level - 1);
+        let index_bits = if levels == 3 && level == 3 { 2 } else { bits };
+        let index = (addr >> shift) & ((1 << index_bits) - 1);
+        let entry = read(table + index * entry_size, entry_size as usize)?;
+        if entry & PRESENT == 0 {
+            return Err(PageFault::NotPresent { level });
+        }
+
+        let mut reserved = if nx_enabled { 0 } else { NO_EXECUTE };
+        if levels == 3 && level == 3 {
+            // PAE PDPTEs have no access rights.
+            reserved |= NO_EXECUTE | 0x1e6;
+        } else {
+            translation.writable &= entry & WRITABLE != 0;
+            translation.user &= entry & USER != 0;
+            translation.executable &= entry & NO_EXECUTE == 0;
+        }
+
+        // 4 MiB pages need CR4.PSE without PAE. 1 GiB pages do not exist
+        // with PAE.
+        let large_page_allowed = match (levels, level) {
+            (2, 2) => sregs.cr4 & CR4_PSE != 0,
+            (_, 2) | (4 | 5, 3) => true,
+            _ => false,
+        };
+        let is_leaf = level == 1 || (large_page_allowed && entry & PAGE_SIZE != 0);
+        if !is_leaf && level >= 4 {
+            reserved |= PAGE_SIZE;
+        }
+        let offset_mask = (1 << shift) - 1;
+        if is_leaf && level > 1 {
+            reserved |= if entry_size == 4 {
+                // Bit 21 is the only one between the PSE-36 address bits and
+                // the 4 MiB frame.
+                1 << 21
+            } else {
+                // Bit 12 is PAT in large pages.
+                offset_mask & addr_mask & !(1 << 12)
+            };
+        }
+        if entry & reserved != 0 {
+            return Err(PageFault::ReservedBit { level });
+        }
+
+        if is_leaf {
+            let mut base = entry & addr_mask & !offset_mask;
+            if entry_size == 4 && level == 2 {
+                // PSE-36: bits 13-20 of a 4 MiB PDE hold bits 32-39 of the
+                // address.
+                base |= ((entry >> 13) & 0xff) << 32;
+            }
+            translation.phys_addr = base | (addr & offset_mask);
+            translation.page_size = 1 << shift;
+            return Ok(translation);
+        }
+        table = entry & addr_mask;
+    }
+    unreachable!()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::{MemoryRegion, Mmapped};
+    use nix::sys::mman::MapFlags;
+    use std::num::NonZeroUsize;
+
+    const MEMORY_SIZE: usize = 1 << 21;
+
+    fn memory() -> GuestMemory {
+        let mmap = Mmapped::new(
+            None,
+            NonZeroUsize::new(MEMORY_SIZE).unwrap(),
+            MapFlags::MAP_PRIVATE,
+            0,
+        )
+        .unwrap();
+        let region = MemoryRegion {
+            guest_addr: 0,
+            size: MEMORY_SIZE as u64,
+            host_offset: 0,
+        };
+        GuestMemory::new(mmap, None, vec![region], None)
+    }
+
+    fn sregs(cr3: u64, cr4: u64, efer: u64) -> kvm_sregs {
+        kvm_sregs {
+            cr0: CR0_PG,
+            cr3,
+            cr4,
+            efer,
+            ..Default::default()
+        }
+    }
+
+    fn set(memory: &GuestMemory, table: u64, index: u64, entry: u64) {
+        memory.write_obj(&entry, table + index * 8).unwrap();
+    }
+
+    fn set32(memory: &GuestMemory, table: u64, index: u64, entry: u32) {
+        memory.write_obj(&entry, table + index * 4).unwrap();
+    }
+
+    fn leaf(phys_addr: u64, page_size: u64, writable: bool, executable: bool) -> Translation {
+        Translation {
+            phys_addr,
+            page_size,
+            writable,
+            user: true,
+            executable,
+        }
+    }
+
+    #[test]
+    fn disabled_is_identity() {
+        let memory = memory();
+        let sregs = kvm_sregs::default();
+        assert_eq!(PagingMode::from_sregs(&sregs), PagingMode::Disabled);
+        assert_eq!(
+            translate(&memory, &sregs, 0x1234_5678_9abc),
+            Ok(leaf(0x1234_5678_9abc, 1 << 12, true, true))
+        );
+    }
+
+    /// PD at 0x1000 and PT at 0x2000
+    fn bits32_tables() -> GuestMemory {
+        let memory = memory();
+        let (user, writable, present) = (USER as u32, WRITABLE as u32, PRESENT as u32);
+        let large = PAGE_SIZE as u32 | present;
+        set32(&memory, 0x1000, 1, 0x2000 | user | writable | present);
+        set32(&memory, 0x2000, 3, 0x0012_3000 | user | present);
+        // PSE-36 bits 0x12 go to bits 32-39 of the address.
+        set32(
+            &memory,
+            0x1000,
+            2,
+            0x00c0_0000 | 0x12 << 13 | user | writable | large,
+        );
+        set32(&memory, 0x1000, 3, 0x0100_0000 | 1 << 21 | large);
+        memory
+    }
+
+    #[test]
+    fn bits32_4kib_page() {
+        let memory = bits32_tables();
+        let sregs = sregs(0x1000, 0, 0);
+        assert_eq!(PagingMode::from_sregs(&sregs), PagingMode::Bits32);
+        let expected = Ok(leaf(0x12_3123, 1 << 12, false, true));
+        assert_eq!(translate(&memory, &sregs, 0x0040_3123), expected);
+        // Linear addresses are truncated to 32 bits.
+        assert_eq!(translate(&memory, &sregs, 0x1_0040_3123), expected);
+    }
+
+    #[test]
+    fn bits32_4mib_page() {
+        let memory = bits32_tables();
+        let sregs = sregs(0x1000, CR4_PSE, 0);
+        assert_eq!(
+            translate(&memory, &sregs, 0x0081_2345),
+            Ok(leaf(0x12_00c1_2345, 1 << 22, true, true))
+        );
+        assert_eq!(
+            translate(&memory, &sregs, 0x00c0_0000),
+            Err(PageFault::ReservedBit { level: 2 })
+        );
+    }
+
+    #[test]
+    fn bits32_large_page_needs_pse() {
+        let memory = bits32_tables();
+        let sregs = sregs(0x1000, 0, 0);
+        // Without CR4.PSE the PDE points to a page table outside memory.
+        assert_eq!(
+            translate(&memory, &sregs, 0x0081_2345),
+            Err(PageFault::OutsideMemory(0x00c2_4000 + 0x12 * 4))
+        );
+    }
+
+    #[test]
+    fn bits32_not_present() {
+        let memory = bits32_tables();
+        let sregs = sregs(0x1000, CR4_PSE, 0);
+        assert_eq!(
+            translate(&memory, &sregs, 0x0100_0000),
+            Err(PageFault::NotPresent { level: 2 })
+        );
+        assert_eq!(
+            translate(&memory, &sregs, 0x0040_4000),
+            Err(PageFault::NotPresent { level: 1 })
+        );
+    }
+
+    /// PDPT at 0x1020, PD at 0x2000 and PT at 0x3000
+    fn pae_tables() -> GuestMemory {
+        let memory = memory();
+        set(&memory, 0x1020, 0, 0x2000 | PRESENT);
+        set(&memory, 0x1020, 1, 0x2000 | WRITABLE | PRESENT);
+        set(&memory, 0x1020, 2, 0x2000 | PAGE_SIZE | PRESENT);
+        set(&memory, 0x1020, 3, 0x2000 | NO_EXECUTE | PRESENT);
+        set(&memory, 0x2000, 0, 0x3000 | USER | WRITABLE | PRESENT);
+        set(&memory, 0x2000, 1, 0x4060_0000 | PAGE_SIZE | USER | PRESENT);
+        set(
+            &memory,
+            0x3000,
+            5,
+            0x8_0000_5000 | USER | WRITABLE | PRESENT,
+        );
+        set(&memory, 0x3000, 6, 0x6000 | NO_EXECUTE | USER | PRESENT);
+        memory
+    }
+
+    #[test]
+    fn pae_pages() {
+        let memory = pae_tables();
+        let sregs = sregs(0x1020, CR4_PAE, 0);
+        assert_eq!(PagingMode::from_sregs(&sregs), PagingMode::Pae);
+        assert_eq!(
+            translate(&memory, &sregs, 0x5abc),
+            Ok(leaf(0x8_0000_5abc, 1 << 12, true, true))
+        );
+        assert_eq!(
+            translate(&memory, &sregs, 0x1_0030_5678),
+            Ok(leaf(0x4070_5678, 1 << 21, false, true))
+        );
+    }
+
+    #[test]
+    fn pae_pdpte_reserved_bits() {
+        let memory = pae_tables();
+        for nxe in [0, EFER_NXE] {
+            let sregs = sregs(0x1020, CR4_PAE, nxe);
+            for addr in [0x4000_0000, 0x8000_0000, 0xc000_0000] {
+                assert_eq!(
+                    translate(&memory, &sregs, addr),
+                    Err(PageFault::ReservedBit { level: 3 })
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn pae_no_execute() {
+        let memory = pae_tables();
+        assert_eq!(
+            translate(&memory, &sregs(0x1020, CR4_PAE, 0), 0x6000),
+            Err(PageFault::ReservedBit { level: 1 })
+        );
+        assert_eq!(
+            translate(&memory, &sregs(0x1020, CR4_PAE, EFER_NXE), 0x6000),
+            Ok(leaf(0x6000, 1 << 12, false, false))
+        );
+    }
+
+    #[test]
+    fn pae_not_present() {
+        let memory = pae_tables();
+        set(&memory, 0x1020, 3, 0);
+        let sregs = sregs(0x1020, CR4_PAE, 0);
+        assert_eq!(
+            translate(&memory, &sregs, 0xc000_0000),
+            Err(PageFault::NotPresent { level: 3 })
+        );
+        assert_eq!(
+            translate(&memory, &sregs, 0x0040_0000),
+            Err(PageFault::NotPresent { level: 2 })
+        );
+        assert_eq!(
+            translate(&memory, &sregs, 0x7000),
+            Err(PageFault::NotPresent { level: 1 })
+        );
+    }
+
+    /// PML4 at 0x1000, PDPT at 0x2000, PD at 0x3000 and PT at 0x4000
+    fn level4_tables() -> GuestMemory {
+        let memory = memory();
+        let table = USER | WRITABLE | PRESENT;
+        set(&memory, 0x1000, 0, 0x2000 | table);
+        set(&memory, 0x1000, 1, 0x2000 | PAGE_SIZE | table);
+        set(&memory, 0x1000, 256, 0x2000 | USER | PRESENT);
+        set(&memory, 0x2000, 0, 0x3000 | table);
+        set(&memory, 0x2000, 1, 0x1_4000_0000 | PAGE_SIZE | table);
+        set(
+            &memory,
+            0x2000,
+            2,
+            0x8000_0000 | 1 << 13 | PAGE_SIZE | table,
+        );
+        set(&memory, 0x3000, 0, 0x4000 | table);
+        // Bit 12 is PAT, not an address bit.
+        set(
+            &memory,
+            0x3000,
+            1,
+            0x00a0_0000 | 1 << 12 | PAGE_SIZE | table,
+        );
+        set(&memory, 0x4000, 1, 0x7000 | table);
+        set(&memory, 0x4000, 3, 0x9000 | NO_EXECUTE | table);
+        memory
+    }
+
+    fn level4_sregs(efer: u64) -> kvm_sregs {
+        sregs(0x1000, CR4_PAE, EFER_LMA | efer)
+    }
+
+    #[test]
+    fn level4_pages() {
+        let memory = level4_tables();
+        let sregs = level4_sregs(EFER_NXE);
+        assert_eq!(PagingMode::from_sregs(&sregs), PagingMode::Level4);
+        assert_eq!(
+            translate(&memory, &sregs, 0x1234),
+            Ok(leaf(0x7234, 1 << 12, true, true))
+        );
+        assert_eq!(
+            translate(&memory, &sregs, 0x0020_1234),
+            Ok(leaf(0x00a0_1234, 1 << 21, true, true))
+        );
+        assert_eq!(
+            translate(&memory, &sregs, 0x4123_4567),
+            Ok(leaf(0x1_4123_4567, 1 << 30, true, true))
+        );
+        assert_eq!(
+            translate(&memory, &sregs, 0xffff_8000_0000_1234),
+            Ok(leaf(0x7234, 1 << 12, false, true))
+        );
+        assert_eq!(
+            translate(&memory, &sregs, 0x3000),
+            Ok(leaf(0x9000, 1 << 12, true, false))
+        );
+    }
+
+    #[test]
+    fn level4_reserved_bits() {
+        let memory = level4_tables();
+        let sregs = level4_sregs(0);
+        assert_eq!(
+            translate(&memory, &sregs, 0x3000),
+            Err(PageFault::ReservedBit { level: 1 })
+        );
+        assert_eq!(
+            translate(&memory, &sregs, 0x8000_0000),
+            Err(PageFault::ReservedBit { level: 3 })
+        );
+        assert_eq!(
+            translate(&memory, &sregs, 0x80_0000_0000),
+            Err(PageFault::ReservedBit { level: 4 })
+        );
+    }
+
+    #[test]
+    fn level4_non_canonical() {
+        let memory = level4_tables();
+        let sregs = level4_sregs(0);
+        for addr in [
+            0x8000_0000_0000,
+            0xffff_7fff_ffff_ffff,
+            0x0100_0000_0000_0000,
+        ] {
+            assert_eq!(
+                translate(&memory, &sregs, addr),
+                Err(PageFault::NonCanonical(addr))
+            );
+        }
+    }
+
+    #[test]
+    fn level4_not_present() {
+        let memory = level4_tables();
+        let sregs = level4_sregs(0);
+        for (addr, level) in [
+            (0x100_0000_0000, 4),
+            (0xc000_0000, 3),
+            (0x0040_0000, 2),
+            (0x2000, 1),
+        ] {
+            assert_eq!(
+                translate(&memory, &sregs, addr),
+                Err(PageFault::NotPresent { level })
+            );
+        }
+    }
+
+    #[test]
+    fn level5() {
+        let memory = level4_tables();
+        set(&memory, 0x5000, 0, 0x1000 | USER | WRITABLE | PRESENT);
+        let sregs = sregs(0x5000, CR4_PAE | CR4_LA57, EFER_LMA);
+        assert_eq!(PagingMode::from_sregs(&sregs), PagingMode::Level5);
+        // Canonical only with 5-level paging
+        assert_eq!(
+            translate(&memory, &sregs, 0x8000_0000_1234),
+            Ok(leaf(0x7234, 1 << 12, false, true))
+        );
+        assert_eq!(
+            translate(&memory, &sregs, 0x0001_0000_0000_0000),
+            Err(PageFault::NotPresent { level: 5 })
+        );
+        assert_eq!(
+            translate(&memory, &sregs, 0x0100_0000_0000_0000),
+            Err(PageFault::NonCanonical(0x0100_0000_0000_0000))
+        );
+    }
+}
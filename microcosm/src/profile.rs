@@ -0,0 +1,319 @@
+use crate::{cpu::Control, device::PortIoHub};
+use std::{collections::BTreeMap, fmt, sync::Arc, time::Duration};
+use sys::kvm_bindings::{self, kvm_run, KVM_EXIT_IO, KVM_EXIT_IO_IN, KVM_EXIT_IO_OUT};
+
+const NUM_BUCKETS: usize = 64;
+
+/// Histogram of latencies with power-of-two buckets
+///
+/// Bucket `i` counts latencies in `[2^i, 2^(i+1))` nanoseconds, except that
+/// bucket 0 also counts zero.
+#[derive(Debug, Clone)]
+pub struct Histogram {
+    buckets: [u64; NUM_BUCKETS],
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Self {
+            buckets: [0; NUM_BUCKETS],
+        }
+    }
+}
+
+impl Histogram {
+    fn record(&mut self, latency: Duration) {
+        let ns = u64::try_from(latency.as_nanos()).unwrap_or(u64::MAX);
+        self.buckets[ns.max(1).ilog2() as usize] += 1;
+    }
+
+    fn merge(&mut self, other: &Self) {
+        for (a, b) in self.buckets.iter_mut().zip(&other.buckets) {
+            *a += b;
+        }
+    }
+
+    /// Returns the upper bound of each non-empty bucket along with its count.
+    pub fn buckets(&self) -> impl Iterator<Item = (Duration, u64)> + '_ {
+        self.buckets
+            .iter()
+            .enumerate()
+            .filter(|(_, &count)| count > 0)
+            .map(|(i, &count)| (bucket_upper_bound(i), count))
+    }
+
+    /// Returns an upper bound of the `p`-th percentile (0-100).
+    pub fn percentile(&self, p: f64) -> Duration {
+        let total: u64 = self.buckets.iter().sum();
+        let target = (total as f64 * p / 100.0).ceil().max(1.0) as u64;
+        let mut count = 0;
+        for (i, &n) in self.buckets.iter().enumerate() {
+            count += n;
+            if count >= target {
+                return bucket_upper_bound(i);
+            }
+        }
+        Duration::ZERO
+    }
+}
+
+fn bucket_upper_bound(i: usize) -> Duration {
+    Duration::from_nanos(1u64.checked_shl(i as u32 + 1).unwrap_or(u64::MAX))
+}
+
+/// Counter and latency distribution of a kind of VM exit
+#[derive(Debug, Clone, Default)]
+pub struct ExitStats {
+    pub count: u64,
+    pub total: Duration,
+    pub max: Duration,
+    pub histogram: Histogram,
+}
+
+impl ExitStats {
+    fn record(&mut self, latency: Duration) {
+        self.count += 1;
+        self.total += latency;
+        self.max = self.max.max(latency);
+        self.histogram.record(latency);
+    }
+
+    fn merge(&mut self, other: &Self) {
+        self.count += other.count;
+        self.total += other.total;
+        self.max = self.max.max(other.max);
+        self.histogram.merge(&other.histogram);
+    }
+
+    /// Returns an upper bound of the `p`-th percentile (0-100) of the
+    /// latencies.
+    pub fn percentile(&self, p: f64) -> Duration {
+        self.histogram.percentile(p).min(self.max)
+    }
+
+    pub fn mean(&self) -> Duration {
+        match u32::try_from(self.count) {
+            Ok(0) => Duration::ZERO,
+            Ok(count) => self.total / count,
+            Err(_) => Duration::from_nanos((self.total.as_nanos() / u128::from(self.count)) as u64),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum IoDirection {
+    In,
+    Out,
+}
+
+impl fmt::Display for IoDirection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad(match self {
+            Self::In => "in",
+            Self::Out => "out",
+        })
+    }
+}
+
+/// VM exits of a vCPU
+///
+/// Latencies are the time spent handling exits in userspace, from the return
+/// of `KVM_RUN` until the vCPU is about to enter the guest again.
+#[derive(Debug, Clone, Default)]
+pub struct VcpuExitStats {
+    /// Statistics keyed by `KVM_EXIT_*` exit reason
+    pub exits: BTreeMap<u32, ExitStats>,
+
+    /// Statistics of `KVM_EXIT_IO` keyed by port and direction
+    pub port_io: BTreeMap<(u16, IoDirection), ExitStats>,
+}
+
+impl VcpuExitStats {
+    pub(crate) fn record(&mut self, run: &kvm_run, latency: Duration) {
+        self.exits
+            .entry(run.exit_reason)
+            .or_default()
+            .record(latency);
+        if run.exit_reason == KVM_EXIT_IO {
+            let io = unsafe { run.__bindgen_anon_1.io };
+            let direction = match io.direction.into() {
+                KVM_EXIT_IO_IN => IoDirection::In,
+                KVM_EXIT_IO_OUT => IoDirection::Out,
+                _ => return,
+            };
+            self.port_io
+                .entry((io.port, direction))
+                .or_default()
+                .record(latency);
+        }
+    }
+
+    fn merge(&mut self, other: &Self) {
+        for (reason, stats) in &other.exits {
+            self.exits.entry(*reason).or_default().merge(stats);
+        }
+        for (key, stats) in &other.port_io {
+            self.port_io.entry(*key).or_default().merge(stats);
+        }
+    }
+}
+
+/// Snapshot of the VM exit statistics of all the vCPUs
+#[derive(Debug, Clone)]
+pub struct ExitProfile {
+    /// Statistics indexed by vCPU ID
+    pub vcpus: Vec<VcpuExitStats>,
+
+    /// Names of the devices owning the ports that appear in the statistics
+    pub devices: BTreeMap<u16, String>,
+}
+
+impl ExitProfile {
+    /// Returns the statistics of all the vCPUs combined.
+    pub fn total(&self) -> VcpuExitStats {
+        let mut total = VcpuExitStats::default();
+        for stats in &self.vcpus {
+            total.merge(stats);
+        }
+        total
+    }
+}
+
+impl fmt::Display for ExitProfile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        const HEADER: &str = "     Count      Total       Mean     p50 <=     p99 <=        Max";
+
+        let row = |f: &mut fmt::Formatter<'_>, stats: &ExitStats| {
+            writeln!(
+                f,
+                " {:>9} {:>10} {:>10} {:>10} {:>10} {:>10}",
+                stats.count,
+                DisplayDuration(stats.total),
+                DisplayDuration(stats.mean()),
+                DisplayDuration(stats.percentile(50.0)),
+                DisplayDuration(stats.percentile(99.0)),
+                DisplayDuration(stats.max),
+            )
+        };
+
+        let total = self.total();
+        writeln!(f, "{:<28}{HEADER}", "Exit reason")?;
+        for (reason, stats) in &total.exits {
+            write!(f, "{:<28}", exit_reason_name(*reason))?;
+            row(f, stats)?;
+        }
+
+        if !total.port_io.is_empty() {
+            writeln!(f)?;
+            writeln!(f, "{:<12} {:>6} {:<3}    {HEADER}", "Device", "Port", "Dir")?;
+            for ((port, direction), stats) in &total.port_io {
+                let device = self.devices.get(port).map_or("-", String::as_str);
+                write!(f, "{device:<12} {port:#6x} {direction:<3}    ")?;
+                row(f, stats)?;
+            }
+        }
+
+        writeln!(f)?;
+        write!(f, "Exits per vCPU:")?;
+        for (id, stats) in self.vcpus.iter().enumerate() {
+            let count: u64 = stats.exits.values().map(|stats| stats.count).sum();
+            write!(f, " {id}={count}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Formats a duration with a unit, respecting width and alignment.
+struct DisplayDuration(Duration);
+
+impl fmt::Display for DisplayDuration {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let ns = self.0.as_nanos() as f64;
+        let s = if ns < 1e3 {
+            format!("{ns}ns")
+        } else if ns < 1e6 {
+            format!("{:.1}us", ns / 1e3)
+        } else if ns < 1e9 {
+            format!("{:.1}ms", ns / 1e6)
+        } else {
+            format!("{:.1}s", ns / 1e9)
+        };
+        f.pad(&s)
+    }
+}
+
+/// Returns the name of a `KVM_EXIT_*` constant.
+pub fn exit_reason_name(reason: u32) -> &'static str {
+    macro_rules! names {
+        ($($v:ident,)*) => {
+            match reason {
+                $(kvm_bindings::$v => stringify!($v),)*
+                _ => "Unknown",
+            }
+        }
+    }
+    names! {
+        KVM_EXIT_UNKNOWN,
+        KVM_EXIT_EXCEPTION,
+        KVM_EXIT_IO,
+        KVM_EXIT_HYPERCALL,
+        KVM_EXIT_DEBUG,
+        KVM_EXIT_HLT,
+        KVM_EXIT_MMIO,
+        KVM_EXIT_IRQ_WINDOW_OPEN,
+        KVM_EXIT_SHUTDOWN,
+        KVM_EXIT_FAIL_ENTRY,
+        KVM_EXIT_INTR,
+        KVM_EXIT_SET_TPR,
+        KVM_EXIT_TPR_ACCESS,
+        KVM_EXIT_S390_SIEIC,
+        KVM_EXIT_S390_RESET,
+        KVM_EXIT_DCR,
+        KVM_EXIT_NMI,
+        KVM_EXIT_INTERNAL_ERROR,
+        KVM_EXIT_OSI,
+        KVM_EXIT_PAPR_HCALL,
+        KVM_EXIT_S390_UCONTROL,
+        KVM_EXIT_WATCHDOG,
+        KVM_EXIT_S390_TSCH,
+        KVM_EXIT_EPR,
+        KVM_EXIT_SYSTEM_EVENT,
+        KVM_EXIT_S390_STSI,
+        KVM_EXIT_IOAPIC_EOI,
+        KVM_EXIT_HYPERV,
+        KVM_EXIT_ARM_NISV,
+        KVM_EXIT_X86_RDMSR,
+        KVM_EXIT_X86_WRMSR,
+        KVM_EXIT_DIRTY_RING_FULL,
+        KVM_EXIT_AP_RESET_HOLD,
+        KVM_EXIT_X86_BUS_LOCK,
+        KVM_EXIT_XEN,
+        KVM_EXIT_RISCV_SBI,
+        KVM_EXIT_RISCV_CSR,
+        KVM_EXIT_NOTIFY,
+    }
+}
+
+/// Takes snapshots of the VM exit statistics of a guest while it runs
+#[derive(Clone)]
+pub struct ExitProfiler {
+    pub(crate) control: Arc<Control>,
+    pub(crate) port_io_hub: Arc<PortIoHub>,
+}
+
+impl ExitProfiler {
+    pub fn snapshot(&self) -> ExitProfile {
+        let vcpus: Vec<_> = self
+            .control
+            .contexts()
+            .iter()
+            .map(|context| context.exit_stats.lock().unwrap().clone())
+            .collect();
+        let devices = vcpus
+            .iter()
+            .flat_map(|stats| stats.port_io.keys())
+            .filter_map(|&(port, _)| Some((port, self.port_io_hub.device_name(port)?.to_owned())))
+            .collect();
+        ExitProfile { vcpus, devices }
+    }
+}
@@ -0,0 +1,501 @@
+mod codec;
+mod lazy;
+
+pub use codec::{Decoder, Encoder};
+pub use lazy::{LazyMemory, LazyRestore};
+
+use crate::{
+    boot::Bootable,
+    cpu::CpuContext,
+    kvm::{Vcpu, Vm},
+    load::BootProtocol,
+    memory::{MemoryRegion, PAGE_SIZE},
+    Error, KernelParams, Result,
+};
+use nix::{
+    errno::Errno,
+    unistd::{lseek, Whence},
+};
+use std::{
+    ffi::{CString, OsStr},
+    fs::File,
+    os::unix::{ffi::OsStrExt, fs::FileExt, io::AsRawFd},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+use sys::kvm_bindings::{
+    kvm_clock_data, kvm_debugregs, kvm_irqchip, kvm_lapic_state, kvm_msr_entry, kvm_pit_state2,
+    kvm_regs, kvm_sregs, kvm_vcpu_events, kvm_xcrs, kvm_xsave, CpuId, Msrs, KVM_IRQCHIP_IOAPIC,
+    KVM_IRQCHIP_PIC_MASTER, KVM_IRQCHIP_PIC_SLAVE, KVM_MAX_MSR_ENTRIES,
+    KVM_VCPUEVENT_VALID_NMI_PENDING, KVM_VCPUEVENT_VALID_SIPI_VECTOR,
+};
+
+// A snapshot file consists of:
+// - a header with the magic, the format version, reserved flags and the
+//   length of the encoded state,
+// - the state of the guest, encoded with `Encoder`,
+// - the contents of guest RAM, starting at the next page boundary and laid
+//   out as in the host mapping. Zero pages are left as holes.
+
+const MAGIC: [u8; 8] = *b"MCSMSNAP";
+
+/// Version of the file format, incremented on incompatible changes
+const VERSION: u32 = 1;
+
+const HEADER_SIZE: u64 = 24;
+
+/// Returns the offset of guest RAM in a snapshot file.
+fn memory_offset(state_len: u64) -> u64 {
+    (HEADER_SIZE + state_len).next_multiple_of(PAGE_SIZE as u64)
+}
+
+/// State of a vCPU that is not held in guest memory
+pub struct VcpuState {
+    cpuid: CpuId,
+    tsc_khz: u32,
+    regs: kvm_regs,
+    sregs: kvm_sregs,
+    xsave: Box<[u32; 1024]>,
+    xcrs: kvm_xcrs,
+    debugregs: kvm_debugregs,
+    lapic: kvm_lapic_state,
+    mp_state: u32,
+    events: kvm_vcpu_events,
+    msrs: Vec<kvm_msr_entry>,
+}
+
+impl VcpuState {
+    /// Saves the state of a vCPU that is not running, including the MSRs
+    /// among `msr_indices` that it supports.
+    pub fn capture(vcpu: &Vcpu, msr_indices: &[u32]) -> Result<Self> {
+        let mut msrs = Vec::with_capacity(msr_indices.len());
+        let mut indices = msr_indices;
+        while !indices.is_empty() {
+            let chunk = &indices[..indices.len().min(KVM_MAX_MSR_ENTRIES)];
+            let entries = chunk
+                .iter()
+                .map(|&index| kvm_msr_entry {
+                    index,
+                    ..Default::default()
+                })
+                .collect::<Vec<_>>();
+            let mut chunk_msrs = Msrs::from_entries(&entries).unwrap();
+            let n = vcpu.get_msrs(&mut chunk_msrs)?;
+            msrs.extend_from_slice(&chunk_msrs.as_slice()[..n]);
+            // KVM stops at the first MSR that the vCPU does not support.
+            indices = &indices[(n + 1).min(chunk.len())..];
+        }
+
+        Ok(Self {
+            cpuid: vcpu.cpuid()?,
+            tsc_khz: vcpu.tsc_khz()?,
+            regs: vcpu.regs()?,
+            sregs: vcpu.sregs()?,
+            xsave: Box::new(vcpu.xsave()?.region),
+            xcrs: vcpu.xcrs()?,
+            debugregs: vcpu.debugregs()?,
+            lapic: vcpu.lapic()?,
+            mp_state: vcpu.mp_state()?,
+            events: vcpu.vcpu_events()?,
+            msrs,
+        })
+    }
+
+    /// Loads the saved state into a vCPU that has not run yet.
+    pub fn restore(&self, vcpu: &Vcpu) -> Result<()> {
+        vcpu.set_cpuid(&self.cpuid)?;
+        if vcpu.tsc_khz()? != self.tsc_khz {
+            vcpu.set_tsc_khz(self.tsc_khz)?;
+        }
+        vcpu.set_mp_state(self.mp_state)?;
+        vcpu.set_regs(&self.regs)?;
+        // The APIC base in the special registers must be set before the
+        // local APIC, and both before the MSRs that depend on them.
+        vcpu.set_sregs(&self.sregs)?;
+        let mut xsave = kvm_xsave::default();
+        xsave.region = *self.xsave;
+        vcpu.set_xsave(&xsave)?;
+        vcpu.set_xcrs(&self.xcrs)?;
+        vcpu.set_debugregs(&self.debugregs)?;
+        vcpu.set_lapic(&self.lapic)?;
+
+        for chunk in self.msrs.chunks(KVM_MAX_MSR_ENTRIES) {
+            let mut chunk = chunk;
+            while !chunk.is_empty() {
+                let n = vcpu.set_msrs(&Msrs::from_entries(chunk).unwrap())?;
+                if n < chunk.len() {
+                    eprintln!("Failed to restore MSR {:#x}", chunk[n].index);
+                }
+                chunk = &chunk[(n + 1).min(chunk.len())..];
+            }
+        }
+
+        let mut events = self.events;
+        events.flags |= KVM_VCPUEVENT_VALID_NMI_PENDING | KVM_VCPUEVENT_VALID_SIPI_VECTOR;
+        vcpu.set_vcpu_events(&events)?;
+        Ok(())
+    }
+
+    fn encode(&self, encoder: &mut Encoder) {
+        encoder.pods(self.cpuid.as_slice());
+        encoder.u32(self.tsc_khz);
+        encoder.pod(&self.regs);
+        encoder.pod(&self.sregs);
+        encoder.pod(&*self.xsave);
+        encoder.pod(&self.xcrs);
+        encoder.pod(&self.debugregs);
+        encoder.pod(&self.lapic);
+        encoder.u32(self.mp_state);
+        encoder.pod(&self.events);
+        encoder.pods(&self.msrs);
+    }
+
+    fn decode(decoder: &mut Decoder) -> Result<Self> {
+        let cpuid = CpuId::from_entries(&decoder.pods()?)
+            .map_err(|e| Error::InvalidSnapshot(format!("Invalid CPUID: {e:?}")))?;
+        Ok(Self {
+            cpuid,
+            tsc_khz: decoder.u32()?,
+            regs: decoder.pod()?,
+            sregs: decoder.pod()?,
+            xsave: Box::new(decoder.pod()?),
+            xcrs: decoder.pod()?,
+            debugregs: decoder.pod()?,
+            lapic: decoder.pod()?,
+            mp_state: decoder.u32()?,
+            events: decoder.pod()?,
+            msrs: decoder.pods()?,
+        })
+    }
+}
+
+/// State of the in-kernel devices and clock of a VM
+pub struct VmState {
+    clock: kvm_clock_data,
+    irqchips: Vec<kvm_irqchip>,
+    pit: kvm_pit_state2,
+}
+
+impl VmState {
+    pub fn capture(vm: &Vm) -> Result<Self> {
+        let irqchips = [
+            KVM_IRQCHIP_PIC_MASTER,
+            KVM_IRQCHIP_PIC_SLAVE,
+            KVM_IRQCHIP_IOAPIC,
+        ]
+        .map(|chip_id| vm.irqchip(chip_id))
+        .into_iter()
+        .collect::<nix::Result<_>>()?;
+        Ok(Self {
+            clock: vm.clock()?,
+            irqchips,
+            pit: vm.pit2()?,
+        })
+    }
+
+    pub fn restore(&self, vm: &Vm) -> Result<()> {
+        for irqchip in &self.irqchips {
+            vm.set_irqchip(irqchip)?;
+        }
+        vm.set_pit2(&self.pit)?;
+        // Without flags, kvmclock resumes from the saved value instead of
+        // accounting for the time spent in the snapshot.
+        vm.set_clock(&kvm_clock_data {
+            clock: self.clock.clock,
+            ..Default::default()
+        })?;
+        Ok(())
+    }
+
+    fn encode(&self, encoder: &mut Encoder) {
+        encoder.pod(&self.clock);
+        encoder.pods(&self.irqchips);
+        encoder.pod(&self.pit);
+    }
+
+    fn decode(decoder: &mut Decoder) -> Result<Self> {
+        Ok(Self {
+            clock: decoder.pod()?,
+            irqchips: decoder.pods()?,
+            pit: decoder.pod()?,
+        })
+    }
+}
+
+/// State saved by a port I/O device
+pub struct DeviceState {
+    pub name: String,
+
+    /// First port of the device, which tells apart devices of the same type
+    pub port: u16,
+
+    pub data: Vec<u8>,
+}
+
+/// Everything needed to rebuild a guest, except for the contents of RAM
+pub struct GuestState {
+    pub regions: Vec<MemoryRegion>,
+
+    // Needed to reboot the restored guest
+    pub kernel_path: PathBuf,
+    pub kernel_params: KernelParams,
+    pub bootable: Bootable,
+
+    pub vm: VmState,
+    pub vcpus: Vec<VcpuState>,
+    pub devices: Vec<DeviceState>,
+}
+
+impl GuestState {
+    /// Loads the saved state into a VM whose vCPUs have not run yet.
+    pub fn restore(&self, vm: &Vm, contexts: &[Arc<CpuContext>]) -> Result<()> {
+        for (state, context) in self.vcpus.iter().zip(contexts) {
+            state.restore(&context.vcpu)?;
+        }
+        self.vm.restore(vm)
+    }
+
+    pub fn encode(&self, encoder: &mut Encoder) {
+        encoder.u32(self.regions.len() as u32);
+        for region in &self.regions {
+            encoder.u64(region.guest_addr);
+            encoder.u64(region.size);
+            encoder.u64(region.host_offset);
+        }
+
+        encoder.bytes(self.kernel_path.as_os_str().as_bytes());
+        let params = &self.kernel_params;
+        match &params.cmdline {
+            Some(cmdline) => {
+                encoder.u8(1);
+                encoder.bytes(cmdline.as_bytes());
+            }
+            None => encoder.u8(0),
+        }
+        match &params.initrd_path {
+            Some(path) => {
+                encoder.u8(1);
+                encoder.bytes(path.as_os_str().as_bytes());
+            }
+            None => encoder.u8(0),
+        }
+        encoder.u32(params.module_paths.len() as u32);
+        for path in &params.module_paths {
+            encoder.bytes(path.as_os_str().as_bytes());
+        }
+        encoder.u8(match self.bootable.protocol {
+            BootProtocol::Linux32 => 0,
+            BootProtocol::Linux64 => 1,
+            BootProtocol::Pvh => 2,
+            BootProtocol::Multiboot => 3,
+        });
+        encoder.u64(self.bootable.entry_addr);
+        encoder.u64(self.bootable.params_addr);
+
+        self.vm.encode(encoder);
+        encoder.u32(self.vcpus.len() as u32);
+        for vcpu in &self.vcpus {
+            vcpu.encode(encoder);
+        }
+        encoder.u32(self.devices.len() as u32);
+        for device in &self.devices {
+            encoder.bytes(device.name.as_bytes());
+            encoder.u16(device.port);
+            encoder.bytes(&device.data);
+        }
+    }
+
+    pub fn decode(decoder: &mut Decoder) -> Result<Self> {
+        let path = |decoder: &mut Decoder| -> Result<PathBuf> {
+            Ok(Path::new(OsStr::from_bytes(decoder.bytes()?)).to_owned())
+        };
+
+        let regions = (0..decoder.u32()?)
+            .map(|_| {
+                Ok(MemoryRegion {
+                    guest_addr: decoder.u64()?,
+                    size: decoder.u64()?,
+                    host_offset: decoder.u64()?,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let kernel_path = path(decoder)?;
+        let cmdline =
+            match decoder.u8()? {
+                0 => None,
+                _ => Some(CString::new(decoder.bytes()?).map_err(|_| {
+                    Error::InvalidSnapshot("Invalid kernel command line".to_owned())
+                })?),
+            };
+        let initrd_path = match decoder.u8()? {
+            0 => None,
+            _ => Some(path(decoder)?),
+        };
+        let module_paths = (0..decoder.u32()?)
+            .map(|_| path(decoder))
+            .collect::<Result<_>>()?;
+        let protocol = match decoder.u8()? {
+            0 => BootProtocol::Linux32,
+            1 => BootProtocol::Linux64,
+            2 => BootProtocol::Pvh,
+            3 => BootProtocol::Multiboot,
+            n => return Err(Error::InvalidSnapshot(format!("Unknown boot protocol {n}"))),
+        };
+        let bootable = Bootable {
+            protocol,
+            entry_addr: decoder.u64()?,
+            params_addr: decoder.u64()?,
+        };
+
+        let vm = VmState::decode(decoder)?;
+        let vcpus = (0..decoder.u32()?)
+            .map(|_| VcpuState::decode(decoder))
+            .collect::<Result<Vec<_>>>()?;
+        let devices = (0..decoder.u32()?)
+            .map(|_| {
+                let name = String::from_utf8(decoder.bytes()?.to_vec())
+                    .map_err(|_| Error::InvalidSnapshot("Invalid device name".to_owned()))?;
+                Ok(DeviceState {
+                    name,
+                    port: decoder.u16()?,
+                    data: decoder.bytes()?.to_vec(),
+                })
+            })
+            .collect::<Result<_>>()?;
+
+        if regions.is_empty() || vcpus.is_empty() {
+            return Err(Error::InvalidSnapshot("No memory or vCPUs".to_owned()));
+        }
+        Ok(Self {
+            regions,
+            kernel_path,
+            kernel_params: KernelParams {
+                cmdline,
+                initrd_path,
+                module_paths,
+            },
+            bootable,
+            vm,
+            vcpus,
+            devices,
+        })
+    }
+
+    /// Returns the size of guest RAM.
+    pub fn memory_size(&self) -> u64 {
+        self.regions.iter().map(|region| region.size).sum()
+    }
+}
+
+/// Writes `state` and `memory`, the host mapping of guest RAM, to a snapshot
+/// file at `path`.
+pub fn write(path: &Path, state: &GuestState, memory: &[u8]) -> Result<()> {
+    let mut encoder = Encoder::new();
+    state.encode(&mut encoder);
+    let state = encoder.finish();
+
+    let mut header = Encoder::new();
+    header.pod(&MAGIC);
+    header.u32(VERSION);
+    header.u32(0);
+    header.u64(state.len() as u64);
+
+    let file = File::create(path)?;
+    file.write_all_at(&header.finish(), 0)?;
+    file.write_all_at(&state, HEADER_SIZE)?;
+
+    let memory_offset = memory_offset(state.len() as u64);
+    let mut run_start = None;
+    for (i, page) in memory.chunks(PAGE_SIZE).enumerate() {
+        let offset = i * PAGE_SIZE;
+        match (is_zero(page), run_start) {
+            (false, None) => run_start = Some(offset),
+            (true, Some(start)) => {
+                file.write_all_at(&memory[start..offset], memory_offset + start as u64)?;
+                run_start = None;
+            }
+            _ => {}
+        }
+    }
+    if let Some(start) = run_start {
+        file.write_all_at(&memory[start..], memory_offset + start as u64)?;
+    }
+    file.set_len(memory_offset + memory.len() as u64)?;
+    Ok(())
+}
+
+pub fn is_zero(page: &[u8]) -> bool {
+    let (prefix, words, suffix) = unsafe { page.align_to::<u64>() };
+    prefix.iter().chain(suffix).all(|&b| b == 0) && words.iter().all(|&word| word == 0)
+}
+
+/// A snapshot file whose state has been read
+pub struct SnapshotFile {
+    file: File,
+    pub state: GuestState,
+    memory_offset: u64,
+}
+
+impl SnapshotFile {
+    pub fn open(path: &Path) -> Result<Self> {
+        let file = File::open(path)?;
+        let mut header = [0; HEADER_SIZE as usize];
+        file.read_exact_at(&mut header, 0)?;
+        let mut decoder = Decoder::new(&header);
+        if decoder.pod::<[u8; 8]>()? != MAGIC {
+            return Err(Error::InvalidSnapshot("Not a snapshot file".to_owned()));
+        }
+        let version = decoder.u32()?;
+        if version != VERSION {
+            return Err(Error::UnsupportedSnapshotVersion(version));
+        }
+        let _flags = decoder.u32()?;
+        let state_len = decoder.u64()?;
+        decoder.finish()?;
+
+        let len = usize::try_from(state_len)
+            .map_err(|_| Error::InvalidSnapshot(format!("Invalid state length {state_len}")))?;
+        let mut buf = vec![0; len];
+        file.read_exact_at(&mut buf, HEADER_SIZE)?;
+        let mut decoder = Decoder::new(&buf);
+        let state = GuestState::decode(&mut decoder)?;
+        decoder.finish()?;
+
+        Ok(Self {
+            file,
+            state,
+            memory_offset: memory_offset(state_len),
+        })
+    }
+
+    /// Reads the contents of guest RAM into `memory`, its host mapping.
+    ///
+    /// Only the pages stored in the file are written to, unless `zero` is
+    /// set, in which case the others are cleared first.
+    pub fn read_memory(&self, memory: &mut [u8], zero: bool) -> Result<()> {
+        if zero {
+            memory.fill(0);
+        }
+        let fd = self.file.as_raw_fd();
+        let start = self.memory_offset;
+        let end = start + memory.len() as u64;
+        let mut offset = start;
+        while offset < end {
+            let data = match lseek(fd, offset as i64, Whence::SeekData) {
+                Ok(data) => data as u64,
+                // There is no data past `offset`.
+                Err(Errno::ENXIO) => break,
+                Err(e) => return Err(e.into()),
+            };
+            if data >= end {
+                break;
+            }
+            let hole = (lseek(fd, data as i64, Whence::SeekHole)? as u64).min(end);
+            let range = (data - start) as usize..(hole - start) as usize;
+            self.file.read_exact_at(&mut memory[range], data)?;
+            offset = hole;
+        }
+        Ok(())
+    }
+}
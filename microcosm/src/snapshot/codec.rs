@@ -0,0 +1,238 @@
+use crate::{Error, Result};
+use std::mem::size_of;
+use sys::kvm_bindings::{
+    kvm_clock_data, kvm_cpuid_entry2, kvm_debugregs, kvm_irqchip, kvm_lapic_state, kvm_msr_entry,
+    kvm_pit_state2, kvm_regs, kvm_sregs, kvm_vcpu_events, kvm_xcrs,
+};
+
+/// Data that is saved as its in-memory representation
+///
+/// # Safety
+///
+/// Implementors must not contain padding, and any bit pattern must be a valid
+/// value.
+pub unsafe trait Pod: Copy {}
+
+unsafe impl Pod for u8 {}
+unsafe impl Pod for u32 {}
+unsafe impl<T: Pod, const N: usize> Pod for [T; N] {}
+
+// The structures of the KVM API are laid out with explicit padding so that
+// they are the same for 32-bit and 64-bit userspace.
+unsafe impl Pod for kvm_clock_data {}
+unsafe impl Pod for kvm_cpuid_entry2 {}
+unsafe impl Pod for kvm_debugregs {}
+unsafe impl Pod for kvm_irqchip {}
+unsafe impl Pod for kvm_lapic_state {}
+unsafe impl Pod for kvm_msr_entry {}
+unsafe impl Pod for kvm_pit_state2 {}
+unsafe impl Pod for kvm_regs {}
+unsafe impl Pod for kvm_sregs {}
+unsafe impl Pod for kvm_vcpu_events {}
+unsafe impl Pod for kvm_xcrs {}
+
+/// Serializes state in the little-endian encoding of snapshots
+#[derive(Default)]
+pub struct Encoder {
+    buf: Vec<u8>,
+}
+
+impl Encoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn u8(&mut self, value: u8) {
+        self.buf.push(value);
+    }
+
+    pub fn u16(&mut self, value: u16) {
+        self.buf.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn u32(&mut self, value: u32) {
+        self.buf.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn u64(&mut self, value: u64) {
+        self.buf.extend_from_slice(&value.to_le_bytes());
+    }
+
+    /// Writes a length-prefixed byte string.
+    pub fn bytes(&mut self, bytes: &[u8]) {
+        self.u64(bytes.len() as u64);
+        self.buf.extend_from_slice(bytes);
+    }
+
+    pub fn pod<T: Pod>(&mut self, value: &T) {
+        let bytes = unsafe {
+            std::slice::from_raw_parts(std::ptr::from_ref(value).cast::<u8>(), size_of::<T>())
+        };
+        self.buf.extend_from_slice(bytes);
+    }
+
+    /// Writes a count-prefixed array.
+    pub fn pods<T: Pod>(&mut self, values: &[T]) {
+        self.u32(values.len() as u32);
+        for value in values {
+            self.pod(value);
+        }
+    }
+
+    pub fn finish(self) -> Vec<u8> {
+        self.buf
+    }
+}
+
+/// Deserializes state written by [`Encoder`]
+pub struct Decoder<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Decoder<'a> {
+    pub fn new(buf: &'a [u8]) -> Self {
+        Self { buf }
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        if len > self.buf.len() {
+            return Err(Error::InvalidSnapshot("Truncated state".to_owned()));
+        }
+        let (bytes, rest) = self.buf.split_at(len);
+        self.buf = rest;
+        Ok(bytes)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N]> {
+        Ok(self.take(N)?.try_into().unwrap())
+    }
+
+    pub fn u8(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn u16(&mut self) -> Result<u16> {
+        self.array().map(u16::from_le_bytes)
+    }
+
+    pub fn u32(&mut self) -> Result<u32> {
+        self.array().map(u32::from_le_bytes)
+    }
+
+    pub fn u64(&mut self) -> Result<u64> {
+        self.array().map(u64::from_le_bytes)
+    }
+
+    pub fn bytes(&mut self) -> Result<&'a [u8]> {
+        let len = self.u64()?;
+        let len = usize::try_from(len)
+            .map_err(|_| Error::InvalidSnapshot(format!("Invalid length {len}")))?;
+        self.take(len)
+    }
+
+    pub fn pod<T: Pod>(&mut self) -> Result<T> {
+        let bytes = self.take(size_of::<T>())?;
+        Ok(unsafe { bytes.as_ptr().cast::<T>().read_unaligned() })
+    }
+
+    pub fn pods<T: Pod>(&mut self) -> Result<Vec<T>> {
+        let len = self.u32()?;
+        (0..len).map(|_| self.pod()).collect()
+    }
+
+    /// Checks that all the input has been consumed.
+    pub fn finish(self) -> Result<()> {
+        if self.buf.is_empty() {
+            Ok(())
+        } else {
+            Err(Error::InvalidSnapshot(format!(
+                "{} bytes of trailing data",
+                self.buf.len()
+            )))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode() -> Vec<u8> {
+        let mut encoder = Encoder::new();
+        encoder.u8(0x12);
+        encoder.u16(0x3456);
+        encoder.u32(0x789a_bcde);
+        encoder.u64(0x0123_4567_89ab_cdef);
+        encoder.bytes(b"state");
+        encoder.pods(&[[1u8, 2, 3], [4, 5, 6]]);
+        encoder.pod(&kvm_msr_entry {
+            index: 0x10,
+            reserved: 0,
+            data: 42,
+        });
+        encoder.finish()
+    }
+
+    #[test]
+    fn round_trip() {
+        let buf = encode();
+        let mut decoder = Decoder::new(&buf);
+        assert_eq!(decoder.u8().unwrap(), 0x12);
+        assert_eq!(decoder.u16().unwrap(), 0x3456);
+        assert_eq!(decoder.u32().unwrap(), 0x789a_bcde);
+        assert_eq!(decoder.u64().unwrap(), 0x0123_4567_89ab_cdef);
+        assert_eq!(decoder.bytes().unwrap(), b"state");
+        assert_eq!(decoder.pods::<[u8; 3]>().unwrap(), [[1, 2, 3], [4, 5, 6]]);
+        let msr = decoder.pod::<kvm_msr_entry>().unwrap();
+        assert_eq!((msr.index, msr.data), (0x10, 42));
+        decoder.finish().unwrap();
+    }
+
+    #[test]
+    fn encoding_is_little_endian() {
+        let mut encoder = Encoder::new();
+        encoder.u32(0x0102_0304);
+        encoder.bytes(&[0xaa]);
+        assert_eq!(encoder.finish(), [4, 3, 2, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0xaa]);
+    }
+
+    #[test]
+    fn truncated_input_is_rejected() {
+        let buf = encode();
+        for len in 0..buf.len() {
+            let mut decoder = Decoder::new(&buf[..len]);
+            let result = (|| {
+                decoder.u8()?;
+                decoder.u16()?;
+                decoder.u32()?;
+                decoder.u64()?;
+                decoder.bytes()?;
+                decoder.pods::<[u8; 3]>()?;
+                decoder.pod::<kvm_msr_entry>()
+            })();
+            assert!(
+                matches!(result, Err(Error::InvalidSnapshot(_))),
+                "truncated to {len} bytes"
+            );
+        }
+    }
+
+    #[test]
+    fn oversized_length_is_rejected() {
+        let mut encoder = Encoder::new();
+        encoder.u64(u64::MAX);
+        let buf = encoder.finish();
+        assert!(matches!(
+            Decoder::new(&buf).bytes(),
+            Err(Error::InvalidSnapshot(_))
+        ));
+    }
+
+    #[test]
+    fn trailing_data_is_rejected() {
+        let buf = encode();
+        let mut decoder = Decoder::new(&buf);
+        decoder.u8().unwrap();
+        assert!(matches!(decoder.finish(), Err(Error::InvalidSnapshot(_))));
+    }
+}
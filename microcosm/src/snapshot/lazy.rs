@@ -0,0 +1,361 @@
+use super::SnapshotFile;
+use crate::{
+    memory::{MemoryBackend, MemoryOptions, Mmapped, PAGE_SIZE},
+    thread::ThreadConfig,
+    Error, Result,
+};
+use nix::{
+    errno::Errno,
+    libc,
+    unistd::{lseek, read, Whence},
+};
+use std::{
+    fs::File,
+    mem::size_of,
+    os::{
+        fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+        unix::fs::FileExt,
+    },
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Weak,
+    },
+};
+use sys::userfaultfd::{
+    self, uffd_msg, uffdio_api, uffdio_copy, uffdio_range, uffdio_register, uffdio_zeropage,
+    UFFDIO_REGISTER_MODE_MISSING, UFFD_API, UFFD_EVENT_PAGEFAULT, UFFD_FEATURE_MISSING_HUGETLBFS,
+    UFFD_FEATURE_MISSING_SHMEM,
+};
+
+/// How long the fault handler waits for faults before checking whether
+/// guest RAM is still mapped
+const POLL_TIMEOUT_MS: libc::c_int = 100;
+
+/// Amount of memory copied at once by the prefetcher, unless pages are
+/// larger
+const PREFETCH_CHUNK_SIZE: usize = 1 << 20;
+
+/// How guest RAM is loaded from a snapshot file when restoring lazily
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LazyRestore {
+    /// Pages are read from the file only when first accessed.
+    OnDemand,
+
+    /// As with `OnDemand`, and a background thread copies the rest of the
+    /// file so that the guest eventually stops waiting for it.
+    Prefetch,
+}
+
+/// Guest RAM registered with userfaultfd, whose missing pages are served
+/// from a snapshot file
+pub struct LazyMemory {
+    uffd: OwnedFd,
+    file: File,
+    memory_offset: u64,
+
+    /// Host address of guest RAM
+    base: u64,
+    size: usize,
+
+    /// Granularity at which pages are faulted in, which is the size of
+    /// hugetlb pages for hugetlb-backed memory
+    page_size: usize,
+
+    /// Number of bytes loaded so far
+    loaded: AtomicUsize,
+}
+
+impl LazyMemory {
+    /// Registers `memory`, freshly mapped by `backend`, so that accesses to
+    /// its pages block until they are loaded from `snapshot`.
+    pub fn register(
+        snapshot: &SnapshotFile,
+        memory: &Mmapped<u8>,
+        backend: &MemoryBackend,
+        options: &MemoryOptions,
+    ) -> Result<Self> {
+        let (page_size, features) = match backend {
+            MemoryBackend::Anonymous | MemoryBackend::TransparentHugePages => (PAGE_SIZE, 0),
+            MemoryBackend::HugeTlb(page_size) => {
+                (page_size.bytes(), UFFD_FEATURE_MISSING_HUGETLBFS)
+            }
+            MemoryBackend::Memfd { .. } => (PAGE_SIZE, UFFD_FEATURE_MISSING_SHMEM),
+            MemoryBackend::HugeTlbFs(_) | MemoryBackend::File(_) => {
+                return Err(Error::LazyRestoreUnsupported(
+                    "memory backed by a file keeps its previous contents".to_owned(),
+                ))
+            }
+        };
+        if options.prefault.is_some() || options.lock {
+            return Err(Error::LazyRestoreUnsupported(
+                "memory is faulted in before the guest starts".to_owned(),
+            ));
+        }
+
+        let uffd = open_userfaultfd()?;
+        let mut api = uffdio_api {
+            api: UFFD_API.into(),
+            features: features.into(),
+            ioctls: 0,
+        };
+        unsafe { userfaultfd::uffdio_api(uffd.as_raw_fd(), &raw mut api) }.map_err(|e| {
+            if e == Errno::EINVAL {
+                Error::LazyRestoreUnsupported(
+                    "the host cannot handle faults in this type of memory".to_owned(),
+                )
+            } else {
+                Error::from(e)
+            }
+        })?;
+
+        let memory = memory.as_slice();
+        let base = memory.as_ptr() as u64;
+        let mut register = uffdio_register {
+            range: uffdio_range {
+                start: base,
+                len: memory.len() as u64,
+            },
+            mode: UFFDIO_REGISTER_MODE_MISSING.into(),
+            ioctls: 0,
+        };
+        unsafe { userfaultfd::uffdio_register(uffd.as_raw_fd(), &raw mut register) }?;
+
+        Ok(Self {
+            uffd,
+            file: snapshot.file.try_clone()?,
+            memory_offset: snapshot.memory_offset,
+            base,
+            size: memory.len(),
+            page_size,
+            loaded: AtomicUsize::new(0),
+        })
+    }
+
+    /// Starts serving faults in `memory`, the mapping registered with
+    /// [`LazyMemory::register`], and with [`LazyRestore::Prefetch`], copying
+    /// all of the snapshot into it in the background.
+    ///
+    /// The threads run with `thread_config`, and exit once all the pages are
+    /// loaded or the mapping is dropped.
+    pub fn spawn(
+        self,
+        memory: &Weak<Mmapped<u8>>,
+        mode: LazyRestore,
+        thread_config: &ThreadConfig,
+    ) -> std::io::Result<()> {
+        let this = Arc::new(self);
+        if mode == LazyRestore::Prefetch {
+            let this = this.clone();
+            let memory = memory.clone();
+            let thread_config = thread_config.clone();
+            std::thread::Builder::new()
+                .name("memory-prefetch".to_owned())
+                .spawn(move || {
+                    if let Err(e) = thread_config.apply() {
+                        eprintln!("Failed to configure the memory prefetch thread: {e}");
+                    }
+                    if let Err(e) = this.prefetch(&memory) {
+                        eprintln!("Failed to prefetch guest memory: {e}");
+                    }
+                })?;
+        }
+        let memory = memory.clone();
+        let thread_config = thread_config.clone();
+        std::thread::Builder::new()
+            .name("memory-faults".to_owned())
+            .spawn(move || {
+                if let Err(e) = thread_config.apply() {
+                    eprintln!("Failed to configure the memory fault thread: {e}");
+                }
+                if let Err(e) = this.serve(&memory) {
+                    eprintln!("Failed to load guest memory: {e}");
+                }
+            })?;
+        Ok(())
+    }
+
+    fn is_loaded(&self) -> bool {
+        self.loaded.load(Ordering::Relaxed) >= self.size
+    }
+
+    /// Loads the pages that are faulted on until all of them are loaded.
+    fn serve(&self, memory: &Weak<Mmapped<u8>>) -> Result<()> {
+        let fd = self.uffd.as_raw_fd();
+        let mut buf = vec![0; self.page_size];
+        while !self.is_loaded() {
+            let mut pollfd = libc::pollfd {
+                fd,
+                events: libc::POLLIN,
+                revents: 0,
+            };
+            let n = unsafe { libc::poll(&raw mut pollfd, 1, POLL_TIMEOUT_MS) };
+            if n < 0 {
+                match Errno::last() {
+                    Errno::EINTR => continue,
+                    e => return Err(e.into()),
+                }
+            }
+            let Some(_memory) = memory.upgrade() else {
+                break;
+            };
+            if n == 0 {
+                continue;
+            }
+
+            let mut msg = [0; size_of::<uffd_msg>()];
+            match read(fd, &mut msg) {
+                Ok(_) => {}
+                // Another thread woke up the faulting one in the meantime.
+                Err(Errno::EAGAIN) => continue,
+                Err(e) => return Err(e.into()),
+            }
+            let msg = unsafe { msg.as_ptr().cast::<uffd_msg>().read_unaligned() };
+            if u32::from(msg.event) != UFFD_EVENT_PAGEFAULT {
+                continue;
+            }
+            let arg = msg.arg;
+            let address = unsafe { arg.pagefault.address };
+            let offset = (address - self.base) as usize & !(self.page_size - 1);
+
+            self.file
+                .read_exact_at(&mut buf, self.memory_offset + offset as u64)?;
+            if self.copy(offset, &buf)? < buf.len() {
+                // The page was loaded by the prefetcher, which may not have
+                // woken up the thread that faulted on it.
+                let mut range = uffdio_range {
+                    start: self.base + offset as u64,
+                    len: self.page_size as u64,
+                };
+                unsafe { userfaultfd::uffdio_wake(fd, &raw mut range) }?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Copies the whole snapshot into guest RAM, skipping the pages that were
+    /// already faulted in.
+    fn prefetch(&self, memory: &Weak<Mmapped<u8>>) -> Result<()> {
+        let chunk_size = PREFETCH_CHUNK_SIZE.max(self.page_size);
+        let mut buf = vec![0; chunk_size];
+        for offset in (0..self.size).step_by(chunk_size) {
+            if self.is_loaded() {
+                break;
+            }
+            let Some(_memory) = memory.upgrade() else {
+                break;
+            };
+            let buf = &mut buf[..chunk_size.min(self.size - offset)];
+            // Hugetlb pages cannot be mapped to the zero page.
+            if self.page_size == PAGE_SIZE && self.is_hole(offset, buf.len())? {
+                self.zero(offset, buf.len())?;
+            } else {
+                self.file
+                    .read_exact_at(buf, self.memory_offset + offset as u64)?;
+                self.copy(offset, buf)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns whether the snapshot holds no data for `len` bytes at
+    /// `offset` in guest RAM.
+    fn is_hole(&self, offset: usize, len: usize) -> Result<bool> {
+        let start = self.memory_offset + offset as u64;
+        match lseek(self.file.as_raw_fd(), start as i64, Whence::SeekData) {
+            Ok(data) => Ok(data as u64 >= start + len as u64),
+            // There is no data past `start`.
+            Err(Errno::ENXIO) => Ok(true),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Copies `src` to `offset` in guest RAM, and returns the number of bytes
+    /// copied, which excludes the pages that were already loaded.
+    fn copy(&self, offset: usize, src: &[u8]) -> Result<usize> {
+        self.fill(offset, src.len(), |start, len| {
+            let mut copy = uffdio_copy {
+                dst: self.base + start as u64,
+                src: src[start - offset..].as_ptr() as u64,
+                len: len as u64,
+                mode: 0,
+                copy: 0,
+            };
+            let result = unsafe { userfaultfd::uffdio_copy(self.uffd.as_raw_fd(), &raw mut copy) };
+            (result, copy.copy)
+        })
+    }
+
+    /// Maps the zero page to `len` bytes at `offset` in guest RAM, and returns
+    /// the number of bytes mapped, which excludes the pages that were already
+    /// loaded.
+    fn zero(&self, offset: usize, len: usize) -> Result<usize> {
+        self.fill(offset, len, |start, len| {
+            let mut zeropage = uffdio_zeropage {
+                range: uffdio_range {
+                    start: self.base + start as u64,
+                    len: len as u64,
+                },
+                mode: 0,
+                zeropage: 0,
+            };
+            let result =
+                unsafe { userfaultfd::uffdio_zeropage(self.uffd.as_raw_fd(), &raw mut zeropage) };
+            (result, zeropage.zeropage)
+        })
+    }
+
+    /// Fills `len` bytes at `offset` in guest RAM with `op`, which is given
+    /// the remaining range and returns the result of the ioctl along with the
+    /// number of bytes it filled.
+    fn fill(
+        &self,
+        offset: usize,
+        len: usize,
+        mut op: impl FnMut(usize, usize) -> (nix::Result<libc::c_int>, i64),
+    ) -> Result<usize> {
+        let end = offset + len;
+        let mut pos = offset;
+        let mut filled = 0;
+        while pos < end {
+            let n = match op(pos, end - pos) {
+                (Ok(_), _) => end - pos,
+                // Only part of the range was filled, or none of it if the
+                // mappings changed in the meantime.
+                (Err(Errno::EAGAIN), n) => usize::try_from(n).unwrap_or(0),
+                // The first page was already loaded.
+                (Err(Errno::EEXIST), _) => {
+                    pos += self.page_size;
+                    continue;
+                }
+                (Err(e), _) => return Err(e.into()),
+            };
+            pos += n;
+            filled += n;
+        }
+        self.loaded.fetch_add(filled, Ordering::Relaxed);
+        Ok(filled)
+    }
+}
+
+/// Creates a userfaultfd that can handle the faults raised by KVM on
+/// behalf of the guest.
+fn open_userfaultfd() -> Result<OwnedFd> {
+    let flags = libc::O_CLOEXEC | libc::O_NONBLOCK;
+    let fd = unsafe { libc::syscall(libc::SYS_userfaultfd, flags) };
+    if fd >= 0 {
+        return Ok(unsafe { OwnedFd::from_raw_fd(fd as RawFd) });
+    }
+    // Unless vm.unprivileged_userfaultfd is set, the system call only lets
+    // processes without CAP_SYS_PTRACE handle faults in user mode, but the
+    // device has no such restriction.
+    let e = Errno::last();
+    if e != Errno::EPERM {
+        return Err(e.into());
+    }
+    let device = File::options()
+        .read(true)
+        .write(true)
+        .open("/dev/userfaultfd")?;
+    let fd = unsafe { userfaultfd::userfaultfd_ioc_new(device.as_raw_fd(), flags) }?;
+    Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+}
@@ -0,0 +1,279 @@
+use crate::{Error, Result};
+use std::{fmt, fs::File, os::unix::fs::FileExt, sync::Arc};
+use sys::kvm_bindings::{
+    KVM_STATS_BASE_MASK, KVM_STATS_BASE_POW10, KVM_STATS_BASE_POW2, KVM_STATS_TYPE_CUMULATIVE,
+    KVM_STATS_TYPE_INSTANT, KVM_STATS_TYPE_LINEAR_HIST, KVM_STATS_TYPE_LOG_HIST,
+    KVM_STATS_TYPE_MASK, KVM_STATS_TYPE_PEAK, KVM_STATS_UNIT_BOOLEAN, KVM_STATS_UNIT_BYTES,
+    KVM_STATS_UNIT_CYCLES, KVM_STATS_UNIT_MASK, KVM_STATS_UNIT_NONE, KVM_STATS_UNIT_SECONDS,
+};
+
+// The format is described in
+// https://docs.kernel.org/virt/kvm/api.html#kvm-get-stats-fd
+
+/// Size of `struct kvm_stats_header`
+const HEADER_SIZE: usize = 24;
+
+/// Size of `struct kvm_stats_desc` without the name
+const DESC_HEADER_SIZE: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StatKind {
+    /// Monotonically increasing counter
+    Cumulative,
+
+    /// Current value
+    Instant,
+
+    /// Highest value seen so far
+    Peak,
+
+    /// Histogram with buckets of `bucket_size` width
+    LinearHistogram,
+
+    /// Histogram whose bucket `i` covers `[2^(i-1), 2^i)`, except that bucket
+    /// 0 holds zero
+    LogHistogram,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StatUnit {
+    None,
+    Bytes,
+    Seconds,
+    Cycles,
+    Boolean,
+}
+
+/// Description of a statistic
+#[derive(Debug, Clone)]
+pub struct StatDesc {
+    pub name: String,
+    pub kind: StatKind,
+    pub unit: StatUnit,
+
+    /// Values are in units of `base^exponent`, where `base` is 10 or 2.
+    pub base: u32,
+    pub exponent: i16,
+
+    /// Number of values, which is more than one for histograms
+    pub size: u16,
+
+    /// Width of the buckets of a linear histogram
+    pub bucket_size: u32,
+
+    /// Offset of the values from the start of the data block
+    offset: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StatValue {
+    Scalar(u64),
+    Histogram(Vec<u64>),
+}
+
+impl fmt::Display for StatValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Scalar(value) => write!(f, "{value}"),
+            Self::Histogram(buckets) => {
+                for (i, value) in buckets.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{value}")?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Stat {
+    pub desc: Arc<StatDesc>,
+    pub value: StatValue,
+}
+
+/// Values of the statistics of a VM or a vCPU at a point in time
+#[derive(Debug, Clone)]
+pub struct StatsSnapshot {
+    /// Identifier of the VM or the vCPU assigned by KVM
+    pub id: String,
+
+    pub stats: Vec<Stat>,
+}
+
+impl StatsSnapshot {
+    pub fn get(&self, name: &str) -> Option<&StatValue> {
+        self.stats
+            .iter()
+            .find(|stat| stat.desc.name == name)
+            .map(|stat| &stat.value)
+    }
+}
+
+/// Reads the binary statistics of a VM or a vCPU
+///
+/// The descriptors are parsed once, and only the values are read on each
+/// snapshot.
+pub struct StatsReader {
+    file: File,
+    id: String,
+    descs: Vec<Arc<StatDesc>>,
+    data_offset: u64,
+    data_size: usize,
+}
+
+impl StatsReader {
+    pub fn new(file: File) -> Result<Self> {
+        let mut header = [0; HEADER_SIZE];
+        file.read_exact_at(&mut header, 0)?;
+        let field = |i: usize| read_u32(&header, i * 4);
+        let name_size = field(1) as usize;
+        let num_desc = field(2) as usize;
+        let id_offset = field(3);
+        let desc_offset = field(4);
+        let data_offset = field(5);
+
+        let mut id = vec![0; name_size];
+        file.read_exact_at(&mut id, id_offset.into())?;
+
+        let desc_size = DESC_HEADER_SIZE + name_size;
+        let mut buf = vec![0; desc_size * num_desc];
+        file.read_exact_at(&mut buf, desc_offset.into())?;
+        let mut descs = Vec::with_capacity(num_desc);
+        let mut data_size = 0;
+        for desc in buf.chunks_exact(desc_size) {
+            let flags = read_u32(desc, 0);
+            let size = u16::from_le_bytes([desc[6], desc[7]]);
+            let offset = read_u32(desc, 8);
+            data_size = data_size.max(offset as usize + usize::from(size) * 8);
+
+            let kind = match flags & KVM_STATS_TYPE_MASK {
+                KVM_STATS_TYPE_CUMULATIVE => StatKind::Cumulative,
+                KVM_STATS_TYPE_INSTANT => StatKind::Instant,
+                KVM_STATS_TYPE_PEAK => StatKind::Peak,
+                KVM_STATS_TYPE_LINEAR_HIST => StatKind::LinearHistogram,
+                KVM_STATS_TYPE_LOG_HIST => StatKind::LogHistogram,
+                _ => continue,
+            };
+            let unit = match flags & KVM_STATS_UNIT_MASK {
+                KVM_STATS_UNIT_NONE => StatUnit::None,
+                KVM_STATS_UNIT_BYTES => StatUnit::Bytes,
+                KVM_STATS_UNIT_SECONDS => StatUnit::Seconds,
+                KVM_STATS_UNIT_CYCLES => StatUnit::Cycles,
+                KVM_STATS_UNIT_BOOLEAN => StatUnit::Boolean,
+                _ => continue,
+            };
+            let base = match flags & KVM_STATS_BASE_MASK {
+                KVM_STATS_BASE_POW10 => 10,
+                KVM_STATS_BASE_POW2 => 2,
+                _ => continue,
+            };
+            descs.push(Arc::new(StatDesc {
+                name: c_string(&desc[DESC_HEADER_SIZE..])?,
+                kind,
+                unit,
+                base,
+                exponent: i16::from_le_bytes([desc[4], desc[5]]),
+                size,
+                bucket_size: read_u32(desc, 12),
+                offset,
+            }));
+        }
+
+        Ok(Self {
+            file,
+            id: c_string(&id)?,
+            descs,
+            data_offset: data_offset.into(),
+            data_size,
+        })
+    }
+
+    pub fn descs(&self) -> impl Iterator<Item = &StatDesc> {
+        self.descs.iter().map(AsRef::as_ref)
+    }
+
+    pub fn snapshot(&self) -> Result<StatsSnapshot> {
+        let mut data = vec![0; self.data_size];
+        self.file.read_exact_at(&mut data, self.data_offset)?;
+        let stats = self
+            .descs
+            .iter()
+            .map(|desc| {
+                let offset = desc.offset as usize;
+                let mut values = data[offset..offset + usize::from(desc.size) * 8]
+                    .chunks_exact(8)
+                    .map(|value| u64::from_le_bytes(value.try_into().unwrap()));
+                let value = match desc.kind {
+                    StatKind::LinearHistogram | StatKind::LogHistogram => {
+                        StatValue::Histogram(values.collect())
+                    }
+                    StatKind::Cumulative | StatKind::Instant | StatKind::Peak => {
+                        StatValue::Scalar(values.next().unwrap_or(0))
+                    }
+                };
+                Stat {
+                    desc: desc.clone(),
+                    value,
+                }
+            })
+            .collect();
+        Ok(StatsSnapshot {
+            id: self.id.clone(),
+            stats,
+        })
+    }
+}
+
+fn read_u32(buf: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(buf[offset..offset + 4].try_into().unwrap())
+}
+
+fn c_string(buf: &[u8]) -> Result<String> {
+    let len = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+    String::from_utf8(buf[..len].to_vec()).map_err(|_| Error::InvalidKvmStats)
+}
+
+/// Reads the binary statistics of a VM and its vCPUs
+pub struct KvmStats {
+    pub(crate) vm: StatsReader,
+    pub(crate) vcpus: Vec<StatsReader>,
+}
+
+impl KvmStats {
+    pub fn snapshot(&self) -> Result<KvmStatsSnapshot> {
+        Ok(KvmStatsSnapshot {
+            vm: self.vm.snapshot()?,
+            vcpus: self
+                .vcpus
+                .iter()
+                .map(StatsReader::snapshot)
+                .collect::<Result<_>>()?,
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct KvmStatsSnapshot {
+    pub vm: StatsSnapshot,
+
+    /// Snapshots indexed by vCPU ID
+    pub vcpus: Vec<StatsSnapshot>,
+}
+
+impl fmt::Display for KvmStatsSnapshot {
+    /// Writes one `<scope>.<name> <value>` line per statistic.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for stat in &self.vm.stats {
+            writeln!(f, "vm.{} {}", stat.desc.name, stat.value)?;
+        }
+        for (id, snapshot) in self.vcpus.iter().enumerate() {
+            for stat in &snapshot.stats {
+                writeln!(f, "vcpu{id}.{} {}", stat.desc.name, stat.value)?;
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,71 @@
+use crate::Result;
+use nix::{
+    errno::Errno,
+    libc,
+    sched::{sched_setaffinity, CpuSet},
+    unistd::Pid,
+};
+
+/// Scheduling policy of a thread
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SchedPolicy {
+    /// `SCHED_FIFO` real-time policy with the given static priority (1-99)
+    Fifo(i32),
+
+    /// `SCHED_OTHER` policy with the given nice value (-20-19)
+    Nice(i32),
+}
+
+/// Host-side placement and scheduling of a thread
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ThreadConfig {
+    affinity: Option<Vec<usize>>,
+    policy: Option<SchedPolicy>,
+}
+
+impl ThreadConfig {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Restricts the thread to the given host CPUs.
+    #[must_use]
+    pub fn affinity(mut self, cpus: impl IntoIterator<Item = usize>) -> Self {
+        self.affinity = Some(cpus.into_iter().collect());
+        self
+    }
+
+    #[must_use]
+    pub fn policy(mut self, policy: SchedPolicy) -> Self {
+        self.policy = Some(policy);
+        self
+    }
+
+    /// Applies the configuration to the calling thread.
+    pub fn apply(&self) -> Result<()> {
+        if let Some(cpus) = &self.affinity {
+            let mut cpu_set = CpuSet::new();
+            for &cpu in cpus {
+                cpu_set.set(cpu)?;
+            }
+            // PID 0 refers to the calling thread.
+            sched_setaffinity(Pid::from_raw(0), &cpu_set)?;
+        }
+        match self.policy {
+            Some(SchedPolicy::Fifo(priority)) => {
+                let param = libc::sched_param {
+                    sched_priority: priority,
+                };
+                Errno::result(unsafe {
+                    libc::sched_setscheduler(0, libc::SCHED_FIFO, &raw const param)
+                })?;
+            }
+            Some(SchedPolicy::Nice(nice)) => {
+                // On Linux, nice values are per-thread attributes.
+                Errno::result(unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, nice) })?;
+            }
+            None => {}
+        }
+        Ok(())
+    }
+}
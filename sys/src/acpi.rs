@@ -1078,6 +1078,7 @@ pub const ACPI_PLD_PANEL_BACK: u32 = 5;
 pub const ACPI_PLD_PANEL_UNKNOWN: u32 = 6;
 pub type u8_ = ::std::os::raw::c_uchar;
 pub type u64_ = ::std::os::raw::c_ulong;
+pub type u16_ = ::std::os::raw::c_ushort;
 pub type u32_ = ::std::os::raw::c_uint;
 #[doc = " Master ACPI Table Header. This common header is used by all ACPI tables\n except the RSDP and FACS.\n"]
 #[repr(C, packed)]
@@ -1093,6 +1094,16 @@ pub struct acpi_table_header {
     pub asl_compiler_id: [::std::os::raw::c_char; 4usize],
     pub asl_compiler_revision: u32_,
 }
+#[doc = " GAS - Generic Address Structure (ACPI 2.0+)\n"]
+#[repr(C, packed)]
+#[derive(Debug, Default, Copy, Clone, zerocopy :: AsBytes)]
+pub struct acpi_generic_address {
+    pub space_id: u8_,
+    pub bit_width: u8_,
+    pub bit_offset: u8_,
+    pub access_width: u8_,
+    pub address: u64_,
+}
 #[doc = " RSDP - Root System Description Pointer (Signature is \"RSD PTR \")\n        Version 2\n"]
 #[repr(C, packed)]
 #[derive(Debug, Default, Copy, Clone, zerocopy :: AsBytes)]
@@ -1122,6 +1133,67 @@ pub struct acpi_table_madt {
     pub address: u32_,
     pub flags: u32_,
 }
+#[doc = " FADT - Fixed ACPI Description Table (Signature \"FACP\")\n"]
+#[repr(C, packed)]
+#[derive(Debug, Default, Copy, Clone, zerocopy :: AsBytes)]
+pub struct acpi_table_fadt {
+    pub header: acpi_table_header,
+    pub facs: u32_,
+    pub dsdt: u32_,
+    pub model: u8_,
+    pub preferred_profile: u8_,
+    pub sci_interrupt: u16_,
+    pub smi_command: u32_,
+    pub acpi_enable: u8_,
+    pub acpi_disable: u8_,
+    pub s4_bios_request: u8_,
+    pub pstate_control: u8_,
+    pub pm1a_event_block: u32_,
+    pub pm1b_event_block: u32_,
+    pub pm1a_control_block: u32_,
+    pub pm1b_control_block: u32_,
+    pub pm2_control_block: u32_,
+    pub pm_timer_block: u32_,
+    pub gpe0_block: u32_,
+    pub gpe1_block: u32_,
+    pub pm1_event_length: u8_,
+    pub pm1_control_length: u8_,
+    pub pm2_control_length: u8_,
+    pub pm_timer_length: u8_,
+    pub gpe0_block_length: u8_,
+    pub gpe1_block_length: u8_,
+    pub gpe1_base: u8_,
+    pub cst_control: u8_,
+    pub c2_latency: u16_,
+    pub c3_latency: u16_,
+    pub flush_size: u16_,
+    pub flush_stride: u16_,
+    pub duty_offset: u8_,
+    pub duty_width: u8_,
+    pub day_alarm: u8_,
+    pub month_alarm: u8_,
+    pub century: u8_,
+    pub boot_flags: u16_,
+    pub reserved: u8_,
+    pub flags: u32_,
+    pub reset_register: acpi_generic_address,
+    pub reset_value: u8_,
+    pub arm_boot_flags: u16_,
+    pub minor_revision: u8_,
+    pub Xfacs: u64_,
+    pub Xdsdt: u64_,
+    pub xpm1a_event_block: acpi_generic_address,
+    pub xpm1b_event_block: acpi_generic_address,
+    pub xpm1a_control_block: acpi_generic_address,
+    pub xpm1b_control_block: acpi_generic_address,
+    pub xpm2_control_block: acpi_generic_address,
+    pub xpm_timer_block: acpi_generic_address,
+    pub xgpe0_block: acpi_generic_address,
+    pub xgpe1_block: acpi_generic_address,
+    pub sleep_control: acpi_generic_address,
+    pub sleep_status: acpi_generic_address,
+    pub hypervisor_id: u64_,
+}
 pub const acpi_madt_type_ACPI_MADT_TYPE_LOCAL_APIC: acpi_madt_type = 0;
 pub const acpi_madt_type_ACPI_MADT_TYPE_IO_APIC: acpi_madt_type = 1;
 pub const acpi_madt_type_ACPI_MADT_TYPE_INTERRUPT_OVERRIDE: acpi_madt_type = 2;
@@ -441,7 +441,9 @@ pub struct elf32_hdr {
 }
 pub type Elf32_Ehdr = elf32_hdr;
 #[repr(C)]
-#[derive(Debug, Copy, Clone, zerocopy :: FromZeroes, zerocopy :: FromBytes)]
+#[derive(
+    Debug, Copy, Clone, zerocopy :: FromZeroes, zerocopy :: FromBytes, zerocopy :: AsBytes,
+)]
 pub struct elf64_hdr {
     pub e_ident: [::std::os::raw::c_uchar; 16usize],
     pub e_type: Elf64_Half,
@@ -473,7 +475,9 @@ pub struct elf32_phdr {
 }
 pub type Elf32_Phdr = elf32_phdr;
 #[repr(C)]
-#[derive(Debug, Copy, Clone, zerocopy :: FromZeroes, zerocopy :: FromBytes)]
+#[derive(
+    Debug, Copy, Clone, zerocopy :: FromZeroes, zerocopy :: FromBytes, zerocopy :: AsBytes,
+)]
 pub struct elf64_phdr {
     pub p_type: Elf64_Word,
     pub p_flags: Elf64_Word,
@@ -524,7 +528,9 @@ pub struct elf32_note {
 }
 pub type Elf32_Nhdr = elf32_note;
 #[repr(C)]
-#[derive(Debug, Copy, Clone, zerocopy :: FromZeroes, zerocopy :: FromBytes)]
+#[derive(
+    Debug, Copy, Clone, zerocopy :: FromZeroes, zerocopy :: FromBytes, zerocopy :: AsBytes,
+)]
 pub struct elf64_note {
     pub n_namesz: Elf64_Word,
     pub n_descsz: Elf64_Word,
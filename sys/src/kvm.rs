@@ -1,6 +1,8 @@
 use kvm_bindings::{
-    kvm_cpuid2, kvm_irq_level, kvm_pit_config, kvm_regs, kvm_sregs, kvm_userspace_memory_region,
-    KVMIO,
+    kvm_clear_dirty_log, kvm_clock_data, kvm_coalesced_mmio_zone, kvm_cpuid2, kvm_debugregs,
+    kvm_dirty_log, kvm_enable_cap, kvm_fpu, kvm_guest_debug, kvm_irq_level, kvm_irqchip,
+    kvm_lapic_state, kvm_mp_state, kvm_msr_list, kvm_msrs, kvm_pit_config, kvm_pit_state2,
+    kvm_regs, kvm_sregs, kvm_userspace_memory_region, kvm_vcpu_events, kvm_xcrs, kvm_xsave, KVMIO,
 };
 use nix::{ioctl_read, ioctl_readwrite, ioctl_write_int_bad, ioctl_write_ptr, request_code_none};
 
@@ -22,6 +24,7 @@ macro_rules! ioctl_none {
 
 ioctl_none!(get_api_version, KVMIO, 0x00);
 ioctl_write_int_bad!(create_vm, request_code_none!(KVMIO, 0x01));
+ioctl_readwrite!(get_msr_index_list, KVMIO, 0x02, kvm_msr_list);
 ioctl_write_int_bad!(check_extension, request_code_none!(KVMIO, 0x03));
 ioctl_none!(get_vcpu_mmap_size, KVMIO, 0x04);
 ioctl_readwrite!(get_supported_cpuid, KVMIO, 0x05, kvm_cpuid2);
@@ -32,11 +35,54 @@ ioctl_write_ptr!(
     kvm_userspace_memory_region
 );
 ioctl_write_int_bad!(create_vpu, request_code_none!(KVMIO, 0x41));
+ioctl_write_ptr!(get_dirty_log, KVMIO, 0x42, kvm_dirty_log);
 ioctl_none!(create_irqchip, KVMIO, 0x60);
 ioctl_write_ptr!(irq_line, KVMIO, 0x61, kvm_irq_level);
+ioctl_readwrite!(get_irqchip, KVMIO, 0x62, kvm_irqchip);
+ioctl_read!(set_irqchip, KVMIO, 0x63, kvm_irqchip);
+ioctl_write_ptr!(
+    register_coalesced_mmio,
+    KVMIO,
+    0x67,
+    kvm_coalesced_mmio_zone
+);
+ioctl_write_ptr!(
+    unregister_coalesced_mmio,
+    KVMIO,
+    0x68,
+    kvm_coalesced_mmio_zone
+);
 ioctl_write_ptr!(create_pit2, KVMIO, 0x77, kvm_pit_config);
+ioctl_write_ptr!(set_clock, KVMIO, 0x7b, kvm_clock_data);
+ioctl_read!(get_clock, KVMIO, 0x7c, kvm_clock_data);
 ioctl_none!(run, KVMIO, 0x80);
+ioctl_read!(get_regs, KVMIO, 0x81, kvm_regs);
 ioctl_write_ptr!(set_regs, KVMIO, 0x82, kvm_regs);
 ioctl_read!(get_sregs, KVMIO, 0x83, kvm_sregs);
 ioctl_write_ptr!(set_sregs, KVMIO, 0x84, kvm_sregs);
+ioctl_readwrite!(get_msrs, KVMIO, 0x88, kvm_msrs);
+ioctl_write_ptr!(set_msrs, KVMIO, 0x89, kvm_msrs);
+ioctl_write_ptr!(set_fpu, KVMIO, 0x8d, kvm_fpu);
+ioctl_read!(get_lapic, KVMIO, 0x8e, kvm_lapic_state);
+ioctl_write_ptr!(set_lapic, KVMIO, 0x8f, kvm_lapic_state);
 ioctl_write_ptr!(set_cpuid2, KVMIO, 0x90, kvm_cpuid2);
+ioctl_readwrite!(get_cpuid2, KVMIO, 0x91, kvm_cpuid2);
+ioctl_read!(get_mp_state, KVMIO, 0x98, kvm_mp_state);
+ioctl_write_ptr!(set_mp_state, KVMIO, 0x99, kvm_mp_state);
+ioctl_write_ptr!(set_guest_debug, KVMIO, 0x9b, kvm_guest_debug);
+ioctl_read!(get_pit2, KVMIO, 0x9f, kvm_pit_state2);
+ioctl_read!(get_vcpu_events, KVMIO, 0x9f, kvm_vcpu_events);
+ioctl_write_ptr!(set_pit2, KVMIO, 0xa0, kvm_pit_state2);
+ioctl_write_ptr!(set_vcpu_events, KVMIO, 0xa0, kvm_vcpu_events);
+ioctl_read!(get_debugregs, KVMIO, 0xa1, kvm_debugregs);
+ioctl_write_ptr!(set_debugregs, KVMIO, 0xa2, kvm_debugregs);
+ioctl_write_int_bad!(set_tsc_khz, request_code_none!(KVMIO, 0xa2));
+ioctl_write_ptr!(enable_cap, KVMIO, 0xa3, kvm_enable_cap);
+ioctl_none!(get_tsc_khz, KVMIO, 0xa3);
+ioctl_read!(get_xsave, KVMIO, 0xa4, kvm_xsave);
+ioctl_write_ptr!(set_xsave, KVMIO, 0xa5, kvm_xsave);
+ioctl_read!(get_xcrs, KVMIO, 0xa6, kvm_xcrs);
+ioctl_write_ptr!(set_xcrs, KVMIO, 0xa7, kvm_xcrs);
+ioctl_readwrite!(clear_dirty_log, KVMIO, 0xc0, kvm_clear_dirty_log);
+ioctl_none!(reset_dirty_rings, KVMIO, 0xc7);
+ioctl_none!(get_stats_fd, KVMIO, 0xce);
@@ -0,0 +1,36 @@
+/* automatically generated by rust-bindgen 0.69.4 */
+
+pub const KVM_CPUID_SIGNATURE: u32 = 1073741824;
+pub const KVM_SIGNATURE: &[u8; 13] = b"KVMKVMKVM\0\0\0\0";
+pub const KVM_CPUID_FEATURES: u32 = 1073741825;
+pub const KVM_FEATURE_CLOCKSOURCE: u32 = 0;
+pub const KVM_FEATURE_NOP_IO_DELAY: u32 = 1;
+pub const KVM_FEATURE_MMU_OP: u32 = 2;
+pub const KVM_FEATURE_CLOCKSOURCE2: u32 = 3;
+pub const KVM_FEATURE_ASYNC_PF: u32 = 4;
+pub const KVM_FEATURE_STEAL_TIME: u32 = 5;
+pub const KVM_FEATURE_PV_EOI: u32 = 6;
+pub const KVM_FEATURE_PV_UNHALT: u32 = 7;
+pub const KVM_FEATURE_PV_TLB_FLUSH: u32 = 9;
+pub const KVM_FEATURE_ASYNC_PF_VMEXIT: u32 = 10;
+pub const KVM_FEATURE_PV_SEND_IPI: u32 = 11;
+pub const KVM_FEATURE_POLL_CONTROL: u32 = 12;
+pub const KVM_FEATURE_PV_SCHED_YIELD: u32 = 13;
+pub const KVM_FEATURE_ASYNC_PF_INT: u32 = 14;
+pub const KVM_FEATURE_MSI_EXT_DEST_ID: u32 = 15;
+pub const KVM_FEATURE_HC_MAP_GPA_RANGE: u32 = 16;
+pub const KVM_FEATURE_MIGRATION_CONTROL: u32 = 17;
+pub const KVM_HINTS_REALTIME: u32 = 0;
+pub const KVM_FEATURE_CLOCKSOURCE_STABLE_BIT: u32 = 24;
+pub const MSR_KVM_WALL_CLOCK: u32 = 17;
+pub const MSR_KVM_SYSTEM_TIME: u32 = 18;
+pub const KVM_MSR_ENABLED: u32 = 1;
+pub const MSR_KVM_WALL_CLOCK_NEW: u32 = 1263947008;
+pub const MSR_KVM_SYSTEM_TIME_NEW: u32 = 1263947009;
+pub const MSR_KVM_ASYNC_PF_EN: u32 = 1263947010;
+pub const MSR_KVM_STEAL_TIME: u32 = 1263947011;
+pub const MSR_KVM_PV_EOI_EN: u32 = 1263947012;
+pub const MSR_KVM_POLL_CONTROL: u32 = 1263947013;
+pub const MSR_KVM_ASYNC_PF_INT: u32 = 1263947014;
+pub const MSR_KVM_ASYNC_PF_ACK: u32 = 1263947015;
+pub const MSR_KVM_MIGRATION_CONTROL: u32 = 1263947016;
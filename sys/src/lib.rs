@@ -11,8 +11,11 @@ pub mod e820;
 pub mod elf;
 pub mod elfnote;
 pub mod kvm;
+pub mod kvm_para;
 pub mod multiboot;
 pub mod serial_reg;
 pub mod start_info;
+pub mod userfaultfd;
+pub mod virtio;
 
 pub use kvm_bindings;
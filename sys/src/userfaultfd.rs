@@ -0,0 +1,140 @@
+/* automatically generated by rust-bindgen 0.69.4 */
+
+pub const UFFD_API: u32 = 170;
+pub const UFFD_EVENT_PAGEFAULT: u32 = 18;
+pub const UFFD_EVENT_FORK: u32 = 19;
+pub const UFFD_EVENT_REMAP: u32 = 20;
+pub const UFFD_EVENT_REMOVE: u32 = 21;
+pub const UFFD_EVENT_UNMAP: u32 = 22;
+pub const UFFD_PAGEFAULT_FLAG_WRITE: u32 = 1;
+pub const UFFD_PAGEFAULT_FLAG_WP: u32 = 2;
+pub const UFFD_PAGEFAULT_FLAG_MINOR: u32 = 4;
+pub const UFFD_FEATURE_PAGEFAULT_FLAG_WP: u32 = 1;
+pub const UFFD_FEATURE_EVENT_FORK: u32 = 2;
+pub const UFFD_FEATURE_EVENT_REMAP: u32 = 4;
+pub const UFFD_FEATURE_EVENT_REMOVE: u32 = 8;
+pub const UFFD_FEATURE_MISSING_HUGETLBFS: u32 = 16;
+pub const UFFD_FEATURE_MISSING_SHMEM: u32 = 32;
+pub const UFFD_FEATURE_EVENT_UNMAP: u32 = 64;
+pub const UFFD_FEATURE_SIGBUS: u32 = 128;
+pub const UFFD_FEATURE_THREAD_ID: u32 = 256;
+pub const UFFD_FEATURE_MINOR_HUGETLBFS: u32 = 512;
+pub const UFFD_FEATURE_MINOR_SHMEM: u32 = 1024;
+pub const UFFD_FEATURE_EXACT_ADDRESS: u32 = 2048;
+pub const UFFD_FEATURE_WP_HUGETLBFS_SHMEM: u32 = 4096;
+pub const UFFDIO_REGISTER_MODE_MISSING: u32 = 1;
+pub const UFFDIO_REGISTER_MODE_WP: u32 = 2;
+pub const UFFDIO_REGISTER_MODE_MINOR: u32 = 4;
+pub const UFFDIO_COPY_MODE_DONTWAKE: u32 = 1;
+pub const UFFDIO_COPY_MODE_WP: u32 = 2;
+pub const UFFDIO_ZEROPAGE_MODE_DONTWAKE: u32 = 1;
+pub const UFFD_USER_MODE_ONLY: u32 = 1;
+pub type __u8 = ::std::os::raw::c_uchar;
+pub type __u16 = ::std::os::raw::c_ushort;
+pub type __u32 = ::std::os::raw::c_uint;
+pub type __s64 = ::std::os::raw::c_longlong;
+pub type __u64 = ::std::os::raw::c_ulonglong;
+#[repr(C, packed)]
+#[derive(Copy, Clone)]
+pub struct uffd_msg {
+    pub event: __u8,
+    pub reserved1: __u8,
+    pub reserved2: __u16,
+    pub reserved3: __u32,
+    pub arg: uffd_msg__bindgen_ty_1,
+}
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub union uffd_msg__bindgen_ty_1 {
+    pub pagefault: uffd_msg__bindgen_ty_1__bindgen_ty_1,
+    pub fork: uffd_msg__bindgen_ty_1__bindgen_ty_2,
+    pub remap: uffd_msg__bindgen_ty_1__bindgen_ty_3,
+    pub remove: uffd_msg__bindgen_ty_1__bindgen_ty_4,
+    pub reserved: uffd_msg__bindgen_ty_1__bindgen_ty_5,
+}
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct uffd_msg__bindgen_ty_1__bindgen_ty_1 {
+    pub flags: __u64,
+    pub address: __u64,
+    pub feat: uffd_msg__bindgen_ty_1__bindgen_ty_1__bindgen_ty_1,
+}
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub union uffd_msg__bindgen_ty_1__bindgen_ty_1__bindgen_ty_1 {
+    pub ptid: __u32,
+}
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct uffd_msg__bindgen_ty_1__bindgen_ty_2 {
+    pub ufd: __u32,
+}
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct uffd_msg__bindgen_ty_1__bindgen_ty_3 {
+    pub from: __u64,
+    pub to: __u64,
+    pub len: __u64,
+}
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct uffd_msg__bindgen_ty_1__bindgen_ty_4 {
+    pub start: __u64,
+    pub end: __u64,
+}
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct uffd_msg__bindgen_ty_1__bindgen_ty_5 {
+    pub reserved1: __u64,
+    pub reserved2: __u64,
+    pub reserved3: __u64,
+}
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct uffdio_api {
+    pub api: __u64,
+    pub features: __u64,
+    pub ioctls: __u64,
+}
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct uffdio_range {
+    pub start: __u64,
+    pub len: __u64,
+}
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct uffdio_register {
+    pub range: uffdio_range,
+    pub mode: __u64,
+    pub ioctls: __u64,
+}
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct uffdio_copy {
+    pub dst: __u64,
+    pub src: __u64,
+    pub len: __u64,
+    pub mode: __u64,
+    pub copy: __s64,
+}
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct uffdio_zeropage {
+    pub range: uffdio_range,
+    pub mode: __u64,
+    pub zeropage: __s64,
+}
+
+// ioctl numbers can be found in
+// https://git.kernel.org/pub/scm/linux/kernel/git/torvalds/linux.git/tree/include/uapi/linux/userfaultfd.h
+
+pub const UFFDIO: u8 = 0xaa;
+
+nix::ioctl_readwrite!(uffdio_register, UFFDIO, 0x00, uffdio_register);
+nix::ioctl_read!(uffdio_unregister, UFFDIO, 0x01, uffdio_range);
+nix::ioctl_read!(uffdio_wake, UFFDIO, 0x02, uffdio_range);
+nix::ioctl_readwrite!(uffdio_copy, UFFDIO, 0x03, uffdio_copy);
+nix::ioctl_readwrite!(uffdio_zeropage, UFFDIO, 0x04, uffdio_zeropage);
+nix::ioctl_readwrite!(uffdio_api, UFFDIO, 0x3f, uffdio_api);
+nix::ioctl_write_int_bad!(userfaultfd_ioc_new, nix::request_code_none!(UFFDIO, 0x00));
@@ -0,0 +1,225 @@
+/* automatically generated by rust-bindgen 0.69.4 */
+
+pub const VIRTIO_CONFIG_S_ACKNOWLEDGE: u32 = 1;
+pub const VIRTIO_CONFIG_S_DRIVER: u32 = 2;
+pub const VIRTIO_CONFIG_S_DRIVER_OK: u32 = 4;
+pub const VIRTIO_CONFIG_S_FEATURES_OK: u32 = 8;
+pub const VIRTIO_CONFIG_S_NEEDS_RESET: u32 = 64;
+pub const VIRTIO_CONFIG_S_FAILED: u32 = 128;
+pub const VIRTIO_TRANSPORT_F_START: u32 = 28;
+pub const VIRTIO_TRANSPORT_F_END: u32 = 41;
+pub const VIRTIO_F_NOTIFY_ON_EMPTY: u32 = 24;
+pub const VIRTIO_F_ANY_LAYOUT: u32 = 27;
+pub const VIRTIO_F_VERSION_1: u32 = 32;
+pub const VIRTIO_F_ACCESS_PLATFORM: u32 = 33;
+pub const VIRTIO_F_IOMMU_PLATFORM: u32 = 33;
+pub const VIRTIO_F_RING_PACKED: u32 = 34;
+pub const VIRTIO_F_IN_ORDER: u32 = 35;
+pub const VIRTIO_F_ORDER_PLATFORM: u32 = 36;
+pub const VIRTIO_F_SR_IOV: u32 = 37;
+pub const VIRTIO_F_RING_RESET: u32 = 40;
+pub const VIRTIO_ID_MEM: u32 = 24;
+pub const VRING_DESC_F_NEXT: u32 = 1;
+pub const VRING_DESC_F_WRITE: u32 = 2;
+pub const VRING_DESC_F_INDIRECT: u32 = 4;
+pub const VRING_USED_F_NO_NOTIFY: u32 = 1;
+pub const VRING_AVAIL_F_NO_INTERRUPT: u32 = 1;
+pub const VIRTIO_RING_F_INDIRECT_DESC: u32 = 28;
+pub const VIRTIO_RING_F_EVENT_IDX: u32 = 29;
+pub const VRING_AVAIL_ALIGN_SIZE: u32 = 2;
+pub const VRING_USED_ALIGN_SIZE: u32 = 4;
+pub const VRING_DESC_ALIGN_SIZE: u32 = 16;
+pub const VIRTIO_MMIO_MAGIC_VALUE: u32 = 0;
+pub const VIRTIO_MMIO_VERSION: u32 = 4;
+pub const VIRTIO_MMIO_DEVICE_ID: u32 = 8;
+pub const VIRTIO_MMIO_VENDOR_ID: u32 = 12;
+pub const VIRTIO_MMIO_DEVICE_FEATURES: u32 = 16;
+pub const VIRTIO_MMIO_DEVICE_FEATURES_SEL: u32 = 20;
+pub const VIRTIO_MMIO_DRIVER_FEATURES: u32 = 32;
+pub const VIRTIO_MMIO_DRIVER_FEATURES_SEL: u32 = 36;
+pub const VIRTIO_MMIO_GUEST_PAGE_SIZE: u32 = 40;
+pub const VIRTIO_MMIO_QUEUE_SEL: u32 = 48;
+pub const VIRTIO_MMIO_QUEUE_NUM_MAX: u32 = 52;
+pub const VIRTIO_MMIO_QUEUE_NUM: u32 = 56;
+pub const VIRTIO_MMIO_QUEUE_ALIGN: u32 = 60;
+pub const VIRTIO_MMIO_QUEUE_PFN: u32 = 64;
+pub const VIRTIO_MMIO_QUEUE_READY: u32 = 68;
+pub const VIRTIO_MMIO_QUEUE_NOTIFY: u32 = 80;
+pub const VIRTIO_MMIO_INTERRUPT_STATUS: u32 = 96;
+pub const VIRTIO_MMIO_INTERRUPT_ACK: u32 = 100;
+pub const VIRTIO_MMIO_STATUS: u32 = 112;
+pub const VIRTIO_MMIO_QUEUE_DESC_LOW: u32 = 128;
+pub const VIRTIO_MMIO_QUEUE_DESC_HIGH: u32 = 132;
+pub const VIRTIO_MMIO_QUEUE_AVAIL_LOW: u32 = 144;
+pub const VIRTIO_MMIO_QUEUE_AVAIL_HIGH: u32 = 148;
+pub const VIRTIO_MMIO_QUEUE_USED_LOW: u32 = 160;
+pub const VIRTIO_MMIO_QUEUE_USED_HIGH: u32 = 164;
+pub const VIRTIO_MMIO_SHM_SEL: u32 = 172;
+pub const VIRTIO_MMIO_SHM_LEN_LOW: u32 = 176;
+pub const VIRTIO_MMIO_SHM_LEN_HIGH: u32 = 180;
+pub const VIRTIO_MMIO_SHM_BASE_LOW: u32 = 184;
+pub const VIRTIO_MMIO_SHM_BASE_HIGH: u32 = 188;
+pub const VIRTIO_MMIO_CONFIG_GENERATION: u32 = 252;
+pub const VIRTIO_MMIO_CONFIG: u32 = 256;
+pub const VIRTIO_MMIO_INT_VRING: u32 = 1;
+pub const VIRTIO_MMIO_INT_CONFIG: u32 = 2;
+pub const VIRTIO_MEM_F_ACPI_PXM: u32 = 0;
+pub const VIRTIO_MEM_F_UNPLUGGED_INACCESSIBLE: u32 = 1;
+pub const VIRTIO_MEM_REQ_PLUG: u32 = 0;
+pub const VIRTIO_MEM_REQ_UNPLUG: u32 = 1;
+pub const VIRTIO_MEM_REQ_UNPLUG_ALL: u32 = 2;
+pub const VIRTIO_MEM_REQ_STATE: u32 = 3;
+pub const VIRTIO_MEM_RESP_ACK: u32 = 0;
+pub const VIRTIO_MEM_RESP_NACK: u32 = 1;
+pub const VIRTIO_MEM_RESP_BUSY: u32 = 2;
+pub const VIRTIO_MEM_RESP_ERROR: u32 = 3;
+pub const VIRTIO_MEM_STATE_PLUGGED: u32 = 0;
+pub const VIRTIO_MEM_STATE_UNPLUGGED: u32 = 1;
+pub const VIRTIO_MEM_STATE_MIXED: u32 = 2;
+pub type __u8 = ::std::os::raw::c_uchar;
+pub type __u16 = ::std::os::raw::c_ushort;
+pub type __u32 = ::std::os::raw::c_uint;
+pub type __u64 = ::std::os::raw::c_ulonglong;
+pub type __le16 = __u16;
+pub type __le32 = __u32;
+pub type __le64 = __u64;
+pub type __virtio16 = __u16;
+pub type __virtio32 = __u32;
+pub type __virtio64 = __u64;
+#[repr(C)]
+#[derive(
+    Debug, Default, Copy, Clone, zerocopy :: FromZeroes, zerocopy :: FromBytes, zerocopy :: AsBytes,
+)]
+pub struct vring_desc {
+    pub addr: __virtio64,
+    pub len: __virtio32,
+    pub flags: __virtio16,
+    pub next: __virtio16,
+}
+#[repr(C)]
+#[derive(Debug, Default)]
+pub struct vring_avail {
+    pub flags: __virtio16,
+    pub idx: __virtio16,
+    pub ring: __IncompleteArrayField<__virtio16>,
+}
+#[repr(C)]
+#[derive(
+    Debug, Default, Copy, Clone, zerocopy :: FromZeroes, zerocopy :: FromBytes, zerocopy :: AsBytes,
+)]
+pub struct vring_used_elem {
+    pub id: __virtio32,
+    pub len: __virtio32,
+}
+pub type vring_used_elem_t = vring_used_elem;
+#[repr(C)]
+#[derive(Debug, Default)]
+pub struct vring_used {
+    pub flags: __virtio16,
+    pub idx: __virtio16,
+    pub ring: __IncompleteArrayField<vring_used_elem_t>,
+}
+#[repr(C)]
+#[derive(
+    Debug, Default, Copy, Clone, zerocopy :: FromZeroes, zerocopy :: FromBytes, zerocopy :: AsBytes,
+)]
+pub struct virtio_mem_req_plug {
+    pub addr: __virtio64,
+    pub nb_blocks: __virtio16,
+    pub padding: [__virtio16; 3usize],
+}
+#[repr(C)]
+#[derive(
+    Debug, Default, Copy, Clone, zerocopy :: FromZeroes, zerocopy :: FromBytes, zerocopy :: AsBytes,
+)]
+pub struct virtio_mem_req_unplug {
+    pub addr: __virtio64,
+    pub nb_blocks: __virtio16,
+    pub padding: [__virtio16; 3usize],
+}
+#[repr(C)]
+#[derive(
+    Debug, Default, Copy, Clone, zerocopy :: FromZeroes, zerocopy :: FromBytes, zerocopy :: AsBytes,
+)]
+pub struct virtio_mem_req_state {
+    pub addr: __virtio64,
+    pub nb_blocks: __virtio16,
+    pub padding: [__virtio16; 3usize],
+}
+#[repr(C)]
+#[derive(Copy, Clone, zerocopy :: FromZeroes, zerocopy :: FromBytes)]
+pub struct virtio_mem_req {
+    pub type_: __virtio16,
+    pub padding: [__virtio16; 3usize],
+    pub u: virtio_mem_req__bindgen_ty_1,
+}
+#[repr(C)]
+#[derive(Copy, Clone, zerocopy :: FromZeroes, zerocopy :: FromBytes)]
+pub union virtio_mem_req__bindgen_ty_1 {
+    pub plug: virtio_mem_req_plug,
+    pub unplug: virtio_mem_req_unplug,
+    pub state: virtio_mem_req_state,
+}
+#[repr(C)]
+#[derive(
+    Debug, Default, Copy, Clone, zerocopy :: FromZeroes, zerocopy :: FromBytes, zerocopy :: AsBytes,
+)]
+pub struct virtio_mem_resp_state {
+    pub state: __virtio16,
+}
+#[repr(C)]
+#[derive(Copy, Clone, zerocopy :: FromZeroes, zerocopy :: AsBytes)]
+pub struct virtio_mem_resp {
+    pub type_: __virtio16,
+    pub padding: [__virtio16; 3usize],
+    pub u: virtio_mem_resp__bindgen_ty_1,
+}
+#[repr(C)]
+#[derive(Copy, Clone, zerocopy :: FromZeroes, zerocopy :: AsBytes)]
+pub union virtio_mem_resp__bindgen_ty_1 {
+    pub state: virtio_mem_resp_state,
+}
+#[repr(C)]
+#[derive(
+    Debug, Default, Copy, Clone, zerocopy :: FromZeroes, zerocopy :: FromBytes, zerocopy :: AsBytes,
+)]
+pub struct virtio_mem_config {
+    pub block_size: __le64,
+    pub node_id: __le16,
+    pub padding: [__u8; 6usize],
+    pub addr: __le64,
+    pub region_size: __le64,
+    pub usable_region_size: __le64,
+    pub plugged_size: __le64,
+    pub requested_size: __le64,
+}
+#[repr(C)]
+#[derive(Default)]
+pub struct __IncompleteArrayField<T>(::std::marker::PhantomData<T>, [T; 0]);
+impl<T> __IncompleteArrayField<T> {
+    #[inline]
+    pub const fn new() -> Self {
+        __IncompleteArrayField(::std::marker::PhantomData, [])
+    }
+    #[inline]
+    pub fn as_ptr(&self) -> *const T {
+        self as *const _ as *const T
+    }
+    #[inline]
+    pub fn as_mut_ptr(&mut self) -> *mut T {
+        self as *mut _ as *mut T
+    }
+    #[inline]
+    pub unsafe fn as_slice(&self, len: usize) -> &[T] {
+        ::std::slice::from_raw_parts(self.as_ptr(), len)
+    }
+    #[inline]
+    pub unsafe fn as_mut_slice(&mut self, len: usize) -> &mut [T] {
+        ::std::slice::from_raw_parts_mut(self.as_mut_ptr(), len)
+    }
+}
+impl<T> ::std::fmt::Debug for __IncompleteArrayField<T> {
+    fn fmt(&self, fmt: &mut ::std::fmt::Formatter<'_>) -> ::std::fmt::Result {
+        fmt.write_str("__IncompleteArrayField")
+    }
+}